@@ -0,0 +1,134 @@
+// Serves vehicle telemetry and a few commands as JSON over WebSocket.
+//
+//   ardu-gateway [--url <url>] [--key <passphrase>] [--link-id <id>] [--listen <address>]
+//                [--allow-remote] [--allow-origin <origin>]...
+//
+// The url defaults to $ARDU_URL, or udpin:0.0.0.0:14550 when unset, and the address to
+// 127.0.0.1:8765. With --key, the link to the vehicle is signed like with `ardu`. Anyone who
+// can reach the gateway can command the vehicle, so addresses other than loopback are refused
+// without --allow-remote. Browsers are refused unless their page comes from an origin given
+// with --allow-origin, e.g. http://localhost:3000. See `ardutils::gateway` for the messages.
+
+use std::sync::Arc;
+
+use ardutils::{
+    connection::{MavlinkConnection, SharedConnection, UrlConnection},
+    gateway,
+    signing::{self, SigningKey},
+};
+use mavlink::ardupilotmega::{
+    MavAutopilot, MavMessage, MavModeFlag, MavState, MavType, HEARTBEAT_DATA,
+};
+
+type Connection = Arc<SharedConnection>;
+
+fn fail(message: impl std::fmt::Display) -> ! {
+    eprintln!("{message}");
+    std::process::exit(1);
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: ardu-gateway [--url <url>] [--key <passphrase>] [--link-id <id>] \
+         [--listen <address>] [--allow-remote] [--allow-origin <origin>]..."
+    );
+    std::process::exit(2);
+}
+
+// Autopilots only stream telemetry once they have heard from a GCS.
+fn start_heartbeats(connection: Connection) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            let _ = connection.send(&MavMessage::HEARTBEAT(HEARTBEAT_DATA {
+                custom_mode: 0,
+                mavtype: MavType::MAV_TYPE_GCS,
+                autopilot: MavAutopilot::MAV_AUTOPILOT_INVALID,
+                base_mode: MavModeFlag::empty(),
+                system_status: MavState::MAV_STATE_ACTIVE,
+                mavlink_version: 3,
+            }));
+        }
+    });
+}
+
+async fn run(args: Vec<String>) {
+    let mut url = std::env::var("ARDU_URL").unwrap_or_else(|_| "udpin:0.0.0.0:14550".to_string());
+    let mut listen = "127.0.0.1:8765".to_string();
+    let mut allow_remote = false;
+    let mut options = gateway::Options::default();
+    let mut key = None;
+    let mut link_id = 0;
+    let mut args = args.into_iter();
+    while let Some(flag) = args.next() {
+        match flag.as_str() {
+            "--url" | "-u" => url = args.next().unwrap_or_else(|| usage()),
+            "--key" => {
+                key = Some(SigningKey::from_passphrase(
+                    &args.next().unwrap_or_else(|| usage()),
+                ))
+            }
+            "--link-id" => {
+                link_id = args
+                    .next()
+                    .and_then(|id| id.parse().ok())
+                    .unwrap_or_else(|| usage())
+            }
+            "--listen" | "-l" => listen = args.next().unwrap_or_else(|| usage()),
+            "--allow-remote" => allow_remote = true,
+            "--allow-origin" => options
+                .allowed_origins
+                .push(args.next().unwrap_or_else(|| usage())),
+            _ => usage(),
+        }
+    }
+
+    let listener = tokio::net::TcpListener::bind(&listen)
+        .await
+        .unwrap_or_else(|e| fail(format!("{listen}: {e}")));
+    let local = listener
+        .local_addr()
+        .unwrap_or_else(|e| fail(format!("{listen}: {e}")));
+    if !local.ip().is_loopback() && !allow_remote {
+        fail(format!(
+            "{listen}: not a loopback address, the gateway has no authentication. \
+             Pass --allow-remote to serve it anyway."
+        ));
+    }
+
+    let connection = match key {
+        Some(key) => UrlConnection::connect_signed(
+            &url,
+            signing::Options {
+                key,
+                link_id,
+                accept_unsigned: false,
+            },
+        ),
+        None => UrlConnection::connect(&url),
+    };
+    let connection = connection.unwrap_or_else(|e| fail(format!("{url}: {e}")));
+    let connection: Connection = Arc::new(
+        SharedConnection::new(Arc::new(connection)).unwrap_or_else(|e| fail(format!("{url}: {e}"))),
+    );
+    start_heartbeats(connection.clone());
+    eprintln!("{url}: serving ws://{listen}");
+
+    if let Err(e) = gateway::serve(connection, listener, options).await {
+        fail(e);
+    }
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .enable_io()
+        .build()
+        .unwrap_or_else(|e| fail(e));
+
+    runtime.block_on(run(std::env::args().skip(1).collect()));
+
+    // Receiving blocks a thread that would otherwise keep the runtime from shutting down.
+    std::process::exit(0);
+}
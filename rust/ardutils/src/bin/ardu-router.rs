@@ -0,0 +1,72 @@
+// Routes MAVLink between endpoints.
+//
+//   ardu-router <config.yaml>
+//   ardu-router [--key <passphrase>] [--link-id <id>] <url> <url> [<url>...]
+//
+// With --key, every endpoint given on the command line is signed. The configuration file signs
+// endpoints one by one, see `ardutils::router` for its format.
+
+use ardutils::{
+    router::{Config, Router},
+    signing::{self, SigningKey},
+};
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: ardu-router <config.yaml> | \
+         ardu-router [--key <passphrase>] [--link-id <id>] <url> <url> [<url>...]"
+    );
+    std::process::exit(2);
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    let mut key = None;
+    let mut link_id = 0;
+    let mut args = args.as_slice();
+    loop {
+        match args {
+            [flag, value, ..] if flag == "--key" => key = Some(SigningKey::from_passphrase(value)),
+            [flag, value, ..] if flag == "--link-id" => {
+                link_id = value.parse().unwrap_or_else(|_| usage())
+            }
+            _ => break,
+        }
+        args = &args[2..];
+    }
+
+    let config = match args {
+        [] => usage(),
+        [path] if key.is_none() => {
+            let yaml = std::fs::read_to_string(path).unwrap_or_else(|e| {
+                eprintln!("Could not read {path}: {e}");
+                std::process::exit(1);
+            });
+            Config::from_yaml(&yaml).unwrap_or_else(|e| {
+                eprintln!("Invalid configuration {path}: {e}");
+                std::process::exit(1);
+            })
+        }
+        [_] => usage(),
+        urls => {
+            let mut config = Config::from_urls(urls.iter().cloned());
+            for endpoint in &mut config.endpoints {
+                endpoint.signing = key.map(|key| signing::Options {
+                    key,
+                    link_id,
+                    accept_unsigned: false,
+                });
+            }
+            config
+        }
+    };
+
+    for endpoint in &config.endpoints {
+        eprintln!("{}: {}", endpoint.name, endpoint.url);
+    }
+
+    for handle in Router::new(config).run() {
+        let _ = handle.join();
+    }
+}
@@ -0,0 +1,349 @@
+// Common vehicle operations from the command line.
+//
+//   ardu [--url <url>] [--key <passphrase>] [--link-id <id>] <command>
+//
+// The url is any address understood by `mavlink::connect`, it defaults to $ARDU_URL,
+// or udpin:0.0.0.0:14550 when unset. With --key, frames are signed with the key derived from
+// the passphrase, and unsigned ones are dropped.
+
+use std::{path::PathBuf, sync::Arc};
+
+use ardutils::{
+    command::Command,
+    connection::{MavlinkConnection, SharedConnection, UrlConnection},
+    logs,
+    mission::{clear::clear_mission, download::download_mission, file, upload::MissionUpload},
+    mode::ChangeMode,
+    param::{self, ParamChange},
+    signing::{self, SigningKey},
+};
+use mavlink::ardupilotmega::{
+    MavAutopilot, MavCmd, MavMessage, MavModeFlag, MavResult, MavState, MavType, PlaneMode,
+    COMMAND_LONG_DATA, HEARTBEAT_DATA,
+};
+
+const USAGE: &str = "usage: ardu [--url <url>] [--key <passphrase>] [--link-id <id>] <command>
+
+commands:
+  mission upload <file>        upload a .plan or WPL mission
+  mission download <file>      save the vehicle mission, .plan or WPL by extension
+  mission clear
+  param get <name>
+  param set <name> <value>
+  param load <file>            set every parameter of a .parm file that differs
+  param save <file>
+  param diff <file>            compare a .parm file with the vehicle
+  mode <mode>                  e.g. GUIDED, AUTO, RTL
+  arm [--force]
+  disarm [--force]
+  watch [<message>...]         print received messages, by default a few telemetry ones
+  logs list
+  logs download <id|latest> <file>
+  signing setup <passphrase>   provision the vehicle with a signing key, over a trusted link
+  signing disable";
+
+// ArduPilot skips its arming checks when param2 of MAV_CMD_COMPONENT_ARM_DISARM is this value.
+const FORCE_ARM_MAGIC: f32 = 21196.0;
+
+const WATCHED: [&str; 6] = [
+    "HEARTBEAT",
+    "GLOBAL_POSITION_INT",
+    "VFR_HUD",
+    "SYS_STATUS",
+    "BATTERY_STATUS",
+    "STATUSTEXT",
+];
+
+type Connection = Arc<SharedConnection>;
+
+fn fail(message: impl std::fmt::Display) -> ! {
+    eprintln!("{message}");
+    std::process::exit(1);
+}
+
+fn usage() -> ! {
+    eprintln!("{USAGE}");
+    std::process::exit(2);
+}
+
+// Autopilots only stream telemetry once they have heard from a GCS.
+fn start_heartbeats(connection: Connection) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            let _ = connection.send(&MavMessage::HEARTBEAT(HEARTBEAT_DATA {
+                custom_mode: 0,
+                mavtype: MavType::MAV_TYPE_GCS,
+                autopilot: MavAutopilot::MAV_AUTOPILOT_INVALID,
+                base_mode: MavModeFlag::empty(),
+                system_status: MavState::MAV_STATE_ACTIVE,
+                mavlink_version: 3,
+            }));
+        }
+    });
+}
+
+async fn mission(connection: Connection, args: &[String]) {
+    match args {
+        [cmd, path] if cmd == "upload" => {
+            let items = file::load(path).unwrap_or_else(|e| fail(format!("{path}: {e}")));
+            let count = items
+                .upload_mission(connection, Default::default())
+                .await
+                .unwrap_or_else(|e| fail(e));
+            println!("Uploaded {count} items");
+        }
+        [cmd, path] if cmd == "download" => {
+            let items = download_mission(connection, Default::default())
+                .await
+                .unwrap_or_else(|e| fail(e));
+            file::save(path, &items).unwrap_or_else(|e| fail(format!("{path}: {e}")));
+            println!("Downloaded {} items", items.len());
+        }
+        [cmd] if cmd == "clear" => {
+            clear_mission(connection, std::time::Duration::from_secs(2))
+                .await
+                .unwrap_or_else(|e| fail(e));
+            println!("Mission cleared");
+        }
+        _ => usage(),
+    }
+}
+
+async fn vehicle_params(connection: Connection) -> param::Params {
+    param::list_params(connection, Default::default())
+        .await
+        .unwrap_or_else(|e| fail(e))
+        .into_iter()
+        .map(|p| (p.id, p.value))
+        .collect()
+}
+
+fn read_params(path: &str) -> param::Params {
+    let text = std::fs::read_to_string(path).unwrap_or_else(|e| fail(format!("{path}: {e}")));
+    param::parse_params(&text).unwrap_or_else(|e| fail(format!("{path}: {e}")))
+}
+
+async fn params(connection: Connection, args: &[String]) {
+    match args {
+        [cmd, id] if cmd == "get" => {
+            let param = param::get_param(connection, id, Default::default())
+                .await
+                .unwrap_or_else(|e| fail(format!("{id}: {e}")));
+            println!("{},{}", param.id, param.value);
+        }
+        [cmd, id, value] if cmd == "set" => {
+            let value = value
+                .parse()
+                .unwrap_or_else(|_| fail(format!("Invalid value: {value}")));
+            let param = param::set_param(connection, id, value, Default::default())
+                .await
+                .unwrap_or_else(|e| fail(format!("{id}: {e}")));
+            println!("{},{}", param.id, param.value);
+        }
+        [cmd, path] if cmd == "load" => {
+            let wanted = read_params(path);
+            let current = vehicle_params(connection.clone()).await;
+
+            for (id, change) in param::diff(&current, &wanted) {
+                match change {
+                    ParamChange::Changed { to, .. } => {
+                        match param::set_param(connection.clone(), &id, to, Default::default())
+                            .await
+                        {
+                            Ok(param) => println!("{},{}", param.id, param.value),
+                            Err(e) => eprintln!("{id}: {e}"),
+                        }
+                    }
+                    ParamChange::Added(_) => eprintln!("{id}: unknown to the vehicle, skipped"),
+                    ParamChange::Removed(_) => {}
+                }
+            }
+        }
+        [cmd, path] if cmd == "save" => {
+            let params = vehicle_params(connection).await;
+            std::fs::write(path, param::format_params(&params))
+                .unwrap_or_else(|e| fail(format!("{path}: {e}")));
+            println!("Saved {} parameters", params.len());
+        }
+        [cmd, path] if cmd == "diff" => {
+            let saved = read_params(path);
+            let current = vehicle_params(connection).await;
+
+            for (id, change) in param::diff(&saved, &current) {
+                match change {
+                    ParamChange::Changed { from, to } => println!("{id}: {from} -> {to}"),
+                    ParamChange::Added(value) => println!("{id}: only on the vehicle ({value})"),
+                    ParamChange::Removed(value) => println!("{id}: only in {path} ({value})"),
+                }
+            }
+        }
+        _ => usage(),
+    }
+}
+
+async fn mode(connection: Connection, name: &str) {
+    let mode: PlaneMode = serde_yaml::from_str(&format!("PLANE_MODE_{}", name.to_uppercase()))
+        .unwrap_or_else(|_| fail(format!("Unknown mode: {name}")));
+    mode.change_mode(connection)
+        .await
+        .unwrap_or_else(|e| fail(e));
+    println!("Mode changed to {mode:?}");
+}
+
+async fn arm(connection: Connection, arm: bool, force: bool) {
+    let command = COMMAND_LONG_DATA {
+        target_system: connection.target_system(),
+        target_component: connection.target_component(),
+        command: MavCmd::MAV_CMD_COMPONENT_ARM_DISARM,
+        param1: if arm { 1.0 } else { 0.0 },
+        param2: if force { FORCE_ARM_MAGIC } else { 0.0 },
+        ..Default::default()
+    };
+
+    let ack = command
+        .command_retry(connection, std::time::Duration::from_secs(2), 2)
+        .await
+        .unwrap_or_else(|e| fail(e));
+
+    match ack.result {
+        MavResult::MAV_RESULT_ACCEPTED => println!("{}", if arm { "Armed" } else { "Disarmed" }),
+        result => fail(format!("{result:?}")),
+    }
+}
+
+async fn watch(connection: Connection, names: &[String]) {
+    use mavlink::Message;
+
+    let names: Vec<String> = if names.is_empty() {
+        WATCHED.iter().map(|n| n.to_string()).collect()
+    } else {
+        names.iter().map(|n| n.to_uppercase()).collect()
+    };
+
+    let _ = connection
+        .monitor(None, move |msg| {
+            if names.iter().any(|n| n == msg.message_name()) {
+                println!("{msg:?}");
+            }
+            Some(())
+        })
+        .await;
+}
+
+async fn download_logs(connection: Connection, args: &[String]) {
+    let entries = logs::list_logs(connection.clone(), Default::default())
+        .await
+        .unwrap_or_else(|e| fail(e));
+
+    match args {
+        [cmd] if cmd == "list" => {
+            for entry in entries {
+                println!("{}\t{} bytes\t{}", entry.id, entry.size, entry.time_utc);
+            }
+        }
+        [cmd, id, path] if cmd == "download" => {
+            let entry = match id.as_str() {
+                "latest" => entries.iter().max_by_key(|e| e.id),
+                id => {
+                    let id: u16 = id
+                        .parse()
+                        .unwrap_or_else(|_| fail(format!("Invalid log id: {id}")));
+                    entries.iter().find(|e| e.id == id)
+                }
+            }
+            .unwrap_or_else(|| fail(format!("No log {id}")));
+
+            let (mut progress, handle) =
+                logs::download_log(connection, entry, PathBuf::from(path), Default::default());
+            tokio::spawn(async move {
+                while progress.changed().await.is_ok() {
+                    let fraction = progress.borrow().fraction();
+                    eprint!("\r{:.0}%", fraction * 100.0);
+                }
+                eprintln!();
+            });
+
+            let size = handle
+                .await
+                .unwrap_or_else(|e| fail(e))
+                .unwrap_or_else(|e| fail(e));
+            println!("Downloaded {size} bytes to {path}");
+        }
+        _ => usage(),
+    }
+}
+
+async fn signing(connection: Connection, args: &[String]) {
+    let key = match args {
+        [cmd, passphrase] if cmd == "setup" => SigningKey::from_passphrase(passphrase),
+        [cmd] if cmd == "disable" => SigningKey::default(),
+        _ => usage(),
+    };
+    signing::setup_signing(connection.as_ref(), key).unwrap_or_else(|e| fail(e));
+    // SETUP_SIGNING is not acknowledged.
+    println!("Signing key sent");
+}
+
+async fn run(args: Vec<String>) {
+    let mut url = std::env::var("ARDU_URL").unwrap_or_else(|_| "udpin:0.0.0.0:14550".to_string());
+    let mut key = None;
+    let mut link_id = 0;
+    let mut args = args.as_slice();
+    loop {
+        match args {
+            [flag, value, ..] if flag == "--url" || flag == "-u" => url = value.clone(),
+            [flag, value, ..] if flag == "--key" => key = Some(SigningKey::from_passphrase(value)),
+            [flag, value, ..] if flag == "--link-id" => {
+                link_id = value.parse().unwrap_or_else(|_| usage())
+            }
+            _ => break,
+        }
+        args = &args[2..];
+    }
+    if args.is_empty() {
+        usage();
+    }
+
+    let connection = match key {
+        Some(key) => UrlConnection::connect_signed(
+            &url,
+            signing::Options {
+                key,
+                link_id,
+                accept_unsigned: false,
+            },
+        ),
+        None => UrlConnection::connect(&url),
+    };
+    let connection = connection.unwrap_or_else(|e| fail(format!("{url}: {e}")));
+    let connection: Connection = Arc::new(
+        SharedConnection::new(Arc::new(connection)).unwrap_or_else(|e| fail(format!("{url}: {e}"))),
+    );
+    start_heartbeats(connection.clone());
+
+    match (args[0].as_str(), &args[1..]) {
+        ("mission", rest) => mission(connection, rest).await,
+        ("param", rest) => params(connection, rest).await,
+        ("mode", [name]) => mode(connection, name).await,
+        ("arm", rest) => arm(connection, true, rest.iter().any(|a| a == "--force")).await,
+        ("disarm", rest) => arm(connection, false, rest.iter().any(|a| a == "--force")).await,
+        ("watch", names) => watch(connection, names).await,
+        ("logs", rest) => download_logs(connection, rest).await,
+        ("signing", rest) => signing(connection, rest).await,
+        _ => usage(),
+    }
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap_or_else(|e| fail(e));
+
+    runtime.block_on(run(std::env::args().skip(1).collect()));
+
+    // Receiving blocks a thread that would otherwise keep the runtime from shutting down.
+    std::process::exit(0);
+}
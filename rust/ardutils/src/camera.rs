@@ -0,0 +1,384 @@
+// Camera commands, and the images the camera reports as captured. Surveys check the captures
+// against the triggers, and fetch again what was lost on the way.
+
+use std::{collections::BTreeMap, fmt::Debug, sync::Arc};
+
+use mavlink::{
+    ardupilotmega::{
+        MavCmd, MavFrame, MavMessage, MavResult, CAMERA_IMAGE_CAPTURED_DATA, COMMAND_INT_DATA,
+        COMMAND_LONG_DATA,
+    },
+    Message,
+};
+
+use crate::{
+    command::Command,
+    connection::{FilterRes, MavlinkConnection, MonitorHandle},
+    error::{Error, ErrorKind},
+    geo::Location,
+};
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Options {
+    // How long to wait for each answer before asking again.
+    pub timeout: std::time::Duration,
+    pub retries: u8,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            timeout: std::time::Duration::from_secs(1),
+            retries: 2,
+        }
+    }
+}
+
+// Sends the command until acknowledged, and fails unless it was accepted.
+async fn accepted<C, T>(connection: Arc<C>, command: T, options: &Options) -> Result<(), Error>
+where
+    C: MavlinkConnection + Debug + Send + Sync + 'static,
+    T: Command<Message = MavMessage, Ack = mavlink::ardupilotmega::COMMAND_ACK_DATA>,
+{
+    let ack = command
+        .command_retry(connection, options.timeout, options.retries)
+        .await?;
+    match ack.result {
+        MavResult::MAV_RESULT_ACCEPTED => Ok(()),
+        result => Err(Error::new(ErrorKind::Rejected(format!("{result:?}")))),
+    }
+}
+
+fn command_long<C>(connection: &C, command: MavCmd, params: [f32; 4]) -> COMMAND_LONG_DATA
+where
+    C: MavlinkConnection,
+{
+    COMMAND_LONG_DATA {
+        target_system: connection.target_system(),
+        target_component: connection.target_component(),
+        command,
+        param1: params[0],
+        param2: params[1],
+        param3: params[2],
+        param4: params[3],
+        ..Default::default()
+    }
+}
+
+// Takes `count` images, 0 for as many as possible until stopped, every `interval`.
+pub async fn start_image_capture<C>(
+    connection: Arc<C>,
+    interval: std::time::Duration,
+    count: u32,
+    options: &Options,
+) -> Result<(), Error>
+where
+    C: MavlinkConnection + Debug + Send + Sync + 'static,
+{
+    let params = [0.0, interval.as_secs_f32(), count as f32, 0.0];
+    let command = command_long(
+        connection.as_ref(),
+        MavCmd::MAV_CMD_IMAGE_START_CAPTURE,
+        params,
+    );
+    accepted(connection, command, options)
+        .await
+        .map_err(|e| e.operation("image capture"))
+}
+
+pub async fn stop_image_capture<C>(connection: Arc<C>, options: &Options) -> Result<(), Error>
+where
+    C: MavlinkConnection + Debug + Send + Sync + 'static,
+{
+    let command = command_long(
+        connection.as_ref(),
+        MavCmd::MAV_CMD_IMAGE_STOP_CAPTURE,
+        [0.0; 4],
+    );
+    accepted(connection, command, options)
+        .await
+        .map_err(|e| e.operation("image capture"))
+}
+
+// Stream 0 records every stream.
+pub async fn start_video_capture<C>(
+    connection: Arc<C>,
+    stream_id: u8,
+    options: &Options,
+) -> Result<(), Error>
+where
+    C: MavlinkConnection + Debug + Send + Sync + 'static,
+{
+    let params = [stream_id as f32, 0.0, 0.0, 0.0];
+    let command = command_long(
+        connection.as_ref(),
+        MavCmd::MAV_CMD_VIDEO_START_CAPTURE,
+        params,
+    );
+    accepted(connection, command, options)
+        .await
+        .map_err(|e| e.operation("video capture"))
+}
+
+pub async fn stop_video_capture<C>(
+    connection: Arc<C>,
+    stream_id: u8,
+    options: &Options,
+) -> Result<(), Error>
+where
+    C: MavlinkConnection + Debug + Send + Sync + 'static,
+{
+    let params = [stream_id as f32, 0.0, 0.0, 0.0];
+    let command = command_long(
+        connection.as_ref(),
+        MavCmd::MAV_CMD_VIDEO_STOP_CAPTURE,
+        params,
+    );
+    accepted(connection, command, options)
+        .await
+        .map_err(|e| e.operation("video capture"))
+}
+
+// Points the camera, through its gimbal, at `location`. None goes back to the default pointing.
+pub async fn set_roi<C>(
+    connection: Arc<C>,
+    location: Option<Location>,
+    options: &Options,
+) -> Result<(), Error>
+where
+    C: MavlinkConnection + Debug + Send + Sync + 'static,
+{
+    let res = match location {
+        Some(location) => {
+            let command = COMMAND_INT_DATA {
+                target_system: connection.target_system(),
+                target_component: connection.target_component(),
+                command: MavCmd::MAV_CMD_DO_SET_ROI_LOCATION,
+                frame: MavFrame::MAV_FRAME_GLOBAL,
+                x: location.lat_int(),
+                y: location.lon_int(),
+                z: location.alt,
+                ..Default::default()
+            };
+            accepted(connection, command, options).await
+        }
+        None => {
+            let command = command_long(
+                connection.as_ref(),
+                MavCmd::MAV_CMD_DO_SET_ROI_NONE,
+                [0.0; 4],
+            );
+            accepted(connection, command, options).await
+        }
+    };
+    res.map_err(|e| e.operation("roi"))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CapturedImage {
+    pub index: i32,
+    pub camera_id: u8,
+    // Where the image was taken, altitude AMSL.
+    pub location: Location,
+    // Meters above home.
+    pub relative_alt: f32,
+    // None when the camera has no time.
+    pub time_utc: Option<std::time::SystemTime>,
+    pub success: bool,
+    pub file_url: String,
+}
+
+impl From<CAMERA_IMAGE_CAPTURED_DATA> for CapturedImage {
+    fn from(data: CAMERA_IMAGE_CAPTURED_DATA) -> Self {
+        let len = data
+            .file_url
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(data.file_url.len());
+        Self {
+            index: data.image_index,
+            camera_id: data.camera_id,
+            location: Location::from_int(data.lat, data.lon, data.alt),
+            relative_alt: data.relative_alt as f32 / 1000.0,
+            time_utc: (data.time_utc != 0)
+                .then(|| std::time::UNIX_EPOCH + std::time::Duration::from_micros(data.time_utc)),
+            success: data.capture_result == 1,
+            file_url: String::from_utf8_lossy(&data.file_url[..len]).into_owned(),
+        }
+    }
+}
+
+// Images reported so far, by index.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Captures {
+    pub images: BTreeMap<i32, CapturedImage>,
+}
+
+impl Captures {
+    // Indexes between the first and the last image that were never reported.
+    pub fn missing(&self) -> Vec<i32> {
+        let (Some(first), Some(last)) = (self.images.keys().next(), self.images.keys().last())
+        else {
+            return vec![];
+        };
+        (*first..*last)
+            .filter(|index| !self.images.contains_key(index))
+            .collect()
+    }
+
+    pub fn failed(&self) -> impl Iterator<Item = &CapturedImage> {
+        self.images.values().filter(|image| !image.success)
+    }
+}
+
+// Records every CAMERA_IMAGE_CAPTURED, until the returned handle is dropped.
+pub fn track_captures<C>(
+    connection: Arc<C>,
+) -> (tokio::sync::watch::Receiver<Captures>, MonitorHandle)
+where
+    C: MavlinkConnection + Debug + Send + Sync + 'static,
+{
+    let (tx, rx) = tokio::sync::watch::channel(Captures::default());
+
+    let handle = connection.monitor(None, move |msg| {
+        if let MavMessage::CAMERA_IMAGE_CAPTURED(data) = msg {
+            let image = CapturedImage::from(data);
+            tx.send_if_modified(|captures| {
+                let previous = captures.images.insert(image.index, image.clone());
+                previous.as_ref() != Some(&image)
+            });
+        }
+        Some(())
+    });
+
+    (rx, handle)
+}
+
+// Asks the camera for the CAMERA_IMAGE_CAPTURED of an image again, e.g. one in
+// `Captures::missing`.
+pub async fn request_captured_image<C>(
+    connection: Arc<C>,
+    index: i32,
+    options: &Options,
+) -> Result<CapturedImage, Error>
+where
+    C: MavlinkConnection + Debug + Send + Sync + 'static,
+{
+    let id = MavMessage::CAMERA_IMAGE_CAPTURED(Default::default()).message_id();
+    let params = [id as f32, index as f32, 0.0, 0.0];
+    let msg = MavMessage::COMMAND_LONG(command_long(
+        connection.as_ref(),
+        MavCmd::MAV_CMD_REQUEST_MESSAGE,
+        params,
+    ));
+
+    let start = std::time::Instant::now();
+    for attempt in 0..=options.retries {
+        let res = connection
+            .clone()
+            .send_wait(&msg, options.timeout, move |msg| match msg {
+                MavMessage::CAMERA_IMAGE_CAPTURED(data) if data.image_index == index => {
+                    FilterRes::Ready(Some(CapturedImage::from(data)))
+                }
+                _ => FilterRes::NotReady,
+            })
+            .await;
+
+        match res {
+            Ok(Some(image)) => return Ok(image),
+            Ok(None) => {}
+            Err(e) if e.is_timeout() => {
+                tracing::event!(tracing::Level::DEBUG, index, attempt, "Requesting again");
+            }
+            Err(e) => return Err(e.seq(index as u32).operation("captured image")),
+        }
+    }
+
+    Err(Error::timeout(start.elapsed())
+        .message_id(id)
+        .seq(index as u32)
+        .operation("captured image"))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use mavlink::ardupilotmega::{MavCmd, MavMessage, MavResult, CAMERA_IMAGE_CAPTURED_DATA};
+
+    use super::{request_captured_image, start_image_capture, track_captures};
+    use crate::{error::ErrorKind, sim::SimVehicle};
+
+    fn captured(index: i32) -> MavMessage {
+        MavMessage::CAMERA_IMAGE_CAPTURED(CAMERA_IMAGE_CAPTURED_DATA {
+            image_index: index,
+            capture_result: 1,
+            lat: 473_977_420,
+            lon: 85_455_940,
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn starts_capture() {
+        let connection: Arc<Box<SimVehicle>> = Default::default();
+        let interval = std::time::Duration::from_secs(2);
+
+        let res = start_image_capture(connection.clone(), interval, 0, &Default::default()).await;
+        assert!(matches!(res, Err(e) if matches!(e.kind, ErrorKind::Rejected(_))));
+
+        connection.set_command_result(
+            MavCmd::MAV_CMD_IMAGE_START_CAPTURE,
+            MavResult::MAV_RESULT_ACCEPTED,
+        );
+        start_image_capture(connection.clone(), interval, 0, &Default::default())
+            .await
+            .unwrap();
+
+        let Some(MavMessage::COMMAND_LONG(data)) = connection.received().pop() else {
+            panic!("IMAGE_START_CAPTURE not sent");
+        };
+        assert_eq!(data.param2, 2.0);
+    }
+
+    #[tokio::test]
+    async fn tracks_missing_images() {
+        let connection: Arc<Box<SimVehicle>> = Default::default();
+        let (mut captures, _handle) = track_captures(connection.clone());
+
+        for index in [0, 1, 3, 5] {
+            connection.inject(captured(index));
+        }
+
+        tokio::time::timeout(std::time::Duration::from_secs(1), async {
+            while captures.borrow_and_update().images.len() < 4 {
+                captures.changed().await.unwrap();
+            }
+        })
+        .await
+        .unwrap();
+
+        let captures = captures.borrow().clone();
+        assert_eq!(captures.missing(), [2, 4]);
+        assert_eq!(captures.failed().count(), 0);
+        assert_eq!(captures.images[&3].location.lat, 47.397742);
+    }
+
+    #[tokio::test]
+    async fn requests_missing_image() {
+        let connection: Arc<Box<SimVehicle>> = Default::default();
+        connection.set_handler(Box::new(|msg| match msg {
+            MavMessage::COMMAND_LONG(data) if data.command == MavCmd::MAV_CMD_REQUEST_MESSAGE => {
+                Some(vec![captured(data.param2 as i32)])
+            }
+            _ => None,
+        }));
+
+        let image = request_captured_image(connection.clone(), 4, &Default::default())
+            .await
+            .unwrap();
+
+        assert_eq!(image.index, 4);
+        assert!(image.success);
+    }
+}
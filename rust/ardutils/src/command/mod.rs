@@ -1,57 +1,54 @@
 use std::{fmt::Debug, sync::Arc};
 
-use mavlink::ardupilotmega::{MavMessage, MavResult, COMMAND_ACK_DATA};
+use mavlink::{ardupilotmega, common, Message};
 
-use crate::connection::{MavlinkConnection, MavlinkConnectionError};
+use crate::{
+    connection::MavlinkConnection,
+    error::{Error, ErrorKind},
+};
 
-pub trait Command {
-    fn command<C>(&self, connection: Arc<C>) -> Result<usize, MavlinkConnectionError>
-    where
-        C: MavlinkConnection + Debug + Send + Sync;
+// Implemented for COMMAND_INT and COMMAND_LONG of the ardupilotmega and common dialects.
+#[async_trait::async_trait]
+pub trait Command: Clone + Send + Sync + 'static {
+    type Message: Message + Debug + Send + Sync + 'static;
+    type Ack: Clone + Send + Sync + 'static;
 
-    fn command_monitor<C>(
-        &self,
-        connection: Arc<C>,
-        timeout: Option<std::time::Duration>,
-    ) -> tokio::sync::watch::Receiver<Option<COMMAND_ACK_DATA>>
-    where
-        C: MavlinkConnection + Debug + Send + Sync;
+    fn message(&self) -> Self::Message;
 
-    fn command_retry<C>(
-        &self,
-        _connection: Arc<C>,
-        _timeout: std::time::Duration,
-        _retry_max: u8,
-    ) -> Result<Option<COMMAND_ACK_DATA>, MavlinkConnectionError>
-    where
-        C: MavlinkConnection + Debug + Send + Sync,
-    {
-        unimplemented!("Command Retries are not implemented")
+    // The acknowledgement of this command carried by `msg`, if any, and whether it is the final
+    // one. Acknowledgements of other commands are ignored.
+    fn ack(&self, msg: Self::Message) -> Option<(Self::Ack, bool)>;
+
+    // The same command, for another vehicle.
+    fn retarget(&self, target_system: u8, target_component: u8) -> Self;
+
+    // The same command, sent again for the `confirmation`th time. Only COMMAND_LONG counts.
+    fn resend(&self, _confirmation: u8) -> Self {
+        self.clone()
     }
-}
 
-impl Command for mavlink::ardupilotmega::COMMAND_INT_DATA {
-    fn command<C>(&self, connection: Arc<C>) -> Result<usize, MavlinkConnectionError>
+    fn command<C>(&self, connection: Arc<C>) -> Result<usize, Error>
     where
-        C: MavlinkConnection + Debug + Send + Sync,
+        C: MavlinkConnection<Self::Message> + Debug + Send + Sync,
     {
-        connection.send(&MavMessage::COMMAND_INT(self.clone()))
+        connection.send(&self.message())
     }
 
     fn command_monitor<C>(
         &self,
         connection: Arc<C>,
         timeout: Option<std::time::Duration>,
-    ) -> tokio::sync::watch::Receiver<Option<COMMAND_ACK_DATA>>
+    ) -> Result<tokio::sync::watch::Receiver<Option<Self::Ack>>, Error>
     where
-        C: MavlinkConnection + Debug + Send + Sync,
+        C: MavlinkConnection<Self::Message> + Debug + Send + Sync,
     {
         let (tx, rx) = tokio::sync::watch::channel(None);
+        let command = self.clone();
 
-        connection.clone().monitor(timeout, move |msg| {
-            if let MavMessage::COMMAND_ACK(data) = msg {
-                tx.send(Some(data.clone())).unwrap();
-                if !matches!(data.result, MavResult::MAV_RESULT_IN_PROGRESS) {
+        let monitor = connection.clone().monitor(timeout, move |msg| {
+            if let Some((data, done)) = command.ack(msg) {
+                // Nobody is listening anymore.
+                if tx.send(Some(data)).is_err() || done {
                     return None;
                 }
             }
@@ -59,59 +56,142 @@ impl Command for mavlink::ardupilotmega::COMMAND_INT_DATA {
         });
 
         connection
-            .send(&MavMessage::COMMAND_INT(self.clone()))
-            .expect("Could not send message across mavlink connection -- must have closed");
-
-        rx
-    }
-}
+            .send(&self.message())
+            .map_err(|e| e.operation("command"))?;
 
-impl Command for mavlink::ardupilotmega::COMMAND_LONG_DATA {
-    fn command<C>(&self, connection: Arc<C>) -> Result<usize, MavlinkConnectionError>
-    where
-        C: MavlinkConnection + Debug + Send + Sync,
-    {
-        connection.send(&MavMessage::COMMAND_LONG(self.clone()))
+        // Runs until the final acknowledgement, the timeout, or the receiver is dropped.
+        monitor.detach();
+        Ok(rx)
     }
 
-    fn command_monitor<C>(
+    // Sends the command until it is acknowledged, waiting `timeout` for it every time. Once
+    // acknowledged as in progress, it is not sent again, and its final acknowledgement is
+    // waited for until `timeout` passes without any acknowledgement.
+    async fn command_retry<C>(
         &self,
         connection: Arc<C>,
-        timeout: Option<std::time::Duration>,
-    ) -> tokio::sync::watch::Receiver<Option<COMMAND_ACK_DATA>>
+        timeout: std::time::Duration,
+        retry_max: u8,
+    ) -> Result<Self::Ack, Error>
     where
-        C: MavlinkConnection + Debug + Send + Sync,
+        C: MavlinkConnection<Self::Message> + Debug + Send + Sync + 'static,
     {
-        let (tx, rx) = tokio::sync::watch::channel(None);
-
-        connection.clone().monitor(timeout, move |msg| {
-            if let MavMessage::COMMAND_ACK(data) = msg {
-                tx.send(Some(data.clone())).unwrap();
-                if !matches!(data.result, MavResult::MAV_RESULT_IN_PROGRESS) {
-                    return None;
+        let start = std::time::Instant::now();
+
+        // A late acknowledgement of an earlier attempt counts as well.
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let monitor = connection.clone().monitor(None, {
+            let command = self.clone();
+            move |msg| match command.ack(msg) {
+                // Done, not to take any other message off the connection.
+                Some((data, true)) => {
+                    let _ = tx.send((data, true));
+                    None
+                }
+                Some(ack) => {
+                    let _ = tx.send(ack);
+                    Some(())
                 }
+                None => Some(()),
             }
-            Some(())
         });
 
-        connection
-            .send(&MavMessage::COMMAND_LONG(self.clone()))
-            .expect("Could not send message across mavlink connection -- must have closed");
+        let mut attempt = 0;
+        let mut in_progress = false;
+        self.command(connection.clone())
+            .map_err(|e| e.operation("command"))?;
+        loop {
+            match tokio::time::timeout(timeout, rx.recv()).await {
+                Ok(Some((ack, true))) => return Ok(ack),
+                Ok(Some((_, false))) => {
+                    tracing::event!(tracing::Level::DEBUG, attempt, "Command in progress");
+                    in_progress = true;
+                }
+                // The monitor failed, e.g. the connection was closed.
+                Ok(None) => {
+                    let e = match monitor.await {
+                        Err(e) => e,
+                        Ok(()) => Error::new(ErrorKind::Aborted("Monitor stopped".to_string())),
+                    };
+                    return Err(e.operation("command"));
+                }
+                Err(_) if in_progress || attempt == retry_max => break,
+                Err(_) => {
+                    attempt += 1;
+                    tracing::event!(tracing::Level::DEBUG, attempt, "Sending command again");
+                    self.resend(attempt)
+                        .command(connection.clone())
+                        .map_err(|e| e.operation("command"))?;
+                }
+            }
+        }
 
-        rx
+        Err(Error::new(ErrorKind::Timeout)
+            .elapsed(start.elapsed())
+            .message_id(self.message().message_id())
+            .operation("command"))
     }
 }
 
+macro_rules! impl_command {
+    ($dialect:ident, $data:ident, $variant:ident $(, $confirmation:ident)?) => {
+        impl Command for $dialect::$data {
+            type Message = $dialect::MavMessage;
+            type Ack = $dialect::COMMAND_ACK_DATA;
+
+            fn message(&self) -> Self::Message {
+                $dialect::MavMessage::$variant(self.clone())
+            }
+
+            fn ack(&self, msg: Self::Message) -> Option<(Self::Ack, bool)> {
+                match msg {
+                    $dialect::MavMessage::COMMAND_ACK(data) if data.command == self.command => {
+                        let done =
+                            !matches!(data.result, $dialect::MavResult::MAV_RESULT_IN_PROGRESS);
+                        Some((data, done))
+                    }
+                    _ => None,
+                }
+            }
+
+            fn retarget(&self, target_system: u8, target_component: u8) -> Self {
+                Self {
+                    target_system,
+                    target_component,
+                    ..self.clone()
+                }
+            }
+
+            $(
+                fn resend(&self, confirmation: u8) -> Self {
+                    Self {
+                        $confirmation: confirmation,
+                        ..self.clone()
+                    }
+                }
+            )?
+        }
+    };
+}
+
+impl_command!(ardupilotmega, COMMAND_INT_DATA, COMMAND_INT);
+impl_command!(ardupilotmega, COMMAND_LONG_DATA, COMMAND_LONG, confirmation);
+impl_command!(common, COMMAND_INT_DATA, COMMAND_INT);
+impl_command!(common, COMMAND_LONG_DATA, COMMAND_LONG, confirmation);
+
 #[cfg(test)]
 mod test {
     use crate::command::Command;
-    use std::sync::Arc;
+    use std::sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc,
+    };
 
     use mavlink::ardupilotmega::{
-        MavMessage, MavResult, COMMAND_ACK_DATA, COMMAND_INT_DATA, COMMAND_LONG_DATA,
+        MavCmd, MavMessage, MavResult, COMMAND_ACK_DATA, COMMAND_INT_DATA, COMMAND_LONG_DATA,
     };
 
-    use crate::connection::test::*;
+    use crate::{connection::test::*, sim::SimVehicle};
 
     #[tokio::test]
     async fn command_int() {
@@ -145,12 +225,126 @@ mod test {
         ));
     }
 
+    #[tokio::test]
+    async fn command_long_common() {
+        use mavlink::common;
+
+        let connection: Arc<Box<TestMavConnection<common::MavMessage>>> = Default::default();
+
+        let mut rx = common::COMMAND_LONG_DATA::default()
+            .command_monitor(connection.clone(), Some(std::time::Duration::from_secs(1)))
+            .unwrap();
+
+        connection.inject_msg(common::MavMessage::COMMAND_ACK(common::COMMAND_ACK_DATA {
+            result: common::MavResult::MAV_RESULT_ACCEPTED,
+            ..Default::default()
+        }));
+
+        tokio::time::timeout(std::time::Duration::from_secs(1), rx.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            connection.last_sent().unwrap(),
+            common::MavMessage::COMMAND_LONG(common::COMMAND_LONG_DATA { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn retries_until_acknowledged() {
+        let connection: Arc<Box<crate::sim::SimVehicle>> = Default::default();
+
+        let ack = COMMAND_LONG_DATA {
+            command: mavlink::ardupilotmega::MavCmd::MAV_CMD_COMPONENT_ARM_DISARM,
+            param1: 1.0,
+            ..Default::default()
+        }
+        .command_retry(connection.clone(), std::time::Duration::from_secs(1), 2)
+        .await
+        .unwrap();
+
+        assert_eq!(ack.result, MavResult::MAV_RESULT_ACCEPTED);
+        assert!(connection.armed());
+    }
+
+    #[tokio::test]
+    async fn counts_confirmations() {
+        let connection: Arc<Box<SimVehicle>> = Default::default();
+        let dropped = AtomicU8::new(0);
+        connection.set_loss(Box::new(move |msg| {
+            matches!(msg, MavMessage::COMMAND_ACK(_)) && dropped.fetch_add(1, Ordering::SeqCst) < 2
+        }));
+
+        let ack = COMMAND_LONG_DATA {
+            command: MavCmd::MAV_CMD_COMPONENT_ARM_DISARM,
+            param1: 1.0,
+            ..Default::default()
+        }
+        .command_retry(connection.clone(), std::time::Duration::from_millis(200), 3)
+        .await
+        .unwrap();
+
+        assert_eq!(ack.result, MavResult::MAV_RESULT_ACCEPTED);
+        let confirmations: Vec<u8> = connection
+            .received()
+            .into_iter()
+            .filter_map(|msg| match msg {
+                MavMessage::COMMAND_LONG(data) => Some(data.confirmation),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(confirmations, [0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn waits_while_in_progress() {
+        let ack = |result| {
+            MavMessage::COMMAND_ACK(COMMAND_ACK_DATA {
+                command: MavCmd::MAV_CMD_DO_CHANGE_SPEED,
+                result,
+                ..Default::default()
+            })
+        };
+        let connection: Arc<Box<SimVehicle>> = Default::default();
+        connection.set_handler(Box::new(move |msg| {
+            matches!(msg, MavMessage::COMMAND_LONG(_))
+                .then(|| vec![ack(MavResult::MAV_RESULT_IN_PROGRESS)])
+        }));
+        tokio::spawn({
+            let connection = connection.clone();
+            async move {
+                tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+                connection.inject(ack(MavResult::MAV_RESULT_IN_PROGRESS));
+                tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+                connection.inject(ack(MavResult::MAV_RESULT_ACCEPTED));
+            }
+        });
+
+        // Longer than the timeout, without being sent again.
+        let ack = COMMAND_LONG_DATA {
+            command: MavCmd::MAV_CMD_DO_CHANGE_SPEED,
+            ..Default::default()
+        }
+        .command_retry(connection.clone(), std::time::Duration::from_millis(300), 3)
+        .await
+        .unwrap();
+
+        assert_eq!(ack.result, MavResult::MAV_RESULT_ACCEPTED);
+        let sent = connection
+            .received()
+            .into_iter()
+            .filter(|msg| matches!(msg, MavMessage::COMMAND_LONG(_)))
+            .count();
+        assert_eq!(sent, 1);
+    }
+
     #[tokio::test]
     async fn monitor_int() {
         let connection: Arc<Box<TestMavConnection>> = Default::default();
 
         let mut rx = COMMAND_INT_DATA::default()
-            .command_monitor(connection.clone(), Some(std::time::Duration::from_secs(1)));
+            .command_monitor(connection.clone(), Some(std::time::Duration::from_secs(1)))
+            .unwrap();
 
         connection.inject_msg(MavMessage::COMMAND_ACK(COMMAND_ACK_DATA {
             result: MavResult::MAV_RESULT_ACCEPTED,
@@ -173,12 +367,33 @@ mod test {
         .unwrap();
     }
 
+    #[test]
+    fn ignores_other_commands() {
+        use mavlink::ardupilotmega::MavCmd;
+
+        let command = COMMAND_LONG_DATA {
+            command: MavCmd::MAV_CMD_DO_SET_HOME,
+            ..Default::default()
+        };
+        let ack = |command| {
+            MavMessage::COMMAND_ACK(COMMAND_ACK_DATA {
+                command,
+                result: MavResult::MAV_RESULT_ACCEPTED,
+                ..Default::default()
+            })
+        };
+
+        assert!(command.ack(ack(MavCmd::MAV_CMD_REQUEST_MESSAGE)).is_none());
+        assert!(command.ack(ack(MavCmd::MAV_CMD_DO_SET_HOME)).is_some());
+    }
+
     #[tokio::test]
     async fn monitor_long() {
         let connection: Arc<Box<TestMavConnection>> = Default::default();
 
         let mut rx = COMMAND_LONG_DATA::default()
-            .command_monitor(connection.clone(), Some(std::time::Duration::from_secs(1)));
+            .command_monitor(connection.clone(), Some(std::time::Duration::from_secs(1)))
+            .unwrap();
 
         connection.inject_msg(MavMessage::COMMAND_ACK(COMMAND_ACK_DATA {
             result: MavResult::MAV_RESULT_ACCEPTED,
@@ -205,7 +420,9 @@ mod test {
     async fn monitor_int_failed() {
         let connection: Arc<Box<TestMavConnection>> = Default::default();
 
-        let mut rx = COMMAND_INT_DATA::default().command_monitor(connection.clone(), None);
+        let mut rx = COMMAND_INT_DATA::default()
+            .command_monitor(connection.clone(), None)
+            .unwrap();
 
         connection.inject_msg(MavMessage::COMMAND_ACK(COMMAND_ACK_DATA {
             result: MavResult::MAV_RESULT_FAILED,
@@ -234,7 +451,9 @@ mod test {
     async fn monitor_long_failed() {
         let connection: Arc<Box<TestMavConnection>> = Default::default();
 
-        let mut rx = COMMAND_LONG_DATA::default().command_monitor(connection.clone(), None);
+        let mut rx = COMMAND_LONG_DATA::default()
+            .command_monitor(connection.clone(), None)
+            .unwrap();
 
         connection.inject_msg(MavMessage::COMMAND_ACK(COMMAND_ACK_DATA {
             result: MavResult::MAV_RESULT_FAILED,
@@ -262,7 +481,9 @@ mod test {
     async fn monitor_long_progress() {
         let connection: Arc<Box<TestMavConnection>> = Default::default();
 
-        let mut rx = COMMAND_INT_DATA::default().command_monitor(connection.clone(), None);
+        let mut rx = COMMAND_INT_DATA::default()
+            .command_monitor(connection.clone(), None)
+            .unwrap();
 
         connection.inject_msg(MavMessage::COMMAND_ACK(COMMAND_ACK_DATA {
             result: MavResult::MAV_RESULT_IN_PROGRESS,
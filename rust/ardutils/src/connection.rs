@@ -1,34 +1,118 @@
-use std::{fmt::Debug, sync::Arc};
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex},
+};
 
 use mavlink::{
     ardupilotmega::MavMessage,
     error::{MessageReadError, MessageWriteError},
-    MavConnection, MavHeader,
+    MavConnection, MavHeader, Message,
 };
 use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
 
-#[derive(Debug)]
-pub enum MavlinkConnectionError {
-    Timeout,
-    ReadError(MessageReadError),
-    WriteError(MessageWriteError),
-    Other(String),
+use crate::error::{Error, ErrorKind};
+
+// Target ids are fixed at build time, an invalid one fails the build rather than a command.
+const fn parse_target(id: &str) -> u8 {
+    let bytes = id.as_bytes();
+    assert!(!bytes.is_empty(), "Target ids cannot be empty");
+    let mut value: u32 = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        assert!(bytes[i].is_ascii_digit(), "Target ids must be numbers");
+        value = value * 10 + (bytes[i] - b'0') as u32;
+        assert!(value <= u8::MAX as u32, "Target ids must fit in a u8");
+        i += 1;
+    }
+    value as u8
 }
 
+const TARGET_SYSTEM: u8 = parse_target(env!("MAV_TARGET_SYSTEM"));
+const TARGET_COMPONENT: u8 = parse_target(env!("MAV_TARGET_COMPONENT"));
+
 pub enum FilterRes<T> {
     Ready(Option<T>),
     NotReady,
 }
 
+// A running monitor, stopped as soon as its handle is dropped. Awaiting the handle gives how
+// the monitor ended.
+#[must_use = "the monitor stops when its handle is dropped"]
+pub struct MonitorHandle<T = ()> {
+    // Only None once detached.
+    task: Option<JoinHandle<Result<T, Error>>>,
+    cancel: CancellationToken,
+}
+
+impl<T> MonitorHandle<T> {
+    pub fn new(task: JoinHandle<Result<T, Error>>, cancel: CancellationToken) -> Self {
+        Self {
+            task: Some(task),
+            cancel,
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    // Cancelling the token stops the monitor, even once detached.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.task.as_ref().is_none_or(|task| task.is_finished())
+    }
+
+    // Lets the monitor run on its own, until it is done, times out or its token is cancelled.
+    pub fn detach(mut self) {
+        self.task.take();
+    }
+}
+
+impl<T> std::future::Future for MonitorHandle<T> {
+    type Output = Result<T, Error>;
+
+    fn poll(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Self::Output> {
+        let Some(task) = self.task.as_mut() else {
+            return std::task::Poll::Ready(Err(Error::new(ErrorKind::Aborted(
+                "Monitor detached".to_string(),
+            ))));
+        };
+        std::pin::Pin::new(task)
+            .poll(cx)
+            .map(|res| res.unwrap_or_else(|e| Err(Error::new(ErrorKind::Aborted(e.to_string())))))
+    }
+}
+
+impl<T> Drop for MonitorHandle<T> {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            self.cancel.cancel();
+            task.abort();
+        }
+    }
+}
+
+// Generic over the dialect, `M`, so the same helpers work with ArduPilot on ardupilotmega
+// and PX4 on common.
 #[async_trait::async_trait]
-pub trait MavlinkConnection {
-    fn send(&self, msg: &MavMessage) -> Result<usize, MavlinkConnectionError>;
+pub trait MavlinkConnection<M = MavMessage>
+where
+    M: Message + Send + Sync + 'static,
+{
+    fn send(&self, msg: &M) -> Result<usize, Error>;
     async fn send_wait<R>(
         self: Arc<Self>,
-        msg: &MavMessage,
+        msg: &M,
         timeout: std::time::Duration,
-        filter: impl Fn(MavMessage) -> FilterRes<R> + Send + Sync + 'static,
-    ) -> Result<Option<R>, MavlinkConnectionError>
+        filter: impl Fn(M) -> FilterRes<R> + Send + Sync + 'static,
+    ) -> Result<Option<R>, Error>
     where
         R: Send + Sync + 'static;
 
@@ -37,16 +121,36 @@ pub trait MavlinkConnection {
     fn monitor(
         self: Arc<Self>,
         timeout: Option<std::time::Duration>,
-        monitor: impl Fn(MavMessage) -> Option<()> + Send + Sync + 'static,
-    ) -> JoinHandle<Result<(), MavlinkConnectionError>>;
+        monitor: impl Fn(M) -> Option<()> + Send + Sync + 'static,
+    ) -> MonitorHandle {
+        self.monitor_until(timeout, CancellationToken::new(), monitor)
+    }
 
-    async fn _receive(self: Arc<Self>) -> Result<(MavHeader, MavMessage), MavlinkConnectionError>;
+    // Like `monitor`, but also stops once `cancel` is cancelled, e.g. on shutdown.
+    fn monitor_until(
+        self: Arc<Self>,
+        timeout: Option<std::time::Duration>,
+        cancel: CancellationToken,
+        monitor: impl Fn(M) -> Option<()> + Send + Sync + 'static,
+    ) -> MonitorHandle;
+
+    // Like `monitor_until`, for the messages whose header `validate` accepts rather than the
+    // ones `Self::validate` does.
+    fn monitor_validated(
+        self: Arc<Self>,
+        timeout: Option<std::time::Duration>,
+        cancel: CancellationToken,
+        validate: impl Fn(MavHeader) -> bool + Send + Sync + 'static,
+        monitor: impl Fn(M) -> Option<()> + Send + Sync + 'static,
+    ) -> MonitorHandle;
+
+    async fn _receive(self: Arc<Self>) -> Result<(MavHeader, M), Error>;
 
     fn target_system(&self) -> u8 {
-        env!("MAV_TARGET_SYSTEM").parse().unwrap()
+        TARGET_SYSTEM
     }
     fn target_component(&self) -> u8 {
-        env!("MAV_TARGET_COMPONENT").parse().unwrap()
+        TARGET_COMPONENT
     }
 
     fn validate(&self, _header: MavHeader) -> bool {
@@ -54,69 +158,103 @@ pub trait MavlinkConnection {
     }
 }
 
+// A connection opened from an address understood by `mavlink::connect`,
+// e.g. udpin:0.0.0.0:14550, tcpout:127.0.0.1:5760 or serial:/dev/ttyACM0:115200.
+pub struct UrlConnection {
+    url: String,
+    inner: Box<dyn MavConnection<MavMessage> + Send + Sync>,
+}
+
+impl UrlConnection {
+    pub fn connect(url: &str) -> std::io::Result<Self> {
+        Ok(Self {
+            url: url.to_string(),
+            inner: mavlink::connect(url)?,
+        })
+    }
+
+    // Signs what it sends, and drops what is not signed with the same key.
+    pub fn connect_signed(url: &str, options: crate::signing::Options) -> std::io::Result<Self> {
+        Ok(Self {
+            url: url.to_string(),
+            inner: Box::new(crate::signing::connect(url, options)?),
+        })
+    }
+}
+
+impl Debug for UrlConnection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "UrlConnection [{}]", self.url)
+    }
+}
+
+impl MavConnection<MavMessage> for UrlConnection {
+    fn recv(&self) -> Result<(MavHeader, MavMessage), MessageReadError> {
+        self.inner.recv()
+    }
+
+    fn send(&self, header: &MavHeader, data: &MavMessage) -> Result<usize, MessageWriteError> {
+        self.inner.send(header, data)
+    }
+
+    fn set_protocol_version(&mut self, version: mavlink::MavlinkVersion) {
+        self.inner.set_protocol_version(version)
+    }
+
+    fn get_protocol_version(&self) -> mavlink::MavlinkVersion {
+        self.inner.get_protocol_version()
+    }
+}
+
 #[async_trait::async_trait]
-impl<T> MavlinkConnection for Box<T>
+impl<M, T> MavlinkConnection<M> for Box<T>
 where
-    T: MavConnection<MavMessage> + Send + Sync + 'static,
+    M: Message + Debug + Send + Sync + 'static,
+    T: MavConnection<M> + Send + Sync + 'static,
 {
     #[tracing::instrument(skip(self))]
-    fn send(&self, msg: &MavMessage) -> Result<usize, MavlinkConnectionError> {
-        MavConnection::send(self.as_ref(), &Default::default(), msg)
-            .map_err(MavlinkConnectionError::WriteError)
+    fn send(&self, msg: &M) -> Result<usize, Error> {
+        MavConnection::<M>::send(self.as_ref(), &Default::default(), msg)
+            .map_err(|e| Error::from(e).message_id(msg.message_id()))
     }
 
     #[tracing::instrument(skip(self, filter))]
     async fn send_wait<R>(
         self: Arc<Self>,
-        msg: &MavMessage,
+        msg: &M,
         timeout: std::time::Duration,
-        filter: impl Fn(MavMessage) -> FilterRes<R> + Send + Sync + 'static,
-    ) -> Result<Option<R>, MavlinkConnectionError>
+        filter: impl Fn(M) -> FilterRes<R> + Send + Sync + 'static,
+    ) -> Result<Option<R>, Error>
     where
         R: Send + Sync + 'static,
     {
-        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
-
-        self.clone().monitor(Some(timeout), move |msg| {
-            if let FilterRes::Ready(inner) = filter(msg) {
-                tx.try_send(inner).unwrap();
-                None
-            } else {
-                Some(())
-            }
-        });
-
-        self.send(msg)?;
-
-        tracing::event!(tracing::Level::TRACE, "Message Sent");
-
-        rx.recv().await.ok_or(MavlinkConnectionError::Timeout)
+        send_and_wait(self, msg, timeout, filter).await
     }
 
-    fn monitor(
+    // Each monitor reads the connection on its own: concurrent monitors each get part of the
+    // messages, and a monitor stopped while waiting for a message still takes the next one off
+    // the connection. Wrap the connection in a `SharedConnection` when that matters.
+    fn monitor_until(
         self: Arc<Self>,
         timeout: Option<std::time::Duration>,
-        // Currently when this returns None it implies that we are done
-        // And returning Some(()) means that we should continue monitoring
-        // I don't like this API, should at least change to maybe true/false to be more clear
-        monitor: impl Fn(MavMessage) -> Option<()> + Send + Sync + 'static,
-    ) -> JoinHandle<Result<(), MavlinkConnectionError>> {
-        let instant = std::time::Instant::now();
-        tokio::task::spawn(async move {
-            loop {
-                if let Some(t) = timeout {
-                    if instant.elapsed() > t {
-                        return Err(MavlinkConnectionError::Timeout);
-                    }
-                }
+        cancel: CancellationToken,
+        monitor: impl Fn(M) -> Option<()> + Send + Sync + 'static,
+    ) -> MonitorHandle {
+        let validate = {
+            let conn = self.clone();
+            move |header| conn.validate(header)
+        };
+        self.monitor_validated(timeout, cancel, validate, monitor)
+    }
 
-                if let Ok((header, msg)) = self.clone()._receive().await {
-                    if self.validate(header) && monitor(msg).is_none() {
-                        return Ok(());
-                    }
-                }
-            }
-        })
+    fn monitor_validated(
+        self: Arc<Self>,
+        timeout: Option<std::time::Duration>,
+        cancel: CancellationToken,
+        validate: impl Fn(MavHeader) -> bool + Send + Sync + 'static,
+        monitor: impl Fn(M) -> Option<()> + Send + Sync + 'static,
+    ) -> MonitorHandle {
+        spawn_monitor(self, validate, timeout, cancel, monitor)
     }
 
     // This is in fact a blocking call -- since conn.recv() is blocking --
@@ -124,14 +262,425 @@ where
     // If this function blocks forever, meaning there are no messages to return -- then there is nothing we can do...
     // BUT, since this is in a spawn_blocking, it will at least not hold up the execution of OTHER things
     //  (except things in it's consequent execution tree.
-    async fn _receive(self: Arc<Self>) -> Result<(MavHeader, MavMessage), MavlinkConnectionError> {
+    async fn _receive(self: Arc<Self>) -> Result<(MavHeader, M), Error> {
         return tokio::task::spawn_blocking({
             let conn = self.clone();
-            move || conn.recv()
+            move || MavConnection::<M>::recv(&**conn)
         })
         .await
-        .unwrap()
-        .map_err(MavlinkConnectionError::ReadError);
+        .map_err(|e| Error::new(ErrorKind::Aborted(e.to_string())))?
+        .map_err(Error::from);
+    }
+}
+
+// Where a monitor gets its messages from.
+#[async_trait::async_trait]
+trait Receive<M>: Send + 'static {
+    async fn receive(&mut self) -> Result<(MavHeader, M), Error>;
+}
+
+#[async_trait::async_trait]
+impl<M, T> Receive<M> for Arc<Box<T>>
+where
+    M: Message + Debug + Send + Sync + 'static,
+    T: MavConnection<M> + Send + Sync + 'static,
+{
+    async fn receive(&mut self) -> Result<(MavHeader, M), Error> {
+        self.clone()._receive().await
+    }
+}
+
+#[async_trait::async_trait]
+impl<M> Receive<M> for tokio::sync::broadcast::Receiver<(MavHeader, M)>
+where
+    M: Clone + Send + Sync + 'static,
+{
+    async fn receive(&mut self) -> Result<(MavHeader, M), Error> {
+        loop {
+            match self.recv().await {
+                Ok(received) => return Ok(received),
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::event!(tracing::Level::WARN, skipped, "Monitor lagging behind");
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => {
+                    let reason = "Connection closed".to_string();
+                    return Err(Error::new(ErrorKind::Aborted(reason)));
+                }
+            }
+        }
+    }
+}
+
+// Sends `msg`, then waits for the first message `filter` is ready with.
+async fn send_and_wait<M, C, R>(
+    connection: Arc<C>,
+    msg: &M,
+    timeout: std::time::Duration,
+    filter: impl Fn(M) -> FilterRes<R> + Send + Sync + 'static,
+) -> Result<Option<R>, Error>
+where
+    M: Message + Send + Sync + 'static,
+    C: MavlinkConnection<M> + ?Sized,
+    R: Send + Sync + 'static,
+{
+    let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+
+    let monitor = connection.clone().monitor(Some(timeout), move |msg| {
+        if let FilterRes::Ready(inner) = filter(msg) {
+            // Only the first answer counts.
+            let _ = tx.try_send(inner);
+            None
+        } else {
+            Some(())
+        }
+    });
+
+    connection.send(msg)?;
+
+    tracing::event!(tracing::Level::TRACE, "Message Sent");
+
+    match rx.recv().await {
+        Some(res) => Ok(res),
+        // The monitor ended without an answer, it knows why.
+        None => Err(match monitor.await {
+            Err(e) => e,
+            Ok(()) => Error::timeout(timeout),
+        }
+        .message_id(msg.message_id())),
+    }
+}
+
+// Sends `msg` like `send_wait`, again every time `timeout` passes without an answer, up to
+// `retries` more times.
+pub async fn send_wait_retry<M, C, R>(
+    connection: &Arc<C>,
+    msg: &M,
+    timeout: std::time::Duration,
+    retries: u8,
+    filter: impl Fn(M) -> FilterRes<R> + Clone + Send + Sync + 'static,
+) -> Result<Option<R>, Error>
+where
+    M: Message + Send + Sync + 'static,
+    C: MavlinkConnection<M> + Send + Sync,
+    R: Send + Sync + 'static,
+{
+    let start = std::time::Instant::now();
+    for attempt in 0..=retries {
+        match connection
+            .clone()
+            .send_wait(msg, timeout, filter.clone())
+            .await
+        {
+            Err(e) if e.is_timeout() => {
+                let id = msg.message_id();
+                tracing::event!(tracing::Level::DEBUG, id, attempt, "Sending again");
+            }
+            res => return res,
+        }
+    }
+
+    Err(Error::timeout(start.elapsed()).message_id(msg.message_id()))
+}
+
+fn spawn_monitor<M>(
+    mut receiver: impl Receive<M>,
+    validate: impl Fn(MavHeader) -> bool + Send + Sync + 'static,
+    timeout: Option<std::time::Duration>,
+    cancel: CancellationToken,
+    // Currently when this returns None it implies that we are done
+    // And returning Some(()) means that we should continue monitoring
+    // I don't like this API, should at least change to maybe true/false to be more clear
+    monitor: impl Fn(M) -> Option<()> + Send + Sync + 'static,
+) -> MonitorHandle
+where
+    M: Message + Send + Sync + 'static,
+{
+    let instant = std::time::Instant::now();
+    // The handle owns a child token: dropping it must not cancel the caller's token.
+    let cancel = cancel.child_token();
+    let cancelled = cancel.clone();
+
+    let task = tokio::task::spawn(async move {
+        loop {
+            if let Some(t) = timeout {
+                if instant.elapsed() > t {
+                    return Err(Error::timeout(instant.elapsed()));
+                }
+            }
+
+            // Cancelling does not wait for the next message.
+            let received = tokio::select! {
+                _ = cancelled.cancelled() => {
+                    let reason = "Monitor cancelled".to_string();
+                    return Err(Error::new(ErrorKind::Aborted(reason)));
+                }
+                received = receiver.receive() => received,
+            };
+
+            match received {
+                Ok((header, msg)) => {
+                    if !validate(header) {
+                        continue;
+                    }
+                    // A panicking monitor ends with an error, instead of silently taking
+                    // the task, and whoever waits on it, down.
+                    let id = msg.message_id();
+                    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| monitor(msg))) {
+                        Ok(Some(())) => {}
+                        Ok(None) => return Ok(()),
+                        Err(_) => {
+                            tracing::event!(tracing::Level::ERROR, id, "Monitor panicked");
+                            return Err(Error::new(ErrorKind::Aborted(
+                                "Monitor panicked".to_string(),
+                            ))
+                            .message_id(id));
+                        }
+                    }
+                }
+                // Nothing will ever come anymore.
+                Err(
+                    e @ Error {
+                        kind: ErrorKind::Aborted(_),
+                        ..
+                    },
+                ) => return Err(e),
+                Err(_) => {}
+            }
+        }
+    });
+
+    MonitorHandle::new(task, cancel)
+}
+
+// Messages kept for each monitor until it gets to them, a monitor lagging further behind skips
+// the oldest.
+const SHARED_CAPACITY: usize = 1024;
+
+type Sender<M> = tokio::sync::broadcast::Sender<(MavHeader, M)>;
+
+// Reads the connection from a single thread and hands every message to every monitor, so
+// concurrent monitors, e.g. telemetry next to a command, each see all of the messages, and a
+// stopped monitor does not take one off the connection. The thread stops after the first
+// message received once the connection is dropped, or when the connection fails for good, e.g.
+// closed by the other end. Monitors then end with `ErrorKind::Aborted`.
+pub struct SharedConnection<M = MavMessage> {
+    inner: Arc<dyn MavConnection<M> + Send + Sync>,
+    // None once the reader stopped.
+    messages: Arc<Mutex<Option<Sender<M>>>>,
+}
+
+impl<M> SharedConnection<M>
+where
+    M: Message + Clone + Send + Sync + 'static,
+{
+    pub fn new<T>(connection: Arc<T>) -> Result<Self, Error>
+    where
+        T: MavConnection<M> + Send + Sync + 'static,
+    {
+        let inner: Arc<dyn MavConnection<M> + Send + Sync> = connection;
+        let sender = tokio::sync::broadcast::channel(SHARED_CAPACITY).0;
+        let messages = Arc::new(Mutex::new(Some(sender)));
+
+        let reader = {
+            let inner = inner.clone();
+            let messages = Arc::downgrade(&messages);
+            move || loop {
+                let received = inner.recv();
+                let Some(messages) = messages.upgrade() else {
+                    return;
+                };
+                match received {
+                    // Nobody monitoring is fine, the message is not for anyone.
+                    Ok(received) => {
+                        if let Some(sender) = messages.lock().unwrap().as_ref() {
+                            let _ = sender.send(received);
+                        }
+                    }
+                    Err(MessageReadError::Io(e))
+                        if !matches!(
+                            e.kind(),
+                            std::io::ErrorKind::WouldBlock
+                                | std::io::ErrorKind::TimedOut
+                                | std::io::ErrorKind::Interrupted
+                        ) =>
+                    {
+                        tracing::event!(tracing::Level::WARN, %e, "Connection lost");
+                        // Dropping the sender closes every monitor.
+                        messages.lock().unwrap().take();
+                        return;
+                    }
+                    Err(e) => tracing::event!(tracing::Level::TRACE, %e, "Receive failed"),
+                }
+            }
+        };
+        std::thread::Builder::new()
+            .name("mavlink-reader".to_string())
+            .spawn(reader)
+            .map_err(|e| Error::from(e).operation("reader thread"))?;
+
+        Ok(Self { inner, messages })
+    }
+
+    fn subscribe(&self) -> tokio::sync::broadcast::Receiver<(MavHeader, M)> {
+        match self.messages.lock().unwrap().as_ref() {
+            Some(sender) => sender.subscribe(),
+            // Without a sender, the receiver is closed from the start.
+            None => tokio::sync::broadcast::channel(1).1,
+        }
+    }
+}
+
+impl<M> Debug for SharedConnection<M> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.messages.lock().unwrap().as_ref() {
+            Some(sender) => write!(f, "SharedConnection [{} monitors]", sender.receiver_count()),
+            None => write!(f, "SharedConnection [closed]"),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<M> MavlinkConnection<M> for SharedConnection<M>
+where
+    M: Message + Clone + Debug + Send + Sync + 'static,
+{
+    #[tracing::instrument(skip(self))]
+    fn send(&self, msg: &M) -> Result<usize, Error> {
+        self.inner
+            .send(&Default::default(), msg)
+            .map_err(|e| Error::from(e).message_id(msg.message_id()))
+    }
+
+    #[tracing::instrument(skip(self, filter))]
+    async fn send_wait<R>(
+        self: Arc<Self>,
+        msg: &M,
+        timeout: std::time::Duration,
+        filter: impl Fn(M) -> FilterRes<R> + Send + Sync + 'static,
+    ) -> Result<Option<R>, Error>
+    where
+        R: Send + Sync + 'static,
+    {
+        send_and_wait(self, msg, timeout, filter).await
+    }
+
+    // The monitor gets every message received from now on.
+    fn monitor_until(
+        self: Arc<Self>,
+        timeout: Option<std::time::Duration>,
+        cancel: CancellationToken,
+        monitor: impl Fn(M) -> Option<()> + Send + Sync + 'static,
+    ) -> MonitorHandle {
+        let validate = {
+            let conn = self.clone();
+            move |header| conn.validate(header)
+        };
+        self.monitor_validated(timeout, cancel, validate, monitor)
+    }
+
+    fn monitor_validated(
+        self: Arc<Self>,
+        timeout: Option<std::time::Duration>,
+        cancel: CancellationToken,
+        validate: impl Fn(MavHeader) -> bool + Send + Sync + 'static,
+        monitor: impl Fn(M) -> Option<()> + Send + Sync + 'static,
+    ) -> MonitorHandle {
+        spawn_monitor(self.subscribe(), validate, timeout, cancel, monitor)
+    }
+
+    // Only for monitors, a message received here is not seen by them.
+    async fn _receive(self: Arc<Self>) -> Result<(MavHeader, M), Error> {
+        self.subscribe().receive().await
+    }
+}
+
+// One vehicle among several: targets `system` and `component` rather than the ids the crate
+// is built with, and only hears from `system`, as other vehicles may share the link.
+#[derive(Debug)]
+pub struct Targeted<C> {
+    inner: Arc<C>,
+    system: u8,
+    component: u8,
+}
+
+impl<C> Targeted<C> {
+    pub fn new(inner: Arc<C>, system: u8, component: u8) -> Self {
+        Self {
+            inner,
+            system,
+            component,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<M, C> MavlinkConnection<M> for Targeted<C>
+where
+    M: Message + Send + Sync + 'static,
+    C: MavlinkConnection<M> + Send + Sync + 'static,
+{
+    fn send(&self, msg: &M) -> Result<usize, Error> {
+        self.inner.send(msg)
+    }
+
+    async fn send_wait<R>(
+        self: Arc<Self>,
+        msg: &M,
+        timeout: std::time::Duration,
+        filter: impl Fn(M) -> FilterRes<R> + Send + Sync + 'static,
+    ) -> Result<Option<R>, Error>
+    where
+        R: Send + Sync + 'static,
+    {
+        send_and_wait(self, msg, timeout, filter).await
+    }
+
+    fn monitor_until(
+        self: Arc<Self>,
+        timeout: Option<std::time::Duration>,
+        cancel: CancellationToken,
+        monitor: impl Fn(M) -> Option<()> + Send + Sync + 'static,
+    ) -> MonitorHandle {
+        let validate = {
+            let conn = self.clone();
+            move |header| conn.validate(header)
+        };
+        self.inner
+            .clone()
+            .monitor_validated(timeout, cancel, validate, monitor)
+    }
+
+    fn monitor_validated(
+        self: Arc<Self>,
+        timeout: Option<std::time::Duration>,
+        cancel: CancellationToken,
+        validate: impl Fn(MavHeader) -> bool + Send + Sync + 'static,
+        monitor: impl Fn(M) -> Option<()> + Send + Sync + 'static,
+    ) -> MonitorHandle {
+        self.inner
+            .clone()
+            .monitor_validated(timeout, cancel, validate, monitor)
+    }
+
+    async fn _receive(self: Arc<Self>) -> Result<(MavHeader, M), Error> {
+        loop {
+            let (header, msg) = self.inner.clone()._receive().await?;
+            if self.validate(header) {
+                return Ok((header, msg));
+            }
+        }
+    }
+
+    fn target_system(&self) -> u8 {
+        self.system
+    }
+
+    fn target_component(&self) -> u8 {
+        self.component
+    }
+
+    fn validate(&self, header: MavHeader) -> bool {
+        header.system_id == self.system
     }
 }
 
@@ -143,35 +692,52 @@ pub mod test {
         sync::{Arc, Mutex},
     };
 
-    use super::{FilterRes, MavlinkConnection, MavlinkConnectionError};
-    use mavlink::{
-        ardupilotmega::{MavMessage, HEARTBEAT_DATA},
-        MavConnection,
-    };
+    use mavlink::{ardupilotmega::MavMessage, MavConnection, Message};
+
+    // Only used by the tests below, the rest of the module is also built with `tester`.
+    #[cfg(test)]
+    use super::{FilterRes, MavlinkConnection, MonitorHandle, SharedConnection};
+    #[cfg(test)]
+    use crate::error::{Error, ErrorKind};
+    #[cfg(test)]
+    use crate::sim::SimVehicle;
+    #[cfg(test)]
+    use mavlink::ardupilotmega::HEARTBEAT_DATA;
+    #[cfg(test)]
+    use tokio_util::sync::CancellationToken;
 
-    #[derive(Default)]
-    pub struct TestMavConnection {
-        sent: Arc<Mutex<Option<MavMessage>>>,
-        value: Arc<Mutex<Option<MavMessage>>>,
+    // Generic over the dialect, ardupilotmega by default.
+    pub struct TestMavConnection<M = MavMessage> {
+        sent: Arc<Mutex<Option<M>>>,
+        value: Arc<Mutex<Option<M>>>,
     }
 
-    impl TestMavConnection {
-        pub fn inject_msg(&self, data: MavMessage) {
+    impl<M> Default for TestMavConnection<M> {
+        fn default() -> Self {
+            Self {
+                sent: Default::default(),
+                value: Default::default(),
+            }
+        }
+    }
+
+    impl<M: Clone> TestMavConnection<M> {
+        pub fn inject_msg(&self, data: M) {
             self.value.lock().unwrap().replace(data);
         }
 
-        pub fn last_sent(&self) -> Option<MavMessage> {
+        pub fn last_sent(&self) -> Option<M> {
             self.sent.lock().unwrap().take()
         }
     }
 
-    impl Debug for TestMavConnection {
+    impl<M: Debug> Debug for TestMavConnection<M> {
         fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
             write!(f, "TestMavConnection [{:?}]", self.value.lock().unwrap())
         }
     }
 
-    impl MavConnection<MavMessage> for TestMavConnection {
+    impl<M: Message + Clone> MavConnection<M> for TestMavConnection<M> {
         fn get_protocol_version(&self) -> mavlink::MavlinkVersion {
             mavlink::MavlinkVersion::V1
         }
@@ -179,18 +745,16 @@ pub mod test {
         fn send(
             &self,
             _header: &mavlink::MavHeader,
-            data: &MavMessage,
+            data: &M,
         ) -> Result<usize, mavlink::error::MessageWriteError> {
             self.sent.lock().unwrap().replace(data.clone());
             // TODO(bjc) this is not representative
             Ok(1)
         }
 
-        fn recv(
-            &self,
-        ) -> Result<(mavlink::MavHeader, MavMessage), mavlink::error::MessageReadError> {
+        fn recv(&self) -> Result<(mavlink::MavHeader, M), mavlink::error::MessageReadError> {
             loop {
-                if let Some(value) = self.value.lock().unwrap().as_mut().take() {
+                if let Some(value) = self.value.lock().unwrap().as_ref() {
                     return Ok((Default::default(), value.clone()));
                 }
                 std::thread::sleep(std::time::Duration::from_secs_f64(0.01));
@@ -198,6 +762,73 @@ pub mod test {
         }
     }
 
+    // Several simulated vehicles on one link: what is sent reaches all of them, and what any
+    // of them sends is received.
+    #[cfg(test)]
+    pub struct Bus {
+        vehicles: Vec<Arc<SimVehicle>>,
+        received: Mutex<std::sync::mpsc::Receiver<(mavlink::MavHeader, MavMessage)>>,
+    }
+
+    #[cfg(test)]
+    impl Bus {
+        pub fn new(vehicles: Vec<Arc<SimVehicle>>) -> Self {
+            let (tx, rx) = std::sync::mpsc::channel();
+            for vehicle in &vehicles {
+                let vehicle = vehicle.clone();
+                let tx = tx.clone();
+                std::thread::spawn(move || {
+                    while let Ok(received) = vehicle.recv() {
+                        if tx.send(received).is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+            Self {
+                vehicles,
+                received: Mutex::new(rx),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    impl MavConnection<MavMessage> for Bus {
+        fn get_protocol_version(&self) -> mavlink::MavlinkVersion {
+            mavlink::MavlinkVersion::V2
+        }
+        fn set_protocol_version(&mut self, _version: mavlink::MavlinkVersion) {}
+
+        fn send(
+            &self,
+            header: &mavlink::MavHeader,
+            data: &MavMessage,
+        ) -> Result<usize, mavlink::error::MessageWriteError> {
+            for vehicle in &self.vehicles {
+                vehicle.send(header, data)?;
+            }
+            Ok(1)
+        }
+
+        fn recv(
+            &self,
+        ) -> Result<(mavlink::MavHeader, MavMessage), mavlink::error::MessageReadError> {
+            self.received.lock().unwrap().recv().map_err(|_| {
+                std::io::Error::new(std::io::ErrorKind::BrokenPipe, "Bus closed").into()
+            })
+        }
+    }
+
+    // How many messages named `name` the vehicle was sent.
+    #[cfg(test)]
+    pub fn sent(vehicle: &SimVehicle, name: &str) -> usize {
+        vehicle
+            .received()
+            .iter()
+            .filter(|msg| msg.message_name() == name)
+            .count()
+    }
+
     pub fn start_heartbeats(conn: Arc<Box<TestMavConnection>>) {
         tokio::spawn({
             async move {
@@ -224,7 +855,7 @@ pub mod test {
             )
             .await;
 
-        assert!(matches!(res, Err(MavlinkConnectionError::Timeout)));
+        assert!(res.is_err_and(|e| e.is_timeout()));
     }
 
     #[tokio::test]
@@ -296,10 +927,9 @@ pub mod test {
                 Some(std::time::Duration::from_secs_f64(0.001)),
                 |_| Some(()),
             )
-            .await
-            .unwrap();
+            .await;
 
-        assert!(matches!(res, Err(MavlinkConnectionError::Timeout)))
+        assert!(res.is_err_and(|e| e.is_timeout()))
     }
 
     #[tokio::test]
@@ -310,7 +940,7 @@ pub mod test {
 
         let (tx, mut rx) = tokio::sync::watch::channel(None);
 
-        connection.monitor(None, move |msg| {
+        let _monitor = connection.monitor(None, move |msg| {
             if let MavMessage::HEARTBEAT(data) = msg {
                 tx.send(Some(data)).unwrap();
                 return None;
@@ -326,4 +956,194 @@ pub mod test {
         .await
         .unwrap();
     }
+
+    #[tokio::test]
+    async fn monitor_panic() {
+        let connection: Arc<Box<TestMavConnection>> = Default::default();
+
+        start_heartbeats(connection.clone());
+
+        let res = connection.monitor(None, |_| panic!("Broken monitor")).await;
+
+        assert!(matches!(
+            res,
+            Err(Error {
+                kind: ErrorKind::Aborted(_),
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn monitor_stops_when_dropped() {
+        let connection: Arc<Box<TestMavConnection>> = Default::default();
+
+        start_heartbeats(connection.clone());
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<()>();
+        let monitor = connection.clone().monitor(None, move |_| {
+            let _ = tx.send(());
+            Some(())
+        });
+        rx.recv().await.unwrap();
+
+        drop(monitor);
+
+        // The monitor, and its sender with it, is gone: the channel drains then closes.
+        tokio::time::timeout(std::time::Duration::from_secs(1), async {
+            while rx.recv().await.is_some() {}
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn monitor_cancelled() {
+        let connection: Arc<Box<TestMavConnection>> = Default::default();
+
+        // Pending receives must be able to finish for the runtime to shut down.
+        connection.inject_msg(MavMessage::HEARTBEAT(Default::default()));
+
+        let shutdown = CancellationToken::new();
+        let first = connection
+            .clone()
+            .monitor_until(None, shutdown.clone(), |_| Some(()));
+        let second = connection
+            .clone()
+            .monitor_until(None, shutdown.clone(), |_| Some(()));
+
+        // Dropping a handle only stops its own monitor.
+        drop(first);
+        assert!(!shutdown.is_cancelled());
+
+        shutdown.cancel();
+        let res = tokio::time::timeout(std::time::Duration::from_secs(1), second)
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            res,
+            Err(Error {
+                kind: ErrorKind::Aborted(_),
+                ..
+            })
+        ));
+    }
+
+    // Marks the messages injected into the simulator, as it sends many others on its own.
+    #[cfg(test)]
+    fn marked(index: u16) -> MavMessage {
+        MavMessage::PARAM_VALUE(mavlink::ardupilotmega::PARAM_VALUE_DATA {
+            param_index: index,
+            param_count: u16::MAX,
+            ..Default::default()
+        })
+    }
+
+    // Collects the marked messages seen by a monitor, until `count` of them.
+    #[cfg(test)]
+    fn collect_marked(
+        connection: Arc<SharedConnection>,
+        count: usize,
+    ) -> (Arc<Mutex<Vec<u16>>>, MonitorHandle) {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let monitor = connection.monitor(Some(std::time::Duration::from_secs(2)), {
+            let seen = seen.clone();
+            move |msg| {
+                let mut seen = seen.lock().unwrap();
+                match msg {
+                    MavMessage::PARAM_VALUE(data) if data.param_count == u16::MAX => {
+                        seen.push(data.param_index)
+                    }
+                    _ => {}
+                }
+                (seen.len() < count).then_some(())
+            }
+        });
+        (seen, monitor)
+    }
+
+    #[tokio::test]
+    async fn shared_monitors_see_every_message() {
+        let sim: Arc<SimVehicle> = Default::default();
+        let connection = Arc::new(SharedConnection::new(sim.clone()).unwrap());
+
+        let (first, first_monitor) = collect_marked(connection.clone(), 3);
+        let (second, second_monitor) = collect_marked(connection.clone(), 3);
+        for index in 0..3 {
+            sim.inject(marked(index));
+        }
+
+        first_monitor.await.unwrap();
+        second_monitor.await.unwrap();
+        assert_eq!(*first.lock().unwrap(), vec![0, 1, 2]);
+        assert_eq!(*second.lock().unwrap(), vec![0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn stopped_monitor_takes_nothing() {
+        let sim: Arc<SimVehicle> = Default::default();
+        let connection = Arc::new(SharedConnection::new(sim.clone()).unwrap());
+
+        // Stopped while waiting for a message.
+        let (_, stopped) = collect_marked(connection.clone(), 3);
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        drop(stopped);
+
+        let (seen, monitor) = collect_marked(connection.clone(), 3);
+        for index in 0..3 {
+            sim.inject(marked(index));
+        }
+
+        monitor.await.unwrap();
+        assert_eq!(*seen.lock().unwrap(), vec![0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn shared_send_wait() {
+        let sim: Arc<SimVehicle> = Default::default();
+        let connection = Arc::new(SharedConnection::new(sim.clone()).unwrap());
+
+        let res = connection
+            .clone()
+            .send_wait(
+                &MavMessage::PARAM_REQUEST_READ(Default::default()),
+                std::time::Duration::from_secs(2),
+                |msg| match msg {
+                    MavMessage::HEARTBEAT(_) => FilterRes::Ready(Some(())),
+                    _ => FilterRes::NotReady,
+                },
+            )
+            .await;
+
+        assert!(matches!(res, Ok(Some(()))), "{res:?}");
+        assert!(matches!(
+            sim.received().last(),
+            Some(MavMessage::PARAM_REQUEST_READ(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn closes_monitors_on_lost_connection() {
+        // Nobody on the bus, receiving fails for good.
+        let connection = Arc::new(SharedConnection::new(Arc::new(Bus::new(vec![]))).unwrap());
+
+        let res = tokio::time::timeout(
+            std::time::Duration::from_secs(1),
+            connection.monitor(None, |_| Some(())),
+        )
+        .await
+        .unwrap();
+
+        assert!(
+            matches!(
+                res,
+                Err(Error {
+                    kind: ErrorKind::Aborted(_),
+                    ..
+                })
+            ),
+            "{res:?}"
+        );
+    }
 }
@@ -0,0 +1,195 @@
+// The error returned by every operation of the crate: what went wrong, and where it went wrong
+// in the exchange with the vehicle.
+
+use std::fmt::Display;
+
+use mavlink::error::{MessageReadError, MessageWriteError};
+
+use crate::{ftp::payload::NakError, prearm::ReadinessReport};
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug)]
+pub enum ErrorKind {
+    // No answer came in time.
+    Timeout,
+    Read(MessageReadError),
+    Write(MessageWriteError),
+    Io(std::io::Error),
+    // The vehicle answered, and refused, e.g. with a MAV_RESULT or MAV_MISSION_RESULT.
+    Rejected(String),
+    // The vehicle answered something that does not fit the request.
+    InvalidResponse(String),
+    // Unreadable input, like a mission or parameter file.
+    Parse(String),
+    TooManyItems(u32),
+    Nak(NakError),
+    // Carries the last report, which lists what was still failing.
+    NotReadyToArm(Box<ReadinessReport>),
+    // A task stopped before finishing, e.g. a monitor that panicked.
+    Aborted(String),
+    Other(String),
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Timeout => write!(f, "timed out"),
+            Self::Read(e) => write!(f, "could not read: {e}"),
+            Self::Write(e) => write!(f, "could not write: {e}"),
+            Self::Io(e) => write!(f, "{e}"),
+            Self::Rejected(reason) => write!(f, "rejected: {reason}"),
+            Self::InvalidResponse(reason) => write!(f, "invalid response: {reason}"),
+            Self::Parse(reason) => write!(f, "could not parse: {reason}"),
+            Self::TooManyItems(count) => write!(f, "too many items: {count}"),
+            Self::Nak(nak) => write!(f, "refused: {nak:?}"),
+            Self::NotReadyToArm(_) => write!(f, "not ready to arm"),
+            Self::Aborted(reason) => write!(f, "aborted: {reason}"),
+            Self::Other(reason) => write!(f, "{reason}"),
+        }
+    }
+}
+
+// Where an error happened, as far as it is known.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Context {
+    // e.g. "mission upload".
+    pub operation: Option<&'static str>,
+    // Message sent, or expected, when the error happened.
+    pub message_id: Option<u32>,
+    // Mission item, log chunk or parameter index being exchanged.
+    pub seq: Option<u32>,
+    // Time spent before giving up.
+    pub elapsed: Option<std::time::Duration>,
+}
+
+#[derive(Debug)]
+pub struct Error {
+    pub kind: ErrorKind,
+    pub context: Context,
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind) -> Self {
+        Self {
+            kind,
+            context: Default::default(),
+        }
+    }
+
+    pub fn timeout(elapsed: std::time::Duration) -> Self {
+        Self::new(ErrorKind::Timeout).elapsed(elapsed)
+    }
+
+    pub fn other(reason: impl Into<String>) -> Self {
+        Self::new(ErrorKind::Other(reason.into()))
+    }
+
+    pub fn is_timeout(&self) -> bool {
+        matches!(self.kind, ErrorKind::Timeout)
+    }
+
+    // The context setters keep what is already known: the innermost context is the most precise.
+    pub fn operation(mut self, operation: &'static str) -> Self {
+        self.context.operation.get_or_insert(operation);
+        self
+    }
+
+    pub fn message_id(mut self, id: u32) -> Self {
+        self.context.message_id.get_or_insert(id);
+        self
+    }
+
+    pub fn seq(mut self, seq: u32) -> Self {
+        self.context.seq.get_or_insert(seq);
+        self
+    }
+
+    pub fn elapsed(mut self, elapsed: std::time::Duration) -> Self {
+        self.context.elapsed.get_or_insert(elapsed);
+        self
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(operation) = self.context.operation {
+            write!(f, "{operation}: ")?;
+        }
+        write!(f, "{}", self.kind)?;
+
+        let mut details = vec![];
+        if let Some(id) = self.context.message_id {
+            details.push(format!("message {id}"));
+        }
+        if let Some(seq) = self.context.seq {
+            details.push(format!("seq {seq}"));
+        }
+        if let Some(elapsed) = self.context.elapsed {
+            details.push(format!("after {elapsed:?}"));
+        }
+        if !details.is_empty() {
+            write!(f, " ({})", details.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.kind {
+            ErrorKind::Read(e) => Some(e),
+            ErrorKind::Write(e) => Some(e),
+            ErrorKind::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Self {
+        Self::new(kind)
+    }
+}
+
+impl From<MessageReadError> for Error {
+    fn from(e: MessageReadError) -> Self {
+        Self::new(ErrorKind::Read(e))
+    }
+}
+
+impl From<MessageWriteError> for Error {
+    fn from(e: MessageWriteError) -> Self {
+        Self::new(ErrorKind::Write(e))
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Self::new(ErrorKind::Io(e))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Error, ErrorKind};
+
+    #[test]
+    fn displays_context() {
+        let error = Error::timeout(std::time::Duration::from_secs(1))
+            .seq(3)
+            .message_id(73)
+            .operation("mission upload")
+            .operation("fleet upload");
+
+        assert!(error.is_timeout());
+        assert_eq!(
+            error.to_string(),
+            "mission upload: timed out (message 73, seq 3, after 1s)"
+        );
+        assert_eq!(
+            Error::new(ErrorKind::Rejected("MAV_MISSION_DENIED".to_string())).to_string(),
+            "rejected: MAV_MISSION_DENIED"
+        );
+    }
+}
@@ -0,0 +1,440 @@
+// Conditions a mission supervisor has to react to: low battery, lost radio or GCS link, fence
+// breaches, a failing EKF, and the vehicle heading home or landing on its own. Each is reported
+// once when it starts, and once when it ends.
+
+use std::{collections::HashMap, fmt::Debug, sync::Arc};
+
+use mavlink::ardupilotmega::{
+    FenceBreach, MavAutopilot, MavMessage, MavSysStatusSensor, MavType, PlaneMode,
+    BATTERY_STATUS_DATA, EKF_STATUS_REPORT_DATA, SYS_STATUS_DATA,
+};
+
+use crate::connection::{MavlinkConnection, MonitorHandle};
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Options {
+    // Remaining capacity in percent at or under which the battery is low, and critical.
+    pub battery_low: u8,
+    pub battery_critical: u8,
+    // Battery voltage at or under which the battery is low, and critical, when set.
+    pub voltage_low: Option<f32>,
+    pub voltage_critical: Option<f32>,
+    // Variance over which an EKF estimate is failing, see FS_EKF_THRESH in ArduPilot.
+    pub ekf_threshold: f32,
+    // custom_mode values of the return and land modes, these differ per vehicle type.
+    pub rtl_modes: Vec<u32>,
+    pub land_modes: Vec<u32>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            battery_low: 30,
+            battery_critical: 15,
+            voltage_low: None,
+            voltage_critical: None,
+            ekf_threshold: 0.8,
+            rtl_modes: vec![
+                PlaneMode::PLANE_MODE_RTL as u32,
+                PlaneMode::PLANE_MODE_QRTL as u32,
+            ],
+            land_modes: vec![PlaneMode::PLANE_MODE_QLAND as u32],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BatteryLevel {
+    Low,
+    Critical,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Failsafe {
+    Battery { id: u8, level: BatteryLevel },
+    // The vehicle lost the RC receiver.
+    Radio,
+    // The vehicle lost the ground station, as announced in STATUSTEXT.
+    Gcs,
+    Fence(FenceBreach),
+    Ekf,
+    Rtl { custom_mode: u32 },
+    Land { custom_mode: u32 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FailsafeEvent {
+    pub failsafe: Failsafe,
+    // False when the condition is over.
+    pub active: bool,
+    pub time: std::time::SystemTime,
+}
+
+// Turns telemetry into failsafe events.
+#[derive(Debug)]
+pub struct FailsafeTracker {
+    options: Options,
+    active: Vec<Failsafe>,
+    batteries: HashMap<u8, Option<BatteryLevel>>,
+}
+
+impl FailsafeTracker {
+    pub fn new(options: Options) -> Self {
+        Self {
+            options,
+            active: vec![],
+            batteries: HashMap::new(),
+        }
+    }
+
+    pub fn active(&self) -> &[Failsafe] {
+        &self.active
+    }
+
+    pub fn update(&mut self, msg: MavMessage, time: std::time::SystemTime) -> Vec<FailsafeEvent> {
+        let mut events = vec![];
+        match msg {
+            MavMessage::BATTERY_STATUS(data) => {
+                let (remaining, voltage) = battery_status(&data);
+                self.battery(data.id, remaining, voltage, time, &mut events);
+            }
+            MavMessage::SYS_STATUS(data) => {
+                let (remaining, voltage) = sys_status(&data);
+                // The first battery, which BATTERY_STATUS reports as id 0 too.
+                if remaining.is_some() || voltage.is_some() {
+                    self.battery(0, remaining, voltage, time, &mut events);
+                }
+                let rc = MavSysStatusSensor::MAV_SYS_STATUS_SENSOR_RC_RECEIVER;
+                let monitored = data.onboard_control_sensors_present.contains(rc)
+                    && data.onboard_control_sensors_enabled.contains(rc);
+                let lost = monitored && !data.onboard_control_sensors_health.contains(rc);
+                self.set(Failsafe::Radio, lost, time, &mut events);
+            }
+            MavMessage::FENCE_STATUS(data) => {
+                let breach = (data.breach_status != 0).then_some(data.breach_type);
+                let ended = |f: &Failsafe| matches!(f, Failsafe::Fence(b) if Some(*b) != breach);
+                self.clear(ended, time, &mut events);
+                if let Some(breach) = breach {
+                    self.set(Failsafe::Fence(breach), true, time, &mut events);
+                }
+            }
+            MavMessage::EKF_STATUS_REPORT(data) => {
+                let failing = self.ekf_failing(&data);
+                self.set(Failsafe::Ekf, failing, time, &mut events);
+            }
+            MavMessage::STATUSTEXT(data) => {
+                let len = data.text.iter().position(|b| *b == 0);
+                let text = String::from_utf8_lossy(&data.text[..len.unwrap_or(data.text.len())])
+                    .to_lowercase();
+                // ArduPilot announces "GCS Failsafe", and "GCS Failsafe Cleared".
+                if text.contains("gcs failsafe") {
+                    let cleared = text.contains("clear") || text.contains("off");
+                    self.set(Failsafe::Gcs, !cleared, time, &mut events);
+                }
+            }
+            MavMessage::HEARTBEAT(beat) => {
+                let from_autopilot = !matches!(beat.mavtype, MavType::MAV_TYPE_GCS)
+                    && !matches!(beat.autopilot, MavAutopilot::MAV_AUTOPILOT_INVALID);
+                if from_autopilot {
+                    self.mode(beat.custom_mode, time, &mut events);
+                }
+            }
+            _ => {}
+        }
+        events
+    }
+
+    fn set(
+        &mut self,
+        failsafe: Failsafe,
+        active: bool,
+        time: std::time::SystemTime,
+        events: &mut Vec<FailsafeEvent>,
+    ) {
+        let position = self.active.iter().position(|f| *f == failsafe);
+        match (position, active) {
+            (None, true) => self.active.push(failsafe),
+            (Some(position), false) => {
+                self.active.remove(position);
+            }
+            _ => return,
+        }
+        events.push(FailsafeEvent {
+            failsafe,
+            active,
+            time,
+        });
+    }
+
+    fn clear(
+        &mut self,
+        matches: impl Fn(&Failsafe) -> bool,
+        time: std::time::SystemTime,
+        events: &mut Vec<FailsafeEvent>,
+    ) {
+        let ended: Vec<Failsafe> = self.active.iter().copied().filter(matches).collect();
+        for failsafe in ended {
+            self.set(failsafe, false, time, events);
+        }
+    }
+
+    // `remaining` in percent, `voltage` in volts.
+    fn battery(
+        &mut self,
+        id: u8,
+        remaining: Option<u8>,
+        voltage: Option<f32>,
+        time: std::time::SystemTime,
+        events: &mut Vec<FailsafeEvent>,
+    ) {
+        let options = &self.options;
+        let under = |percent: u8, volts: Option<f32>| {
+            remaining.is_some_and(|r| r <= percent)
+                || voltage.zip(volts).is_some_and(|(v, volts)| v <= volts)
+        };
+        let level = if under(options.battery_critical, options.voltage_critical) {
+            Some(BatteryLevel::Critical)
+        } else if under(options.battery_low, options.voltage_low) {
+            Some(BatteryLevel::Low)
+        } else {
+            None
+        };
+
+        if self.batteries.insert(id, level) == Some(level) {
+            return;
+        }
+        for each in [BatteryLevel::Low, BatteryLevel::Critical] {
+            let failsafe = Failsafe::Battery { id, level: each };
+            self.set(failsafe, level == Some(each), time, events);
+        }
+    }
+
+    // As ArduPilot: two of the velocity, position and compass variances over the threshold.
+    fn ekf_failing(&self, data: &EKF_STATUS_REPORT_DATA) -> bool {
+        let threshold = self.options.ekf_threshold;
+        let over = [
+            data.velocity_variance,
+            data.pos_horiz_variance,
+            data.compass_variance,
+        ]
+        .into_iter()
+        .filter(|variance| *variance >= threshold)
+        .count();
+        threshold > 0.0 && over >= 2
+    }
+
+    fn mode(
+        &mut self,
+        custom_mode: u32,
+        time: std::time::SystemTime,
+        events: &mut Vec<FailsafeEvent>,
+    ) {
+        self.clear(
+            |f| match f {
+                Failsafe::Rtl { custom_mode: mode } | Failsafe::Land { custom_mode: mode } => {
+                    *mode != custom_mode
+                }
+                _ => false,
+            },
+            time,
+            events,
+        );
+        if self.options.rtl_modes.contains(&custom_mode) {
+            self.set(Failsafe::Rtl { custom_mode }, true, time, events);
+        } else if self.options.land_modes.contains(&custom_mode) {
+            self.set(Failsafe::Land { custom_mode }, true, time, events);
+        }
+    }
+}
+
+// Remaining percent and total voltage, when known.
+fn battery_status(data: &BATTERY_STATUS_DATA) -> (Option<u8>, Option<f32>) {
+    let cells: Vec<u16> = data
+        .voltages
+        .iter()
+        .copied()
+        .filter(|mv| *mv != u16::MAX)
+        .collect();
+    let voltage =
+        (!cells.is_empty()).then(|| cells.iter().map(|mv| *mv as f32).sum::<f32>() / 1000.0);
+    (u8::try_from(data.battery_remaining).ok(), voltage)
+}
+
+fn sys_status(data: &SYS_STATUS_DATA) -> (Option<u8>, Option<f32>) {
+    let voltage = (data.voltage_battery != u16::MAX && data.voltage_battery != 0)
+        .then(|| data.voltage_battery as f32 / 1000.0);
+    (u8::try_from(data.battery_remaining).ok(), voltage)
+}
+
+// Sends every failsafe event to the returned receiver, until the returned handle is dropped.
+pub fn monitor_failsafes<C>(
+    connection: Arc<C>,
+    options: Options,
+) -> (
+    tokio::sync::mpsc::UnboundedReceiver<FailsafeEvent>,
+    MonitorHandle,
+)
+where
+    C: MavlinkConnection + Debug + Send + Sync + 'static,
+{
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    let tracker = std::sync::Mutex::new(FailsafeTracker::new(options));
+
+    let handle = connection.monitor(None, move |msg| {
+        let events = tracker
+            .lock()
+            .unwrap()
+            .update(msg, std::time::SystemTime::now());
+        for event in events {
+            tracing::event!(
+                tracing::Level::WARN,
+                failsafe = ?event.failsafe,
+                event.active,
+                "Failsafe"
+            );
+            // Nobody is listening anymore.
+            if tx.send(event).is_err() {
+                return None;
+            }
+        }
+        Some(())
+    });
+
+    (rx, handle)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use mavlink::ardupilotmega::{
+        FenceBreach, MavMessage, MavSysStatusSensor, PlaneMode, BATTERY_STATUS_DATA,
+        EKF_STATUS_REPORT_DATA, FENCE_STATUS_DATA, HEARTBEAT_DATA, SYS_STATUS_DATA,
+    };
+
+    use super::{monitor_failsafes, BatteryLevel, Failsafe, FailsafeTracker};
+    use crate::sim::SimVehicle;
+
+    fn changes(tracker: &mut FailsafeTracker, msg: MavMessage) -> Vec<(Failsafe, bool)> {
+        tracker
+            .update(msg, std::time::SystemTime::now())
+            .into_iter()
+            .map(|event| (event.failsafe, event.active))
+            .collect()
+    }
+
+    fn battery(remaining: i8) -> MavMessage {
+        MavMessage::BATTERY_STATUS(BATTERY_STATUS_DATA {
+            id: 1,
+            battery_remaining: remaining,
+            voltages: [u16::MAX; 10],
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn battery_levels() {
+        let mut tracker = FailsafeTracker::new(Default::default());
+        let low = Failsafe::Battery {
+            id: 1,
+            level: BatteryLevel::Low,
+        };
+        let critical = Failsafe::Battery {
+            id: 1,
+            level: BatteryLevel::Critical,
+        };
+
+        assert_eq!(changes(&mut tracker, battery(80)), []);
+        assert_eq!(changes(&mut tracker, battery(30)), [(low, true)]);
+        assert_eq!(changes(&mut tracker, battery(25)), []);
+        assert_eq!(
+            changes(&mut tracker, battery(10)),
+            [(low, false), (critical, true)]
+        );
+        // Unknown, e.g. after swapping the battery.
+        assert_eq!(changes(&mut tracker, battery(-1)), [(critical, false)]);
+        assert!(tracker.active().is_empty());
+    }
+
+    #[test]
+    fn link_ekf_and_modes() {
+        let mut tracker = FailsafeTracker::new(Default::default());
+
+        let rc = MavSysStatusSensor::MAV_SYS_STATUS_SENSOR_RC_RECEIVER;
+        let status = |health| {
+            MavMessage::SYS_STATUS(SYS_STATUS_DATA {
+                onboard_control_sensors_present: rc,
+                onboard_control_sensors_enabled: rc,
+                onboard_control_sensors_health: health,
+                voltage_battery: u16::MAX,
+                battery_remaining: -1,
+                ..Default::default()
+            })
+        };
+        assert_eq!(
+            changes(&mut tracker, status(MavSysStatusSensor::empty())),
+            [(Failsafe::Radio, true)]
+        );
+        assert_eq!(
+            changes(&mut tracker, status(rc)),
+            [(Failsafe::Radio, false)]
+        );
+
+        let ekf = MavMessage::EKF_STATUS_REPORT(EKF_STATUS_REPORT_DATA {
+            velocity_variance: 1.0,
+            compass_variance: 0.9,
+            ..Default::default()
+        });
+        assert_eq!(changes(&mut tracker, ekf), [(Failsafe::Ekf, true)]);
+
+        let heartbeat = |mode: PlaneMode| {
+            MavMessage::HEARTBEAT(HEARTBEAT_DATA {
+                custom_mode: mode as u32,
+                ..Default::default()
+            })
+        };
+        let rtl = Failsafe::Rtl {
+            custom_mode: PlaneMode::PLANE_MODE_RTL as u32,
+        };
+        let qland = Failsafe::Land {
+            custom_mode: PlaneMode::PLANE_MODE_QLAND as u32,
+        };
+        assert_eq!(
+            changes(&mut tracker, heartbeat(PlaneMode::PLANE_MODE_RTL)),
+            [(rtl, true)]
+        );
+        assert_eq!(
+            changes(&mut tracker, heartbeat(PlaneMode::PLANE_MODE_RTL)),
+            []
+        );
+        assert_eq!(
+            changes(&mut tracker, heartbeat(PlaneMode::PLANE_MODE_QLAND)),
+            [(rtl, false), (qland, true)]
+        );
+        assert_eq!(tracker.active(), [Failsafe::Ekf, qland]);
+    }
+
+    #[tokio::test]
+    async fn reports_fence_breach() {
+        let connection: Arc<Box<SimVehicle>> = Default::default();
+        let (mut events, _handle) = monitor_failsafes(connection.clone(), Default::default());
+
+        connection.inject(MavMessage::FENCE_STATUS(FENCE_STATUS_DATA {
+            breach_status: 1,
+            breach_count: 1,
+            breach_type: FenceBreach::FENCE_BREACH_MAXALT,
+            ..Default::default()
+        }));
+
+        let event = tokio::time::timeout(std::time::Duration::from_secs(1), events.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            event.failsafe,
+            Failsafe::Fence(FenceBreach::FENCE_BREACH_MAXALT)
+        );
+        assert!(event.active);
+        assert!(event.time <= std::time::SystemTime::now());
+    }
+}
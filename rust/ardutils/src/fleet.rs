@@ -0,0 +1,511 @@
+// The same command, or mode change, sent to several vehicles at once. Each vehicle answers on
+// its own, so results are reported per vehicle rather than as a single error.
+
+use std::{collections::BTreeMap, fmt::Debug, sync::Arc};
+
+use crate::{
+    command::Command,
+    connection::{MavlinkConnection, Targeted},
+    error::{Error, ErrorKind, Result},
+    mode::ChangeMode,
+};
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Options {
+    // How long to wait for each vehicle to answer before sending again.
+    pub timeout: std::time::Duration,
+    pub retries: u8,
+    // Mode changes only: unless every vehicle changed mode, every vehicle goes back to the mode
+    // it was in.
+    pub rollback: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            timeout: std::time::Duration::from_secs(1),
+            retries: 2,
+            rollback: false,
+        }
+    }
+}
+
+// Vehicles by system id. Whatever the connection targets otherwise, commands and mode changes
+// are sent to that system id.
+#[derive(Debug)]
+pub struct Fleet<C> {
+    vehicles: BTreeMap<u8, Arc<C>>,
+}
+
+impl<C> Default for Fleet<C> {
+    fn default() -> Self {
+        Self {
+            vehicles: BTreeMap::new(),
+        }
+    }
+}
+
+impl<C> Clone for Fleet<C> {
+    fn clone(&self) -> Self {
+        Self {
+            vehicles: self.vehicles.clone(),
+        }
+    }
+}
+
+impl<C> Fleet<C> {
+    pub fn insert(&mut self, id: u8, connection: Arc<C>) -> Option<Arc<C>> {
+        self.vehicles.insert(id, connection)
+    }
+
+    pub fn remove(&mut self, id: u8) -> Option<Arc<C>> {
+        self.vehicles.remove(&id)
+    }
+
+    pub fn get(&self, id: u8) -> Option<&Arc<C>> {
+        self.vehicles.get(&id)
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = u8> + '_ {
+        self.vehicles.keys().copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.vehicles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vehicles.is_empty()
+    }
+}
+
+impl<C> FromIterator<(u8, Arc<C>)> for Fleet<C> {
+    fn from_iter<I: IntoIterator<Item = (u8, Arc<C>)>>(iter: I) -> Self {
+        Self {
+            vehicles: iter.into_iter().collect(),
+        }
+    }
+}
+
+// Runs `f` for every vehicle concurrently, with a connection targeting it, and collects the
+// results.
+async fn for_each<M, C, F, Fut, T>(vehicles: &BTreeMap<u8, Arc<C>>, f: F) -> BTreeMap<u8, Result<T>>
+where
+    M: mavlink::Message + Send + Sync + 'static,
+    C: MavlinkConnection<M> + Send + Sync + 'static,
+    F: Fn(u8, Arc<Targeted<C>>) -> Fut,
+    Fut: std::future::Future<Output = Result<T>> + Send + 'static,
+    T: Send + 'static,
+{
+    let tasks: Vec<_> = vehicles
+        .iter()
+        .map(|(id, connection)| {
+            let connection = Targeted::new(connection.clone(), *id, connection.target_component());
+            (*id, tokio::spawn(f(*id, Arc::new(connection))))
+        })
+        .collect();
+
+    let mut results = BTreeMap::new();
+    for (id, task) in tasks {
+        let res = task
+            .await
+            .unwrap_or_else(|e| Err(Error::new(ErrorKind::Aborted(e.to_string()))));
+        results.insert(id, res);
+    }
+    results
+}
+
+impl<C> Fleet<C> {
+    // Sends `command` to every vehicle, retargeted at it, and returns each final acknowledgement.
+    // An acknowledgement is not an acceptance: check its result.
+    pub async fn command<T>(&self, command: T, options: &Options) -> BTreeMap<u8, Result<T::Ack>>
+    where
+        T: Command,
+        C: MavlinkConnection<T::Message> + Debug + Send + Sync + 'static,
+    {
+        let (timeout, retries) = (options.timeout, options.retries);
+        for_each(&self.vehicles, |id, connection| {
+            let command =
+                command.retarget(connection.target_system(), connection.target_component());
+            async move {
+                let res = command.command_retry(connection, timeout, retries).await;
+                if let Err(e) = &res {
+                    tracing::event!(tracing::Level::WARN, id, %e, "Fleet command failed");
+                }
+                res.map_err(|e| e.operation("fleet command"))
+            }
+        })
+        .await
+    }
+
+    // Puts every vehicle in `mode`. With `rollback`, nothing is changed unless every vehicle
+    // reports its current mode first, and every vehicle is put back in it if any change fails.
+    pub async fn change_mode<M>(&self, mode: M, options: &Options) -> ModeChange<M>
+    where
+        M: ChangeMode + Copy + PartialEq + Debug + Send + Sync + 'static,
+        C: MavlinkConnection<M::Message> + Debug + Send + Sync + 'static,
+    {
+        let (timeout, retries) = (options.timeout, options.retries);
+
+        let mut previous = BTreeMap::new();
+        if options.rollback {
+            let modes = for_each(&self.vehicles, move |_, connection| {
+                current_mode::<_, M>(connection, timeout)
+            })
+            .await;
+            let mut errors = BTreeMap::new();
+            for (id, res) in modes {
+                match res {
+                    Ok(mode) => {
+                        previous.insert(id, mode);
+                    }
+                    Err(e) => {
+                        errors.insert(id, e);
+                    }
+                }
+            }
+
+            if !errors.is_empty() {
+                let results = self
+                    .vehicles
+                    .keys()
+                    .map(|id| {
+                        let error = errors.remove(id).unwrap_or_else(|| {
+                            Error::new(ErrorKind::Aborted(
+                                "mode of another vehicle unknown".to_string(),
+                            ))
+                        });
+                        (*id, Err(error.operation("fleet mode change")))
+                    })
+                    .collect();
+                return ModeChange {
+                    results,
+                    previous,
+                    rolled_back: BTreeMap::new(),
+                };
+            }
+        }
+
+        let results = for_each(&self.vehicles, move |_, connection| {
+            change_mode_retry(connection, mode, timeout, retries)
+        })
+        .await;
+
+        let mut rolled_back = BTreeMap::new();
+        if options.rollback && results.values().any(|res| res.is_err()) {
+            tracing::event!(
+                tracing::Level::WARN,
+                ?mode,
+                "Rolling back fleet mode change"
+            );
+            let modes = previous.clone();
+            let changed: BTreeMap<u8, bool> =
+                results.iter().map(|(id, res)| (*id, res.is_ok())).collect();
+            rolled_back = for_each(&self.vehicles, move |id, connection| {
+                let (previous, changed) = (modes[&id], changed[&id]);
+                async move {
+                    // A failed change may still have gone through, e.g. when only its
+                    // HEARTBEAT was lost.
+                    if !changed
+                        && current_mode::<_, M>(connection.clone(), timeout).await? == previous
+                    {
+                        return Ok(());
+                    }
+                    change_mode_retry(connection, previous, timeout, retries).await
+                }
+            })
+            .await;
+        }
+
+        ModeChange {
+            results,
+            previous,
+            rolled_back,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ModeChange<M> {
+    pub results: BTreeMap<u8, Result<()>>,
+    // Modes the vehicles were in, only read for a rollback.
+    pub previous: BTreeMap<u8, M>,
+    // Empty unless the change was rolled back.
+    pub rolled_back: BTreeMap<u8, Result<()>>,
+}
+
+impl<M> ModeChange<M> {
+    pub fn succeeded(&self) -> bool {
+        self.results.values().all(|res| res.is_ok())
+    }
+
+    pub fn failed(&self) -> impl Iterator<Item = (u8, &Error)> {
+        self.results
+            .iter()
+            .filter_map(|(id, res)| res.as_ref().err().map(|e| (*id, e)))
+    }
+}
+
+async fn change_mode_retry<C, M>(
+    connection: Arc<C>,
+    mode: M,
+    timeout: std::time::Duration,
+    retries: u8,
+) -> Result<()>
+where
+    M: ChangeMode + Copy + Debug + Send + Sync + 'static,
+    C: MavlinkConnection<M::Message> + Debug + Send + Sync + 'static,
+{
+    let mut attempt = 0;
+    loop {
+        match mode.change_mode_timeout(connection.clone(), timeout).await {
+            Ok(()) => return Ok(()),
+            Err(e) if e.is_timeout() && attempt < retries => {
+                tracing::event!(tracing::Level::DEBUG, attempt, ?mode, "Changing mode again");
+                attempt += 1;
+            }
+            Err(e) => return Err(e.operation("fleet mode change")),
+        }
+    }
+}
+
+// The mode in the next HEARTBEAT from the autopilot.
+async fn current_mode<C, M>(connection: Arc<C>, timeout: std::time::Duration) -> Result<M>
+where
+    M: ChangeMode + Send + Sync + 'static,
+    C: MavlinkConnection<M::Message> + Debug + Send + Sync + 'static,
+{
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let monitor = connection.monitor(Some(timeout), move |msg| match M::from_heartbeat(&msg) {
+        Some(mode) => {
+            let _ = tx.send(mode);
+            None
+        }
+        None => Some(()),
+    });
+
+    let res = tokio::time::timeout(timeout, rx.recv()).await;
+    drop(monitor);
+    match res {
+        Ok(Some(mode)) => Ok(mode),
+        Ok(None) | Err(_) => Err(Error::timeout(timeout).operation("current mode")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    use mavlink::ardupilotmega::{MavCmd, MavMessage, MavResult, PlaneMode, COMMAND_LONG_DATA};
+
+    use super::{Fleet, Options};
+    use crate::{
+        connection::{test::Bus, SharedConnection},
+        error::ErrorKind,
+        sim,
+        sim::SimVehicle,
+    };
+
+    fn vehicle(id: u8) -> SimVehicle {
+        SimVehicle::new(sim::Options {
+            system_id: id,
+            ..Default::default()
+        })
+    }
+
+    fn fleet(count: u8) -> Fleet<Box<SimVehicle>> {
+        (1..=count)
+            .map(|id| (id, Arc::new(Box::new(vehicle(id)))))
+            .collect()
+    }
+
+    fn modes(fleet: &Fleet<Box<SimVehicle>>) -> Vec<u32> {
+        fleet
+            .ids()
+            .map(|id| fleet.get(id).unwrap().custom_mode())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn collects_acknowledgements() {
+        let fleet = fleet(3);
+        fleet.get(2).unwrap().set_command_result(
+            MavCmd::MAV_CMD_DO_CHANGE_SPEED,
+            MavResult::MAV_RESULT_DENIED,
+        );
+
+        let command = COMMAND_LONG_DATA {
+            command: MavCmd::MAV_CMD_DO_CHANGE_SPEED,
+            param2: 12.0,
+            ..Default::default()
+        };
+        let results = fleet.command(command, &Default::default()).await;
+
+        let results: Vec<(u8, MavResult)> = results
+            .into_iter()
+            .map(|(id, res)| (id, res.unwrap().result))
+            .collect();
+        assert_eq!(
+            results,
+            [
+                (1, MavResult::MAV_RESULT_ACCEPTED),
+                (2, MavResult::MAV_RESULT_DENIED),
+                (3, MavResult::MAV_RESULT_ACCEPTED),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn targets_each_vehicle() {
+        let fleet: Fleet<Box<SimVehicle>> = [4, 7]
+            .into_iter()
+            .map(|id| (id, Arc::new(Box::new(vehicle(id)))))
+            .collect();
+
+        let command = COMMAND_LONG_DATA {
+            command: MavCmd::MAV_CMD_DO_CHANGE_SPEED,
+            param2: 12.0,
+            ..Default::default()
+        };
+        let results = fleet.command(command, &Default::default()).await;
+        assert!(results.values().all(|res| res.is_ok()), "{results:?}");
+        let change = fleet
+            .change_mode(PlaneMode::PLANE_MODE_GUIDED, &Default::default())
+            .await;
+        assert!(change.succeeded());
+
+        for id in fleet.ids() {
+            let targets: Vec<(MavCmd, u8)> = fleet
+                .get(id)
+                .unwrap()
+                .received()
+                .into_iter()
+                .filter_map(|msg| match msg {
+                    MavMessage::COMMAND_LONG(data) => Some((data.command, data.target_system)),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(
+                targets,
+                [
+                    (MavCmd::MAV_CMD_DO_CHANGE_SPEED, id),
+                    (MavCmd::MAV_CMD_DO_SET_MODE, id)
+                ]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn changes_every_mode() {
+        let fleet = fleet(3);
+
+        let change = fleet
+            .change_mode(PlaneMode::PLANE_MODE_GUIDED, &Default::default())
+            .await;
+
+        assert!(change.succeeded());
+        assert_eq!(modes(&fleet), [PlaneMode::PLANE_MODE_GUIDED as u32; 3]);
+    }
+
+    #[tokio::test]
+    async fn rolls_back_mode_change() {
+        let fleet = fleet(3);
+        fleet
+            .get(3)
+            .unwrap()
+            .set_command_result(MavCmd::MAV_CMD_DO_SET_MODE, MavResult::MAV_RESULT_DENIED);
+
+        let options = Options {
+            // Longer than the HEARTBEAT period.
+            timeout: std::time::Duration::from_millis(1500),
+            retries: 0,
+            rollback: true,
+        };
+        let change = fleet
+            .change_mode(PlaneMode::PLANE_MODE_GUIDED, &options)
+            .await;
+
+        assert!(!change.succeeded());
+        let failed: Vec<_> = change.failed().collect();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0, 3);
+        // Refused by its COMMAND_ACK, rather than timed out.
+        assert!(
+            matches!(failed[0].1.kind, ErrorKind::Rejected(_)),
+            "{}",
+            failed[0].1
+        );
+        assert_eq!(change.previous[&1], PlaneMode::PLANE_MODE_MANUAL);
+        // The denied vehicle stays in MANUAL, so going back to it works.
+        assert!(change.rolled_back.values().all(|res| res.is_ok()));
+        assert_eq!(modes(&fleet), [PlaneMode::PLANE_MODE_MANUAL as u32; 3]);
+    }
+
+    #[tokio::test]
+    async fn shares_one_connection() {
+        let vehicles = vec![Arc::new(vehicle(1)), Arc::new(vehicle(2))];
+        vehicles[1].set_command_result(
+            MavCmd::MAV_CMD_DO_CHANGE_SPEED,
+            MavResult::MAV_RESULT_DENIED,
+        );
+        vehicles[1].set_command_result(MavCmd::MAV_CMD_DO_SET_MODE, MavResult::MAV_RESULT_DENIED);
+        // Vehicle 1 answers the second time only, meanwhile it hears vehicle 2 refuse.
+        let dropped = AtomicBool::new(false);
+        vehicles[0].set_loss(Box::new(move |msg| {
+            matches!(msg, MavMessage::COMMAND_ACK(_)) && !dropped.swap(true, Ordering::SeqCst)
+        }));
+        let connection =
+            Arc::new(SharedConnection::new(Arc::new(Bus::new(vehicles.clone()))).unwrap());
+        let fleet: Fleet<SharedConnection<MavMessage>> = [1, 2]
+            .into_iter()
+            .map(|id| (id, connection.clone()))
+            .collect();
+
+        let command = COMMAND_LONG_DATA {
+            command: MavCmd::MAV_CMD_DO_CHANGE_SPEED,
+            param2: 12.0,
+            ..Default::default()
+        };
+        let results: Vec<(u8, MavResult)> = fleet
+            .command(command, &Default::default())
+            .await
+            .into_iter()
+            .map(|(id, res)| (id, res.unwrap().result))
+            .collect();
+        assert_eq!(
+            results,
+            [
+                (1, MavResult::MAV_RESULT_ACCEPTED),
+                (2, MavResult::MAV_RESULT_DENIED),
+            ]
+        );
+
+        let options = Options {
+            // Longer than the HEARTBEAT period.
+            timeout: std::time::Duration::from_millis(1500),
+            retries: 0,
+            rollback: false,
+        };
+        let change = fleet
+            .change_mode(PlaneMode::PLANE_MODE_GUIDED, &options)
+            .await;
+        let failed: Vec<_> = change.failed().collect();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0, 2);
+        assert!(
+            matches!(failed[0].1.kind, ErrorKind::Rejected(_)),
+            "{}",
+            failed[0].1
+        );
+        assert_eq!(
+            vehicles.iter().map(|v| v.custom_mode()).collect::<Vec<_>>(),
+            [
+                PlaneMode::PLANE_MODE_GUIDED as u32,
+                PlaneMode::PLANE_MODE_MANUAL as u32
+            ]
+        );
+    }
+}
@@ -0,0 +1,627 @@
+pub mod payload;
+
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc,
+    },
+};
+
+use mavlink::ardupilotmega::{MavMessage, FILE_TRANSFER_PROTOCOL_DATA};
+use tracing::instrument;
+
+use crate::{
+    connection::{FilterRes, MavlinkConnection},
+    error::{Error, ErrorKind},
+};
+
+use self::payload::{crc32, parse_entries, DirEntry, NakError, Opcode, Payload, MAX_DATA_LEN};
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Options {
+    // How long to wait for each response before retransmitting the request.
+    pub timeout: std::time::Duration,
+    pub retries: u8,
+    // Compares the CRC32 of files read or written with the one the vehicle computes. Skipped
+    // when the vehicle does not implement CalcFileCRC32.
+    pub verify: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            timeout: std::time::Duration::from_millis(500),
+            retries: 5,
+            verify: true,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FtpClient<C> {
+    connection: Arc<C>,
+    options: Options,
+    // Sequence number of the next request.
+    seq: AtomicU16,
+}
+
+impl<C> FtpClient<C>
+where
+    C: MavlinkConnection + Debug + Send + Sync + 'static,
+{
+    pub fn new(connection: Arc<C>, options: Options) -> Self {
+        Self {
+            connection,
+            options,
+            seq: AtomicU16::new(0),
+        }
+    }
+
+    fn message(&self, payload: &Payload) -> MavMessage {
+        let mut data = FILE_TRANSFER_PROTOCOL_DATA {
+            target_network: 0,
+            target_system: self.connection.target_system(),
+            target_component: self.connection.target_component(),
+            ..Default::default()
+        };
+        data.payload
+            .extend_from_slice(&payload.encode())
+            .expect("Encoded payload always fits");
+        MavMessage::FILE_TRANSFER_PROTOCOL(data)
+    }
+
+    // Sends a request, and retransmits it with the same seq until it is answered.
+    // A NAK is returned as an error.
+    #[instrument(skip(self))]
+    async fn request(&self, payload: Payload) -> Result<Payload, Error> {
+        let payload = Payload {
+            seq: self.seq.load(Ordering::SeqCst),
+            ..payload
+        };
+        let expected = payload.seq.wrapping_add(1);
+        let msg = self.message(&payload);
+        let start = std::time::Instant::now();
+
+        for attempt in 0..=self.options.retries {
+            let res = self
+                .connection
+                .clone()
+                .send_wait(&msg, self.options.timeout, move |msg| {
+                    if let MavMessage::FILE_TRANSFER_PROTOCOL(data) = msg {
+                        if let Some(response) = Payload::decode(&data.payload) {
+                            if response.seq == expected
+                                && matches!(response.opcode, Opcode::Ack | Opcode::Nak)
+                            {
+                                return FilterRes::Ready(Some(response));
+                            }
+                        }
+                    }
+                    FilterRes::NotReady
+                })
+                .await;
+
+            match res {
+                Ok(Some(response)) => {
+                    self.seq
+                        .store(response.seq.wrapping_add(1), Ordering::SeqCst);
+                    if response.opcode == Opcode::Nak {
+                        return Err(Error::new(ErrorKind::Nak(response.nak_error()))
+                            .seq(payload.seq as u32)
+                            .operation("ftp"));
+                    }
+                    return Ok(response);
+                }
+                Err(e) if e.is_timeout() => {
+                    tracing::event!(tracing::Level::DEBUG, attempt, "Retransmitting request");
+                }
+                Err(e) => return Err(e.seq(payload.seq as u32).operation("ftp")),
+                Ok(None) => {
+                    return Err(
+                        Error::new(ErrorKind::InvalidResponse("Empty answer".to_string()))
+                            .operation("ftp"),
+                    )
+                }
+            }
+        }
+
+        Err(Error::timeout(start.elapsed())
+            .seq(payload.seq as u32)
+            .operation("ftp"))
+    }
+
+    pub async fn reset_sessions(&self) -> Result<(), Error> {
+        self.request(Payload::request(Opcode::ResetSessions))
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn list_directory(&self, path: &str) -> Result<Vec<DirEntry>, Error> {
+        let mut entries = vec![];
+
+        loop {
+            let res = self
+                .request(
+                    Payload::request(Opcode::ListDirectory)
+                        .with_offset(entries.len() as u32)
+                        .with_data(path.as_bytes()),
+                )
+                .await;
+
+            let batch = match res {
+                Ok(response) => parse_entries(&response.data),
+                Err(Error {
+                    kind: ErrorKind::Nak(NakError::Eof),
+                    ..
+                }) => break,
+                Err(e) => return Err(e),
+            };
+            if batch.is_empty() {
+                break;
+            }
+            entries.extend(batch);
+        }
+
+        Ok(entries)
+    }
+
+    pub async fn create_directory(&self, path: &str) -> Result<(), Error> {
+        self.request(Payload::request(Opcode::CreateDirectory).with_data(path.as_bytes()))
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn remove_directory(&self, path: &str) -> Result<(), Error> {
+        self.request(Payload::request(Opcode::RemoveDirectory).with_data(path.as_bytes()))
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn remove_file(&self, path: &str) -> Result<(), Error> {
+        self.request(Payload::request(Opcode::RemoveFile).with_data(path.as_bytes()))
+            .await
+            .map(|_| ())
+    }
+
+    // See `payload::crc32` to compute the same value locally.
+    pub async fn crc32(&self, path: &str) -> Result<u32, Error> {
+        let response = self
+            .request(Payload::request(Opcode::CalcFileCRC32).with_data(path.as_bytes()))
+            .await?;
+        read_u32(&response.data)
+    }
+
+    pub async fn read_file(&self, path: &str) -> Result<Vec<u8>, Error> {
+        let response = self
+            .request(Payload::request(Opcode::OpenFileRO).with_data(path.as_bytes()))
+            .await?;
+        let session = response.session;
+
+        let res = match read_u32(&response.data) {
+            Ok(size) => self.read_session(session, size).await,
+            Err(e) => Err(e),
+        };
+
+        // The data is complete, the vehicle closes the session on its own eventually.
+        if let Err(e) = self.terminate(session).await {
+            tracing::event!(tracing::Level::WARN, %e, "Could not terminate read session");
+        }
+        let data = res?;
+        self.verify(path, &data).await?;
+        Ok(data)
+    }
+
+    pub async fn write_file(&self, path: &str, data: &[u8]) -> Result<(), Error> {
+        let response = self
+            .request(Payload::request(Opcode::CreateFile).with_data(path.as_bytes()))
+            .await?;
+        let session = response.session;
+
+        let mut res = Ok(());
+        for (i, chunk) in data.chunks(MAX_DATA_LEN).enumerate() {
+            res = self
+                .request(
+                    Payload::request(Opcode::WriteFile)
+                        .with_session(session)
+                        .with_offset((i * MAX_DATA_LEN) as u32)
+                        .with_data(chunk),
+                )
+                .await
+                .map(|_| ());
+            if res.is_err() {
+                break;
+            }
+        }
+
+        self.terminate(session).await?;
+        res?;
+        self.verify(path, data).await
+    }
+
+    async fn verify(&self, path: &str, data: &[u8]) -> Result<(), Error> {
+        if !self.options.verify {
+            return Ok(());
+        }
+        match self.crc32(path).await {
+            Ok(crc) if crc == crc32(0, data) => Ok(()),
+            Ok(crc) => Err(Error::new(ErrorKind::InvalidResponse(format!(
+                "CRC32 {:08x} does not match the vehicle's {crc:08x}",
+                crc32(0, data)
+            )))
+            .operation("ftp verify")),
+            Err(Error {
+                kind: ErrorKind::Nak(NakError::UnknownCommand),
+                ..
+            }) => {
+                tracing::event!(tracing::Level::DEBUG, path, "CalcFileCRC32 unsupported");
+                Ok(())
+            }
+            Err(e) => Err(e.operation("ftp verify")),
+        }
+    }
+
+    async fn terminate(&self, session: u8) -> Result<(), Error> {
+        self.request(Payload::request(Opcode::TerminateSession).with_session(session))
+            .await
+            .map(|_| ())
+    }
+
+    // Reads the whole file with burst reads, then fills any gap left by dropped
+    // packets with individual ReadFile requests.
+    async fn read_session(&self, session: u8, size: u32) -> Result<Vec<u8>, Error> {
+        let mut chunks = BTreeMap::new();
+        let mut offset = 0;
+
+        while offset < size {
+            let burst = self.burst(session, offset).await?;
+            let eof = burst.iter().any(|p| p.opcode == Opcode::Nak);
+
+            let start = offset;
+            for packet in burst.into_iter().filter(|p| p.opcode == Opcode::Ack) {
+                offset = offset.max(packet.offset + packet.data.len() as u32);
+                chunks.insert(packet.offset, packet.data);
+            }
+            // Whatever is still missing is requested below.
+            if eof || offset == start {
+                break;
+            }
+        }
+
+        for (start, end) in gaps(&chunks, size) {
+            let mut start = start;
+            while start < end {
+                let response = self
+                    .request(
+                        Payload::request(Opcode::ReadFile)
+                            .with_session(session)
+                            .with_offset(start)
+                            .with_size((end - start).min(MAX_DATA_LEN as u32) as u8),
+                    )
+                    .await?;
+                if response.data.is_empty() {
+                    return Err(Error::new(ErrorKind::InvalidResponse(
+                        "No data returned".to_string(),
+                    ))
+                    .seq(start)
+                    .operation("ftp read"));
+                }
+                let len = response.data.len() as u32;
+                chunks.insert(start, response.data);
+                start += len;
+            }
+        }
+
+        let mut file = vec![0; size as usize];
+        for (offset, data) in chunks {
+            let start = (offset as usize).min(file.len());
+            let end = (start + data.len()).min(file.len());
+            file[start..end].copy_from_slice(&data[..end - start]);
+        }
+        Ok(file)
+    }
+
+    // Requests a burst starting at `offset`, and collects packets until the vehicle
+    // marks the burst complete, NAKs, or stops sending.
+    async fn burst(&self, session: u8, offset: u32) -> Result<Vec<Payload>, Error> {
+        let request = Payload {
+            seq: self.seq.load(Ordering::SeqCst),
+            ..Payload::request(Opcode::BurstReadFile)
+                .with_session(session)
+                .with_offset(offset)
+                .with_size(MAX_DATA_LEN as u8)
+        };
+        let msg = self.message(&request);
+        let start = std::time::Instant::now();
+
+        for _ in 0..=self.options.retries {
+            let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+            // Packets of the burst have increasing seqs from the request's + 1, some may be
+            // lost. Older ones are duplicates or left over from a previous burst.
+            let next = AtomicU16::new(request.seq.wrapping_add(1));
+
+            let monitor = self.connection.clone().monitor(None, move |msg| {
+                if let MavMessage::FILE_TRANSFER_PROTOCOL(data) = msg {
+                    if let Some(response) = Payload::decode(&data.payload) {
+                        let fresh =
+                            response.seq.wrapping_sub(next.load(Ordering::SeqCst)) < u16::MAX / 2;
+                        if response.session == session
+                            && response.req_opcode == Opcode::BurstReadFile
+                            && fresh
+                        {
+                            next.store(response.seq.wrapping_add(1), Ordering::SeqCst);
+                            let done = response.burst_complete || response.opcode == Opcode::Nak;
+                            // The receiver is only gone once we stopped listening.
+                            let _ = tx.send(response);
+                            if done {
+                                return None;
+                            }
+                        }
+                    }
+                }
+                Some(())
+            });
+
+            if let Err(e) = self.connection.send(&msg) {
+                return Err(e.operation("ftp burst"));
+            }
+
+            let mut packets = vec![];
+            while let Ok(Some(packet)) = tokio::time::timeout(self.options.timeout, rx.recv()).await
+            {
+                packets.push(packet);
+            }
+            drop(monitor);
+
+            if let Some(last) = packets.last() {
+                self.seq.store(last.seq.wrapping_add(1), Ordering::SeqCst);
+                if last.opcode == Opcode::Nak && last.nak_error() != NakError::Eof {
+                    return Err(Error::new(ErrorKind::Nak(last.nak_error()))
+                        .seq(offset)
+                        .operation("ftp burst"));
+                }
+                return Ok(packets);
+            }
+        }
+
+        Err(Error::timeout(start.elapsed())
+            .seq(offset)
+            .operation("ftp burst"))
+    }
+}
+
+fn read_u32(data: &[u8]) -> Result<u32, Error> {
+    data.get(0..4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| Error::new(ErrorKind::InvalidResponse("Missing u32".to_string())))
+}
+
+// Ranges in [0, size) that are not covered by any chunk.
+fn gaps(chunks: &BTreeMap<u32, Vec<u8>>, size: u32) -> Vec<(u32, u32)> {
+    let mut gaps = vec![];
+    let mut covered = 0;
+
+    for (offset, data) in chunks {
+        if *offset > covered {
+            gaps.push((covered, (*offset).min(size)));
+        }
+        covered = covered.max(offset + data.len() as u32);
+    }
+    if covered < size {
+        gaps.push((covered, size));
+    }
+
+    gaps
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::BTreeMap,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+    };
+
+    use mavlink::ardupilotmega::{MavMessage, FILE_TRANSFER_PROTOCOL_DATA};
+
+    use super::{
+        gaps,
+        payload::{DirEntry, NakError, Opcode, Payload, MAX_DATA_LEN},
+        FtpClient, Options,
+    };
+    use crate::connection::test::*;
+    use crate::error::{Error, ErrorKind};
+    use crate::sim::SimVehicle;
+
+    fn response(payload: Payload) -> MavMessage {
+        let mut data = FILE_TRANSFER_PROTOCOL_DATA::default();
+        data.payload.extend_from_slice(&payload.encode()).unwrap();
+        MavMessage::FILE_TRANSFER_PROTOCOL(data)
+    }
+
+    fn decode(msg: &MavMessage) -> Option<Payload> {
+        match msg {
+            MavMessage::FILE_TRANSFER_PROTOCOL(data) => Payload::decode(&data.payload),
+            _ => None,
+        }
+    }
+
+    fn requests(connection: &SimVehicle, opcode: Opcode) -> usize {
+        connection
+            .received()
+            .iter()
+            .filter_map(decode)
+            .filter(|p| p.opcode == opcode)
+            .count()
+    }
+
+    fn options() -> Options {
+        Options {
+            timeout: std::time::Duration::from_millis(100),
+            ..Default::default()
+        }
+    }
+
+    fn content(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn finds_gaps() {
+        let chunks = BTreeMap::from([(0, vec![0; 10]), (20, vec![0; 10])]);
+
+        assert_eq!(gaps(&chunks, 40), vec![(10, 20), (30, 40)]);
+        assert_eq!(gaps(&chunks, 30), vec![(10, 20)]);
+        assert_eq!(gaps(&BTreeMap::new(), 5), vec![(0, 5)]);
+    }
+
+    #[tokio::test]
+    async fn crc32() {
+        let connection: Arc<Box<TestMavConnection>> = Default::default();
+        let client = FtpClient::new(connection.clone(), Default::default());
+
+        connection.inject_msg(response(Payload {
+            seq: 1,
+            req_opcode: Opcode::CalcFileCRC32,
+            ..Payload::request(Opcode::Ack).with_data(&0xDEAD_BEEF_u32.to_le_bytes())
+        }));
+
+        let crc = client.crc32("@SYS/scripts/main.lua").await.unwrap();
+
+        assert_eq!(crc, 0xDEAD_BEEF);
+        assert!(matches!(
+            connection.last_sent().unwrap(),
+            MavMessage::FILE_TRANSFER_PROTOCOL(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn nak() {
+        let connection: Arc<Box<TestMavConnection>> = Default::default();
+        let client = FtpClient::new(connection.clone(), Default::default());
+
+        connection.inject_msg(response(Payload {
+            seq: 1,
+            req_opcode: Opcode::RemoveFile,
+            ..Payload::request(Opcode::Nak).with_data(&[10])
+        }));
+
+        let res = client.remove_file("missing.txt").await;
+
+        assert!(matches!(
+            res,
+            Err(Error {
+                kind: ErrorKind::Nak(NakError::FileNotFound),
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn burst_fills_dropped_chunk() {
+        let connection: Arc<Box<SimVehicle>> = Default::default();
+        let file = content(3 * MAX_DATA_LEN + 10);
+        connection.set_file("/APM/params.parm", &file);
+        // The second packet of every burst is lost.
+        connection.set_loss(Box::new(|msg| {
+            decode(msg).is_some_and(|p| {
+                p.req_opcode == Opcode::BurstReadFile && p.offset == MAX_DATA_LEN as u32
+            })
+        }));
+        let client = FtpClient::new(connection.clone(), options());
+
+        let data = client.read_file("/APM/params.parm").await.unwrap();
+
+        assert_eq!(data, file);
+        assert_eq!(requests(&connection, Opcode::ReadFile), 1);
+        assert_eq!(requests(&connection, Opcode::CalcFileCRC32), 1);
+    }
+
+    #[tokio::test]
+    async fn ignores_stale_burst_reply() {
+        let connection: Arc<Box<SimVehicle>> = Default::default();
+        let file = content(10);
+        connection.set_file("/APM/small.txt", &file);
+        // A leftover reply of an earlier burst arrives before the real one.
+        connection.set_handler(Box::new(move |msg| {
+            let request = decode(msg).filter(|p| p.opcode == Opcode::BurstReadFile)?;
+            let reply = |seq: u16, data: &[u8]| {
+                response(Payload {
+                    seq,
+                    req_opcode: Opcode::BurstReadFile,
+                    burst_complete: true,
+                    ..Payload::request(Opcode::Ack)
+                        .with_session(request.session)
+                        .with_data(data)
+                })
+            };
+            Some(vec![
+                reply(request.seq.wrapping_sub(1), &[0xFF; 10]),
+                reply(request.seq.wrapping_add(1), &content(10)),
+            ])
+        }));
+        let client = FtpClient::new(connection.clone(), options());
+
+        let data = client.read_file("/APM/small.txt").await.unwrap();
+
+        assert_eq!(data, file);
+    }
+
+    #[tokio::test]
+    async fn retransmits_after_timeout() {
+        let connection: Arc<Box<SimVehicle>> = Default::default();
+        let file = content(100);
+        connection.set_file("/APM/small.txt", &file);
+        let dropped = AtomicBool::new(false);
+        connection.set_loss(Box::new(move |msg| {
+            decode(msg).is_some_and(|p| p.req_opcode == Opcode::OpenFileRO)
+                && !dropped.swap(true, Ordering::SeqCst)
+        }));
+        let client = FtpClient::new(connection.clone(), options());
+
+        let data = client.read_file("/APM/small.txt").await.unwrap();
+
+        assert_eq!(data, file);
+        assert_eq!(requests(&connection, Opcode::OpenFileRO), 2);
+    }
+
+    #[tokio::test]
+    async fn lists_directory() {
+        let connection: Arc<Box<SimVehicle>> = Default::default();
+        connection.set_file("/APM/LOGS/00000001.BIN", &content(1000));
+        connection.set_file("/APM/params.parm", &content(20));
+        let client = FtpClient::new(connection.clone(), options());
+
+        let entries = client.list_directory("/APM").await.unwrap();
+
+        assert_eq!(
+            entries,
+            vec![
+                DirEntry::Directory {
+                    name: "LOGS".to_string()
+                },
+                DirEntry::File {
+                    name: "params.parm".to_string(),
+                    size: 20
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn writes_file() {
+        let connection: Arc<Box<SimVehicle>> = Default::default();
+        let file = content(2 * MAX_DATA_LEN + 1);
+        let client = FtpClient::new(connection.clone(), options());
+
+        client
+            .write_file("/APM/scripts/main.lua", &file)
+            .await
+            .unwrap();
+
+        assert_eq!(connection.file("/APM/scripts/main.lua").unwrap(), file);
+        assert_eq!(requests(&connection, Opcode::CalcFileCRC32), 1);
+    }
+}
@@ -0,0 +1,280 @@
+// Encoding of the payload carried by FILE_TRANSFER_PROTOCOL.
+// See https://mavlink.io/en/services/ftp.html#payload
+
+pub const PAYLOAD_LEN: usize = 251;
+pub const HEADER_LEN: usize = 12;
+pub const MAX_DATA_LEN: usize = PAYLOAD_LEN - HEADER_LEN;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    None = 0,
+    TerminateSession = 1,
+    ResetSessions = 2,
+    ListDirectory = 3,
+    OpenFileRO = 4,
+    ReadFile = 5,
+    CreateFile = 6,
+    WriteFile = 7,
+    RemoveFile = 8,
+    CreateDirectory = 9,
+    RemoveDirectory = 10,
+    OpenFileWO = 11,
+    TruncateFile = 12,
+    Rename = 13,
+    CalcFileCRC32 = 14,
+    BurstReadFile = 15,
+    Ack = 128,
+    Nak = 129,
+}
+
+impl TryFrom<u8> for Opcode {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => Self::None,
+            1 => Self::TerminateSession,
+            2 => Self::ResetSessions,
+            3 => Self::ListDirectory,
+            4 => Self::OpenFileRO,
+            5 => Self::ReadFile,
+            6 => Self::CreateFile,
+            7 => Self::WriteFile,
+            8 => Self::RemoveFile,
+            9 => Self::CreateDirectory,
+            10 => Self::RemoveDirectory,
+            11 => Self::OpenFileWO,
+            12 => Self::TruncateFile,
+            13 => Self::Rename,
+            14 => Self::CalcFileCRC32,
+            15 => Self::BurstReadFile,
+            128 => Self::Ack,
+            129 => Self::Nak,
+            other => return Err(other),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NakError {
+    None,
+    Fail,
+    // Carries the errno reported by the vehicle.
+    FailErrno(u8),
+    InvalidDataSize,
+    InvalidSession,
+    NoSessionsAvailable,
+    Eof,
+    UnknownCommand,
+    FileExists,
+    FileProtected,
+    FileNotFound,
+    Unknown(u8),
+}
+
+impl NakError {
+    fn from_data(data: &[u8]) -> Self {
+        match data.first().copied().unwrap_or_default() {
+            0 => Self::None,
+            1 => Self::Fail,
+            2 => Self::FailErrno(data.get(1).copied().unwrap_or_default()),
+            3 => Self::InvalidDataSize,
+            4 => Self::InvalidSession,
+            5 => Self::NoSessionsAvailable,
+            6 => Self::Eof,
+            7 => Self::UnknownCommand,
+            8 => Self::FileExists,
+            9 => Self::FileProtected,
+            10 => Self::FileNotFound,
+            other => Self::Unknown(other),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Payload {
+    pub seq: u16,
+    pub session: u8,
+    pub opcode: Opcode,
+    pub req_opcode: Opcode,
+    pub burst_complete: bool,
+    pub offset: u32,
+    // Length of `data`, except for ReadFile / BurstReadFile requests where it is the length to read.
+    pub size: u8,
+    pub data: Vec<u8>,
+}
+
+impl Payload {
+    pub fn request(opcode: Opcode) -> Self {
+        Self {
+            seq: 0,
+            session: 0,
+            opcode,
+            req_opcode: Opcode::None,
+            burst_complete: false,
+            offset: 0,
+            size: 0,
+            data: vec![],
+        }
+    }
+
+    pub fn with_session(self, session: u8) -> Self {
+        Self { session, ..self }
+    }
+
+    pub fn with_offset(self, offset: u32) -> Self {
+        Self { offset, ..self }
+    }
+
+    pub fn with_size(self, size: u8) -> Self {
+        Self { size, ..self }
+    }
+
+    pub fn with_data(self, data: &[u8]) -> Self {
+        let data = data[..data.len().min(MAX_DATA_LEN)].to_vec();
+        Self {
+            size: data.len() as u8,
+            data,
+            ..self
+        }
+    }
+
+    // Only valid for NAK responses.
+    pub fn nak_error(&self) -> NakError {
+        NakError::from_data(&self.data)
+    }
+
+    pub fn encode(&self) -> [u8; PAYLOAD_LEN] {
+        let mut bytes = [0; PAYLOAD_LEN];
+        bytes[0..2].copy_from_slice(&self.seq.to_le_bytes());
+        bytes[2] = self.session;
+        bytes[3] = self.opcode as u8;
+        bytes[4] = self.size;
+        bytes[5] = self.req_opcode as u8;
+        bytes[6] = self.burst_complete as u8;
+        bytes[8..12].copy_from_slice(&self.offset.to_le_bytes());
+        bytes[HEADER_LEN..HEADER_LEN + self.data.len()].copy_from_slice(&self.data);
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_LEN {
+            return None;
+        }
+        let size = (bytes[4] as usize).min(bytes.len() - HEADER_LEN);
+        Some(Self {
+            seq: u16::from_le_bytes([bytes[0], bytes[1]]),
+            session: bytes[2],
+            opcode: Opcode::try_from(bytes[3]).ok()?,
+            req_opcode: Opcode::try_from(bytes[5]).ok()?,
+            burst_complete: bytes[6] != 0,
+            offset: u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
+            size: size as u8,
+            data: bytes[HEADER_LEN..HEADER_LEN + size].to_vec(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DirEntry {
+    File { name: String, size: u32 },
+    Directory { name: String },
+    // Entries the vehicle could not stat, they still count towards the listing offset.
+    Skipped,
+}
+
+// Parses the NUL separated entries of a ListDirectory ACK.
+pub fn parse_entries(data: &[u8]) -> Vec<DirEntry> {
+    data.split(|b| *b == 0)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let text = String::from_utf8_lossy(&entry[1..]);
+            match entry[0] {
+                b'F' => {
+                    let (name, size) = text.split_once('\t').unwrap_or((&text, "0"));
+                    DirEntry::File {
+                        name: name.to_string(),
+                        size: size.parse().unwrap_or_default(),
+                    }
+                }
+                b'D' => DirEntry::Directory {
+                    name: text.into_owned(),
+                },
+                _ => DirEntry::Skipped,
+            }
+        })
+        .collect()
+}
+
+// CRC32 (reflected 0xEDB88320) without the initial or final inversion, matching
+// the value returned by CalcFileCRC32 on ArduPilot and PX4 when `crc` starts at 0.
+pub fn crc32(mut crc: u32, bytes: &[u8]) -> u32 {
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod test {
+    use super::{crc32, parse_entries, DirEntry, NakError, Opcode, Payload};
+
+    #[test]
+    fn roundtrip() {
+        let payload = Payload::request(Opcode::ReadFile)
+            .with_session(3)
+            .with_offset(478)
+            .with_data(b"hello");
+
+        let decoded = Payload::decode(&payload.encode()).unwrap();
+
+        assert_eq!(decoded, payload);
+    }
+
+    #[test]
+    fn nak() {
+        let mut payload = Payload::request(Opcode::Nak).with_data(&[2, 13]);
+        payload.req_opcode = Opcode::OpenFileRO;
+
+        let decoded = Payload::decode(&payload.encode()).unwrap();
+
+        assert_eq!(decoded.nak_error(), NakError::FailErrno(13));
+    }
+
+    #[test]
+    fn entries() {
+        let entries = parse_entries(b"Fparams.parm\t1024\0D@SYS\0S\0Fscript.lua\t7\0");
+
+        assert_eq!(
+            entries,
+            vec![
+                DirEntry::File {
+                    name: "params.parm".to_string(),
+                    size: 1024
+                },
+                DirEntry::Directory {
+                    name: "@SYS".to_string()
+                },
+                DirEntry::Skipped,
+                DirEntry::File {
+                    name: "script.lua".to_string(),
+                    size: 7
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn crc() {
+        // Standard CRC32 check value, once the inversions are applied by hand.
+        assert_eq!(crc32(0xFFFF_FFFF, b"123456789") ^ 0xFFFF_FFFF, 0xCBF4_3926);
+        assert_eq!(crc32(crc32(0, b"1234"), b"56789"), crc32(0, b"123456789"));
+    }
+}
@@ -0,0 +1,576 @@
+// Serves vehicle telemetry as JSON over WebSocket, for dashboards that cannot speak MAVLink.
+//
+// Every client receives a `{"type": "telemetry", ...}` message every period, and can send
+// commands, each answered by a `{"type": "response", ...}` message carrying the same `id`:
+//
+//   {"id": 1, "command": "mode", "mode": "guided"}
+//   {"id": 2, "command": "arm", "force": false}
+//   {"id": 3, "command": "disarm"}
+//   {"id": 4, "command": "upload_mission", "plan": {...}}   a QGroundControl .plan document
+//
+// There is no authentication. Browsers are only let in from the origins in
+// `Options::allowed_origins`, so that any page the operator visits cannot command the vehicle,
+// and ardu-gateway only listens on other addresses than loopback when explicitly allowed to.
+
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex},
+};
+
+use futures_util::{SinkExt, StreamExt};
+use mavlink::ardupilotmega::{
+    MavAutopilot, MavCmd, MavMessage, MavModeFlag, MavResult, MavType, PlaneMode, COMMAND_LONG_DATA,
+};
+use tokio_tungstenite::tungstenite::{
+    handshake::server::{ErrorResponse, Request as Handshake, Response as Accepted},
+    http::StatusCode,
+    Message,
+};
+
+use crate::{
+    command::Command,
+    connection::{MavlinkConnection, MonitorHandle, SharedConnection},
+    error::{Error, ErrorKind},
+    geo::Location,
+    mission::{file, upload, upload::MissionUpload},
+    mode::ChangeMode,
+};
+
+// ArduPilot skips its arming checks when param2 of MAV_CMD_COMPONENT_ARM_DISARM is this value.
+const FORCE_ARM_MAGIC: f32 = 21196.0;
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Options {
+    // Period between two telemetry messages to each client.
+    pub period: std::time::Duration,
+    // The link is reported down after this long without a HEARTBEAT.
+    pub link_timeout: std::time::Duration,
+    // How long to wait for each acknowledgement of a command, or mission item request, before
+    // sending it again.
+    pub command_timeout: std::time::Duration,
+    pub retries: u8,
+    // Origins browsers may connect from, e.g. http://localhost:3000. Clients sending no Origin,
+    // which browsers always do, are not browsers and are let in.
+    pub allowed_origins: Vec<String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            period: std::time::Duration::from_millis(200),
+            link_timeout: std::time::Duration::from_secs(3),
+            command_timeout: std::time::Duration::from_secs(2),
+            retries: 3,
+            allowed_origins: vec![],
+        }
+    }
+}
+
+// Altitudes in meters, AMSL for the location and above home for `relative_alt`, heading in
+// degrees.
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize)]
+pub struct Position {
+    #[serde(flatten)]
+    pub location: Location,
+    pub relative_alt: f32,
+    pub heading: Option<f32>,
+}
+
+// Speeds in m/s.
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize)]
+pub struct Speeds {
+    pub groundspeed: f32,
+    pub airspeed: f32,
+    pub climb: f32,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize)]
+pub struct Battery {
+    pub voltage: Option<f32>,
+    // Percent.
+    pub remaining: Option<u8>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize)]
+pub struct Link {
+    pub connected: bool,
+    // Milliseconds since the last HEARTBEAT.
+    pub last_heartbeat: Option<u64>,
+    pub received: u64,
+}
+
+// Latest telemetry, fields stay None until their message is received.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct Telemetry {
+    pub armed: Option<bool>,
+    pub custom_mode: Option<u32>,
+    pub position: Option<Position>,
+    pub speeds: Option<Speeds>,
+    pub battery: Option<Battery>,
+    pub link: Link,
+}
+
+#[derive(Debug, Default)]
+struct TelemetryState {
+    telemetry: Telemetry,
+    last_heartbeat: Option<std::time::Instant>,
+}
+
+impl TelemetryState {
+    fn update(&mut self, msg: MavMessage) {
+        let telemetry = &mut self.telemetry;
+        telemetry.link.received += 1;
+        match msg {
+            // Cameras, gimbals and companion computers send heartbeats too, with an invalid
+            // autopilot.
+            MavMessage::HEARTBEAT(beat)
+                if beat.mavtype != MavType::MAV_TYPE_GCS
+                    && beat.autopilot != MavAutopilot::MAV_AUTOPILOT_INVALID =>
+            {
+                self.last_heartbeat = Some(std::time::Instant::now());
+                telemetry.armed = Some(
+                    beat.base_mode
+                        .contains(MavModeFlag::MAV_MODE_FLAG_SAFETY_ARMED),
+                );
+                telemetry.custom_mode = Some(beat.custom_mode);
+            }
+            MavMessage::GLOBAL_POSITION_INT(data) => {
+                telemetry.position = Some(Position {
+                    location: Location::from_int(data.lat, data.lon, data.alt),
+                    relative_alt: data.relative_alt as f32 / 1000.0,
+                    heading: (data.hdg != u16::MAX).then(|| data.hdg as f32 / 100.0),
+                });
+            }
+            MavMessage::VFR_HUD(data) => {
+                telemetry.speeds = Some(Speeds {
+                    groundspeed: data.groundspeed,
+                    airspeed: data.airspeed,
+                    climb: data.climb,
+                });
+            }
+            MavMessage::SYS_STATUS(data) => {
+                telemetry.battery = Some(Battery {
+                    voltage: (data.voltage_battery != u16::MAX)
+                        .then(|| data.voltage_battery as f32 / 1000.0),
+                    remaining: u8::try_from(data.battery_remaining).ok(),
+                });
+            }
+            _ => {}
+        }
+    }
+}
+
+// Telemetry of the vehicle, shared with the monitor feeding it.
+#[derive(Debug, Clone, Default)]
+pub struct TelemetryTracker {
+    state: Arc<Mutex<TelemetryState>>,
+}
+
+impl TelemetryTracker {
+    pub fn update(&self, msg: MavMessage) {
+        self.state.lock().unwrap().update(msg);
+    }
+
+    pub fn snapshot(&self, link_timeout: std::time::Duration) -> Telemetry {
+        let state = self.state.lock().unwrap();
+        let mut telemetry = state.telemetry.clone();
+        let age = state.last_heartbeat.map(|time| time.elapsed());
+        telemetry.link.connected = age.is_some_and(|age| age < link_timeout);
+        telemetry.link.last_heartbeat = age.map(|age| age.as_millis() as u64);
+        telemetry
+    }
+}
+
+// Feeds a tracker from the connection, until the returned handle is dropped.
+pub fn track_telemetry<C>(connection: Arc<C>) -> (TelemetryTracker, MonitorHandle)
+where
+    C: MavlinkConnection + Debug + Send + Sync + 'static,
+{
+    let tracker = TelemetryTracker::default();
+    let handle = connection.monitor(None, {
+        let tracker = tracker.clone();
+        move |msg| {
+            tracker.update(msg);
+            Some(())
+        }
+    });
+    (tracker, handle)
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    // ArduPlane mode name, e.g. "guided" or "RTL".
+    Mode {
+        mode: String,
+    },
+    Arm {
+        #[serde(default)]
+        force: bool,
+    },
+    Disarm {
+        #[serde(default)]
+        force: bool,
+    },
+    UploadMission {
+        plan: serde_json::Value,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+struct Envelope {
+    #[serde(default)]
+    id: Option<u64>,
+    #[serde(flatten)]
+    request: Request,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct Response {
+    pub id: Option<u64>,
+    pub ok: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Outgoing {
+    Telemetry(Telemetry),
+    Response(Response),
+}
+
+async fn arm<C>(connection: Arc<C>, arm: bool, force: bool, options: &Options) -> Result<(), Error>
+where
+    C: MavlinkConnection + Debug + Send + Sync + 'static,
+{
+    let command = COMMAND_LONG_DATA {
+        target_system: connection.target_system(),
+        target_component: connection.target_component(),
+        command: MavCmd::MAV_CMD_COMPONENT_ARM_DISARM,
+        param1: if arm { 1.0 } else { 0.0 },
+        param2: if force { FORCE_ARM_MAGIC } else { 0.0 },
+        ..Default::default()
+    };
+    let ack = command
+        .command_retry(connection, options.command_timeout, options.retries)
+        .await?;
+    match ack.result {
+        MavResult::MAV_RESULT_ACCEPTED => Ok(()),
+        result => Err(Error::new(ErrorKind::Rejected(format!("{result:?}")))),
+    }
+}
+
+pub async fn handle_request<C>(
+    connection: Arc<C>,
+    request: Request,
+    options: &Options,
+) -> Result<(), Error>
+where
+    C: MavlinkConnection + Debug + Send + Sync + 'static,
+{
+    match request {
+        Request::Mode { mode } => {
+            // mavlink enums deserialize from a map tagged with "type".
+            let name = format!("PLANE_MODE_{}", mode.to_uppercase());
+            let mode: PlaneMode = serde_json::from_value(serde_json::json!({ "type": name }))
+                .map_err(|_| {
+                    Error::new(ErrorKind::Parse(format!("Unknown mode: {mode}"))).operation("mode")
+                })?;
+            let mut attempt = 0;
+            loop {
+                match mode
+                    .change_mode_timeout(connection.clone(), options.command_timeout)
+                    .await
+                {
+                    Err(e) if e.is_timeout() && attempt < options.retries => attempt += 1,
+                    res => return res,
+                }
+            }
+        }
+        Request::Arm { force } => arm(connection, true, force, options)
+            .await
+            .map_err(|e| e.operation("arm")),
+        Request::Disarm { force } => arm(connection, false, force, options)
+            .await
+            .map_err(|e| e.operation("disarm")),
+        Request::UploadMission { plan } => {
+            let items = file::parse_plan(&plan.to_string())?;
+            let upload = upload::Options {
+                mission_count_timeout: options.command_timeout,
+                mission_item_timeout: options.command_timeout,
+                mission_item_retries: options.retries,
+            };
+            items.upload_mission(connection, upload).await.map(|_| ())
+        }
+    }
+}
+
+// Answers one text message from a client.
+async fn respond<C>(connection: Arc<C>, text: &str, options: &Options) -> Response
+where
+    C: MavlinkConnection + Debug + Send + Sync + 'static,
+{
+    let envelope: Envelope = match serde_json::from_str(text) {
+        Ok(envelope) => envelope,
+        Err(e) => {
+            return Response {
+                id: None,
+                ok: false,
+                error: Some(format!("invalid request: {e}")),
+            }
+        }
+    };
+
+    let res = handle_request(connection, envelope.request, options).await;
+    if let Err(e) = &res {
+        tracing::event!(tracing::Level::WARN, id = envelope.id, %e, "Gateway command failed");
+    }
+    Response {
+        id: envelope.id,
+        ok: res.is_ok(),
+        error: res.err().map(|e| e.to_string()),
+    }
+}
+
+async fn client<C>(
+    connection: Arc<C>,
+    tracker: TelemetryTracker,
+    stream: tokio::net::TcpStream,
+    options: Arc<Options>,
+) -> Result<(), Error>
+where
+    C: MavlinkConnection + Debug + Send + Sync + 'static,
+{
+    // The error response is tungstenite's.
+    #[allow(clippy::result_large_err)]
+    let check_origin = |request: &Handshake, response: Accepted| {
+        let origin = request.headers().get("Origin");
+        match origin.map(|origin| origin.to_str()) {
+            None => Ok(response),
+            Some(Ok(origin))
+                if options
+                    .allowed_origins
+                    .iter()
+                    .any(|allowed| allowed == origin) =>
+            {
+                Ok(response)
+            }
+            _ => {
+                let mut refused = ErrorResponse::new(Some("Origin not allowed".to_string()));
+                *refused.status_mut() = StatusCode::FORBIDDEN;
+                Err(refused)
+            }
+        }
+    };
+    let websocket = tokio_tungstenite::accept_hdr_async(stream, check_origin)
+        .await
+        .map_err(|e| Error::other(e.to_string()))?;
+    let (mut sink, mut stream) = websocket.split();
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut interval = tokio::time::interval(options.period);
+    loop {
+        let outgoing = tokio::select! {
+            _ = interval.tick() => Outgoing::Telemetry(tracker.snapshot(options.link_timeout)),
+            Some(response) = rx.recv() => Outgoing::Response(response),
+            msg = stream.next() => {
+                match msg {
+                    Some(Ok(Message::Text(text))) => {
+                        // Commands can take seconds, telemetry keeps flowing meanwhile.
+                        let (connection, options, tx) = (connection.clone(), options.clone(), tx.clone());
+                        tokio::spawn(async move {
+                            let _ = tx.send(respond(connection, &text, &options).await);
+                        });
+                    }
+                    Some(Ok(Message::Close(_))) | None => return Ok(()),
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => return Err(Error::other(e.to_string())),
+                }
+                continue;
+            }
+        };
+
+        let text = serde_json::to_string(&outgoing).map_err(|e| Error::other(e.to_string()))?;
+        sink.send(Message::Text(text))
+            .await
+            .map_err(|e| Error::other(e.to_string()))?;
+    }
+}
+
+// Accepts WebSocket clients on `listener` until it fails. Telemetry and commands are monitored
+// at the same time, the shared connection hands each of them every message.
+pub async fn serve(
+    connection: Arc<SharedConnection>,
+    listener: tokio::net::TcpListener,
+    options: Options,
+) -> Result<(), Error> {
+    let (tracker, _handle) = track_telemetry(connection.clone());
+    let options = Arc::new(options);
+
+    loop {
+        let (stream, address) = listener.accept().await?;
+        tracing::event!(tracing::Level::INFO, %address, "Gateway client connected");
+
+        let (connection, tracker, options) = (connection.clone(), tracker.clone(), options.clone());
+        tokio::spawn(async move {
+            let res = client(connection, tracker, stream, options).await;
+            tracing::event!(tracing::Level::INFO, %address, ?res, "Gateway client left");
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use futures_util::{SinkExt, StreamExt};
+    use mavlink::ardupilotmega::{MavAutopilot, MavMessage, MavType, PlaneMode, HEARTBEAT_DATA};
+    use tokio_tungstenite::tungstenite::{client::IntoClientRequest, Message};
+
+    use super::{handle_request, serve, Envelope, Options, Request, TelemetryTracker};
+    use crate::{connection::SharedConnection, sim::SimVehicle};
+
+    #[test]
+    fn parses_requests() {
+        let envelope: Envelope =
+            serde_json::from_str(r#"{"id": 7, "command": "arm", "force": true}"#).unwrap();
+        assert_eq!(envelope.id, Some(7));
+        assert_eq!(envelope.request, Request::Arm { force: true });
+
+        let envelope: Envelope = serde_json::from_str(r#"{"command": "disarm"}"#).unwrap();
+        assert_eq!(envelope.request, Request::Disarm { force: false });
+        assert!(serde_json::from_str::<Envelope>(r#"{"command": "reboot"}"#).is_err());
+    }
+
+    #[test]
+    fn ignores_other_heartbeats() {
+        let tracker = TelemetryTracker::default();
+        tracker.update(MavMessage::HEARTBEAT(HEARTBEAT_DATA {
+            custom_mode: PlaneMode::PLANE_MODE_GUIDED as u32,
+            mavtype: MavType::MAV_TYPE_FIXED_WING,
+            autopilot: MavAutopilot::MAV_AUTOPILOT_ARDUPILOTMEGA,
+            ..Default::default()
+        }));
+        for mavtype in [MavType::MAV_TYPE_CAMERA, MavType::MAV_TYPE_GCS] {
+            tracker.update(MavMessage::HEARTBEAT(HEARTBEAT_DATA {
+                mavtype,
+                autopilot: MavAutopilot::MAV_AUTOPILOT_INVALID,
+                ..Default::default()
+            }));
+        }
+
+        let telemetry = tracker.snapshot(std::time::Duration::from_secs(3));
+        assert_eq!(
+            telemetry.custom_mode,
+            Some(PlaneMode::PLANE_MODE_GUIDED as u32)
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_other_origins() {
+        let connection = Arc::new(SharedConnection::new(Arc::new(SimVehicle::default())).unwrap());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let options = Options {
+            allowed_origins: vec!["http://localhost:3000".to_string()],
+            ..Default::default()
+        };
+        let server = tokio::spawn(serve(connection, listener, options));
+
+        let connect = |origin: &'static str| {
+            let mut request = format!("ws://{address}").into_client_request().unwrap();
+            request
+                .headers_mut()
+                .insert("Origin", origin.parse().unwrap());
+            tokio_tungstenite::connect_async(request)
+        };
+        assert!(connect("http://localhost:3000").await.is_ok());
+        assert!(connect("https://example.com").await.is_err());
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn handles_requests() {
+        let connection: Arc<Box<SimVehicle>> = Default::default();
+        let mode = Request::Mode {
+            mode: "guided".to_string(),
+        };
+        handle_request(connection.clone(), mode, &Default::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            connection.custom_mode(),
+            PlaneMode::PLANE_MODE_GUIDED as u32
+        );
+
+        let mode = Request::Mode {
+            mode: "hover".to_string(),
+        };
+        let e = handle_request(connection.clone(), mode, &Default::default())
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("Unknown mode: hover"), "{e}");
+    }
+
+    #[tokio::test]
+    async fn serves_websocket_clients() {
+        let sim: Arc<SimVehicle> = Default::default();
+        let connection = Arc::new(SharedConnection::new(sim.clone()).unwrap());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        // Without retries, a command only succeeds if telemetry leaves its answer alone.
+        let options = Options {
+            retries: 0,
+            ..Default::default()
+        };
+        let server = tokio::spawn(serve(connection, listener, options));
+
+        let (mut websocket, _) = tokio_tungstenite::connect_async(format!("ws://{address}"))
+            .await
+            .unwrap();
+        websocket
+            .send(Message::Text(
+                r#"{"id": 1, "command": "mode", "mode": "hover"}"#.to_string(),
+            ))
+            .await
+            .unwrap();
+        websocket
+            .send(Message::Text(
+                r#"{"id": 2, "command": "mode", "mode": "guided"}"#.to_string(),
+            ))
+            .await
+            .unwrap();
+
+        let (mut telemetry, mut responses) = (None, vec![]);
+        tokio::time::timeout(std::time::Duration::from_secs(3), async {
+            while telemetry.is_none() || responses.len() < 2 {
+                let Some(Ok(Message::Text(text))) = websocket.next().await else {
+                    panic!("WebSocket closed");
+                };
+                let value: serde_json::Value = serde_json::from_str(&text).unwrap();
+                match value["type"].as_str() {
+                    Some("telemetry")
+                        if value["position"].is_object() && value["link"]["connected"] == true =>
+                    {
+                        telemetry = Some(value)
+                    }
+                    Some("response") => responses.push(value),
+                    _ => {}
+                }
+            }
+        })
+        .await
+        .unwrap();
+
+        responses.sort_by_key(|response| response["id"].as_u64());
+        assert_eq!(responses[0]["ok"], false);
+        assert_eq!(responses[1]["ok"], true, "{}", responses[1]);
+        assert_eq!(sim.custom_mode(), PlaneMode::PLANE_MODE_GUIDED as u32);
+        let telemetry = telemetry.unwrap();
+        assert_eq!(telemetry["position"]["lat"], 47.397742);
+        assert_eq!(telemetry["armed"], false);
+
+        server.abort();
+    }
+}
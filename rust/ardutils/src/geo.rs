@@ -0,0 +1,107 @@
+// Locations on the earth and the distances between them. Offsets use a flat earth around the
+// location they are taken from, good enough up to a few kilometers. Longer distances, like the
+// legs of a mission, use the great circle.
+
+// Meters per degree of latitude.
+pub const METERS_PER_DEGREE: f64 = 111_320.0;
+
+// Mean radius of the earth, in meters.
+pub const EARTH_RADIUS: f64 = 6_371_000.0;
+
+// Latitude and longitude in degrees, altitude in meters. What the altitude is relative to, AMSL
+// or home, is up to where the location comes from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Location {
+    pub lat: f64,
+    pub lon: f64,
+    pub alt: f32,
+}
+
+impl Location {
+    // From degE7 and millimeters, as sent over MAVLink.
+    pub fn from_int(lat: i32, lon: i32, alt: i32) -> Self {
+        Self {
+            lat: lat as f64 / 1e7,
+            lon: lon as f64 / 1e7,
+            alt: alt as f32 / 1000.0,
+        }
+    }
+
+    pub fn lat_int(&self) -> i32 {
+        (self.lat * 1e7).round() as i32
+    }
+
+    pub fn lon_int(&self) -> i32 {
+        (self.lon * 1e7).round() as i32
+    }
+
+    // North, east and down offsets in meters to `other`.
+    pub fn offset_to(&self, other: &Location) -> (f64, f64, f64) {
+        (
+            (other.lat - self.lat) * METERS_PER_DEGREE,
+            (other.lon - self.lon) * METERS_PER_DEGREE * self.lat.to_radians().cos(),
+            (self.alt - other.alt) as f64,
+        )
+    }
+
+    // The location `north` and `east` meters away, at the same altitude.
+    pub fn offset(&self, north: f64, east: f64) -> Location {
+        Location {
+            lat: self.lat + north / METERS_PER_DEGREE,
+            lon: self.lon + east / (METERS_PER_DEGREE * self.lat.to_radians().cos()),
+            alt: self.alt,
+        }
+    }
+
+    // Horizontal and vertical distances in meters to `other`.
+    pub fn distance_to(&self, other: &Location) -> (f64, f32) {
+        let (north, east, _) = self.offset_to(other);
+        (north.hypot(east), (other.alt - self.alt).abs())
+    }
+
+    // Great circle distance in meters, and initial bearing in degrees, to `other`.
+    pub fn distance_bearing(&self, other: &Location) -> (f64, f64) {
+        let (lat1, lat2) = (self.lat.to_radians(), other.lat.to_radians());
+        let dlat = lat2 - lat1;
+        let dlon = (other.lon - self.lon).to_radians();
+
+        let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+        let distance = 2.0 * EARTH_RADIUS * a.sqrt().asin();
+
+        let y = dlon.sin() * lat2.cos();
+        let x = lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * dlon.cos();
+        let bearing = y.atan2(x).to_degrees().rem_euclid(360.0);
+        (distance, bearing)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Location;
+
+    const ZURICH: Location = Location {
+        lat: 47.397742,
+        lon: 8.545594,
+        alt: 488.0,
+    };
+
+    #[test]
+    fn offsets() {
+        let other = ZURICH.offset(300.0, -400.0);
+        let (north, east, down) = ZURICH.offset_to(&other);
+
+        assert!((north - 300.0).abs() < 1e-6);
+        assert!((east + 400.0).abs() < 1e-6);
+        assert_eq!(down, 0.0);
+        assert!((ZURICH.distance_to(&other).0 - 500.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn great_circle_agrees_with_flat_earth() {
+        let other = ZURICH.offset(0.0, 1000.0);
+        let (distance, bearing) = ZURICH.distance_bearing(&other);
+
+        assert!((distance - 1000.0).abs() < 5.0);
+        assert!((bearing - 90.0).abs() < 0.1);
+    }
+}
@@ -0,0 +1,302 @@
+// Points the gimbal with the gimbal manager protocol (v2), falling back to DO_MOUNT_CONTROL
+// for autopilots and mounts that predate it.
+
+use std::{fmt::Debug, sync::Arc};
+
+use mavlink::ardupilotmega::{
+    GimbalManagerFlags, MavCmd, MavMessage, MavMountMode, MavResult, COMMAND_LONG_DATA,
+    GIMBAL_MANAGER_STATUS_DATA,
+};
+
+use crate::{
+    command::Command,
+    connection::{MavlinkConnection, MonitorHandle},
+    error::{Error, ErrorKind},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+pub enum GimbalProtocol {
+    // MAV_CMD_DO_GIMBAL_MANAGER_PITCHYAW.
+    Manager,
+    // MAV_CMD_DO_MOUNT_CONTROL.
+    MountControl,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Options {
+    // How long to wait for each acknowledgement before sending again.
+    pub timeout: std::time::Duration,
+    pub retries: u8,
+    // Gimbal device to point, 0 for the primary one.
+    pub gimbal_device_id: u8,
+    // Skips trying the gimbal manager first when the protocol is already known, e.g. from
+    // an earlier `point_gimbal`.
+    pub protocol: Option<GimbalProtocol>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            timeout: std::time::Duration::from_secs(1),
+            retries: 2,
+            gimbal_device_id: 0,
+            protocol: None,
+        }
+    }
+}
+
+// Angles in degrees, positive pitch is up. Yaw is relative to the vehicle heading, unless locked
+// to the earth frame.
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Deserialize)]
+pub struct GimbalAttitude {
+    pub pitch: f32,
+    pub yaw: f32,
+    pub yaw_lock: bool,
+}
+
+impl GimbalAttitude {
+    fn pitchyaw(&self, device: u8) -> COMMAND_LONG_DATA {
+        let flags = if self.yaw_lock {
+            GimbalManagerFlags::GIMBAL_MANAGER_FLAGS_YAW_LOCK as u32
+        } else {
+            0
+        };
+        COMMAND_LONG_DATA {
+            command: MavCmd::MAV_CMD_DO_GIMBAL_MANAGER_PITCHYAW,
+            param1: self.pitch,
+            param2: self.yaw,
+            // No rate control.
+            param3: f32::NAN,
+            param4: f32::NAN,
+            param5: flags as f32,
+            param7: device as f32,
+            ..Default::default()
+        }
+    }
+
+    fn mount_control(&self) -> COMMAND_LONG_DATA {
+        COMMAND_LONG_DATA {
+            command: MavCmd::MAV_CMD_DO_MOUNT_CONTROL,
+            param1: self.pitch,
+            param2: 0.0,
+            param3: self.yaw,
+            param7: MavMountMode::MAV_MOUNT_MODE_MAVLINK_TARGETING as u32 as f32,
+            ..Default::default()
+        }
+    }
+}
+
+async fn send<C>(
+    connection: Arc<C>,
+    command: COMMAND_LONG_DATA,
+    options: &Options,
+) -> Result<MavResult, Error>
+where
+    C: MavlinkConnection + Debug + Send + Sync + 'static,
+{
+    let command = COMMAND_LONG_DATA {
+        target_system: connection.target_system(),
+        target_component: connection.target_component(),
+        ..command
+    };
+    command
+        .command_retry(connection, options.timeout, options.retries)
+        .await
+        .map(|ack| ack.result)
+}
+
+// Points the gimbal, and returns the protocol that worked.
+pub async fn point_gimbal<C>(
+    connection: Arc<C>,
+    attitude: GimbalAttitude,
+    options: &Options,
+) -> Result<GimbalProtocol, Error>
+where
+    C: MavlinkConnection + Debug + Send + Sync + 'static,
+{
+    if options.protocol != Some(GimbalProtocol::MountControl) {
+        let command = attitude.pitchyaw(options.gimbal_device_id);
+        match send(connection.clone(), command, options).await {
+            Ok(MavResult::MAV_RESULT_ACCEPTED) => return Ok(GimbalProtocol::Manager),
+            // Only an explicit refusal from a gimbal manager is final.
+            Ok(result)
+                if options.protocol == Some(GimbalProtocol::Manager)
+                    || result != MavResult::MAV_RESULT_UNSUPPORTED =>
+            {
+                let reason = format!("{result:?}");
+                return Err(Error::new(ErrorKind::Rejected(reason)).operation("gimbal"));
+            }
+            Ok(_) => {}
+            Err(e) if e.is_timeout() && options.protocol.is_none() => {}
+            Err(e) => return Err(e.operation("gimbal")),
+        }
+        tracing::event!(
+            tracing::Level::DEBUG,
+            "No gimbal manager, using mount control"
+        );
+    }
+
+    match send(connection, attitude.mount_control(), options).await {
+        Ok(MavResult::MAV_RESULT_ACCEPTED) => Ok(GimbalProtocol::MountControl),
+        Ok(result) => {
+            let reason = format!("{result:?}");
+            Err(Error::new(ErrorKind::Rejected(reason)).operation("gimbal"))
+        }
+        Err(e) => Err(e.operation("gimbal")),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GimbalStatus {
+    pub gimbal_device_id: u8,
+    pub flags: GimbalManagerFlags,
+    // System and component ids, (0, 0) when nobody is in control.
+    pub primary_control: (u8, u8),
+    pub secondary_control: (u8, u8),
+}
+
+impl GimbalStatus {
+    pub fn in_control(&self, system_id: u8, component_id: u8) -> bool {
+        self.primary_control == (system_id, component_id)
+            || self.secondary_control == (system_id, component_id)
+    }
+}
+
+impl From<GIMBAL_MANAGER_STATUS_DATA> for GimbalStatus {
+    fn from(data: GIMBAL_MANAGER_STATUS_DATA) -> Self {
+        Self {
+            gimbal_device_id: data.gimbal_device_id,
+            flags: data.flags,
+            primary_control: (data.primary_control_sysid, data.primary_control_compid),
+            secondary_control: (data.secondary_control_sysid, data.secondary_control_compid),
+        }
+    }
+}
+
+// Follows GIMBAL_MANAGER_STATUS, until the returned handle is dropped. Only the gimbal manager
+// protocol reports a status.
+pub fn gimbal_status<C>(
+    connection: Arc<C>,
+) -> (
+    tokio::sync::watch::Receiver<Option<GimbalStatus>>,
+    MonitorHandle,
+)
+where
+    C: MavlinkConnection + Debug + Send + Sync + 'static,
+{
+    let (tx, rx) = tokio::sync::watch::channel(None);
+
+    let handle = connection.monitor(None, move |msg| {
+        if let MavMessage::GIMBAL_MANAGER_STATUS(data) = msg {
+            let status = Some(GimbalStatus::from(data));
+            tx.send_if_modified(|current| {
+                let modified = *current != status;
+                *current = status;
+                modified
+            });
+        }
+        Some(())
+    });
+
+    (rx, handle)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use mavlink::ardupilotmega::{
+        GimbalManagerFlags, MavCmd, MavMessage, MavResult, GIMBAL_MANAGER_STATUS_DATA,
+    };
+
+    use super::{gimbal_status, point_gimbal, GimbalAttitude, GimbalProtocol};
+    use crate::sim::SimVehicle;
+
+    fn sent_commands(connection: &SimVehicle) -> Vec<MavCmd> {
+        connection
+            .received()
+            .into_iter()
+            .filter_map(|msg| match msg {
+                MavMessage::COMMAND_LONG(data) => Some(data.command),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn uses_gimbal_manager() {
+        let connection: Arc<Box<SimVehicle>> = Default::default();
+        connection.set_command_result(
+            MavCmd::MAV_CMD_DO_GIMBAL_MANAGER_PITCHYAW,
+            MavResult::MAV_RESULT_ACCEPTED,
+        );
+
+        let attitude = GimbalAttitude {
+            pitch: -90.0,
+            ..Default::default()
+        };
+        let protocol = point_gimbal(connection.clone(), attitude, &Default::default())
+            .await
+            .unwrap();
+
+        assert_eq!(protocol, GimbalProtocol::Manager);
+        assert_eq!(
+            sent_commands(&connection),
+            [MavCmd::MAV_CMD_DO_GIMBAL_MANAGER_PITCHYAW]
+        );
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_mount_control() {
+        let connection: Arc<Box<SimVehicle>> = Default::default();
+        connection.set_command_result(
+            MavCmd::MAV_CMD_DO_MOUNT_CONTROL,
+            MavResult::MAV_RESULT_ACCEPTED,
+        );
+
+        let attitude = GimbalAttitude {
+            pitch: -45.0,
+            yaw: 10.0,
+            ..Default::default()
+        };
+        let protocol = point_gimbal(connection.clone(), attitude, &Default::default())
+            .await
+            .unwrap();
+
+        assert_eq!(protocol, GimbalProtocol::MountControl);
+        assert_eq!(
+            sent_commands(&connection),
+            [
+                MavCmd::MAV_CMD_DO_GIMBAL_MANAGER_PITCHYAW,
+                MavCmd::MAV_CMD_DO_MOUNT_CONTROL
+            ]
+        );
+        let Some(MavMessage::COMMAND_LONG(data)) = connection.received().pop() else {
+            panic!("DO_MOUNT_CONTROL not sent");
+        };
+        assert_eq!((data.param1, data.param3), (-45.0, 10.0));
+    }
+
+    #[tokio::test]
+    async fn follows_status() {
+        let connection: Arc<Box<SimVehicle>> = Default::default();
+        let (mut status, _handle) = gimbal_status(connection.clone());
+
+        connection.inject(MavMessage::GIMBAL_MANAGER_STATUS(
+            GIMBAL_MANAGER_STATUS_DATA {
+                flags: GimbalManagerFlags::GIMBAL_MANAGER_FLAGS_YAW_LOCK,
+                primary_control_sysid: 255,
+                primary_control_compid: 190,
+                ..Default::default()
+            },
+        ));
+
+        tokio::time::timeout(std::time::Duration::from_secs(1), status.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        let status = status.borrow().clone().unwrap();
+        assert!(status.in_control(255, 190));
+        assert!(!status.in_control(1, 1));
+    }
+}
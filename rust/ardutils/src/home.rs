@@ -0,0 +1,290 @@
+// Home position and EKF origin. Home is where RTL returns to, and what relative altitudes are
+// measured from. The EKF origin anchors the local frame: without GPS, e.g. indoors, it has to be
+// set before the vehicle can navigate.
+
+use std::{fmt::Debug, sync::Arc};
+
+use mavlink::{
+    ardupilotmega::{
+        MavCmd, MavFrame, MavMessage, MavResult, COMMAND_INT_DATA, COMMAND_LONG_DATA,
+        GPS_GLOBAL_ORIGIN_DATA, HOME_POSITION_DATA, SET_GPS_GLOBAL_ORIGIN_DATA,
+    },
+    Message,
+};
+
+use crate::{
+    command::Command,
+    connection::{send_wait_retry, FilterRes, MavlinkConnection},
+    error::{Error, ErrorKind},
+    geo::Location,
+};
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Options {
+    // How long to wait for each answer before asking again.
+    pub timeout: std::time::Duration,
+    pub retries: u8,
+    // Largest distance in meters, horizontally and vertically, between the requested location
+    // and the one the vehicle reports back.
+    pub tolerance: f32,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            timeout: std::time::Duration::from_secs(1),
+            retries: 3,
+            tolerance: 1.0,
+        }
+    }
+}
+
+// Horizontally and vertically within `tolerance` meters of each other.
+fn is_near(a: &Location, b: &Location, tolerance: f32) -> bool {
+    let (horizontal, vertical) = a.distance_to(b);
+    horizontal <= tolerance as f64 && vertical <= tolerance
+}
+
+impl From<HOME_POSITION_DATA> for Location {
+    fn from(data: HOME_POSITION_DATA) -> Self {
+        Self::from_int(data.latitude, data.longitude, data.altitude)
+    }
+}
+
+impl From<GPS_GLOBAL_ORIGIN_DATA> for Location {
+    fn from(data: GPS_GLOBAL_ORIGIN_DATA) -> Self {
+        Self::from_int(data.latitude, data.longitude, data.altitude)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize)]
+pub enum SetHome {
+    // Where the vehicle is now.
+    Current,
+    At(Location),
+}
+
+// Asks for `expected` with MAV_CMD_REQUEST_MESSAGE until it comes back.
+async fn request_message<C, R>(
+    connection: Arc<C>,
+    expected: MavMessage,
+    options: &Options,
+    filter: fn(MavMessage) -> Option<R>,
+) -> Result<R, Error>
+where
+    C: MavlinkConnection + Debug + Send + Sync + 'static,
+    R: Send + Sync + 'static,
+{
+    let id = expected.message_id();
+    let request = MavMessage::COMMAND_LONG(COMMAND_LONG_DATA {
+        target_system: connection.target_system(),
+        target_component: connection.target_component(),
+        command: MavCmd::MAV_CMD_REQUEST_MESSAGE,
+        param1: id as f32,
+        ..Default::default()
+    });
+
+    let filter = move |msg| match filter(msg) {
+        Some(data) => FilterRes::Ready(Some(data)),
+        None => FilterRes::NotReady,
+    };
+    send_wait_retry(
+        &connection,
+        &request,
+        options.timeout,
+        options.retries,
+        filter,
+    )
+    .await?
+    .ok_or_else(|| {
+        Error::new(ErrorKind::InvalidResponse("Empty answer".to_string())).message_id(id)
+    })
+}
+
+pub async fn get_home<C>(connection: Arc<C>, options: &Options) -> Result<Location, Error>
+where
+    C: MavlinkConnection + Debug + Send + Sync + 'static,
+{
+    request_message(
+        connection,
+        MavMessage::HOME_POSITION(Default::default()),
+        options,
+        |msg| match msg {
+            MavMessage::HOME_POSITION(data) => Some(Location::from(data)),
+            _ => None,
+        },
+    )
+    .await
+    .map_err(|e| e.operation("home"))
+}
+
+// Sets home, then reads it back. A given location must be reported back within tolerance.
+pub async fn set_home<C>(
+    connection: Arc<C>,
+    home: SetHome,
+    options: &Options,
+) -> Result<Location, Error>
+where
+    C: MavlinkConnection + Debug + Send + Sync + 'static,
+{
+    let (current, location) = match home {
+        SetHome::Current => (1.0, None),
+        SetHome::At(location) => (0.0, Some(location)),
+    };
+
+    let command = COMMAND_INT_DATA {
+        target_system: connection.target_system(),
+        target_component: connection.target_component(),
+        command: MavCmd::MAV_CMD_DO_SET_HOME,
+        frame: MavFrame::MAV_FRAME_GLOBAL,
+        param1: current,
+        x: location.map_or(0, |l| l.lat_int()),
+        y: location.map_or(0, |l| l.lon_int()),
+        z: location.map_or(0.0, |l| l.alt),
+        ..Default::default()
+    };
+    let ack = command
+        .command_retry(connection.clone(), options.timeout, options.retries)
+        .await
+        .map_err(|e| e.operation("set home"))?;
+    if ack.result != MavResult::MAV_RESULT_ACCEPTED {
+        let reason = format!("{:?}", ack.result);
+        return Err(Error::new(ErrorKind::Rejected(reason)).operation("set home"));
+    }
+
+    let reported = get_home(connection, options)
+        .await
+        .map_err(|e| e.operation("set home"))?;
+    match location {
+        Some(location) if !is_near(&location, &reported, options.tolerance) => Err(Error::new(
+            ErrorKind::InvalidResponse(format!("home reported at {reported:?}")),
+        )
+        .operation("set home")),
+        _ => Ok(reported),
+    }
+}
+
+pub async fn get_ekf_origin<C>(connection: Arc<C>, options: &Options) -> Result<Location, Error>
+where
+    C: MavlinkConnection + Debug + Send + Sync + 'static,
+{
+    request_message(
+        connection,
+        MavMessage::GPS_GLOBAL_ORIGIN(Default::default()),
+        options,
+        |msg| match msg {
+            MavMessage::GPS_GLOBAL_ORIGIN(data) => Some(Location::from(data)),
+            _ => None,
+        },
+    )
+    .await
+    .map_err(|e| e.operation("ekf origin"))
+}
+
+// Sets the EKF origin, until the vehicle reports it back within tolerance. SET_GPS_GLOBAL_ORIGIN
+// is not acknowledged, and ArduPilot ignores it once the origin is set, e.g. from GPS.
+pub async fn set_ekf_origin<C>(
+    connection: Arc<C>,
+    origin: Location,
+    options: &Options,
+) -> Result<Location, Error>
+where
+    C: MavlinkConnection + Debug + Send + Sync + 'static,
+{
+    let msg = MavMessage::SET_GPS_GLOBAL_ORIGIN(SET_GPS_GLOBAL_ORIGIN_DATA {
+        target_system: connection.target_system(),
+        latitude: origin.lat_int(),
+        longitude: origin.lon_int(),
+        altitude: (origin.alt * 1000.0).round() as i32,
+        ..Default::default()
+    });
+
+    let mut reported = None;
+    for attempt in 0..=options.retries {
+        connection
+            .send(&msg)
+            .map_err(|e| e.operation("set ekf origin"))?;
+
+        match get_ekf_origin(connection.clone(), options).await {
+            Ok(location) if is_near(&origin, &location, options.tolerance) => return Ok(location),
+            Ok(location) => reported = Some(location),
+            Err(e) if e.is_timeout() => {}
+            Err(e) => return Err(e.operation("set ekf origin")),
+        }
+        tracing::event!(
+            tracing::Level::DEBUG,
+            attempt,
+            ?reported,
+            "Setting origin again"
+        );
+    }
+
+    Err(match reported {
+        Some(location) => Error::new(ErrorKind::Rejected(format!(
+            "origin stayed at {location:?}"
+        ))),
+        None => Error::new(ErrorKind::Timeout),
+    }
+    .message_id(msg.message_id())
+    .operation("set ekf origin"))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use mavlink::ardupilotmega::{MavCmd, MavMessage, MavResult};
+
+    use super::{get_home, set_ekf_origin, set_home, SetHome};
+    use crate::{error::ErrorKind, geo::Location, sim::SimVehicle};
+
+    #[tokio::test]
+    async fn reads_and_sets_home() {
+        let connection: Arc<Box<SimVehicle>> = Default::default();
+        let options = Default::default();
+
+        let home = get_home(connection.clone(), &options).await.unwrap();
+        assert_eq!(
+            home,
+            Location {
+                lat: 47.397742,
+                lon: 8.545594,
+                alt: 488.0
+            }
+        );
+
+        let moved = Location {
+            lat: 47.4,
+            lon: 8.55,
+            alt: 500.0,
+        };
+        let home = set_home(connection.clone(), SetHome::At(moved), &options)
+            .await
+            .unwrap();
+        assert!(moved.distance_to(&home).0 < 0.1);
+
+        connection.set_command_result(MavCmd::MAV_CMD_DO_SET_HOME, MavResult::MAV_RESULT_DENIED);
+        let res = set_home(connection.clone(), SetHome::Current, &options).await;
+        assert!(matches!(res, Err(e) if matches!(e.kind, ErrorKind::Rejected(_))));
+    }
+
+    #[tokio::test]
+    async fn sets_ekf_origin() {
+        let connection: Arc<Box<SimVehicle>> = Default::default();
+        let origin = Location {
+            lat: -35.363261,
+            lon: 149.165230,
+            alt: 584.0,
+        };
+
+        let reported = set_ekf_origin(connection.clone(), origin, &Default::default())
+            .await
+            .unwrap();
+
+        assert!(origin.distance_to(&reported).0 < 0.1);
+        assert!(connection
+            .received()
+            .iter()
+            .any(|msg| matches!(msg, MavMessage::SET_GPS_GLOBAL_ORIGIN(_))));
+    }
+}
@@ -1,5 +1,30 @@
+pub mod camera;
 pub mod command;
 pub mod connection;
+pub mod error;
+pub mod failsafe;
+pub mod fleet;
+pub mod ftp;
+#[cfg(feature = "gateway")]
+pub mod gateway;
+pub mod geo;
+pub mod gimbal;
+pub mod home;
+pub mod logs;
+#[cfg(any(test, feature = "tester"))]
+pub mod lossy;
+pub mod manual;
 pub mod mission;
 pub mod mode;
+pub mod offboard;
+pub mod param;
+pub mod prearm;
+pub mod router;
+pub mod signing;
+#[cfg(any(test, feature = "tester"))]
+pub mod sim;
+pub mod statustext;
 pub mod telemetry;
+pub mod timesync;
+pub mod tlog;
+pub mod traffic;
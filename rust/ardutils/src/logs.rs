@@ -0,0 +1,332 @@
+use std::{collections::BTreeMap, fmt::Debug, io::SeekFrom, path::PathBuf, sync::Arc};
+
+use mavlink::ardupilotmega::{
+    MavMessage, LOG_ENTRY_DATA, LOG_REQUEST_DATA_DATA, LOG_REQUEST_END_DATA, LOG_REQUEST_LIST_DATA,
+};
+use tokio::{
+    io::{AsyncSeekExt, AsyncWriteExt},
+    task::JoinHandle,
+};
+
+use crate::{connection::MavlinkConnection, error::Error};
+
+// Number of bytes carried by a single LOG_DATA.
+const CHUNK_LEN: u32 = 90;
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Options {
+    // How long the link may stay silent before missing data is requested again.
+    pub timeout: std::time::Duration,
+    // Number of consecutive requests without any progress before giving up.
+    pub retries: u8,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            timeout: std::time::Duration::from_secs(1),
+            retries: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DownloadProgress {
+    pub received: u32,
+    pub size: u32,
+}
+
+impl DownloadProgress {
+    pub fn fraction(&self) -> f64 {
+        if self.size == 0 {
+            return 1.0;
+        }
+        self.received as f64 / self.size as f64
+    }
+}
+
+// Enumerates the logs stored on the vehicle, ordered by id.
+pub async fn list_logs<C>(
+    connection: Arc<C>,
+    options: Options,
+) -> Result<Vec<LOG_ENTRY_DATA>, Error>
+where
+    C: MavlinkConnection + Debug + Send + Sync + 'static,
+{
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let monitor = connection.clone().monitor(None, move |msg| {
+        if let MavMessage::LOG_ENTRY(entry) = msg {
+            // The receiver is only gone once we stopped listening.
+            let _ = tx.send(entry);
+        }
+        Some(())
+    });
+
+    let start = std::time::Instant::now();
+    let res = async {
+        let mut entries = BTreeMap::new();
+        let mut request = (0, u16::MAX);
+        let mut attempts = 0;
+
+        loop {
+            connection
+                .send(&MavMessage::LOG_REQUEST_LIST(LOG_REQUEST_LIST_DATA {
+                    start: request.0,
+                    end: request.1,
+                    target_system: connection.target_system(),
+                    target_component: connection.target_component(),
+                }))
+                .map_err(|e| e.operation("log list"))?;
+
+            let before = entries.len();
+            while let Ok(Some(entry)) = tokio::time::timeout(options.timeout, rx.recv()).await {
+                // An empty log list is reported as a single entry with num_logs == 0.
+                if entry.num_logs == 0 {
+                    return Ok(vec![]);
+                }
+                let num_logs = entry.num_logs as usize;
+                entries.insert(entry.id, entry);
+                if entries.len() == num_logs {
+                    return Ok(entries.into_values().collect());
+                }
+            }
+
+            attempts = if entries.len() > before {
+                0
+            } else {
+                attempts + 1
+            };
+            if attempts > options.retries {
+                return Err(Error::timeout(start.elapsed()).operation("log list"));
+            }
+
+            // Only ask again for the ids we have not seen yet.
+            if let Some(last) = entries.values().next_back() {
+                let missing = (1..=last.last_log_num).find(|id| !entries.contains_key(id));
+                request = (missing.unwrap_or(last.last_log_num), last.last_log_num);
+            }
+        }
+    }
+    .await;
+
+    drop(monitor);
+    res
+}
+
+// Ranges of (offset, count) that have not been received yet.
+fn missing_ranges(received: &[bool], size: u32) -> Vec<(u32, u32)> {
+    let mut ranges: Vec<(u32, u32)> = vec![];
+
+    for (chunk, _) in received.iter().enumerate().filter(|(_, r)| !**r) {
+        let offset = chunk as u32 * CHUNK_LEN;
+        let count = CHUNK_LEN.min(size - offset);
+        match ranges.last_mut() {
+            Some((start, len)) if *start + *len == offset => *len += count,
+            _ => ranges.push((offset, count)),
+        }
+    }
+
+    ranges
+}
+
+// Downloads a log to `path`. Gaps left by dropped LOG_DATA are requested again once the
+// link goes quiet, and LOG_REQUEST_END is sent once the download is over.
+pub fn download_log<C>(
+    connection: Arc<C>,
+    entry: &LOG_ENTRY_DATA,
+    path: PathBuf,
+    options: Options,
+) -> (
+    tokio::sync::watch::Receiver<DownloadProgress>,
+    JoinHandle<Result<u32, Error>>,
+)
+where
+    C: MavlinkConnection + Debug + Send + Sync + 'static,
+{
+    let (id, size) = (entry.id, entry.size);
+    let (progress_tx, progress_rx) =
+        tokio::sync::watch::channel(DownloadProgress { received: 0, size });
+
+    let handle = tokio::spawn(async move {
+        let start = std::time::Instant::now();
+        let mut file = tokio::fs::File::create(&path).await?;
+        file.set_len(size as u64).await?;
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let monitor = connection.clone().monitor(None, move |msg| {
+            if let MavMessage::LOG_DATA(data) = msg {
+                if data.id == id {
+                    let _ = tx.send(data);
+                }
+            }
+            Some(())
+        });
+
+        let res = async {
+            let mut received = vec![false; size.div_ceil(CHUNK_LEN) as usize];
+            let mut remaining = received.len();
+            let mut requests = vec![(0, size)];
+            let mut attempts = 0;
+
+            while !requests.is_empty() {
+                for (ofs, count) in &requests {
+                    connection
+                        .send(&MavMessage::LOG_REQUEST_DATA(LOG_REQUEST_DATA_DATA {
+                            ofs: *ofs,
+                            count: *count,
+                            id,
+                            target_system: connection.target_system(),
+                            target_component: connection.target_component(),
+                        }))
+                        .map_err(|e| e.seq(*ofs).operation("log download"))?;
+                }
+
+                let mut progressed = false;
+                while let Ok(Some(data)) = tokio::time::timeout(options.timeout, rx.recv()).await {
+                    let chunk = (data.ofs / CHUNK_LEN) as usize;
+                    if data.ofs % CHUNK_LEN != 0 || chunk >= received.len() || received[chunk] {
+                        continue;
+                    }
+
+                    let count = (data.count as usize).min(data.data.len());
+                    file.seek(SeekFrom::Start(data.ofs as u64)).await?;
+                    file.write_all(&data.data[..count]).await?;
+                    received[chunk] = true;
+                    remaining -= 1;
+                    progressed = true;
+
+                    progress_tx.send_modify(|p| p.received += count as u32);
+                    if remaining == 0 {
+                        break;
+                    }
+                }
+
+                attempts = if progressed { 0 } else { attempts + 1 };
+                if attempts > options.retries {
+                    let ofs = requests.first().map(|r| r.0).unwrap_or_default();
+                    return Err(Error::timeout(start.elapsed())
+                        .seq(ofs)
+                        .operation("log download"));
+                }
+
+                requests = missing_ranges(&received, size);
+                if !requests.is_empty() {
+                    tracing::event!(
+                        tracing::Level::DEBUG,
+                        gaps = requests.len(),
+                        "Requesting missing log data"
+                    );
+                }
+            }
+
+            file.flush().await?;
+            Ok(size)
+        }
+        .await;
+
+        drop(monitor);
+
+        connection
+            .send(&MavMessage::LOG_REQUEST_END(LOG_REQUEST_END_DATA {
+                target_system: connection.target_system(),
+                target_component: connection.target_component(),
+            }))
+            .map_err(|e| e.operation("log download"))?;
+
+        res
+    });
+
+    (progress_rx, handle)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use mavlink::ardupilotmega::{MavMessage, LOG_DATA_DATA, LOG_ENTRY_DATA};
+
+    use super::{download_log, list_logs, missing_ranges, Options};
+    use crate::connection::test::*;
+
+    #[test]
+    fn coalesces_missing_ranges() {
+        let received = [true, false, false, true, false];
+
+        assert_eq!(
+            missing_ranges(&received, 4 * 90 + 10),
+            vec![(90, 180), (360, 10)]
+        );
+        assert!(missing_ranges(&[true, true], 180).is_empty());
+    }
+
+    #[tokio::test]
+    async fn lists_logs() {
+        let connection: Arc<Box<TestMavConnection>> = Default::default();
+
+        connection.inject_msg(MavMessage::LOG_ENTRY(LOG_ENTRY_DATA {
+            id: 1,
+            num_logs: 1,
+            last_log_num: 1,
+            size: 1024,
+            ..Default::default()
+        }));
+
+        let logs = list_logs(connection.clone(), Default::default())
+            .await
+            .unwrap();
+
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].size, 1024);
+    }
+
+    #[tokio::test]
+    async fn downloads_log() {
+        let connection: Arc<Box<TestMavConnection>> = Default::default();
+        // Tests run in parallel, and so may several test binaries.
+        let dir = std::env::temp_dir().join(format!(
+            "ardutils-downloads-log-{}-{}",
+            std::process::id(),
+            std::time::UNIX_EPOCH.elapsed().unwrap().as_nanos()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("log.bin");
+
+        let mut data = LOG_DATA_DATA {
+            id: 3,
+            count: 5,
+            ..Default::default()
+        };
+        data.data.extend_from_slice(b"hello").unwrap();
+        connection.inject_msg(MavMessage::LOG_DATA(data));
+
+        let (progress, handle) = download_log(
+            connection.clone(),
+            &LOG_ENTRY_DATA {
+                id: 3,
+                size: 5,
+                ..Default::default()
+            },
+            path.clone(),
+            Options {
+                timeout: std::time::Duration::from_millis(100),
+                ..Default::default()
+            },
+        );
+
+        let size = tokio::time::timeout(std::time::Duration::from_secs(2), handle)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(size, 5);
+        assert_eq!(progress.borrow().fraction(), 1.0);
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello");
+        assert!(matches!(
+            connection.last_sent().unwrap(),
+            MavMessage::LOG_REQUEST_END(_)
+        ));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
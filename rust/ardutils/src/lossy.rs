@@ -0,0 +1,630 @@
+// A connection wrapper degrading the link like a marginal radio would: frames are lost,
+// duplicated, reordered, delayed and corrupted, independently in each direction.
+// Every decision is drawn from a seeded generator, so a failing run can be reproduced.
+
+use std::{
+    collections::BinaryHeap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex, Weak,
+    },
+};
+
+use mavlink::{
+    ardupilotmega::MavMessage,
+    error::{MessageReadError, MessageWriteError},
+    MavConnection, MavHeader, MavlinkVersion, Message,
+};
+
+// How often the worker threads check whether the link was dropped.
+const POLL_PERIOD: std::time::Duration = std::time::Duration::from_millis(100);
+
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+pub struct Impairments {
+    // Probabilities, between 0 and 1, applied to every frame.
+    pub loss: f64,
+    pub duplicate: f64,
+    pub reorder: f64,
+    // Probability of flipping a single bit of the serialized frame.
+    pub corrupt: f64,
+    pub latency: std::time::Duration,
+    // Uniformly distributed delay added on top of the latency.
+    pub jitter: std::time::Duration,
+    // Extra delay given to reordered frames, so that the ones sent after overtake them.
+    pub reorder_delay: std::time::Duration,
+}
+
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+pub struct Options {
+    pub seed: u64,
+    // Frames sent to the vehicle.
+    pub uplink: Impairments,
+    // Frames received from the vehicle.
+    pub downlink: Impairments,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LinkStats {
+    pub frames: u64,
+    pub lost: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+    // Corrupted frames that were rejected by the CRC check, and hence lost too.
+    pub corrupted: u64,
+}
+
+// SplitMix64, plenty for picking impairments and keeps the crate free of an RNG dependency.
+#[derive(Debug, Clone)]
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in [0, 1).
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.next_f64() < probability
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    frames: AtomicU64,
+    lost: AtomicU64,
+    duplicated: AtomicU64,
+    reordered: AtomicU64,
+    corrupted: AtomicU64,
+}
+
+impl Counters {
+    fn add(&self, counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn stats(&self) -> LinkStats {
+        LinkStats {
+            frames: self.frames.load(Ordering::Relaxed),
+            lost: self.lost.load(Ordering::Relaxed),
+            duplicated: self.duplicated.load(Ordering::Relaxed),
+            reordered: self.reordered.load(Ordering::Relaxed),
+            corrupted: self.corrupted.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Flips one bit of the serialized frame, and parses it back like the receiving end would.
+fn corrupt<M: Message>(
+    rng: &mut Rng,
+    version: MavlinkVersion,
+    header: MavHeader,
+    msg: &M,
+) -> Option<(MavHeader, M)> {
+    let mut frame = vec![];
+    mavlink::write_versioned_msg(&mut frame, version, header, msg).ok()?;
+
+    let bit = rng.next_u64() as usize % (frame.len() * 8);
+    frame[bit / 8] ^= 1 << (bit % 8);
+
+    mavlink::read_versioned_msg(&mut frame.as_slice(), version).ok()
+}
+
+// Decides the fate of a single frame, returns every copy to deliver along with its delay.
+fn impair<M: Message + Clone>(
+    impairments: &Impairments,
+    rng: &mut Rng,
+    counters: &Counters,
+    version: MavlinkVersion,
+    header: MavHeader,
+    msg: M,
+) -> Vec<(std::time::Duration, MavHeader, M)> {
+    counters.add(&counters.frames);
+
+    if rng.chance(impairments.loss) {
+        counters.add(&counters.lost);
+        return vec![];
+    }
+
+    let (header, msg) = if rng.chance(impairments.corrupt) {
+        match corrupt(rng, version, header, &msg) {
+            Some(frame) => frame,
+            None => {
+                counters.add(&counters.corrupted);
+                return vec![];
+            }
+        }
+    } else {
+        (header, msg)
+    };
+
+    let copies = if rng.chance(impairments.duplicate) {
+        counters.add(&counters.duplicated);
+        2
+    } else {
+        1
+    };
+
+    (0..copies)
+        .map(|_| {
+            let mut delay = impairments.latency + impairments.jitter.mul_f64(rng.next_f64());
+            if rng.chance(impairments.reorder) {
+                counters.add(&counters.reordered);
+                delay += impairments.reorder_delay;
+            }
+            (delay, header, msg.clone())
+        })
+        .collect()
+}
+
+struct Pending<M> {
+    due: std::time::Instant,
+    // Keeps frames due at the same instant in order.
+    order: u64,
+    header: MavHeader,
+    msg: M,
+}
+
+impl<M> PartialEq for Pending<M> {
+    fn eq(&self, other: &Self) -> bool {
+        (self.due, self.order) == (other.due, other.order)
+    }
+}
+
+impl<M> Eq for Pending<M> {}
+
+impl<M> PartialOrd for Pending<M> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Reversed, so that the BinaryHeap pops the earliest frame first.
+impl<M> Ord for Pending<M> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (other.due, other.order).cmp(&(self.due, self.order))
+    }
+}
+
+// Frames travelling in one direction.
+struct Direction<M> {
+    impairments: Impairments,
+    rng: Mutex<Rng>,
+    counters: Counters,
+    queue: Mutex<BinaryHeap<Pending<M>>>,
+    ready: Condvar,
+    order: AtomicU64,
+}
+
+impl<M: Message + Clone> Direction<M> {
+    fn new(impairments: Impairments, seed: u64) -> Self {
+        Self {
+            impairments,
+            rng: Mutex::new(Rng(seed)),
+            counters: Default::default(),
+            queue: Default::default(),
+            ready: Condvar::new(),
+            order: AtomicU64::new(0),
+        }
+    }
+
+    fn push(&self, version: MavlinkVersion, header: MavHeader, msg: M) {
+        let copies = impair(
+            &self.impairments,
+            &mut self.rng.lock().unwrap(),
+            &self.counters,
+            version,
+            header,
+            msg,
+        );
+
+        let now = std::time::Instant::now();
+        let mut queue = self.queue.lock().unwrap();
+        for (delay, header, msg) in copies {
+            queue.push(Pending {
+                due: now + delay,
+                order: self.order.fetch_add(1, Ordering::Relaxed),
+                header,
+                msg,
+            });
+        }
+        self.ready.notify_all();
+    }
+
+    // Waits at most `timeout` for a frame to be due.
+    fn pop(&self, timeout: Option<std::time::Duration>) -> Option<(MavHeader, M)> {
+        let deadline = timeout.map(|t| std::time::Instant::now() + t);
+        let mut queue = self.queue.lock().unwrap();
+
+        loop {
+            let now = std::time::Instant::now();
+            if queue.peek().is_some_and(|p| p.due <= now) {
+                let pending = queue.pop().unwrap();
+                return Some((pending.header, pending.msg));
+            }
+            if deadline.is_some_and(|d| d <= now) {
+                return None;
+            }
+
+            let wait = [queue.peek().map(|p| p.due - now), deadline.map(|d| d - now)]
+                .into_iter()
+                .flatten()
+                .min();
+            queue = match wait {
+                Some(wait) => self.ready.wait_timeout(queue, wait).unwrap().0,
+                None => self.ready.wait(queue).unwrap(),
+            };
+        }
+    }
+}
+
+struct Shared<M> {
+    uplink: Direction<M>,
+    downlink: Direction<M>,
+}
+
+// Wraps a connection with the configured impairments. Reading from and writing to the inner
+// connection happens on two threads, which exit shortly after the link is dropped. Generic
+// over the dialect, ardupilotmega by default.
+pub struct LossyLink<T, M = MavMessage> {
+    inner: Arc<T>,
+    shared: Arc<Shared<M>>,
+}
+
+impl<T, M> LossyLink<T, M>
+where
+    T: MavConnection<M> + Send + Sync + 'static,
+    M: Message + Clone + Send + Sync + 'static,
+{
+    pub fn new(inner: T, options: Options) -> Self {
+        let inner = Arc::new(inner);
+        let shared = Arc::new(Shared {
+            uplink: Direction::new(options.uplink, options.seed),
+            // Keeps both directions from drawing the same sequence.
+            downlink: Direction::new(options.downlink, !options.seed),
+        });
+
+        std::thread::spawn({
+            let inner = inner.clone();
+            let shared = Arc::downgrade(&shared);
+            move || Self::receive(inner, shared)
+        });
+        std::thread::spawn({
+            let inner = inner.clone();
+            let shared = Arc::downgrade(&shared);
+            move || Self::transmit(inner, shared)
+        });
+
+        Self { inner, shared }
+    }
+
+    fn receive(inner: Arc<T>, shared: Weak<Shared<M>>) {
+        loop {
+            let res = inner.recv();
+            let Some(shared) = shared.upgrade() else {
+                return;
+            };
+            match res {
+                Ok((header, msg)) => {
+                    shared
+                        .downlink
+                        .push(inner.get_protocol_version(), header, msg)
+                }
+                // Errors are not forwarded, a bad frame on a real link is just a lost frame.
+                Err(e) => {
+                    tracing::event!(tracing::Level::TRACE, e = ?e, "Dropping unreadable frame");
+                    std::thread::sleep(POLL_PERIOD);
+                }
+            }
+        }
+    }
+
+    fn transmit(inner: Arc<T>, shared: Weak<Shared<M>>) {
+        loop {
+            let Some(shared) = shared.upgrade() else {
+                return;
+            };
+            if let Some((header, msg)) = shared.uplink.pop(Some(POLL_PERIOD)) {
+                if let Err(e) = inner.send(&header, &msg) {
+                    tracing::event!(tracing::Level::WARN, e = ?e, "Could not forward frame");
+                }
+            }
+        }
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn uplink_stats(&self) -> LinkStats {
+        self.shared.uplink.counters.stats()
+    }
+
+    pub fn downlink_stats(&self) -> LinkStats {
+        self.shared.downlink.counters.stats()
+    }
+}
+
+impl<T, M> std::fmt::Debug for LossyLink<T, M> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "LossyLink [uplink: {:?}, downlink: {:?}]",
+            self.shared.uplink.counters.stats(),
+            self.shared.downlink.counters.stats()
+        )
+    }
+}
+
+impl<T, M> MavConnection<M> for LossyLink<T, M>
+where
+    T: MavConnection<M> + Send + Sync + 'static,
+    M: Message + Clone + Send + Sync + 'static,
+{
+    fn recv(&self) -> Result<(MavHeader, M), MessageReadError> {
+        Ok(self
+            .shared
+            .downlink
+            .pop(None)
+            .expect("Waiting without a timeout always yields a frame"))
+    }
+
+    // Succeeds even if the frame ends up lost, just like writing to a radio does.
+    fn send(&self, header: &MavHeader, data: &M) -> Result<usize, MessageWriteError> {
+        let mut frame = vec![];
+        let len =
+            mavlink::write_versioned_msg(&mut frame, self.get_protocol_version(), *header, data)?;
+        self.shared
+            .uplink
+            .push(self.get_protocol_version(), *header, data.clone());
+        Ok(len)
+    }
+
+    // The version is fixed by the inner connection, which is shared with the worker threads.
+    fn set_protocol_version(&mut self, _version: MavlinkVersion) {}
+
+    fn get_protocol_version(&self) -> MavlinkVersion {
+        self.inner.get_protocol_version()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use mavlink::{
+        ardupilotmega::{
+            MavCmd, MavMessage, PlaneMode, COMMAND_LONG_DATA, HEARTBEAT_DATA, MISSION_ITEM_INT_DATA,
+        },
+        MavlinkVersion,
+    };
+
+    use super::{impair, Counters, Impairments, LossyLink, Options, Rng};
+    use crate::{
+        command::Command,
+        connection::test::{sent, TestMavConnection},
+        mission::upload::MissionUpload,
+        mode::ChangeMode,
+        param,
+        sim::SimVehicle,
+    };
+
+    fn fates(seed: u64, impairments: &Impairments) -> (Vec<usize>, Counters) {
+        let mut rng = Rng(seed);
+        let counters = Counters::default();
+        let fates = (0..1000)
+            .map(|i| {
+                impair(
+                    impairments,
+                    &mut rng,
+                    &counters,
+                    MavlinkVersion::V2,
+                    Default::default(),
+                    MavMessage::HEARTBEAT(HEARTBEAT_DATA {
+                        custom_mode: i,
+                        ..Default::default()
+                    }),
+                )
+                .len()
+            })
+            .collect();
+        (fates, counters)
+    }
+
+    #[test]
+    fn reproducible() {
+        let impairments = Impairments {
+            loss: 0.2,
+            duplicate: 0.1,
+            ..Default::default()
+        };
+
+        let (first, counters) = fates(7, &impairments);
+        let (second, _) = fates(7, &impairments);
+        let (other, _) = fates(8, &impairments);
+
+        assert_eq!(first, second);
+        assert_ne!(first, other);
+
+        let stats = counters.stats();
+        assert_eq!(stats.frames, 1000);
+        assert!((150..250).contains(&stats.lost));
+        assert!((50..120).contains(&stats.duplicated));
+    }
+
+    #[test]
+    fn corruption_is_caught_by_crc() {
+        let (fates, counters) = fates(
+            3,
+            &Impairments {
+                corrupt: 1.0,
+                ..Default::default()
+            },
+        );
+
+        // The odd flip in an unused part of the frame, like the signature flag, goes unnoticed.
+        let stats = counters.stats();
+        assert!(stats.corrupted > 900);
+        assert_eq!(
+            fates.iter().filter(|f| **f == 0).count() as u64,
+            stats.corrupted
+        );
+    }
+
+    #[tokio::test]
+    async fn changes_mode_over_bad_link() {
+        let impairments = Impairments {
+            duplicate: 0.3,
+            reorder: 0.3,
+            latency: std::time::Duration::from_millis(20),
+            jitter: std::time::Duration::from_millis(20),
+            reorder_delay: std::time::Duration::from_millis(50),
+            ..Default::default()
+        };
+        let link = LossyLink::new(
+            SimVehicle::default(),
+            Options {
+                seed: 42,
+                uplink: impairments.clone(),
+                downlink: impairments,
+            },
+        );
+        let connection = Arc::new(Box::new(link));
+
+        PlaneMode::PLANE_MODE_LOITER
+            .change_mode(connection.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            connection.inner().custom_mode(),
+            PlaneMode::PLANE_MODE_LOITER as u32
+        );
+        assert!(connection.downlink_stats().frames > 0);
+    }
+
+    #[test]
+    fn carries_other_dialects() {
+        use mavlink::{common, MavConnection};
+
+        let link = LossyLink::new(
+            TestMavConnection::<common::MavMessage>::default(),
+            Default::default(),
+        );
+        let msg = common::MavMessage::HEARTBEAT(Default::default());
+        link.send(&Default::default(), &msg).unwrap();
+
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(1);
+        while link.inner().last_sent().is_none() {
+            assert!(std::time::Instant::now() < deadline, "Frame not forwarded");
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+
+    // Losing frames both ways. The seed loses the first frame sent, so that every exchange has
+    // to be retried at least once.
+    fn lossy(vehicle: SimVehicle) -> Box<LossyLink<SimVehicle>> {
+        let loss = |loss| Impairments {
+            loss,
+            latency: std::time::Duration::from_millis(5),
+            ..Default::default()
+        };
+        Box::new(LossyLink::new(
+            vehicle,
+            Options {
+                seed: 3,
+                uplink: loss(0.2),
+                downlink: loss(0.2),
+            },
+        ))
+    }
+
+    #[tokio::test]
+    async fn uploads_mission_over_lossy_link() {
+        let connection = Arc::new(lossy(SimVehicle::default()));
+        let mission: Vec<MISSION_ITEM_INT_DATA> = (0..10)
+            .map(|seq| MISSION_ITEM_INT_DATA {
+                seq,
+                command: MavCmd::MAV_CMD_NAV_WAYPOINT,
+                z: 50.0,
+                ..Default::default()
+            })
+            .collect();
+        let options = crate::mission::upload::Options {
+            mission_count_timeout: std::time::Duration::from_millis(100),
+            mission_item_timeout: std::time::Duration::from_millis(100),
+            mission_item_retries: 10,
+        };
+
+        let count = mission
+            .clone()
+            .upload_mission(connection.clone(), options)
+            .await
+            .unwrap();
+
+        assert_eq!(count, 10);
+        assert_eq!(connection.inner().mission(), mission);
+        // The count and every item once, the rest was sent again.
+        let stats = connection.uplink_stats();
+        assert!(stats.lost > 0);
+        assert!(stats.frames > 11, "{stats:?}");
+    }
+
+    #[tokio::test]
+    async fn retries_command_over_lossy_link() {
+        let connection = Arc::new(lossy(SimVehicle::default()));
+        let arm = COMMAND_LONG_DATA {
+            command: MavCmd::MAV_CMD_COMPONENT_ARM_DISARM,
+            param1: 1.0,
+            ..Default::default()
+        };
+
+        arm.command_retry(
+            connection.clone(),
+            std::time::Duration::from_millis(100),
+            10,
+        )
+        .await
+        .unwrap();
+
+        assert!(connection.inner().armed());
+        let stats = connection.uplink_stats();
+        assert!(stats.lost > 0);
+        assert!(stats.frames > 1, "{stats:?}");
+    }
+
+    #[tokio::test]
+    async fn downloads_params_over_lossy_link() {
+        let vehicle = SimVehicle::default();
+        for i in 0..50 {
+            vehicle.set_param(&format!("PARAM_{i}"), i as f32);
+        }
+        let connection = Arc::new(lossy(vehicle));
+        let options = param::Options {
+            timeout: std::time::Duration::from_millis(100),
+            retries: 10,
+        };
+
+        let params = param::list_params(connection.clone(), options)
+            .await
+            .unwrap();
+
+        assert_eq!(params.len(), 50);
+        for (i, param) in params.iter().enumerate() {
+            assert_eq!(param.id, format!("PARAM_{i}"));
+            assert_eq!(param.value, i as f32);
+        }
+        // The list was requested again, and lost parameters were read one by one.
+        assert!(connection.uplink_stats().lost > 0);
+        assert!(connection.downlink_stats().lost > 0);
+        assert!(sent(connection.inner(), "PARAM_REQUEST_LIST") >= 1);
+        assert!(sent(connection.inner(), "PARAM_REQUEST_READ") > 0);
+    }
+}
@@ -0,0 +1,342 @@
+use std::{fmt::Debug, sync::Arc};
+
+use mavlink::ardupilotmega::{MavMessage, MANUAL_CONTROL_DATA, RC_CHANNELS_OVERRIDE_DATA};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    connection::{MavlinkConnection, MonitorHandle},
+    error::Error,
+};
+
+// RC_CHANNELS_OVERRIDE value that leaves a channel untouched.
+pub const RC_IGNORE: u16 = u16::MAX;
+// RC_CHANNELS_OVERRIDE value that hands a channel back to the RC receiver.
+pub const RC_RELEASE: u16 = 0;
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Options {
+    // Period between two consecutive sends.
+    pub period: std::time::Duration,
+    // If the input has not been updated for this long, overrides are released
+    // and nothing is sent until the producer updates the input again.
+    pub deadman: std::time::Duration,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            period: std::time::Duration::from_millis(50),
+            deadman: std::time::Duration::from_millis(500),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ManualInput {
+    // MANUAL_CONTROL axes, normalized to [-1000, 1000] (z is [0, 1000] on ArduPilot).
+    pub x: i16,
+    pub y: i16,
+    pub z: i16,
+    pub r: i16,
+    pub buttons: u16,
+    // PWM for channels 1-8, see RC_IGNORE / RC_RELEASE. None sends no override at all.
+    pub rc_override: Option<[u16; 8]>,
+}
+
+impl ManualInput {
+    fn manual_control(&self, target: u8) -> MavMessage {
+        MavMessage::MANUAL_CONTROL(MANUAL_CONTROL_DATA {
+            x: self.x,
+            y: self.y,
+            z: self.z,
+            r: self.r,
+            buttons: self.buttons,
+            target,
+            ..Default::default()
+        })
+    }
+}
+
+fn rc_override(channels: [u16; 8], target_system: u8, target_component: u8) -> MavMessage {
+    let [chan1_raw, chan2_raw, chan3_raw, chan4_raw, chan5_raw, chan6_raw, chan7_raw, chan8_raw] =
+        channels;
+    MavMessage::RC_CHANNELS_OVERRIDE(RC_CHANNELS_OVERRIDE_DATA {
+        chan1_raw,
+        chan2_raw,
+        chan3_raw,
+        chan4_raw,
+        chan5_raw,
+        chan6_raw,
+        chan7_raw,
+        chan8_raw,
+        target_system,
+        target_component,
+        // Channels 9-18 are left as 0, which the autopilot ignores.
+        ..Default::default()
+    })
+}
+
+type Stamped = Option<(std::time::Instant, ManualInput)>;
+
+// The producer side of the shared input state -- every update resets the dead-man timer.
+#[derive(Debug)]
+pub struct InputSender(tokio::sync::watch::Sender<Stamped>);
+
+impl InputSender {
+    pub fn update(&self, input: ManualInput) {
+        self.0
+            .send_replace(Some((std::time::Instant::now(), input)));
+    }
+}
+
+#[derive(Debug)]
+pub struct InputReceiver(tokio::sync::watch::Receiver<Stamped>);
+
+pub fn input_channel() -> (InputSender, InputReceiver) {
+    let (tx, rx) = tokio::sync::watch::channel(None);
+    (InputSender(tx), InputReceiver(rx))
+}
+
+// Sends MANUAL_CONTROL (and RC_CHANNELS_OVERRIDE when set) at a fixed rate from the latest input.
+// Runs until the InputSender is dropped or the handle is cancelled, at which point any override
+// is released. Dropping the handle stops at once, leaving overrides to the autopilot timeout.
+pub fn stream_manual_control<C>(
+    connection: Arc<C>,
+    options: Options,
+    input: InputReceiver,
+) -> MonitorHandle
+where
+    C: MavlinkConnection + Debug + Send + Sync + 'static,
+{
+    let InputReceiver(mut input) = input;
+    let cancel = CancellationToken::new();
+
+    let task = tokio::spawn({
+        let cancel = cancel.clone();
+        async move {
+            let mut interval = tokio::time::interval(options.period);
+            let mut released = true;
+
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = cancel.cancelled() => break,
+                    res = input.changed() => {
+                        if res.is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                }
+
+                let latest = input.borrow_and_update().clone();
+                let Some((updated, latest)) = latest else {
+                    continue;
+                };
+
+                if updated.elapsed() > options.deadman {
+                    if !released {
+                        tracing::event!(
+                            tracing::Level::WARN,
+                            "Manual input is stale, releasing overrides"
+                        );
+                        release(connection.as_ref())?;
+                        released = true;
+                    }
+                    continue;
+                }
+
+                connection.send(&latest.manual_control(connection.target_system()))?;
+                match latest.rc_override {
+                    Some(channels) => {
+                        connection.send(&rc_override(
+                            channels,
+                            connection.target_system(),
+                            connection.target_component(),
+                        ))?;
+                        released = false;
+                    }
+                    // The autopilot would otherwise hold the last overrides until its own timeout.
+                    None if !released => {
+                        release(connection.as_ref())?;
+                        released = true;
+                    }
+                    None => {}
+                }
+            }
+
+            if !released {
+                release(connection.as_ref())?;
+            }
+
+            Ok(())
+        }
+    });
+
+    MonitorHandle::new(task, cancel)
+}
+
+fn release<C>(connection: &C) -> Result<usize, Error>
+where
+    C: MavlinkConnection,
+{
+    connection.send(&rc_override(
+        [RC_RELEASE; 8],
+        connection.target_system(),
+        connection.target_component(),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use mavlink::ardupilotmega::{MavMessage, MANUAL_CONTROL_DATA, RC_CHANNELS_OVERRIDE_DATA};
+
+    use super::{input_channel, stream_manual_control, ManualInput, Options, RC_IGNORE};
+    use crate::{connection::test::*, sim::SimVehicle};
+
+    #[tokio::test]
+    async fn sends_manual_control() {
+        let connection: Arc<Box<TestMavConnection>> = Default::default();
+
+        let (tx, rx) = input_channel();
+        let handle = stream_manual_control(connection.clone(), Default::default(), rx);
+
+        tx.update(ManualInput {
+            x: 500,
+            z: 250,
+            ..Default::default()
+        });
+
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        assert!(matches!(
+            connection.last_sent().unwrap(),
+            MavMessage::MANUAL_CONTROL(MANUAL_CONTROL_DATA { x: 500, z: 250, .. })
+        ));
+
+        drop(tx);
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn deadman_releases_override() {
+        let connection: Arc<Box<TestMavConnection>> = Default::default();
+
+        let (tx, rx) = input_channel();
+        let handle = stream_manual_control(
+            connection.clone(),
+            Options {
+                period: std::time::Duration::from_millis(10),
+                deadman: std::time::Duration::from_millis(60),
+            },
+            rx,
+        );
+
+        tx.update(ManualInput {
+            rc_override: Some([1500, RC_IGNORE, 1100, 1500, 0, 0, 0, 0]),
+            ..Default::default()
+        });
+
+        tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+
+        assert!(matches!(
+            connection.last_sent().unwrap(),
+            MavMessage::RC_CHANNELS_OVERRIDE(RC_CHANNELS_OVERRIDE_DATA {
+                chan1_raw: 1500,
+                chan2_raw: RC_IGNORE,
+                ..
+            })
+        ));
+
+        tokio::time::sleep(std::time::Duration::from_millis(150)).await;
+
+        assert!(matches!(
+            connection.last_sent().unwrap(),
+            MavMessage::RC_CHANNELS_OVERRIDE(RC_CHANNELS_OVERRIDE_DATA {
+                chan1_raw: 0,
+                chan2_raw: 0,
+                chan3_raw: 0,
+                ..
+            })
+        ));
+
+        drop(tx);
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancelling_releases_override() {
+        let connection: Arc<Box<TestMavConnection>> = Default::default();
+
+        let (tx, rx) = input_channel();
+        let handle = stream_manual_control(
+            connection.clone(),
+            Options {
+                period: std::time::Duration::from_millis(10),
+                ..Default::default()
+            },
+            rx,
+        );
+        tx.update(ManualInput {
+            rc_override: Some([1500; 8]),
+            ..Default::default()
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+
+        handle.cancel();
+        handle.await.unwrap();
+
+        assert!(matches!(
+            connection.last_sent().unwrap(),
+            MavMessage::RC_CHANNELS_OVERRIDE(RC_CHANNELS_OVERRIDE_DATA { chan1_raw: 0, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn clearing_override_releases_it() {
+        let connection: Arc<Box<SimVehicle>> = Default::default();
+
+        let (tx, rx) = input_channel();
+        let handle = stream_manual_control(
+            connection.clone(),
+            Options {
+                period: std::time::Duration::from_millis(10),
+                ..Default::default()
+            },
+            rx,
+        );
+
+        tx.update(ManualInput {
+            rc_override: Some([1500; 8]),
+            ..Default::default()
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+
+        tx.update(ManualInput {
+            x: 100,
+            ..Default::default()
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+
+        let overrides: Vec<u16> = connection
+            .received()
+            .into_iter()
+            .filter_map(|msg| match msg {
+                MavMessage::RC_CHANNELS_OVERRIDE(data) => Some(data.chan1_raw),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(overrides.last(), Some(&0));
+        // Released once, not on every period.
+        assert_eq!(overrides.iter().filter(|chan| **chan == 0).count(), 1);
+        assert!(matches!(
+            connection.received().last().unwrap(),
+            MavMessage::MANUAL_CONTROL(MANUAL_CONTROL_DATA { x: 100, .. })
+        ));
+
+        drop(tx);
+        handle.await.unwrap();
+    }
+}
@@ -0,0 +1,60 @@
+use std::{fmt::Debug, sync::Arc};
+
+use mavlink::ardupilotmega::{MavMessage, MavMissionResult, MISSION_CLEAR_ALL_DATA};
+
+use crate::{
+    connection::{FilterRes, MavlinkConnection},
+    error::{Error, ErrorKind},
+};
+
+pub async fn clear_mission<C>(connection: Arc<C>, timeout: std::time::Duration) -> Result<(), Error>
+where
+    C: MavlinkConnection + Debug + Send + Sync,
+{
+    let result = connection
+        .clone()
+        .send_wait(
+            &MavMessage::MISSION_CLEAR_ALL(MISSION_CLEAR_ALL_DATA {
+                target_system: connection.target_system(),
+                target_component: connection.target_component(),
+                ..Default::default()
+            }),
+            timeout,
+            |msg| match msg {
+                MavMessage::MISSION_ACK(ack) => FilterRes::Ready(Some(ack.mavtype)),
+                _ => FilterRes::NotReady,
+            },
+        )
+        .await
+        .and_then(|res| res.ok_or_else(|| Error::timeout(timeout)))
+        .map_err(|e| e.operation("mission clear"))?;
+
+    match result {
+        MavMissionResult::MAV_MISSION_ACCEPTED => Ok(()),
+        other => {
+            Err(Error::new(ErrorKind::Rejected(format!("{other:?}"))).operation("mission clear"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use mavlink::ardupilotmega::MISSION_ITEM_INT_DATA;
+
+    use super::clear_mission;
+    use crate::sim::SimVehicle;
+
+    #[tokio::test]
+    async fn clears() {
+        let connection: Arc<Box<SimVehicle>> = Default::default();
+        connection.set_mission(vec![MISSION_ITEM_INT_DATA::default()]);
+
+        clear_mission(connection.clone(), std::time::Duration::from_secs(1))
+            .await
+            .unwrap();
+
+        assert!(connection.mission().is_empty());
+    }
+}
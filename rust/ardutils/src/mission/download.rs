@@ -0,0 +1,141 @@
+use std::{fmt::Debug, sync::Arc};
+
+use mavlink::ardupilotmega::{
+    MavMessage, MavMissionResult, MISSION_ACK_DATA, MISSION_ITEM_INT_DATA,
+    MISSION_REQUEST_INT_DATA, MISSION_REQUEST_LIST_DATA,
+};
+
+use crate::{
+    connection::{send_wait_retry, FilterRes, MavlinkConnection},
+    error::{Error, ErrorKind},
+};
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Options {
+    // How long to wait for each response before requesting again.
+    pub timeout: std::time::Duration,
+    pub retries: u8,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            timeout: std::time::Duration::from_secs(1),
+            retries: 5,
+        }
+    }
+}
+
+// Sends `msg` until `filter` accepts a response.
+async fn request<C, R>(
+    connection: &Arc<C>,
+    msg: &MavMessage,
+    options: &Options,
+    filter: impl Fn(MavMessage) -> FilterRes<R> + Clone + Send + Sync + 'static,
+) -> Result<R, Error>
+where
+    C: MavlinkConnection + Debug + Send + Sync,
+    R: Send + Sync + 'static,
+{
+    send_wait_retry(connection, msg, options.timeout, options.retries, filter)
+        .await?
+        .ok_or_else(|| {
+            Error::new(ErrorKind::InvalidResponse(
+                "Mission download refused".to_string(),
+            ))
+        })
+}
+
+// Downloads the mission stored on the vehicle. On ArduPilot, item 0 is the home position.
+pub async fn download_mission<C>(
+    connection: Arc<C>,
+    options: Options,
+) -> Result<Vec<MISSION_ITEM_INT_DATA>, Error>
+where
+    C: MavlinkConnection + Debug + Send + Sync,
+{
+    let count = request(
+        &connection,
+        &MavMessage::MISSION_REQUEST_LIST(MISSION_REQUEST_LIST_DATA {
+            target_system: connection.target_system(),
+            target_component: connection.target_component(),
+            ..Default::default()
+        }),
+        &options,
+        |msg| match msg {
+            MavMessage::MISSION_COUNT(data) => FilterRes::Ready(Some(data.count)),
+            _ => FilterRes::NotReady,
+        },
+    )
+    .await
+    .map_err(|e| e.operation("mission download"))?;
+
+    let mut items = Vec::with_capacity(count as usize);
+    for seq in 0..count {
+        let item = request(
+            &connection,
+            &MavMessage::MISSION_REQUEST_INT(MISSION_REQUEST_INT_DATA {
+                seq,
+                target_system: connection.target_system(),
+                target_component: connection.target_component(),
+                ..Default::default()
+            }),
+            &options,
+            move |msg| match msg {
+                MavMessage::MISSION_ITEM_INT(item) if item.seq == seq => {
+                    FilterRes::Ready(Some(item))
+                }
+                MavMessage::MISSION_ACK(ack)
+                    if ack.mavtype != MavMissionResult::MAV_MISSION_ACCEPTED =>
+                {
+                    FilterRes::Ready(None)
+                }
+                _ => FilterRes::NotReady,
+            },
+        )
+        .await
+        .map_err(|e| e.seq(seq as u32).operation("mission download"))?;
+        items.push(item);
+    }
+
+    connection
+        .send(&MavMessage::MISSION_ACK(MISSION_ACK_DATA {
+            target_system: connection.target_system(),
+            target_component: connection.target_component(),
+            mavtype: MavMissionResult::MAV_MISSION_ACCEPTED,
+            ..Default::default()
+        }))
+        .map_err(|e| e.operation("mission download"))?;
+
+    Ok(items)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use mavlink::ardupilotmega::{MavCmd, MISSION_ITEM_INT_DATA};
+
+    use super::download_mission;
+    use crate::sim::SimVehicle;
+
+    #[tokio::test]
+    async fn downloads() {
+        let connection: Arc<Box<SimVehicle>> = Default::default();
+        let mission: Vec<_> = (0..3)
+            .map(|seq| MISSION_ITEM_INT_DATA {
+                seq,
+                command: MavCmd::MAV_CMD_NAV_WAYPOINT,
+                z: 100.0,
+                ..Default::default()
+            })
+            .collect();
+        connection.set_mission(mission.clone());
+
+        let items = download_mission(connection.clone(), Default::default())
+            .await
+            .unwrap();
+
+        assert_eq!(items, mission);
+    }
+}
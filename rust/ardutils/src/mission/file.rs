@@ -0,0 +1,335 @@
+// Mission files, in the QGC WPL 110 text format (.waypoints / .txt) used by Mission Planner
+// and MAVProxy, and the QGroundControl .plan JSON format.
+//
+// Like ArduPilot, item 0 is the home position: it is the first line of a WPL file, and the
+// `plannedHomePosition` of a .plan file.
+
+use std::path::Path;
+
+use mavlink::ardupilotmega::{MavCmd, MavFrame, MISSION_ITEM_INT_DATA};
+use num_traits::FromPrimitive;
+
+use crate::error::{Error, ErrorKind};
+
+const WPL_HEADER: &str = "QGC WPL 110";
+
+fn parse_error(reason: String) -> Error {
+    Error::new(ErrorKind::Parse(reason)).operation("mission file")
+}
+
+// MISSION_ITEM_INT scales x and y by 1e7 for global frames, 1e4 for local frames,
+// and not at all in MAV_FRAME_MISSION.
+fn xy_scale(frame: MavFrame) -> f64 {
+    match frame {
+        MavFrame::MAV_FRAME_GLOBAL
+        | MavFrame::MAV_FRAME_GLOBAL_RELATIVE_ALT
+        | MavFrame::MAV_FRAME_GLOBAL_INT
+        | MavFrame::MAV_FRAME_GLOBAL_RELATIVE_ALT_INT
+        | MavFrame::MAV_FRAME_GLOBAL_TERRAIN_ALT
+        | MavFrame::MAV_FRAME_GLOBAL_TERRAIN_ALT_INT => 1e7,
+        MavFrame::MAV_FRAME_MISSION => 1.0,
+        _ => 1e4,
+    }
+}
+
+// Builds an item from its 7 params, as they appear in both file formats.
+fn item(
+    seq: u16,
+    command: u16,
+    frame: u8,
+    params: [f64; 7],
+    current: bool,
+    autocontinue: bool,
+) -> Result<MISSION_ITEM_INT_DATA, Error> {
+    let command = MavCmd::from_u16(command)
+        .ok_or_else(|| parse_error(format!("Unknown command {command}")))?;
+    let frame =
+        MavFrame::from_u8(frame).ok_or_else(|| parse_error(format!("Unknown frame {frame}")))?;
+    let scale = xy_scale(frame);
+
+    Ok(MISSION_ITEM_INT_DATA {
+        param1: params[0] as f32,
+        param2: params[1] as f32,
+        param3: params[2] as f32,
+        param4: params[3] as f32,
+        x: (params[4] * scale).round() as i32,
+        y: (params[5] * scale).round() as i32,
+        z: params[6] as f32,
+        seq,
+        command,
+        frame,
+        current: current as u8,
+        autocontinue: autocontinue as u8,
+        ..Default::default()
+    })
+}
+
+fn params(item: &MISSION_ITEM_INT_DATA) -> [f64; 7] {
+    let scale = xy_scale(item.frame);
+    [
+        item.param1 as f64,
+        item.param2 as f64,
+        item.param3 as f64,
+        item.param4 as f64,
+        item.x as f64 / scale,
+        item.y as f64 / scale,
+        item.z as f64,
+    ]
+}
+
+// Items are renumbered in the order they appear.
+pub fn parse_wpl(text: &str) -> Result<Vec<MISSION_ITEM_INT_DATA>, Error> {
+    let mut lines = text.lines().map(str::trim).filter(|l| !l.is_empty());
+
+    if !lines.next().is_some_and(|l| l.starts_with("QGC WPL")) {
+        return Err(parse_error("Missing QGC WPL header".to_string()));
+    }
+
+    lines
+        .enumerate()
+        .map(|(seq, line)| {
+            let invalid = || parse_error(format!("Invalid line: {line}"));
+            let fields: Vec<f64> = line
+                .split_whitespace()
+                .map(str::parse)
+                .collect::<Result<_, _>>()
+                .map_err(|_| invalid())?;
+            let [_, current, frame, command, p1, p2, p3, p4, x, y, z, autocontinue] = fields[..]
+            else {
+                return Err(invalid());
+            };
+
+            item(
+                seq as u16,
+                command as u16,
+                frame as u8,
+                [p1, p2, p3, p4, x, y, z],
+                current != 0.0,
+                autocontinue != 0.0,
+            )
+        })
+        .collect()
+}
+
+pub fn format_wpl(items: &[MISSION_ITEM_INT_DATA]) -> String {
+    let mut text = format!("{WPL_HEADER}\n");
+
+    for (seq, item) in items.iter().enumerate() {
+        let params = params(item)
+            .iter()
+            .map(|p| p.to_string())
+            .collect::<Vec<_>>()
+            .join("\t");
+        text += &format!(
+            "{seq}\t{}\t{}\t{}\t{params}\t{}\n",
+            item.current, item.frame as u8, item.command as u16, item.autocontinue
+        );
+    }
+
+    text
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Plan {
+    mission: PlanMission,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlanMission {
+    items: Vec<PlanItem>,
+    planned_home_position: Option<[f64; 3]>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlanItem {
+    #[serde(rename = "type")]
+    kind: String,
+    command: Option<u16>,
+    frame: Option<u8>,
+    // null stands for NaN, which leaves the value unchanged on the vehicle.
+    params: Option<Vec<Option<f64>>>,
+    auto_continue: Option<bool>,
+}
+
+// Complex items, like surveys, are not supported: QGroundControl has to be used to turn them
+// into simple items first.
+pub fn parse_plan(text: &str) -> Result<Vec<MISSION_ITEM_INT_DATA>, Error> {
+    // JSON is valid YAML, which saves a dependency.
+    let plan: Plan = serde_yaml::from_str(text).map_err(|e| parse_error(e.to_string()))?;
+
+    let home = plan.mission.planned_home_position.unwrap_or_default();
+    let mut items = vec![item(
+        0,
+        MavCmd::MAV_CMD_NAV_WAYPOINT as u16,
+        MavFrame::MAV_FRAME_GLOBAL as u8,
+        [0.0, 0.0, 0.0, 0.0, home[0], home[1], home[2]],
+        false,
+        true,
+    )?];
+
+    for (seq, plan_item) in plan.mission.items.into_iter().enumerate() {
+        let (Some(command), Some(frame), Some(values)) =
+            (plan_item.command, plan_item.frame, plan_item.params)
+        else {
+            return Err(parse_error(format!("Unsupported {} item", plan_item.kind)));
+        };
+        if plan_item.kind != "SimpleItem" || values.len() != 7 {
+            return Err(parse_error(format!("Unsupported {} item", plan_item.kind)));
+        }
+
+        let mut params = [f64::NAN; 7];
+        for (param, value) in params.iter_mut().zip(values) {
+            *param = value.unwrap_or(f64::NAN);
+        }
+        items.push(item(
+            seq as u16 + 1,
+            command,
+            frame,
+            params,
+            false,
+            plan_item.auto_continue.unwrap_or(true),
+        )?);
+    }
+
+    Ok(items)
+}
+
+fn json_number(value: f64) -> String {
+    if value.is_finite() {
+        value.to_string()
+    } else {
+        "null".to_string()
+    }
+}
+
+pub fn format_plan(items: &[MISSION_ITEM_INT_DATA]) -> String {
+    let home = items
+        .first()
+        .map(|home| {
+            let p = params(home);
+            [p[4], p[5], p[6]]
+        })
+        .unwrap_or_default();
+
+    let plan_items = items
+        .iter()
+        .skip(1)
+        .enumerate()
+        .map(|(i, item)| {
+            let params = params(item)
+                .iter()
+                .map(|p| json_number(*p))
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!(
+                "            {{\n                \"autoContinue\": {},\n                \"command\": {},\n                \"doJumpId\": {},\n                \"frame\": {},\n                \"params\": [{params}],\n                \"type\": \"SimpleItem\"\n            }}",
+                item.autocontinue != 0,
+                item.command as u16,
+                i + 1,
+                item.frame as u8,
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",\n");
+
+    format!(
+        "{{\n    \"fileType\": \"Plan\",\n    \"geoFence\": {{ \"circles\": [], \"polygons\": [], \"version\": 2 }},\n    \"groundStation\": \"ardutils\",\n    \"mission\": {{\n        \"firmwareType\": 3,\n        \"items\": [\n{plan_items}\n        ],\n        \"plannedHomePosition\": [{}, {}, {}],\n        \"version\": 2\n    }},\n    \"rallyPoints\": {{ \"points\": [], \"version\": 2 }},\n    \"version\": 1\n}}\n",
+        json_number(home[0]),
+        json_number(home[1]),
+        json_number(home[2]),
+    )
+}
+
+fn is_plan(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "plan")
+}
+
+// Picks the format from the extension: .plan, or WPL for anything else.
+pub fn load(path: impl AsRef<Path>) -> Result<Vec<MISSION_ITEM_INT_DATA>, Error> {
+    let path = path.as_ref();
+    let text =
+        std::fs::read_to_string(path).map_err(|e| Error::from(e).operation("mission file"))?;
+    if is_plan(path) {
+        parse_plan(&text)
+    } else {
+        parse_wpl(&text)
+    }
+}
+
+pub fn save(path: impl AsRef<Path>, items: &[MISSION_ITEM_INT_DATA]) -> Result<(), Error> {
+    let path = path.as_ref();
+    let text = if is_plan(path) {
+        format_plan(items)
+    } else {
+        format_wpl(items)
+    };
+    std::fs::write(path, text).map_err(|e| Error::from(e).operation("mission file"))
+}
+
+#[cfg(test)]
+mod test {
+    use mavlink::ardupilotmega::{MavCmd, MavFrame};
+
+    use super::{format_plan, format_wpl, parse_plan, parse_wpl};
+
+    const WPL: &str = "QGC WPL 110
+0\t1\t0\t16\t0\t0\t0\t0\t-35.3632621\t149.1652374\t584.09\t1
+1\t0\t3\t22\t15\t0\t0\t0\t0\t0\t30\t1
+2\t0\t3\t16\t0\t0\t0\t0\t-35.3628\t149.1648\t100\t1
+";
+
+    #[test]
+    fn wpl() {
+        let items = parse_wpl(WPL).unwrap();
+
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[1].command, MavCmd::MAV_CMD_NAV_TAKEOFF);
+        assert_eq!(items[1].param1, 15.0);
+        assert_eq!(items[2].frame, MavFrame::MAV_FRAME_GLOBAL_RELATIVE_ALT);
+        assert_eq!(items[2].x, -353628000);
+        assert_eq!(parse_wpl(&format_wpl(&items)).unwrap(), items);
+        assert!(parse_wpl("0\t1\t0\t16").is_err());
+    }
+
+    #[test]
+    fn plan() {
+        let plan = r#"{
+    "fileType": "Plan",
+    "mission": {
+        "items": [
+            {
+                "autoContinue": true,
+                "command": 22,
+                "doJumpId": 1,
+                "frame": 3,
+                "params": [15, 0, 0, null, 0, 0, 30],
+                "type": "SimpleItem"
+            }
+        ],
+        "plannedHomePosition": [-35.3632621, 149.1652374, 584.09]
+    },
+    "version": 1
+}"#;
+
+        let items = parse_plan(plan).unwrap();
+
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].x, -353632621);
+        assert_eq!(items[1].command, MavCmd::MAV_CMD_NAV_TAKEOFF);
+        assert!(items[1].param4.is_nan());
+
+        let roundtrip = parse_plan(&format_plan(&items)).unwrap();
+        assert_eq!(roundtrip[1].z, 30.0);
+        assert_eq!(roundtrip[0].y, items[0].y);
+    }
+
+    #[test]
+    fn complex_items() {
+        let plan =
+            r#"{"mission": {"items": [{"type": "ComplexItem", "complexItemType": "survey"}]}}"#;
+
+        assert!(parse_plan(plan).is_err());
+    }
+}
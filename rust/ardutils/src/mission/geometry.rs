@@ -0,0 +1,342 @@
+// Figures for reviewing a mission before it is uploaded: where it goes, how far, how high, and
+// for how long. The mission is walked in the order the vehicle flies it, with DO_JUMP loops
+// unrolled.
+//
+// Like ArduPilot, item 0 is the home position. It is where the path starts, and altitudes are
+// relative to it.
+
+use std::collections::HashMap;
+
+use mavlink::ardupilotmega::{MavCmd, MavFrame, MISSION_ITEM_INT_DATA};
+
+use crate::{
+    error::{Error, ErrorKind},
+    geo::Location,
+};
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Options {
+    // Ground speed in m/s the flight time is estimated at.
+    pub cruise_speed: f32,
+    // Largest number of items once DO_JUMP loops are unrolled.
+    pub max_items: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            cruise_speed: 15.0,
+            max_items: 10_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Leg {
+    // Sequence of the items the leg goes from and to, 0 for home.
+    pub from: u16,
+    pub to: u16,
+    // Horizontal distance in meters.
+    pub distance: f64,
+    // Degrees from north, meaningless when the leg is vertical.
+    pub bearing: f64,
+    // Meters, negative when descending.
+    pub climb: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundingBox {
+    pub south: f64,
+    pub west: f64,
+    pub north: f64,
+    pub east: f64,
+}
+
+impl BoundingBox {
+    fn extend(bounds: Option<Self>, point: &Location) -> Self {
+        match bounds {
+            None => Self {
+                south: point.lat,
+                west: point.lon,
+                north: point.lat,
+                east: point.lon,
+            },
+            Some(b) => Self {
+                south: b.south.min(point.lat),
+                west: b.west.min(point.lon),
+                north: b.north.max(point.lat),
+                east: b.east.max(point.lon),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MissionGeometry {
+    // Sequences of the items in the order they are executed, DO_JUMP excluded.
+    pub expanded: Vec<u16>,
+    pub legs: Vec<Leg>,
+    // Horizontal length of the path in meters.
+    pub length: f64,
+    // Flying the path at cruise speed, plus the time spent loitering and waiting.
+    pub flight_time: std::time::Duration,
+    // None when no item has a position.
+    pub bounds: Option<BoundingBox>,
+    // Meters above home.
+    pub max_alt: f32,
+}
+
+impl MissionGeometry {
+    // Whether the mission can be flown in `endurance`, keeping `reserve` (0.2 for 20%) of it.
+    pub fn fits_endurance(&self, endurance: std::time::Duration, reserve: f32) -> bool {
+        self.flight_time.as_secs_f64() <= endurance.as_secs_f64() * (1.0 - reserve as f64)
+    }
+}
+
+fn is_nav(item: &MISSION_ITEM_INT_DATA) -> bool {
+    item.command as u32 <= MavCmd::MAV_CMD_NAV_LAST as u32
+}
+
+// Altitude above home, for the frames with global coordinates.
+fn relative_alt(item: &MISSION_ITEM_INT_DATA, home_alt: f32) -> Option<f32> {
+    match item.frame {
+        MavFrame::MAV_FRAME_GLOBAL | MavFrame::MAV_FRAME_GLOBAL_INT => Some(item.z - home_alt),
+        // Terrain following altitudes are taken as if the ground were flat.
+        MavFrame::MAV_FRAME_GLOBAL_RELATIVE_ALT
+        | MavFrame::MAV_FRAME_GLOBAL_RELATIVE_ALT_INT
+        | MavFrame::MAV_FRAME_GLOBAL_TERRAIN_ALT
+        | MavFrame::MAV_FRAME_GLOBAL_TERRAIN_ALT_INT => Some(item.z),
+        _ => None,
+    }
+}
+
+// Indexes of the items in the order they are executed, following DO_JUMP as ArduPilot does:
+// each jumps back `param2` times to `param1`, then lets the mission continue.
+pub fn expand(items: &[MISSION_ITEM_INT_DATA], max_items: usize) -> Result<Vec<usize>, Error> {
+    let mut order = vec![];
+    let mut jumps: HashMap<usize, i32> = HashMap::new();
+
+    let mut index = 1;
+    while let Some(item) = items.get(index) {
+        if item.command != MavCmd::MAV_CMD_DO_JUMP {
+            if order.len() == max_items {
+                return Err(Error::new(ErrorKind::TooManyItems(max_items as u32)));
+            }
+            order.push(index);
+            index += 1;
+            continue;
+        }
+
+        let repeat = item.param2 as i32;
+        if repeat < 0 {
+            let reason = format!("DO_JUMP {} repeats forever", item.seq);
+            return Err(Error::new(ErrorKind::Other(reason)).seq(item.seq as u32));
+        }
+        let done = jumps.entry(index).or_default();
+        if *done < repeat {
+            *done += 1;
+            let target = item.param1 as u16;
+            index = items
+                .iter()
+                .position(|i| i.seq == target && target != 0)
+                .ok_or_else(|| {
+                    let reason = format!("DO_JUMP {} to missing item {target}", item.seq);
+                    Error::new(ErrorKind::Other(reason)).seq(item.seq as u32)
+                })?;
+        } else {
+            index += 1;
+        }
+    }
+
+    Ok(order)
+}
+
+pub fn analyze(
+    items: &[MISSION_ITEM_INT_DATA],
+    options: &Options,
+) -> Result<MissionGeometry, Error> {
+    let order = expand(items, options.max_items).map_err(|e| e.operation("mission geometry"))?;
+
+    let home = items.first().filter(|home| home.x != 0 || home.y != 0);
+    let home_alt = home.map_or(0.0, |home| home.z);
+    let home = home.map(|home| Location {
+        lat: home.x as f64 / 1e7,
+        lon: home.y as f64 / 1e7,
+        alt: 0.0,
+    });
+
+    let mut position = home;
+    let mut from = 0;
+    let mut legs = vec![];
+    let mut bounds = home.map(|home| BoundingBox::extend(None, &home));
+    let mut max_alt: f32 = 0.0;
+    let mut hold = 0.0;
+
+    for index in &order {
+        let item = &items[*index];
+        if !is_nav(item) {
+            continue;
+        }
+        match item.command {
+            MavCmd::MAV_CMD_NAV_LOITER_TIME | MavCmd::MAV_CMD_NAV_DELAY => {
+                hold += item.param1.max(0.0) as f64;
+            }
+            _ => {}
+        }
+
+        let target = match (item.command, position, relative_alt(item, home_alt)) {
+            // Back home, at the altitude it was flying at.
+            (MavCmd::MAV_CMD_NAV_RETURN_TO_LAUNCH, Some(current), _) => home.map(|home| Location {
+                alt: current.alt,
+                ..home
+            }),
+            // No coordinates: where the vehicle already is, e.g. takeoff or land.
+            (_, Some(current), Some(alt)) if item.x == 0 && item.y == 0 => {
+                Some(Location { alt, ..current })
+            }
+            (_, _, Some(alt)) if item.x != 0 || item.y != 0 => Some(Location {
+                lat: item.x as f64 / 1e7,
+                lon: item.y as f64 / 1e7,
+                alt,
+            }),
+            _ => None,
+        };
+        let Some(target) = target else {
+            continue;
+        };
+
+        if let Some(current) = position.filter(|current| *current != target) {
+            let (distance, bearing) = current.distance_bearing(&target);
+            legs.push(Leg {
+                from,
+                to: item.seq,
+                distance,
+                bearing,
+                climb: target.alt - current.alt,
+            });
+        }
+        bounds = Some(BoundingBox::extend(bounds, &target));
+        max_alt = max_alt.max(target.alt);
+        position = Some(target);
+        from = item.seq;
+    }
+
+    let length: f64 = legs.iter().map(|leg| leg.distance).sum();
+    let flying = length / options.cruise_speed as f64;
+    let flight_time =
+        std::time::Duration::try_from_secs_f64(flying + hold).unwrap_or(std::time::Duration::MAX);
+
+    Ok(MissionGeometry {
+        expanded: order.iter().map(|index| items[*index].seq).collect(),
+        legs,
+        length,
+        flight_time,
+        bounds,
+        max_alt,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use mavlink::ardupilotmega::{MavCmd, MavFrame, MISSION_ITEM_INT_DATA};
+
+    use super::{analyze, Options};
+
+    // Degrees of latitude per meter, on the sphere used for distances.
+    const DEGREES_PER_METER: f64 = 1.0 / 111_194.93;
+
+    fn item(seq: u16, command: MavCmd, north: f64, alt: f32) -> MISSION_ITEM_INT_DATA {
+        MISSION_ITEM_INT_DATA {
+            seq,
+            command,
+            frame: MavFrame::MAV_FRAME_GLOBAL_RELATIVE_ALT_INT,
+            x: if north < 0.0 {
+                0
+            } else {
+                ((47.0 + north * DEGREES_PER_METER) * 1e7).round() as i32
+            },
+            y: if north < 0.0 { 0 } else { 80_000_000 },
+            z: alt,
+            ..Default::default()
+        }
+    }
+
+    fn mission() -> Vec<MISSION_ITEM_INT_DATA> {
+        vec![
+            MISSION_ITEM_INT_DATA {
+                frame: MavFrame::MAV_FRAME_GLOBAL,
+                ..item(0, MavCmd::MAV_CMD_NAV_WAYPOINT, 0.0, 500.0)
+            },
+            item(1, MavCmd::MAV_CMD_NAV_TAKEOFF, -1.0, 30.0),
+            item(2, MavCmd::MAV_CMD_NAV_WAYPOINT, 1000.0, 50.0),
+            item(3, MavCmd::MAV_CMD_NAV_WAYPOINT, 2000.0, 80.0),
+            MISSION_ITEM_INT_DATA {
+                param1: 2.0,
+                param2: 1.0,
+                ..item(4, MavCmd::MAV_CMD_DO_JUMP, -1.0, 0.0)
+            },
+            MISSION_ITEM_INT_DATA {
+                param1: 30.0,
+                ..item(5, MavCmd::MAV_CMD_NAV_LOITER_TIME, -1.0, 80.0)
+            },
+            item(6, MavCmd::MAV_CMD_NAV_RETURN_TO_LAUNCH, -1.0, 0.0),
+        ]
+    }
+
+    #[test]
+    fn unrolls_jumps() {
+        let options = Options {
+            cruise_speed: 20.0,
+            ..Default::default()
+        };
+        let geometry = analyze(&mission(), &options).unwrap();
+
+        assert_eq!(geometry.expanded, [1, 2, 3, 2, 3, 5, 6]);
+        let legs: Vec<(u16, u16, i64, i64, i64)> = geometry
+            .legs
+            .iter()
+            .map(|leg| {
+                let bearing = if leg.distance < 1.0 { 0.0 } else { leg.bearing };
+                (
+                    leg.from,
+                    leg.to,
+                    leg.distance.round() as i64,
+                    bearing.round() as i64,
+                    leg.climb.round() as i64,
+                )
+            })
+            .collect();
+        assert_eq!(
+            legs,
+            [
+                (0, 1, 0, 0, 30),
+                (1, 2, 1000, 0, 20),
+                (2, 3, 1000, 0, 30),
+                (3, 2, 1000, 180, -30),
+                (2, 3, 1000, 0, 30),
+                // Loitering where it is.
+                (5, 6, 2000, 180, 0),
+            ]
+        );
+
+        assert!((geometry.length - 6000.0).abs() < 1.0);
+        // 300 s flying, 30 s loitering.
+        assert!((geometry.flight_time.as_secs_f64() - 330.0).abs() < 0.1);
+        assert_eq!(geometry.max_alt, 80.0);
+        let bounds = geometry.bounds.unwrap();
+        assert_eq!((bounds.south, bounds.west, bounds.east), (47.0, 8.0, 8.0));
+        assert!((bounds.north - 47.0 - 2000.0 * DEGREES_PER_METER).abs() < 1e-7);
+
+        assert!(geometry.fits_endurance(std::time::Duration::from_secs(600), 0.4));
+        assert!(!geometry.fits_endurance(std::time::Duration::from_secs(600), 0.5));
+    }
+
+    #[test]
+    fn rejects_endless_jump() {
+        let mut mission = mission();
+        mission[4].param2 = -1.0;
+
+        let res = analyze(&mission, &Default::default());
+        assert!(matches!(res, Err(e) if e.context.seq == Some(4)));
+    }
+}
@@ -1 +1,6 @@
+pub mod clear;
+pub mod download;
+pub mod file;
+pub mod geometry;
+pub mod progress;
 pub mod upload;
@@ -0,0 +1,186 @@
+use std::{fmt::Debug, sync::Arc};
+
+use mavlink::ardupilotmega::{MavCmd, MavMessage, MISSION_ITEM_INT_DATA};
+
+use crate::connection::MavlinkConnection;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MissionProgress {
+    // Number of items in the mission being tracked.
+    pub total: u16,
+    // Sequence of the item the vehicle is currently executing (MISSION_CURRENT).
+    pub current: Option<u16>,
+    // Every sequence reported by MISSION_ITEM_REACHED, in the order it was reported.
+    pub reached: Vec<u16>,
+    // Distance in meters to the active waypoint (NAV_CONTROLLER_OUTPUT).
+    pub wp_dist: Option<u16>,
+    pub complete: bool,
+}
+
+impl MissionProgress {
+    pub fn last_reached(&self) -> Option<u16> {
+        self.reached.last().copied()
+    }
+}
+
+pub trait MissionProgressMonitor {
+    // Watches the execution of a mission that has already been uploaded.
+    // The sender is dropped once the mission completes, or the timeout elapses.
+    fn progress_monitor<C>(
+        &self,
+        connection: Arc<C>,
+        timeout: Option<std::time::Duration>,
+    ) -> tokio::sync::watch::Receiver<MissionProgress>
+    where
+        C: MavlinkConnection + Debug + Send + Sync;
+}
+
+impl MissionProgressMonitor for Vec<MISSION_ITEM_INT_DATA> {
+    fn progress_monitor<C>(
+        &self,
+        connection: Arc<C>,
+        timeout: Option<std::time::Duration>,
+    ) -> tokio::sync::watch::Receiver<MissionProgress>
+    where
+        C: MavlinkConnection + Debug + Send + Sync,
+    {
+        let total = self.len() as u16;
+
+        // MISSION_ITEM_REACHED is only emitted for navigation commands, so a trailing
+        // DO_* command would never be reported -- the last nav command marks completion.
+        let final_seq = self
+            .iter()
+            .rposition(|item| item.command as u32 <= MavCmd::MAV_CMD_NAV_LAST as u32)
+            .unwrap_or(self.len().saturating_sub(1)) as u16;
+
+        let (tx, rx) = tokio::sync::watch::channel(MissionProgress {
+            total,
+            ..Default::default()
+        });
+
+        let monitor = connection.monitor(timeout, move |msg| {
+            // Nobody is watching anymore.
+            if tx.is_closed() {
+                return None;
+            }
+            tx.send_if_modified(|progress| match msg {
+                MavMessage::MISSION_CURRENT(data) => {
+                    let modified = progress.current != Some(data.seq);
+                    progress.current = Some(data.seq);
+                    modified
+                }
+                MavMessage::MISSION_ITEM_REACHED(data) => {
+                    if progress.last_reached() == Some(data.seq) {
+                        return false;
+                    }
+                    progress.reached.push(data.seq);
+                    progress.complete = data.seq >= final_seq;
+                    true
+                }
+                MavMessage::NAV_CONTROLLER_OUTPUT(data) => {
+                    let modified = progress.wp_dist != Some(data.wp_dist);
+                    progress.wp_dist = Some(data.wp_dist);
+                    modified
+                }
+                _ => false,
+            });
+
+            if tx.borrow().complete {
+                return None;
+            }
+            Some(())
+        });
+        monitor.detach();
+
+        rx
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use mavlink::ardupilotmega::{
+        MavCmd, MavMessage, MISSION_CURRENT_DATA, MISSION_ITEM_INT_DATA, MISSION_ITEM_REACHED_DATA,
+        NAV_CONTROLLER_OUTPUT_DATA,
+    };
+
+    use super::MissionProgressMonitor;
+    use crate::connection::test::*;
+
+    fn mission() -> Vec<MISSION_ITEM_INT_DATA> {
+        vec![
+            MISSION_ITEM_INT_DATA {
+                command: MavCmd::MAV_CMD_NAV_TAKEOFF,
+                ..Default::default()
+            },
+            MISSION_ITEM_INT_DATA {
+                seq: 1,
+                command: MavCmd::MAV_CMD_NAV_WAYPOINT,
+                ..Default::default()
+            },
+            MISSION_ITEM_INT_DATA {
+                seq: 2,
+                command: MavCmd::MAV_CMD_DO_CHANGE_SPEED,
+                ..Default::default()
+            },
+        ]
+    }
+
+    #[tokio::test]
+    async fn tracks_progress() {
+        let connection: Arc<Box<TestMavConnection>> = Default::default();
+
+        let mut rx = mission().progress_monitor(connection.clone(), None);
+
+        connection.inject_msg(MavMessage::MISSION_CURRENT(MISSION_CURRENT_DATA {
+            seq: 1,
+            ..Default::default()
+        }));
+
+        tokio::time::timeout(std::time::Duration::from_secs(1), async {
+            rx.changed().await.unwrap();
+            assert_eq!(rx.borrow_and_update().current, Some(1));
+        })
+        .await
+        .unwrap();
+
+        connection.inject_msg(MavMessage::NAV_CONTROLLER_OUTPUT(
+            NAV_CONTROLLER_OUTPUT_DATA {
+                wp_dist: 42,
+                ..Default::default()
+            },
+        ));
+
+        tokio::time::timeout(std::time::Duration::from_secs(1), async {
+            rx.changed().await.unwrap();
+            let progress = rx.borrow_and_update().clone();
+            assert_eq!(progress.wp_dist, Some(42));
+            assert_eq!(progress.total, 3);
+            assert!(!progress.complete);
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn completes_on_last_nav_item() {
+        let connection: Arc<Box<TestMavConnection>> = Default::default();
+
+        let mut rx = mission().progress_monitor(connection.clone(), None);
+
+        connection.inject_msg(MavMessage::MISSION_ITEM_REACHED(
+            MISSION_ITEM_REACHED_DATA { seq: 1 },
+        ));
+
+        tokio::time::timeout(std::time::Duration::from_secs(1), async move {
+            while rx.changed().await.is_ok() {}
+
+            let progress = rx.borrow().clone();
+            assert!(progress.complete);
+            assert_eq!(progress.last_reached(), Some(1));
+        })
+        .await
+        .unwrap();
+    }
+}
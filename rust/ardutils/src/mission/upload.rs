@@ -1,97 +1,485 @@
 use std::{fmt::Debug, sync::Arc};
 
-use mavlink::ardupilotmega::{
-    MavMessage, MavMissionResult, MISSION_COUNT_DATA, MISSION_ITEM_INT_DATA,
-};
+use mavlink::{ardupilotmega, common, Message};
 use tracing::instrument;
 
-use crate::connection::{FilterRes, MavlinkConnection, MavlinkConnectionError};
+use crate::{
+    connection::{FilterRes, MavlinkConnection},
+    error::{Error, ErrorKind},
+};
 
 #[derive(Debug, serde::Deserialize)]
 pub struct Options {
-    mission_count_timeout: std::time::Duration,
-    mission_item_timeout: std::time::Duration,
+    pub mission_count_timeout: std::time::Duration,
+    pub mission_item_timeout: std::time::Duration,
+    // The count, or an item, whose next request does not come in time is sent again, this many
+    // times, before the upload fails.
+    pub mission_item_retries: u8,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            mission_count_timeout: std::time::Duration::from_secs(1),
+            mission_item_timeout: std::time::Duration::from_secs(1),
+            mission_item_retries: 2,
+        }
+    }
 }
 
 #[async_trait::async_trait]
 pub trait MissionUpload {
+    type Message: Message + Send + Sync + 'static;
+
     // If successful, returns the number of mission items uploaded.
-    // Otherwise, returns the error.
-    async fn upload_mission<C>(
+    // Otherwise, returns the error, with the seq of the first item the vehicle has not
+    // acknowledged.
+    async fn upload_mission<C>(self, connection: Arc<C>, options: Options) -> Result<u16, Error>
+    where
+        C: MavlinkConnection<Self::Message> + Debug + Send + Sync;
+
+    // Continues an upload that failed at `seq`, without starting over with MISSION_COUNT.
+    // Only works while the vehicle still waits for the item, otherwise it rejects it and the
+    // mission has to be uploaded again.
+    async fn resume_mission_upload<C>(
         self,
         connection: Arc<C>,
+        seq: u16,
         options: Options,
-    ) -> Result<u16, MissionUploadError>
+    ) -> Result<u16, Error>
     where
-        C: MavlinkConnection + Debug + Send + Sync;
-}
+        C: MavlinkConnection<Self::Message> + Debug + Send + Sync;
 
-pub enum MissionUploadError {
-    TooManyItems(u32),
-    Other(String),
-    ConnectionError(MavlinkConnectionError),
+    // Replaces the items from `start` on with these, with MISSION_WRITE_PARTIAL_LIST, and keeps
+    // the rest of the mission. The items must be numbered from `start`, and cannot extend the
+    // mission. Returns the number of items written.
+    async fn update_mission<C>(
+        self,
+        connection: Arc<C>,
+        start: u16,
+        options: Options,
+    ) -> Result<u16, Error>
+    where
+        C: MavlinkConnection<Self::Message> + Debug + Send + Sync;
 }
 
+// Answers the vehicle's requests for items, starting with `seq`, until it acknowledges them
+// with MISSION_ACK. `items` are the mission items from `first` on, a request for any other item
+// fails the upload.
 #[async_trait::async_trait]
-impl MissionUpload for Vec<MISSION_ITEM_INT_DATA> {
-    #[instrument]
-    async fn upload_mission<C>(
-        self,
+trait SendItems {
+    type Message: Message + Send + Sync + 'static;
+
+    async fn send_items<C>(
+        &self,
         connection: Arc<C>,
-        options: Options,
-    ) -> Result<u16, MissionUploadError>
+        first: u16,
+        seq: u16,
+        options: &Options,
+    ) -> Result<(), Error>
     where
-        C: MavlinkConnection + Debug + Send + Sync,
-    {
-        let count = self.len() as u16;
+        C: MavlinkConnection<Self::Message> + Debug + Send + Sync;
+}
 
-        let count_request = MavMessage::MISSION_COUNT(MISSION_COUNT_DATA {
-            count,
-            target_system: connection.target_system(),
-            target_component: connection.target_component(),
-        });
+// The protocol is the same in every dialect, only the message types differ.
+macro_rules! impl_mission_upload {
+    ($dialect:ident) => {
+        #[async_trait::async_trait]
+        impl SendItems for Vec<$dialect::MISSION_ITEM_INT_DATA> {
+            type Message = $dialect::MavMessage;
 
-        let mut req = connection
-            .clone()
-            .send_wait(&count_request, options.mission_count_timeout, |msg| {
-                if let MavMessage::MISSION_REQUEST_INT(req) = msg {
-                    if req.seq == 0 {
-                        FilterRes::Ready(Some(req.seq))
-                    } else {
-                        FilterRes::Ready(None)
+            async fn send_items<C>(
+                &self,
+                connection: Arc<C>,
+                first: u16,
+                seq: u16,
+                options: &Options,
+            ) -> Result<(), Error>
+            where
+                C: MavlinkConnection<Self::Message> + Debug + Send + Sync,
+            {
+                use $dialect::{MavMessage, MavMissionResult};
+
+                let mut req = seq;
+                let mut attempt = 0;
+                loop {
+                    let Some(item) = req
+                        .checked_sub(first)
+                        .and_then(|index| self.get(index as usize))
+                        .cloned()
+                    else {
+                        return Err(Error::new(ErrorKind::InvalidResponse(format!(
+                            "Item {req} requested, outside of {first}..{}",
+                            first as usize + self.len()
+                        )))
+                        .seq(req as u32));
+                    };
+
+                    let res = connection
+                        .clone()
+                        .send_wait(
+                            &MavMessage::MISSION_ITEM_INT(item),
+                            options.mission_item_timeout,
+                            move |msg| match msg {
+                                MavMessage::MISSION_REQUEST_INT(req) => {
+                                    FilterRes::Ready(Some(Ok(Some(req.seq))))
+                                }
+                                MavMessage::MISSION_ACK(ack) => match ack.mavtype {
+                                    MavMissionResult::MAV_MISSION_ACCEPTED => {
+                                        FilterRes::Ready(Some(Ok(None)))
+                                    }
+                                    result => FilterRes::Ready(Some(Err(result))),
+                                },
+                                _ => FilterRes::NotReady,
+                            },
+                        )
+                        .await
+                        .and_then(|res| match res {
+                            Some(Ok(seq)) => Ok(seq),
+                            Some(Err(result)) => {
+                                Err(Error::new(ErrorKind::Rejected(format!("{result:?}"))))
+                            }
+                            None => Err(Error::new(ErrorKind::InvalidResponse(
+                                "Unexpected answer".to_string(),
+                            ))),
+                        });
+
+                    match res {
+                        Ok(Some(seq)) => {
+                            req = seq;
+                            attempt = 0;
+                        }
+                        Ok(None) => return Ok(()),
+                        // The vehicle asks for the item it misses again.
+                        Err(e) if e.is_timeout() && attempt < options.mission_item_retries => {
+                            tracing::event!(
+                                tracing::Level::DEBUG,
+                                req,
+                                attempt,
+                                "Sending item again"
+                            );
+                            attempt += 1;
+                        }
+                        Err(e) => return Err(e.seq(req as u32)),
                     }
-                } else {
-                    FilterRes::NotReady
                 }
+            }
+        }
+
+        #[async_trait::async_trait]
+        impl MissionUpload for Vec<$dialect::MISSION_ITEM_INT_DATA> {
+            type Message = $dialect::MavMessage;
+
+            #[instrument]
+            async fn upload_mission<C>(
+                self,
+                connection: Arc<C>,
+                options: Options,
+            ) -> Result<u16, Error>
+            where
+                C: MavlinkConnection<Self::Message> + Debug + Send + Sync,
+            {
+                use $dialect::{MavMessage, MISSION_COUNT_DATA};
+
+                let count = u16::try_from(self.len()).map_err(|_| {
+                    Error::new(ErrorKind::TooManyItems(self.len() as u32))
+                        .operation("mission upload")
+                })?;
+
+                let count_request = MavMessage::MISSION_COUNT(MISSION_COUNT_DATA {
+                    count,
+                    target_system: connection.target_system(),
+                    target_component: connection.target_component(),
+                    ..Default::default()
+                });
+
+                let mut attempt = 0;
+                let req = loop {
+                    let res = connection
+                        .clone()
+                        .send_wait(&count_request, options.mission_count_timeout, |msg| {
+                            if let MavMessage::MISSION_REQUEST_INT(req) = msg {
+                                if req.seq == 0 {
+                                    FilterRes::Ready(Some(req.seq))
+                                } else {
+                                    FilterRes::Ready(None)
+                                }
+                            } else {
+                                FilterRes::NotReady
+                            }
+                        })
+                        .await;
+                    match res {
+                        Err(e) if e.is_timeout() && attempt < options.mission_item_retries => {
+                            tracing::event!(tracing::Level::DEBUG, attempt, "Sending count again");
+                            attempt += 1;
+                        }
+                        res => {
+                            break res
+                                .and_then(|res| {
+                                    res.ok_or_else(|| {
+                                        Error::new(ErrorKind::InvalidResponse(
+                                            "Upload did not start at item 0".to_string(),
+                                        ))
+                                    })
+                                })
+                                .map_err(|e| e.operation("mission upload"))?
+                        }
+                    }
+                };
+
+                self.send_items(connection, 0, req, &options)
+                    .await
+                    .map_err(|e| e.operation("mission upload"))?;
+                Ok(count)
+            }
+
+            #[instrument]
+            async fn resume_mission_upload<C>(
+                self,
+                connection: Arc<C>,
+                seq: u16,
+                options: Options,
+            ) -> Result<u16, Error>
+            where
+                C: MavlinkConnection<Self::Message> + Debug + Send + Sync,
+            {
+                let count = u16::try_from(self.len()).map_err(|_| {
+                    Error::new(ErrorKind::TooManyItems(self.len() as u32))
+                        .operation("mission upload")
+                })?;
+                if seq >= count {
+                    return Err(Error::new(ErrorKind::InvalidResponse(format!(
+                        "Cannot resume at item {seq} of {count}"
+                    )))
+                    .seq(seq as u32)
+                    .operation("mission upload"));
+                }
+
+                // The vehicle still waits for `seq`, sending it is enough to carry on.
+                self.send_items(connection, 0, seq, &options)
+                    .await
+                    .map_err(|e| e.operation("mission upload"))?;
+                Ok(count)
+            }
+
+            #[instrument]
+            async fn update_mission<C>(
+                self,
+                connection: Arc<C>,
+                start: u16,
+                options: Options,
+            ) -> Result<u16, Error>
+            where
+                C: MavlinkConnection<Self::Message> + Debug + Send + Sync,
+            {
+                use $dialect::{MavMessage, MavMissionResult, MISSION_WRITE_PARTIAL_LIST_DATA};
+
+                // Indexes are i16 on the wire.
+                let range = u16::try_from(self.len())
+                    .ok()
+                    .filter(|count| *count > 0)
+                    .and_then(|count| {
+                        let end = start.checked_add(count - 1)?;
+                        Some((count, i16::try_from(start).ok()?, i16::try_from(end).ok()?))
+                    });
+                let Some((count, start_index, end_index)) = range else {
+                    return Err(Error::new(ErrorKind::TooManyItems(self.len() as u32))
+                        .operation("mission update"));
+                };
+
+                let write_request =
+                    MavMessage::MISSION_WRITE_PARTIAL_LIST(MISSION_WRITE_PARTIAL_LIST_DATA {
+                        start_index,
+                        end_index,
+                        target_system: connection.target_system(),
+                        target_component: connection.target_component(),
+                        ..Default::default()
+                    });
+
+                let req =
+                    connection
+                        .clone()
+                        .send_wait(&write_request, options.mission_count_timeout, move |msg| {
+                            match msg {
+                                MavMessage::MISSION_REQUEST_INT(req) if req.seq == start => {
+                                    FilterRes::Ready(Some(Ok(req.seq)))
+                                }
+                                MavMessage::MISSION_REQUEST_INT(req) => FilterRes::Ready(Some(
+                                    Err(ErrorKind::InvalidResponse(format!(
+                                        "Update did not start at item {start}, but {}",
+                                        req.seq
+                                    ))),
+                                )),
+                                MavMessage::MISSION_ACK(ack)
+                                    if ack.mavtype != MavMissionResult::MAV_MISSION_ACCEPTED =>
+                                {
+                                    FilterRes::Ready(Some(Err(ErrorKind::Rejected(format!(
+                                        "{:?}",
+                                        ack.mavtype
+                                    )))))
+                                }
+                                _ => FilterRes::NotReady,
+                            }
+                        })
+                        .await
+                        .and_then(|res| match res {
+                            Some(Ok(seq)) => Ok(seq),
+                            Some(Err(kind)) => Err(Error::new(kind)),
+                            None => Err(Error::new(ErrorKind::InvalidResponse(
+                                "Unexpected answer".to_string(),
+                            ))),
+                        })
+                        .map_err(|e| e.seq(start as u32).operation("mission update"))?;
+
+                self.send_items(connection, start, req, &options)
+                    .await
+                    .map_err(|e| e.operation("mission update"))?;
+                Ok(count)
+            }
+        }
+    };
+}
+
+impl_mission_upload!(ardupilotmega);
+impl_mission_upload!(common);
+
+#[cfg(test)]
+mod test {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    use mavlink::ardupilotmega::{
+        MavCmd, MavMessage, MISSION_ITEM_INT_DATA, MISSION_REQUEST_INT_DATA,
+    };
+
+    use super::{MissionUpload, Options};
+    use crate::{connection::test::sent, error::ErrorKind, sim::SimVehicle};
+
+    fn mission(count: u16, alt: f32) -> Vec<MISSION_ITEM_INT_DATA> {
+        (0..count)
+            .map(|seq| MISSION_ITEM_INT_DATA {
+                seq,
+                command: MavCmd::MAV_CMD_NAV_WAYPOINT,
+                z: alt,
+                ..Default::default()
             })
+            .collect()
+    }
+
+    // Drops the first MISSION_ITEM_INT numbered `seq`, as a lossy link would.
+    fn lose_item(connection: &SimVehicle, seq: u16) {
+        let lost = AtomicBool::new(false);
+        connection.set_handler(Box::new(move |msg| match msg {
+            MavMessage::MISSION_ITEM_INT(item)
+                if item.seq == seq && !lost.swap(true, Ordering::SeqCst) =>
+            {
+                Some(vec![])
+            }
+            _ => None,
+        }));
+    }
+
+    #[tokio::test]
+    async fn sends_lost_items_again() {
+        let connection: Arc<Box<SimVehicle>> = Default::default();
+        lose_item(&connection, 1);
+
+        let count = mission(3, 50.0)
+            .upload_mission(connection.clone(), Default::default())
             .await
-            .map_err(MissionUploadError::ConnectionError)?
-            .ok_or_else(|| MissionUploadError::Other("Invalid Response".to_string()))?;
-
-        while let Some(item) = self.get(req as usize).cloned() {
-            req = connection
-                .clone()
-                .send_wait(
-                    &MavMessage::MISSION_ITEM_INT(item),
-                    options.mission_item_timeout,
-                    move |msg| match msg {
-                        MavMessage::MISSION_REQUEST_INT(req) => FilterRes::Ready(Some(req.seq)),
-                        MavMessage::MISSION_ACK(ack) => match ack.mavtype {
-                            MavMissionResult::MAV_MISSION_ACCEPTED => FilterRes::Ready(Some(count)),
-                            _ => FilterRes::Ready(None),
-                        },
-                        _ => FilterRes::NotReady,
-                    },
-                )
-                .await
-                .map_err(MissionUploadError::ConnectionError)?
-                .ok_or_else(|| MissionUploadError::Other("Invalid Response".to_string()))?;
+            .unwrap();
 
-            if req == count {
-                break;
+        assert_eq!(count, 3);
+        assert_eq!(connection.mission(), mission(3, 50.0));
+        assert_eq!(sent(&connection, "MISSION_ITEM_INT"), 4);
+    }
+
+    #[tokio::test]
+    async fn rejects_request_out_of_range() {
+        let connection: Arc<Box<SimVehicle>> = Default::default();
+        // Asks for one item past the end instead of acknowledging the upload.
+        connection.set_handler(Box::new(|msg| match msg {
+            MavMessage::MISSION_ITEM_INT(item) if item.seq == 2 => {
+                Some(vec![MavMessage::MISSION_REQUEST_INT(
+                    MISSION_REQUEST_INT_DATA {
+                        seq: 3,
+                        ..Default::default()
+                    },
+                )])
             }
-        }
+            _ => None,
+        }));
+
+        let e = mission(3, 50.0)
+            .upload_mission(connection.clone(), Default::default())
+            .await
+            .unwrap_err();
+
+        assert!(matches!(e.kind, ErrorKind::InvalidResponse(_)), "{e}");
+        assert_eq!(e.context.seq, Some(3));
+    }
+
+    #[tokio::test]
+    async fn resumes_upload() {
+        let connection: Arc<Box<SimVehicle>> = Default::default();
+        lose_item(&connection, 2);
+        let options = || Options {
+            mission_item_timeout: std::time::Duration::from_millis(200),
+            mission_item_retries: 0,
+            ..Default::default()
+        };
+
+        let e = mission(4, 50.0)
+            .upload_mission(connection.clone(), options())
+            .await
+            .unwrap_err();
+        assert!(e.is_timeout(), "{e}");
+        assert_eq!(e.context.seq, Some(2));
+
+        let seq = e.context.seq.unwrap() as u16;
+        let count = mission(4, 50.0)
+            .resume_mission_upload(connection.clone(), seq, options())
+            .await
+            .unwrap();
 
-        Ok(count)
+        assert_eq!(count, 4);
+        assert_eq!(connection.mission(), mission(4, 50.0));
+        assert_eq!(sent(&connection, "MISSION_COUNT"), 1);
+    }
+
+    #[tokio::test]
+    async fn updates_range() {
+        let connection: Arc<Box<SimVehicle>> = Default::default();
+        connection.set_mission(mission(6, 50.0));
+
+        let update: Vec<_> = mission(6, 80.0)[2..4].to_vec();
+        let count = update
+            .clone()
+            .update_mission(connection.clone(), 2, Default::default())
+            .await
+            .unwrap();
+
+        assert_eq!(count, 2);
+        let expected: Vec<_> = mission(6, 50.0)
+            .into_iter()
+            .map(|item| match item.seq {
+                2 | 3 => MISSION_ITEM_INT_DATA { z: 80.0, ..item },
+                _ => item,
+            })
+            .collect();
+        assert_eq!(connection.mission(), expected);
+        assert_eq!(sent(&connection, "MISSION_COUNT"), 0);
+        assert_eq!(sent(&connection, "MISSION_ITEM_INT"), 2);
+
+        // Partial updates cannot extend the mission.
+        let e = update
+            .update_mission(connection.clone(), 5, Default::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(e.kind, ErrorKind::Rejected(_)), "{e}");
+        assert_eq!(connection.mission(), expected);
     }
 }
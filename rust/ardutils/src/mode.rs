@@ -1,45 +1,296 @@
 use std::sync::Arc;
 
-use mavlink::ardupilotmega;
+use mavlink::{ardupilotmega, common, Message};
+use num_traits::FromPrimitive;
 
-use crate::connection::{FilterRes, MavlinkConnection, MavlinkConnectionError};
+use crate::{
+    connection::{FilterRes, MavlinkConnection},
+    error::{Error, ErrorKind},
+};
+
+// How long `change_mode` waits for the vehicle to report the new mode.
+const MODE_CHANGE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
 
 #[async_trait::async_trait]
 pub trait ChangeMode {
-    async fn change_mode<C>(self, connection: Arc<C>) -> Result<(), MavlinkConnectionError>
+    type Message: Message + Send + Sync + 'static;
+
+    async fn change_mode<C>(self, connection: Arc<C>) -> Result<(), Error>
+    where
+        Self: Sized + Send,
+        C: MavlinkConnection<Self::Message> + Send + Sync,
+    {
+        self.change_mode_timeout(connection, MODE_CHANGE_TIMEOUT)
+            .await
+    }
+
+    // Done once a HEARTBEAT reports the mode, within `timeout`. A COMMAND_ACK refusing the
+    // change fails it with `ErrorKind::Rejected` right away.
+    async fn change_mode_timeout<C>(
+        self,
+        connection: Arc<C>,
+        timeout: std::time::Duration,
+    ) -> Result<(), Error>
     where
-        C: MavlinkConnection + Send + Sync;
+        C: MavlinkConnection<Self::Message> + Send + Sync;
+
+    // The mode reported by `msg`, when it is a HEARTBEAT from the autopilot.
+    fn from_heartbeat(msg: &Self::Message) -> Option<Self>
+    where
+        Self: Sized;
+}
+
+// What mode changes need to know of the messages of a dialect.
+trait ModeMessage: Message + Send + Sync + 'static {
+    // The custom_mode of a HEARTBEAT, when it comes from an autopilot. Ground stations, cameras,
+    // gimbals and companion computers send heartbeats too.
+    fn autopilot_mode(&self) -> Option<u32>;
+
+    // The result refusing a mode change, when this is its COMMAND_ACK.
+    fn mode_refusal(&self) -> Option<String>;
+}
+
+macro_rules! impl_mode_message {
+    ($dialect:ident) => {
+        impl ModeMessage for $dialect::MavMessage {
+            fn autopilot_mode(&self) -> Option<u32> {
+                match self {
+                    $dialect::MavMessage::HEARTBEAT(beat)
+                        if beat.mavtype != $dialect::MavType::MAV_TYPE_GCS
+                            && beat.autopilot != $dialect::MavAutopilot::MAV_AUTOPILOT_INVALID =>
+                    {
+                        Some(beat.custom_mode)
+                    }
+                    _ => None,
+                }
+            }
+
+            fn mode_refusal(&self) -> Option<String> {
+                match self {
+                    $dialect::MavMessage::COMMAND_ACK(ack)
+                        if ack.command == $dialect::MavCmd::MAV_CMD_DO_SET_MODE
+                            && !matches!(
+                                ack.result,
+                                $dialect::MavResult::MAV_RESULT_ACCEPTED
+                                    | $dialect::MavResult::MAV_RESULT_IN_PROGRESS
+                            ) =>
+                    {
+                        Some(format!("{:?}", ack.result))
+                    }
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+impl_mode_message!(ardupilotmega);
+impl_mode_message!(common);
+
+// Sends `command`, done once the autopilot reports `custom_mode`.
+async fn change_mode<M, C>(
+    connection: Arc<C>,
+    command: M,
+    custom_mode: u32,
+    timeout: std::time::Duration,
+) -> Result<(), Error>
+where
+    M: ModeMessage,
+    C: MavlinkConnection<M> + Send + Sync,
+{
+    let answer = connection
+        .send_wait(&command, timeout, move |msg| {
+            if msg.autopilot_mode() == Some(custom_mode) {
+                return FilterRes::Ready(Some(Ok(())));
+            }
+            match msg.mode_refusal() {
+                Some(result) => FilterRes::Ready(Some(Err(result))),
+                None => FilterRes::NotReady,
+            }
+        })
+        .await;
+
+    match answer {
+        Ok(Some(Err(result))) => Err(Error::new(ErrorKind::Rejected(result))),
+        Ok(_) => Ok(()),
+        Err(e) => Err(e),
+    }
+    .map_err(|e| e.operation("mode change"))
 }
 
 #[async_trait::async_trait]
 impl ChangeMode for ardupilotmega::PlaneMode {
-    async fn change_mode<C>(self, connection: Arc<C>) -> Result<(), MavlinkConnectionError>
+    type Message = ardupilotmega::MavMessage;
+
+    async fn change_mode_timeout<C>(
+        self,
+        connection: Arc<C>,
+        timeout: std::time::Duration,
+    ) -> Result<(), Error>
     where
-        C: MavlinkConnection + Send + Sync,
+        C: MavlinkConnection<Self::Message> + Send + Sync,
     {
-        connection
-            .clone()
-            .send_wait(
-                &ardupilotmega::MavMessage::COMMAND_LONG(ardupilotmega::COMMAND_LONG_DATA {
-                    target_system: connection.target_system(),
-                    target_component: connection.target_component(),
-                    command: ardupilotmega::MavCmd::MAV_CMD_DO_SET_MODE,
-                    param1: ardupilotmega::MavModeFlag::MAV_MODE_FLAG_CUSTOM_MODE_ENABLED.bits()
-                        as f32,
-                    param2: self as i32 as f32,
-                    ..Default::default()
-                }),
-                std::time::Duration::from_secs(1),
-                move |msg| {
-                    if let ardupilotmega::MavMessage::HEARTBEAT(beat) = msg {
-                        if beat.custom_mode == self as i32 as u32 {
-                            return FilterRes::Ready(Some(()));
-                        }
-                    }
-                    FilterRes::NotReady
-                },
-            )
-            .await
-            .map(|_| ())
+        let command = ardupilotmega::MavMessage::COMMAND_LONG(ardupilotmega::COMMAND_LONG_DATA {
+            target_system: connection.target_system(),
+            target_component: connection.target_component(),
+            command: ardupilotmega::MavCmd::MAV_CMD_DO_SET_MODE,
+            param1: ardupilotmega::MavModeFlag::MAV_MODE_FLAG_CUSTOM_MODE_ENABLED.bits() as f32,
+            param2: self as i32 as f32,
+            ..Default::default()
+        });
+        change_mode(connection, command, self as u32, timeout).await
+    }
+
+    fn from_heartbeat(msg: &Self::Message) -> Option<Self> {
+        msg.autopilot_mode().and_then(Self::from_u32)
+    }
+}
+
+// PX4 flight modes, as a main mode and, for the AUTO modes, a sub mode.
+// See px4_custom_mode.h in PX4-Autopilot.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize)]
+pub enum Px4Mode {
+    Manual,
+    Altitude,
+    Position,
+    Acro,
+    Offboard,
+    Stabilized,
+    Takeoff,
+    // AUTO_LOITER, shown as Hold by QGroundControl.
+    Hold,
+    Mission,
+    ReturnToLaunch,
+    Land,
+}
+
+impl Px4Mode {
+    const ALL: [Self; 11] = [
+        Self::Manual,
+        Self::Altitude,
+        Self::Position,
+        Self::Acro,
+        Self::Offboard,
+        Self::Stabilized,
+        Self::Takeoff,
+        Self::Hold,
+        Self::Mission,
+        Self::ReturnToLaunch,
+        Self::Land,
+    ];
+
+    pub fn main_and_sub_mode(self) -> (u8, u8) {
+        match self {
+            Self::Manual => (1, 0),
+            Self::Altitude => (2, 0),
+            Self::Position => (3, 0),
+            Self::Acro => (5, 0),
+            Self::Offboard => (6, 0),
+            Self::Stabilized => (7, 0),
+            Self::Takeoff => (4, 2),
+            Self::Hold => (4, 3),
+            Self::Mission => (4, 4),
+            Self::ReturnToLaunch => (4, 5),
+            Self::Land => (4, 6),
+        }
+    }
+
+    // The HEARTBEAT custom_mode of this mode: main mode in the third byte, sub mode in the fourth.
+    pub fn custom_mode(self) -> u32 {
+        let (main, sub) = self.main_and_sub_mode();
+        ((main as u32) << 16) | ((sub as u32) << 24)
+    }
+}
+
+#[async_trait::async_trait]
+impl ChangeMode for Px4Mode {
+    type Message = common::MavMessage;
+
+    async fn change_mode_timeout<C>(
+        self,
+        connection: Arc<C>,
+        timeout: std::time::Duration,
+    ) -> Result<(), Error>
+    where
+        C: MavlinkConnection<Self::Message> + Send + Sync,
+    {
+        let (main, sub) = self.main_and_sub_mode();
+        let command = common::MavMessage::COMMAND_LONG(common::COMMAND_LONG_DATA {
+            target_system: connection.target_system(),
+            target_component: connection.target_component(),
+            command: common::MavCmd::MAV_CMD_DO_SET_MODE,
+            param1: common::MavModeFlag::MAV_MODE_FLAG_CUSTOM_MODE_ENABLED.bits() as f32,
+            param2: main as f32,
+            param3: sub as f32,
+            ..Default::default()
+        });
+        change_mode(connection, command, self.custom_mode(), timeout).await
+    }
+
+    fn from_heartbeat(msg: &Self::Message) -> Option<Self> {
+        let custom_mode = msg.autopilot_mode()?;
+        Self::ALL
+            .into_iter()
+            .find(|mode| mode.custom_mode() == custom_mode)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use mavlink::common::{MavCmd, MavMessage, HEARTBEAT_DATA};
+
+    use super::{ChangeMode, Px4Mode};
+    use crate::connection::test::TestMavConnection;
+
+    #[test]
+    fn px4_custom_modes() {
+        assert_eq!(Px4Mode::Position.custom_mode(), 0x0003_0000);
+        assert_eq!(Px4Mode::Mission.custom_mode(), 0x0404_0000);
+
+        let heartbeat = MavMessage::HEARTBEAT(HEARTBEAT_DATA {
+            custom_mode: 0x0404_0000,
+            ..Default::default()
+        });
+        assert_eq!(Px4Mode::from_heartbeat(&heartbeat), Some(Px4Mode::Mission));
+    }
+
+    #[tokio::test]
+    async fn changes_px4_mode() {
+        let connection: Arc<Box<TestMavConnection<MavMessage>>> = Default::default();
+        connection.inject_msg(MavMessage::HEARTBEAT(HEARTBEAT_DATA {
+            custom_mode: Px4Mode::Hold.custom_mode(),
+            ..Default::default()
+        }));
+
+        Px4Mode::Hold.change_mode(connection.clone()).await.unwrap();
+
+        let Some(MavMessage::COMMAND_LONG(data)) = connection.last_sent() else {
+            panic!("COMMAND_LONG not sent");
+        };
+        assert_eq!(data.command, MavCmd::MAV_CMD_DO_SET_MODE);
+        assert_eq!((data.param2, data.param3), (4.0, 3.0));
+    }
+
+    #[tokio::test]
+    async fn ignores_ground_station_heartbeats() {
+        use mavlink::ardupilotmega;
+
+        // Its custom_mode 0 is MANUAL on ArduPlane.
+        let connection: Arc<Box<TestMavConnection>> = Default::default();
+        connection.inject_msg(ardupilotmega::MavMessage::HEARTBEAT(
+            ardupilotmega::HEARTBEAT_DATA {
+                mavtype: ardupilotmega::MavType::MAV_TYPE_GCS,
+                autopilot: ardupilotmega::MavAutopilot::MAV_AUTOPILOT_INVALID,
+                ..Default::default()
+            },
+        ));
+
+        let res = ardupilotmega::PlaneMode::PLANE_MODE_MANUAL
+            .change_mode_timeout(connection.clone(), std::time::Duration::from_millis(100))
+            .await;
+
+        assert!(res.is_err_and(|e| e.is_timeout()));
     }
 }
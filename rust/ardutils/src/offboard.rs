@@ -0,0 +1,388 @@
+use std::{fmt::Debug, sync::Arc};
+
+use mavlink::ardupilotmega::{
+    MavAutopilot, MavFrame, MavMessage, MavType, PlaneMode, PositionTargetTypemask,
+    SET_POSITION_TARGET_GLOBAL_INT_DATA, SET_POSITION_TARGET_LOCAL_NED_DATA,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::connection::{MavlinkConnection, MonitorHandle};
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Options {
+    // Period between two consecutive setpoints.
+    pub period: std::time::Duration,
+    // custom_mode the vehicle reports while in GUIDED, this differs per vehicle type.
+    pub guided_mode: u32,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            period: std::time::Duration::from_millis(100),
+            guided_mode: PlaneMode::PLANE_MODE_GUIDED as u32,
+        }
+    }
+}
+
+// Builds a type_mask by starting with every field ignored, and enabling
+// only the fields that should be controlled.
+#[derive(Debug, Clone, Copy)]
+pub struct TypeMask(PositionTargetTypemask);
+
+impl Default for TypeMask {
+    fn default() -> Self {
+        Self(
+            PositionTargetTypemask::all()
+                - PositionTargetTypemask::POSITION_TARGET_TYPEMASK_FORCE_SET,
+        )
+    }
+}
+
+impl TypeMask {
+    pub fn position(self) -> Self {
+        Self(
+            self.0
+                - (PositionTargetTypemask::POSITION_TARGET_TYPEMASK_X_IGNORE
+                    | PositionTargetTypemask::POSITION_TARGET_TYPEMASK_Y_IGNORE
+                    | PositionTargetTypemask::POSITION_TARGET_TYPEMASK_Z_IGNORE),
+        )
+    }
+
+    pub fn velocity(self) -> Self {
+        Self(
+            self.0
+                - (PositionTargetTypemask::POSITION_TARGET_TYPEMASK_VX_IGNORE
+                    | PositionTargetTypemask::POSITION_TARGET_TYPEMASK_VY_IGNORE
+                    | PositionTargetTypemask::POSITION_TARGET_TYPEMASK_VZ_IGNORE),
+        )
+    }
+
+    pub fn acceleration(self) -> Self {
+        Self(
+            self.0
+                - (PositionTargetTypemask::POSITION_TARGET_TYPEMASK_AX_IGNORE
+                    | PositionTargetTypemask::POSITION_TARGET_TYPEMASK_AY_IGNORE
+                    | PositionTargetTypemask::POSITION_TARGET_TYPEMASK_AZ_IGNORE),
+        )
+    }
+
+    pub fn yaw(self) -> Self {
+        Self(self.0 - PositionTargetTypemask::POSITION_TARGET_TYPEMASK_YAW_IGNORE)
+    }
+
+    pub fn yaw_rate(self) -> Self {
+        Self(self.0 - PositionTargetTypemask::POSITION_TARGET_TYPEMASK_YAW_RATE_IGNORE)
+    }
+
+    pub fn build(self) -> PositionTargetTypemask {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Setpoint {
+    Global(SET_POSITION_TARGET_GLOBAL_INT_DATA),
+    Local(SET_POSITION_TARGET_LOCAL_NED_DATA),
+}
+
+impl Setpoint {
+    // lat/lon in degE7, alt in meters relative to home.
+    pub fn global_position(lat_int: i32, lon_int: i32, alt: f32) -> Self {
+        Self::Global(SET_POSITION_TARGET_GLOBAL_INT_DATA {
+            lat_int,
+            lon_int,
+            alt,
+            type_mask: TypeMask::default().position().build(),
+            coordinate_frame: MavFrame::MAV_FRAME_GLOBAL_RELATIVE_ALT_INT,
+            ..Default::default()
+        })
+    }
+
+    // Velocities in m/s, in the NED frame.
+    pub fn global_velocity(vx: f32, vy: f32, vz: f32) -> Self {
+        Self::Global(SET_POSITION_TARGET_GLOBAL_INT_DATA {
+            vx,
+            vy,
+            vz,
+            type_mask: TypeMask::default().velocity().build(),
+            coordinate_frame: MavFrame::MAV_FRAME_GLOBAL_RELATIVE_ALT_INT,
+            ..Default::default()
+        })
+    }
+
+    // Offsets in meters from the EKF origin, in the NED frame.
+    pub fn local_position(x: f32, y: f32, z: f32) -> Self {
+        Self::Local(SET_POSITION_TARGET_LOCAL_NED_DATA {
+            x,
+            y,
+            z,
+            type_mask: TypeMask::default().position().build(),
+            coordinate_frame: MavFrame::MAV_FRAME_LOCAL_NED,
+            ..Default::default()
+        })
+    }
+
+    // Velocities in m/s, in the NED frame.
+    pub fn local_velocity(vx: f32, vy: f32, vz: f32) -> Self {
+        Self::Local(SET_POSITION_TARGET_LOCAL_NED_DATA {
+            vx,
+            vy,
+            vz,
+            type_mask: TypeMask::default().velocity().build(),
+            coordinate_frame: MavFrame::MAV_FRAME_LOCAL_NED,
+            ..Default::default()
+        })
+    }
+
+    // Yaw in radians.
+    pub fn with_yaw(self, yaw: f32) -> Self {
+        match self {
+            Self::Global(data) => Self::Global(SET_POSITION_TARGET_GLOBAL_INT_DATA {
+                yaw,
+                type_mask: TypeMask(data.type_mask).yaw().build(),
+                ..data
+            }),
+            Self::Local(data) => Self::Local(SET_POSITION_TARGET_LOCAL_NED_DATA {
+                yaw,
+                type_mask: TypeMask(data.type_mask).yaw().build(),
+                ..data
+            }),
+        }
+    }
+
+    // Yaw rate in radians/s.
+    pub fn with_yaw_rate(self, yaw_rate: f32) -> Self {
+        match self {
+            Self::Global(data) => Self::Global(SET_POSITION_TARGET_GLOBAL_INT_DATA {
+                yaw_rate,
+                type_mask: TypeMask(data.type_mask).yaw_rate().build(),
+                ..data
+            }),
+            Self::Local(data) => Self::Local(SET_POSITION_TARGET_LOCAL_NED_DATA {
+                yaw_rate,
+                type_mask: TypeMask(data.type_mask).yaw_rate().build(),
+                ..data
+            }),
+        }
+    }
+
+    fn message(&self, target_system: u8, target_component: u8, time_boot_ms: u32) -> MavMessage {
+        match self.clone() {
+            Self::Global(data) => {
+                MavMessage::SET_POSITION_TARGET_GLOBAL_INT(SET_POSITION_TARGET_GLOBAL_INT_DATA {
+                    target_system,
+                    target_component,
+                    time_boot_ms,
+                    ..data
+                })
+            }
+            Self::Local(data) => {
+                MavMessage::SET_POSITION_TARGET_LOCAL_NED(SET_POSITION_TARGET_LOCAL_NED_DATA {
+                    target_system,
+                    target_component,
+                    time_boot_ms,
+                    ..data
+                })
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamEnd {
+    // A HEARTBEAT reported a mode other than GUIDED.
+    LeftGuided,
+    // The setpoint sender was dropped.
+    SetpointsClosed,
+    // The handle was cancelled.
+    Cancelled,
+}
+
+// Streams the latest setpoint at a fixed rate until the vehicle leaves GUIDED, the setpoint
+// sender is dropped, or the handle is cancelled or dropped. Nothing is sent while the setpoint
+// is None. The vehicle is expected to already be in GUIDED (see `ChangeMode`).
+pub fn stream_setpoints<C>(
+    connection: Arc<C>,
+    options: Options,
+    mut setpoints: tokio::sync::watch::Receiver<Option<Setpoint>>,
+) -> MonitorHandle<StreamEnd>
+where
+    C: MavlinkConnection + Debug + Send + Sync + 'static,
+{
+    let cancel = CancellationToken::new();
+
+    let guided_mode = options.guided_mode;
+    // Moved into the task, and stopped with it.
+    let mut mode_monitor = connection.clone().monitor(None, move |msg| {
+        if let MavMessage::HEARTBEAT(beat) = msg {
+            let from_autopilot = !matches!(beat.mavtype, MavType::MAV_TYPE_GCS)
+                && !matches!(beat.autopilot, MavAutopilot::MAV_AUTOPILOT_INVALID);
+            if from_autopilot && beat.custom_mode != guided_mode {
+                return None;
+            }
+        }
+        Some(())
+    });
+
+    let task = tokio::spawn({
+        let cancel = cancel.clone();
+        async move {
+            let start = std::time::Instant::now();
+            let mut interval = tokio::time::interval(options.period);
+
+            let end = loop {
+                tokio::select! {
+                    _ = interval.tick() => {
+                        let setpoint = setpoints.borrow_and_update().clone();
+                        if let Some(setpoint) = setpoint {
+                            let msg = setpoint.message(
+                                connection.target_system(),
+                                connection.target_component(),
+                                start.elapsed().as_millis() as u32,
+                            );
+                            connection.send(&msg)?;
+                        }
+                    }
+                    _ = cancel.cancelled() => break StreamEnd::Cancelled,
+                    // The mode monitor only finishes by itself once it has seen the vehicle
+                    // leave GUIDED, otherwise it failed, e.g. the connection was closed.
+                    res = &mut mode_monitor => match res {
+                        Ok(()) => break StreamEnd::LeftGuided,
+                        Err(e) => return Err(e.operation("setpoints")),
+                    },
+                    res = setpoints.changed() => {
+                        if res.is_err() {
+                            break StreamEnd::SetpointsClosed;
+                        }
+                    }
+                }
+            };
+
+            tracing::event!(tracing::Level::DEBUG, ?end, "Setpoint stream stopped");
+
+            Ok(end)
+        }
+    });
+
+    MonitorHandle::new(task, cancel)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use mavlink::ardupilotmega::{
+        MavMessage, PlaneMode, PositionTargetTypemask, HEARTBEAT_DATA,
+        SET_POSITION_TARGET_LOCAL_NED_DATA,
+    };
+
+    use super::{stream_setpoints, Options, Setpoint, StreamEnd, TypeMask};
+    use crate::{
+        connection::{test::*, SharedConnection},
+        error::{Error, ErrorKind},
+    };
+
+    #[test]
+    fn type_mask() {
+        let mask = TypeMask::default().velocity().yaw_rate().build();
+
+        assert!(!mask.contains(PositionTargetTypemask::POSITION_TARGET_TYPEMASK_VX_IGNORE));
+        assert!(!mask.contains(PositionTargetTypemask::POSITION_TARGET_TYPEMASK_YAW_RATE_IGNORE));
+        assert!(mask.contains(PositionTargetTypemask::POSITION_TARGET_TYPEMASK_X_IGNORE));
+        assert!(mask.contains(PositionTargetTypemask::POSITION_TARGET_TYPEMASK_YAW_IGNORE));
+        assert!(!mask.contains(PositionTargetTypemask::POSITION_TARGET_TYPEMASK_FORCE_SET));
+    }
+
+    #[tokio::test]
+    async fn streams_until_guided_left() {
+        let connection: Arc<Box<TestMavConnection>> = Default::default();
+
+        connection.inject_msg(MavMessage::HEARTBEAT(HEARTBEAT_DATA {
+            custom_mode: PlaneMode::PLANE_MODE_GUIDED as u32,
+            ..Default::default()
+        }));
+
+        let (tx, rx) = tokio::sync::watch::channel(Some(
+            Setpoint::local_velocity(1.0, 0.0, 0.0).with_yaw_rate(0.5),
+        ));
+
+        let handle = stream_setpoints(
+            connection.clone(),
+            Options {
+                period: std::time::Duration::from_millis(10),
+                ..Default::default()
+            },
+            rx,
+        );
+
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        assert!(matches!(
+            connection.last_sent().unwrap(),
+            MavMessage::SET_POSITION_TARGET_LOCAL_NED(SET_POSITION_TARGET_LOCAL_NED_DATA {
+                vx,
+                yaw_rate,
+                ..
+            }) if vx == 1.0 && yaw_rate == 0.5
+        ));
+
+        connection.inject_msg(MavMessage::HEARTBEAT(HEARTBEAT_DATA {
+            custom_mode: PlaneMode::PLANE_MODE_RTL as u32,
+            ..Default::default()
+        }));
+
+        let res = tokio::time::timeout(std::time::Duration::from_secs(1), handle)
+            .await
+            .unwrap();
+
+        assert!(matches!(res, Ok(StreamEnd::LeftGuided)));
+        drop(tx);
+    }
+
+    #[tokio::test]
+    async fn stops_when_setpoints_closed() {
+        let connection: Arc<Box<TestMavConnection>> = Default::default();
+
+        connection.inject_msg(MavMessage::HEARTBEAT(HEARTBEAT_DATA {
+            custom_mode: PlaneMode::PLANE_MODE_GUIDED as u32,
+            ..Default::default()
+        }));
+
+        let (tx, rx) = tokio::sync::watch::channel(None);
+
+        let handle = stream_setpoints(connection.clone(), Default::default(), rx);
+
+        drop(tx);
+
+        let res = tokio::time::timeout(std::time::Duration::from_secs(1), handle)
+            .await
+            .unwrap();
+
+        assert!(matches!(res, Ok(StreamEnd::SetpointsClosed)));
+        assert!(connection.last_sent().is_none());
+    }
+
+    #[tokio::test]
+    async fn fails_with_mode_monitor() {
+        // Nobody on the bus, the mode monitor fails at once.
+        let connection = Arc::new(SharedConnection::new(Arc::new(Bus::new(vec![]))).unwrap());
+        let (_tx, rx) = tokio::sync::watch::channel(None);
+
+        let handle = stream_setpoints(connection, Default::default(), rx);
+
+        let res = tokio::time::timeout(std::time::Duration::from_secs(1), handle)
+            .await
+            .unwrap();
+        assert!(
+            matches!(
+                res,
+                Err(Error {
+                    kind: ErrorKind::Aborted(_),
+                    ..
+                })
+            ),
+            "{res:?}"
+        );
+    }
+}
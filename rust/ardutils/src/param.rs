@@ -0,0 +1,355 @@
+use std::{collections::BTreeMap, fmt::Debug, sync::Arc};
+
+use mavlink::ardupilotmega::{
+    MavMessage, MavParamType, PARAM_REQUEST_LIST_DATA, PARAM_REQUEST_READ_DATA, PARAM_SET_DATA,
+    PARAM_VALUE_DATA,
+};
+
+use crate::{
+    connection::{send_wait_retry, FilterRes, MavlinkConnection},
+    error::{Error, ErrorKind},
+};
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Options {
+    // How long to wait for each response before requesting again.
+    pub timeout: std::time::Duration,
+    pub retries: u8,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            timeout: std::time::Duration::from_millis(500),
+            retries: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Param {
+    pub id: String,
+    pub value: f32,
+    pub param_type: MavParamType,
+    pub index: u16,
+}
+
+impl From<PARAM_VALUE_DATA> for Param {
+    fn from(data: PARAM_VALUE_DATA) -> Self {
+        Self {
+            id: param_name(&data.param_id),
+            value: data.param_value,
+            param_type: data.param_type,
+            index: data.param_index,
+        }
+    }
+}
+
+// Parameter values by name, as stored in parameter files.
+pub type Params = BTreeMap<String, f32>;
+
+pub(crate) fn param_id(id: &str) -> [u8; 16] {
+    let mut bytes = [0; 16];
+    let len = id.len().min(16);
+    bytes[..len].copy_from_slice(&id.as_bytes()[..len]);
+    bytes
+}
+
+// Ids are NUL terminated, unless they are exactly 16 characters long.
+pub(crate) fn param_name(bytes: &[u8; 16]) -> String {
+    let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..len]).into_owned()
+}
+
+// Sends `msg` until a PARAM_VALUE for `id` comes back.
+async fn request<C>(
+    connection: Arc<C>,
+    msg: MavMessage,
+    id: &str,
+    options: &Options,
+) -> Result<Param, Error>
+where
+    C: MavlinkConnection + Debug + Send + Sync + 'static,
+{
+    let expected = param_id(id);
+    let filter = move |msg| match msg {
+        MavMessage::PARAM_VALUE(data) if data.param_id == expected => {
+            FilterRes::Ready(Some(Param::from(data)))
+        }
+        _ => FilterRes::NotReady,
+    };
+    match send_wait_retry(&connection, &msg, options.timeout, options.retries, filter).await {
+        Ok(Some(param)) => Ok(param),
+        // Unknown parameters are never answered.
+        Err(e) if e.is_timeout() => Err(Error {
+            kind: ErrorKind::Other(format!("No answer for {id}")),
+            context: e.context,
+        }
+        .operation("param")),
+        Err(e) => Err(e.operation("param")),
+        Ok(None) => Err(
+            Error::new(ErrorKind::InvalidResponse("Empty answer".to_string())).operation("param"),
+        ),
+    }
+}
+
+// Unknown parameters are never answered, and end in a timeout.
+pub async fn get_param<C>(connection: Arc<C>, id: &str, options: Options) -> Result<Param, Error>
+where
+    C: MavlinkConnection + Debug + Send + Sync + 'static,
+{
+    let msg = MavMessage::PARAM_REQUEST_READ(PARAM_REQUEST_READ_DATA {
+        param_index: -1,
+        target_system: connection.target_system(),
+        target_component: connection.target_component(),
+        param_id: param_id(id),
+    });
+    request(connection, msg, id, &options).await
+}
+
+// Returns the parameter as stored by the vehicle, which may have rounded or clamped the value.
+// The parameter is read first, as the vehicle expects PARAM_SET to carry its actual type.
+pub async fn set_param<C>(
+    connection: Arc<C>,
+    id: &str,
+    value: f32,
+    options: Options,
+) -> Result<Param, Error>
+where
+    C: MavlinkConnection + Debug + Send + Sync + 'static,
+{
+    let current = get_param(connection.clone(), id, options.clone()).await?;
+
+    let msg = MavMessage::PARAM_SET(PARAM_SET_DATA {
+        param_value: value,
+        target_system: connection.target_system(),
+        target_component: connection.target_component(),
+        param_id: param_id(id),
+        param_type: current.param_type,
+    });
+    request(connection, msg, id, &options).await
+}
+
+// Downloads every parameter, ordered by index. Parameters lost on the way are read again
+// one by one once the link goes quiet.
+pub async fn list_params<C>(connection: Arc<C>, options: Options) -> Result<Vec<Param>, Error>
+where
+    C: MavlinkConnection + Debug + Send + Sync + 'static,
+{
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let monitor = connection.clone().monitor(None, move |msg| {
+        if let MavMessage::PARAM_VALUE(data) = msg {
+            let _ = tx.send(data);
+        }
+        Some(())
+    });
+
+    let start = std::time::Instant::now();
+    let res = async {
+        let mut params: Vec<Option<Param>> = vec![];
+        let mut attempts = 0;
+
+        connection
+            .send(&MavMessage::PARAM_REQUEST_LIST(PARAM_REQUEST_LIST_DATA {
+                target_system: connection.target_system(),
+                target_component: connection.target_component(),
+            }))
+            .map_err(|e| e.operation("param list"))?;
+
+        loop {
+            let mut progressed = false;
+            while let Ok(Some(data)) = tokio::time::timeout(options.timeout, rx.recv()).await {
+                if params.len() != data.param_count as usize {
+                    params.resize(data.param_count as usize, None);
+                }
+                let index = data.param_index as usize;
+                if index < params.len() && params[index].is_none() {
+                    params[index] = Some(Param::from(data));
+                    progressed = true;
+                }
+                if !params.is_empty() && params.iter().all(|p| p.is_some()) {
+                    return Ok(params.into_iter().flatten().collect());
+                }
+            }
+
+            attempts = if progressed { 0 } else { attempts + 1 };
+            if attempts > options.retries {
+                return Err(Error::timeout(start.elapsed()).operation("param list"));
+            }
+
+            if params.is_empty() {
+                connection
+                    .send(&MavMessage::PARAM_REQUEST_LIST(PARAM_REQUEST_LIST_DATA {
+                        target_system: connection.target_system(),
+                        target_component: connection.target_component(),
+                    }))
+                    .map_err(|e| e.operation("param list"))?;
+                continue;
+            }
+
+            let missing: Vec<usize> = (0..params.len()).filter(|i| params[*i].is_none()).collect();
+            tracing::event!(
+                tracing::Level::DEBUG,
+                missing = missing.len(),
+                "Requesting missing parameters"
+            );
+            for index in missing {
+                connection
+                    .send(&MavMessage::PARAM_REQUEST_READ(PARAM_REQUEST_READ_DATA {
+                        param_index: index as i16,
+                        target_system: connection.target_system(),
+                        target_component: connection.target_component(),
+                        param_id: [0; 16],
+                    }))
+                    .map_err(|e| e.operation("param list"))?;
+            }
+        }
+    }
+    .await;
+
+    drop(monitor);
+    res
+}
+
+// Reads the `NAME,VALUE` lines of ArduPilot .parm files. MAVProxy's `NAME VALUE` lines,
+// blank lines and `#` comments are accepted too.
+pub fn parse_params(text: &str) -> Result<Params, Error> {
+    let mut params = Params::new();
+
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let mut fields = line
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|f| !f.is_empty());
+        let parsed = match (fields.next(), fields.next().map(str::parse::<f32>)) {
+            (Some(id), Some(Ok(value))) if id.len() <= 16 => Some((id.to_string(), value)),
+            _ => None,
+        };
+        let (id, value) = parsed
+            .ok_or_else(|| Error::new(ErrorKind::Parse(format!("line {}: {line}", number + 1))))?;
+        params.insert(id, value);
+    }
+
+    Ok(params)
+}
+
+pub fn format_params(params: &Params) -> String {
+    params
+        .iter()
+        .map(|(id, value)| format!("{id},{value}\n"))
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParamChange {
+    Added(f32),
+    Removed(f32),
+    Changed { from: f32, to: f32 },
+}
+
+// What changes between two sets of parameters.
+pub fn diff(from: &Params, to: &Params) -> BTreeMap<String, ParamChange> {
+    let mut changes = BTreeMap::new();
+
+    for (id, value) in from {
+        match to.get(id) {
+            None => {
+                changes.insert(id.clone(), ParamChange::Removed(*value));
+            }
+            // Values go through f32 on the vehicle, anything closer is the same value.
+            Some(to) if (to - value).abs() > f32::EPSILON * value.abs().max(1.0) => {
+                changes.insert(
+                    id.clone(),
+                    ParamChange::Changed {
+                        from: *value,
+                        to: *to,
+                    },
+                );
+            }
+            Some(_) => {}
+        }
+    }
+    for (id, value) in to {
+        if !from.contains_key(id) {
+            changes.insert(id.clone(), ParamChange::Added(*value));
+        }
+    }
+
+    changes
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::{
+        diff, format_params, get_param, list_params, param_id, param_name, parse_params, set_param,
+        ParamChange, Params,
+    };
+    use crate::sim::SimVehicle;
+
+    #[test]
+    fn ids() {
+        assert_eq!(param_name(&param_id("WP_RADIUS")), "WP_RADIUS");
+        assert_eq!(param_name(&param_id("A_VERY_LONG_PARAMETER")).len(), 16);
+    }
+
+    #[test]
+    fn param_files() {
+        let params =
+            parse_params("# Saved\nWP_RADIUS,90\nTRIM_ARSPD_CM 1200 # cruise\n\n").unwrap();
+
+        assert_eq!(params["WP_RADIUS"], 90.0);
+        assert_eq!(params["TRIM_ARSPD_CM"], 1200.0);
+        assert_eq!(parse_params(&format_params(&params)).unwrap(), params);
+        assert!(parse_params("WP_RADIUS").is_err());
+    }
+
+    #[test]
+    fn diffs() {
+        let from = Params::from([("A".to_string(), 1.0), ("B".to_string(), 2.0)]);
+        let to = Params::from([("B".to_string(), 3.0), ("C".to_string(), 4.0)]);
+
+        let changes = diff(&from, &to);
+
+        assert_eq!(changes["A"], ParamChange::Removed(1.0));
+        assert_eq!(changes["B"], ParamChange::Changed { from: 2.0, to: 3.0 });
+        assert_eq!(changes["C"], ParamChange::Added(4.0));
+        assert!(diff(&from, &from).is_empty());
+    }
+
+    #[tokio::test]
+    async fn gets_and_sets() {
+        let connection: Arc<Box<SimVehicle>> = Default::default();
+        connection.set_param("WP_RADIUS", 90.0);
+
+        let param = get_param(connection.clone(), "WP_RADIUS", Default::default())
+            .await
+            .unwrap();
+        assert_eq!(param.value, 90.0);
+
+        let param = set_param(connection.clone(), "WP_RADIUS", 60.0, Default::default())
+            .await
+            .unwrap();
+        assert_eq!(param.value, 60.0);
+        assert_eq!(connection.param("WP_RADIUS"), Some(60.0));
+    }
+
+    #[tokio::test]
+    async fn lists() {
+        let connection: Arc<Box<SimVehicle>> = Default::default();
+        for (i, id) in ["A", "B", "C"].iter().enumerate() {
+            connection.set_param(id, i as f32);
+        }
+
+        let params = list_params(connection, Default::default()).await.unwrap();
+
+        assert_eq!(params.len(), 3);
+        assert_eq!(params[2].id, "C");
+        assert_eq!(params[2].value, 2.0);
+    }
+}
@@ -0,0 +1,499 @@
+use std::{fmt::Debug, sync::Arc};
+
+use mavlink::ardupilotmega::{EkfStatusFlags, MavMessage, MavSysStatusSensor, SYS_STATUS_DATA};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    connection::MavlinkConnection,
+    error::{Error, ErrorKind},
+    statustext::{Capture, StatusText, StatusTextHistory},
+};
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Options {
+    // PreArm STATUSTEXT lines older than this are no longer considered failing.
+    // ArduPilot repeats them every 30 seconds while a check keeps failing.
+    pub prearm_window: std::time::Duration,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            prearm_window: std::time::Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    pub name: String,
+    pub passed: bool,
+    pub reasons: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReadinessReport {
+    // One check per sensor that SYS_STATUS reports as present and enabled.
+    // None until the first SYS_STATUS is received.
+    pub sensors: Option<Vec<Check>>,
+    // None until the first EKF_STATUS_REPORT is received, autopilots other than
+    // ArduPilot never send it.
+    pub ekf: Option<Check>,
+    // PreArm STATUSTEXT lines received within the configured window.
+    pub prearm: Vec<StatusText>,
+    // The same lines as failed checks, one per subsystem they name, named like the sensor checks,
+    // or "PreArm" when no subsystem is recognized.
+    pub prearm_checks: Vec<Check>,
+}
+
+impl ReadinessReport {
+    pub fn ready(&self) -> bool {
+        self.sensors
+            .as_ref()
+            .is_some_and(|sensors| sensors.iter().all(|c| c.passed))
+            && self.ekf.as_ref().is_none_or(|c| c.passed)
+            && self.prearm.is_empty()
+    }
+
+    pub fn failures(&self) -> Vec<&Check> {
+        self.sensors
+            .iter()
+            .flatten()
+            .chain(self.ekf.iter())
+            .chain(self.prearm_checks.iter())
+            .filter(|c| !c.passed)
+            .collect()
+    }
+}
+
+fn sensor_checks(status: &SYS_STATUS_DATA) -> Vec<Check> {
+    (0..u32::BITS)
+        .filter_map(|bit| MavSysStatusSensor::from_bits(1 << bit))
+        .filter(|sensor| {
+            status.onboard_control_sensors_present.contains(*sensor)
+                && status.onboard_control_sensors_enabled.contains(*sensor)
+        })
+        .map(|sensor| {
+            let passed = status.onboard_control_sensors_health.contains(sensor);
+            Check {
+                name: format!("{sensor:?}"),
+                passed,
+                reasons: if passed {
+                    vec![]
+                } else {
+                    vec!["Unhealthy".to_string()]
+                },
+            }
+        })
+        .collect()
+}
+
+fn ekf_check(flags: EkfStatusFlags) -> Check {
+    let mut reasons = vec![];
+
+    if flags.contains(EkfStatusFlags::EKF_UNINITIALIZED) {
+        reasons.push("EKF is not initialized".to_string());
+    }
+    for (flag, reason) in [
+        (EkfStatusFlags::EKF_ATTITUDE, "No attitude estimate"),
+        (
+            EkfStatusFlags::EKF_VELOCITY_HORIZ,
+            "No horizontal velocity estimate",
+        ),
+        (
+            EkfStatusFlags::EKF_VELOCITY_VERT,
+            "No vertical velocity estimate",
+        ),
+        (
+            EkfStatusFlags::EKF_POS_VERT_ABS,
+            "No absolute vertical position estimate",
+        ),
+    ] {
+        if !flags.contains(flag) {
+            reasons.push(reason.to_string());
+        }
+    }
+    if !flags.intersects(EkfStatusFlags::EKF_POS_HORIZ_ABS | EkfStatusFlags::EKF_POS_HORIZ_REL) {
+        reasons.push("No horizontal position estimate".to_string());
+    }
+
+    Check {
+        name: "EKF".to_string(),
+        passed: reasons.is_empty(),
+        reasons,
+    }
+}
+
+// Subsystems of the common ArduPilot PreArm reasons, by the start of the reason.
+const PREARM_SUBSYSTEMS: [(&str, MavSysStatusSensor); 16] = [
+    (
+        "3D Accel",
+        MavSysStatusSensor::MAV_SYS_STATUS_SENSOR_3D_ACCEL,
+    ),
+    ("Accel", MavSysStatusSensor::MAV_SYS_STATUS_SENSOR_3D_ACCEL),
+    ("Gyro", MavSysStatusSensor::MAV_SYS_STATUS_SENSOR_3D_GYRO),
+    ("Compass", MavSysStatusSensor::MAV_SYS_STATUS_SENSOR_3D_MAG),
+    (
+        "Baro",
+        MavSysStatusSensor::MAV_SYS_STATUS_SENSOR_ABSOLUTE_PRESSURE,
+    ),
+    (
+        "Airspeed",
+        MavSysStatusSensor::MAV_SYS_STATUS_SENSOR_DIFFERENTIAL_PRESSURE,
+    ),
+    ("GPS", MavSysStatusSensor::MAV_SYS_STATUS_SENSOR_GPS),
+    ("Need 3D Fix", MavSysStatusSensor::MAV_SYS_STATUS_SENSOR_GPS),
+    ("RC", MavSysStatusSensor::MAV_SYS_STATUS_SENSOR_RC_RECEIVER),
+    (
+        "Radio",
+        MavSysStatusSensor::MAV_SYS_STATUS_SENSOR_RC_RECEIVER,
+    ),
+    ("Battery", MavSysStatusSensor::MAV_SYS_STATUS_SENSOR_BATTERY),
+    (
+        "Motors",
+        MavSysStatusSensor::MAV_SYS_STATUS_SENSOR_MOTOR_OUTPUTS,
+    ),
+    ("AHRS", MavSysStatusSensor::MAV_SYS_STATUS_AHRS),
+    ("EKF", MavSysStatusSensor::MAV_SYS_STATUS_AHRS),
+    ("Logging", MavSysStatusSensor::MAV_SYS_STATUS_LOGGING),
+    ("Fence", MavSysStatusSensor::MAV_SYS_STATUS_GEOFENCE),
+];
+
+fn prearm_checks(lines: &[StatusText]) -> Vec<Check> {
+    let mut checks: Vec<Check> = vec![];
+    for line in lines {
+        let reason = line.text.trim_start_matches("PreArm:").trim();
+        let name = PREARM_SUBSYSTEMS
+            .iter()
+            .find(|(prefix, _)| reason.starts_with(prefix))
+            .map_or("PreArm".to_string(), |(_, sensor)| format!("{sensor:?}"));
+
+        let check = match checks.iter_mut().position(|c| c.name == name) {
+            Some(index) => &mut checks[index],
+            None => {
+                checks.push(Check {
+                    name,
+                    passed: false,
+                    reasons: vec![],
+                });
+                checks.last_mut().unwrap()
+            }
+        };
+        // Repeated every 30 seconds while failing.
+        if !check.reasons.iter().any(|r| r == reason) {
+            check.reasons.push(reason.to_string());
+        }
+    }
+    checks
+}
+
+fn prearm_lines(history: &StatusTextHistory, window: std::time::Duration) -> Vec<StatusText> {
+    let since = std::time::SystemTime::now()
+        .checked_sub(window)
+        .unwrap_or(std::time::UNIX_EPOCH);
+
+    history
+        .since(since)
+        .into_iter()
+        .filter(|t| t.text.starts_with("PreArm:"))
+        .collect()
+}
+
+// Combines SYS_STATUS, EKF_STATUS_REPORT and PreArm STATUSTEXT lines into a readiness report.
+// The STATUSTEXT received are recorded in `history` by the same monitor, there is no need for a
+// `statustext::capture` on the connection, which would take part of the messages.
+pub fn readiness_monitor<C>(
+    connection: Arc<C>,
+    history: StatusTextHistory,
+    options: Options,
+    timeout: Option<std::time::Duration>,
+) -> tokio::sync::watch::Receiver<ReadinessReport>
+where
+    C: MavlinkConnection + Debug + Send + Sync + 'static,
+{
+    let (tx, rx) = tokio::sync::watch::channel(ReadinessReport::default());
+    let capture = Arc::new(Capture::new(history));
+
+    let validate = capture.clone().validate(connection.clone());
+    let monitor =
+        connection.monitor_validated(timeout, CancellationToken::new(), validate, move |msg| {
+            // Nobody is watching anymore.
+            if tx.is_closed() {
+                return None;
+            }
+            capture.update(&msg);
+            tx.send_if_modified(|report| {
+                let previous = report.clone();
+                match msg {
+                    MavMessage::SYS_STATUS(data) => report.sensors = Some(sensor_checks(&data)),
+                    MavMessage::EKF_STATUS_REPORT(data) => report.ekf = Some(ekf_check(data.flags)),
+                    _ => {}
+                }
+                report.prearm = prearm_lines(capture.history(), options.prearm_window);
+                report.prearm_checks = prearm_checks(&report.prearm);
+                *report != previous
+            });
+            Some(())
+        });
+    monitor.detach();
+
+    rx
+}
+
+// Waits until the vehicle reports itself ready to arm.
+pub async fn wait_ready_to_arm<C>(
+    connection: Arc<C>,
+    history: StatusTextHistory,
+    options: Options,
+    timeout: std::time::Duration,
+) -> Result<ReadinessReport, Error>
+where
+    C: MavlinkConnection + Debug + Send + Sync + 'static,
+{
+    let mut rx = readiness_monitor(connection, history, options, Some(timeout));
+
+    let res = tokio::time::timeout(timeout, async {
+        loop {
+            if rx.borrow_and_update().ready() {
+                return true;
+            }
+            if rx.changed().await.is_err() {
+                return false;
+            }
+        }
+    })
+    .await;
+
+    let report = rx.borrow().clone();
+    match res {
+        Ok(true) => Ok(report),
+        _ => Err(Error::new(ErrorKind::NotReadyToArm(Box::new(report))).elapsed(timeout)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use mavlink::ardupilotmega::{
+        EkfStatusFlags, MavMessage, MavSeverity, MavSysStatusSensor, STATUSTEXT_DATA,
+        SYS_STATUS_DATA,
+    };
+
+    use super::{ekf_check, prearm_checks, sensor_checks, wait_ready_to_arm};
+    use crate::{
+        connection::test::*,
+        error::{Error, ErrorKind},
+        sim::SimVehicle,
+        statustext::{StatusText, StatusTextHistory},
+    };
+
+    fn sys_status(health: MavSysStatusSensor) -> SYS_STATUS_DATA {
+        let sensors = MavSysStatusSensor::MAV_SYS_STATUS_SENSOR_3D_GYRO
+            | MavSysStatusSensor::MAV_SYS_STATUS_SENSOR_GPS;
+        SYS_STATUS_DATA {
+            onboard_control_sensors_present: sensors,
+            onboard_control_sensors_enabled: sensors,
+            onboard_control_sensors_health: health,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn unhealthy_sensor() {
+        let checks = sensor_checks(&sys_status(
+            MavSysStatusSensor::MAV_SYS_STATUS_SENSOR_3D_GYRO,
+        ));
+
+        assert_eq!(checks.len(), 2);
+        assert!(checks[0].passed);
+        assert_eq!(checks[1].name, "MAV_SYS_STATUS_SENSOR_GPS");
+        assert!(!checks[1].passed);
+    }
+
+    #[test]
+    fn ekf_flags() {
+        assert!(!ekf_check(EkfStatusFlags::EKF_ATTITUDE).passed);
+        assert!(
+            ekf_check(
+                EkfStatusFlags::EKF_ATTITUDE
+                    | EkfStatusFlags::EKF_VELOCITY_HORIZ
+                    | EkfStatusFlags::EKF_VELOCITY_VERT
+                    | EkfStatusFlags::EKF_POS_HORIZ_REL
+                    | EkfStatusFlags::EKF_POS_VERT_ABS
+            )
+            .passed
+        );
+    }
+
+    #[test]
+    fn maps_prearm_reasons() {
+        let lines: Vec<StatusText> = [
+            "PreArm: GPS 1: Bad fix",
+            "PreArm: Need 3D Fix",
+            "PreArm: Compass not calibrated",
+            "PreArm: Throttle below failsafe",
+            "PreArm: GPS 1: Bad fix",
+        ]
+        .into_iter()
+        .map(|text| StatusText {
+            severity: MavSeverity::MAV_SEVERITY_CRITICAL,
+            text: text.to_string(),
+            received: std::time::SystemTime::now(),
+        })
+        .collect();
+
+        let checks = prearm_checks(&lines);
+
+        let checks: Vec<(&str, Vec<&str>)> = checks
+            .iter()
+            .map(|c| {
+                assert!(!c.passed);
+                (
+                    c.name.as_str(),
+                    c.reasons.iter().map(String::as_str).collect(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            checks,
+            [
+                (
+                    "MAV_SYS_STATUS_SENSOR_GPS",
+                    vec!["GPS 1: Bad fix", "Need 3D Fix"]
+                ),
+                (
+                    "MAV_SYS_STATUS_SENSOR_3D_MAG",
+                    vec!["Compass not calibrated"]
+                ),
+                ("PreArm", vec!["Throttle below failsafe"]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn not_ready() {
+        let connection: Arc<Box<TestMavConnection>> = Default::default();
+
+        connection.inject_msg(MavMessage::SYS_STATUS(sys_status(
+            MavSysStatusSensor::MAV_SYS_STATUS_SENSOR_3D_GYRO,
+        )));
+
+        let res = wait_ready_to_arm(
+            connection,
+            StatusTextHistory::new(10),
+            Default::default(),
+            std::time::Duration::from_millis(100),
+        )
+        .await;
+
+        let Err(Error {
+            kind: ErrorKind::NotReadyToArm(report),
+            ..
+        }) = res
+        else {
+            panic!("Expected the vehicle to not be ready");
+        };
+        assert_eq!(report.failures().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn ready() {
+        let connection: Arc<Box<TestMavConnection>> = Default::default();
+
+        connection.inject_msg(MavMessage::SYS_STATUS(sys_status(
+            MavSysStatusSensor::MAV_SYS_STATUS_SENSOR_3D_GYRO
+                | MavSysStatusSensor::MAV_SYS_STATUS_SENSOR_GPS,
+        )));
+
+        let res = wait_ready_to_arm(
+            connection,
+            StatusTextHistory::new(10),
+            Default::default(),
+            std::time::Duration::from_secs(1),
+        )
+        .await;
+
+        assert!(matches!(res, Ok(report) if report.ekf.is_none()));
+    }
+
+    #[tokio::test]
+    async fn reads_prearm_text_and_status_together() {
+        let connection: Arc<Box<SimVehicle>> = Default::default();
+        let mut statustext = STATUSTEXT_DATA {
+            severity: MavSeverity::MAV_SEVERITY_CRITICAL,
+            ..Default::default()
+        };
+        statustext
+            .text
+            .extend_from_slice(b"PreArm: Compass calib\0")
+            .unwrap();
+        connection.inject(MavMessage::STATUSTEXT(statustext));
+        connection.inject(MavMessage::SYS_STATUS(sys_status(
+            MavSysStatusSensor::MAV_SYS_STATUS_SENSOR_3D_GYRO
+                | MavSysStatusSensor::MAV_SYS_STATUS_SENSOR_GPS,
+        )));
+
+        let history = StatusTextHistory::new(10);
+        let res = wait_ready_to_arm(
+            connection,
+            history.clone(),
+            Default::default(),
+            std::time::Duration::from_millis(300),
+        )
+        .await;
+
+        let Err(Error {
+            kind: ErrorKind::NotReadyToArm(report),
+            ..
+        }) = res
+        else {
+            panic!("Expected the vehicle to not be ready");
+        };
+        // Sensors are healthy, only the PreArm line fails, as the compass.
+        let failures = report.failures();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].name, "MAV_SYS_STATUS_SENSOR_3D_MAG");
+        assert_eq!(failures[0].reasons, ["Compass calib"]);
+        assert_eq!(report.prearm.len(), 1);
+        assert_eq!(history.latest().unwrap().text, "PreArm: Compass calib");
+    }
+
+    #[tokio::test]
+    async fn prearm_text_blocks() {
+        let connection: Arc<Box<TestMavConnection>> = Default::default();
+        let history = StatusTextHistory::new(10);
+
+        history.push(StatusText {
+            severity: MavSeverity::MAV_SEVERITY_CRITICAL,
+            text: "PreArm: Hardware safety switch".to_string(),
+            received: std::time::SystemTime::now(),
+        });
+
+        connection.inject_msg(MavMessage::SYS_STATUS(sys_status(
+            MavSysStatusSensor::MAV_SYS_STATUS_SENSOR_3D_GYRO
+                | MavSysStatusSensor::MAV_SYS_STATUS_SENSOR_GPS,
+        )));
+
+        let res = wait_ready_to_arm(
+            connection,
+            history,
+            Default::default(),
+            std::time::Duration::from_millis(100),
+        )
+        .await;
+
+        let Err(Error {
+            kind: ErrorKind::NotReadyToArm(report),
+            ..
+        }) = res
+        else {
+            panic!("Expected the vehicle to not be ready");
+        };
+        // Not a subsystem, still a failure.
+        let failures = report.failures();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].name, "PreArm");
+        assert_eq!(report.prearm.len(), 1);
+    }
+}
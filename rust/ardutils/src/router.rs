@@ -0,0 +1,575 @@
+// Routes MAVLink between endpoints, following https://mavlink.io/en/guide/routing.html:
+// every (system, component) seen on an endpoint is learned, targeted messages only go to the
+// endpoints their target was seen on, and broadcasts go everywhere but where they came from.
+//
+// Frames are parsed with the ardupilotmega dialect, messages it does not know are dropped.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{Arc, Mutex, RwLock},
+};
+
+use mavlink::{ardupilotmega::MavMessage, error::MessageReadError, MavConnection, MavHeader};
+
+use crate::signing;
+
+// Delay before reconnecting an endpoint that failed.
+const RECONNECT_PERIOD: std::time::Duration = std::time::Duration::from_secs(1);
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize)]
+#[serde(default)]
+pub struct Filter {
+    // Message names, like HEARTBEAT. When set, only these messages pass.
+    pub allow: Option<Vec<String>>,
+    pub deny: Vec<String>,
+    // When set, only messages from these systems pass.
+    pub systems: Option<Vec<u8>>,
+}
+
+impl Filter {
+    pub fn passes(&self, header: &MavHeader, msg: &MavMessage) -> bool {
+        use mavlink::Message;
+
+        let name = msg.message_name();
+        self.allow
+            .as_ref()
+            .is_none_or(|allow| allow.iter().any(|n| n == name))
+            && !self.deny.iter().any(|n| n == name)
+            && self
+                .systems
+                .as_ref()
+                .is_none_or(|systems| systems.contains(&header.system_id))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+pub struct EndpointConfig {
+    pub name: String,
+    // Any address understood by `mavlink::connect`, e.g. serial:/dev/ttyACM0:115200,
+    // udpin:0.0.0.0:14550, udpout:127.0.0.1:14550, tcpin:0.0.0.0:5760 or tcpout:127.0.0.1:5760.
+    pub url: String,
+    // Applied to messages received on this endpoint.
+    #[serde(default)]
+    pub incoming: Filter,
+    // Applied to messages forwarded to this endpoint.
+    #[serde(default)]
+    pub outgoing: Filter,
+    // Signs what is sent to this endpoint, and drops what it receives unsigned.
+    #[serde(default)]
+    pub signing: Option<signing::Options>,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+pub struct Config {
+    pub endpoints: Vec<EndpointConfig>,
+}
+
+impl Config {
+    pub fn from_yaml(yaml: &str) -> Result<Self, serde_yaml::Error> {
+        serde_yaml::from_str(yaml)
+    }
+
+    // One endpoint per url, without any filtering.
+    pub fn from_urls(urls: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            endpoints: urls
+                .into_iter()
+                .enumerate()
+                .map(|(i, url)| EndpointConfig {
+                    name: format!("endpoint{i}"),
+                    url,
+                    incoming: Default::default(),
+                    outgoing: Default::default(),
+                    signing: None,
+                })
+                .collect(),
+        }
+    }
+}
+
+// Target system and component of a message, 0 when it is a broadcast or has no target.
+// The dialect has no accessor for them, hence the lists of messages carrying them.
+macro_rules! targets {
+    ($msg:expr, [$($both:ident),* $(,)?], [$($system:ident),* $(,)?]) => {
+        match $msg {
+            $(MavMessage::$both(data) => (data.target_system, data.target_component),)*
+            $(MavMessage::$system(data) => (data.target_system, 0),)*
+            _ => (0, 0),
+        }
+    };
+}
+
+pub fn targets(msg: &MavMessage) -> (u8, u8) {
+    targets!(
+        msg,
+        [
+            AUTOPILOT_STATE_FOR_GIMBAL_DEVICE,
+            AUTOPILOT_VERSION_REQUEST,
+            CANFD_FRAME,
+            CAN_FILTER_MODIFY,
+            CAN_FRAME,
+            COMMAND_ACK,
+            COMMAND_CANCEL,
+            COMMAND_INT,
+            COMMAND_LONG,
+            CUBEPILOT_FIRMWARE_UPDATE_RESP,
+            CUBEPILOT_FIRMWARE_UPDATE_START,
+            DEVICE_OP_READ,
+            DEVICE_OP_WRITE,
+            DIGICAM_CONFIGURE,
+            DIGICAM_CONTROL,
+            FENCE_FETCH_POINT,
+            FENCE_POINT,
+            FILE_TRANSFER_PROTOCOL,
+            GIMBAL_CONTROL,
+            GIMBAL_DEVICE_ATTITUDE_STATUS,
+            GIMBAL_DEVICE_SET_ATTITUDE,
+            GIMBAL_MANAGER_SET_ATTITUDE,
+            GIMBAL_MANAGER_SET_MANUAL_CONTROL,
+            GIMBAL_MANAGER_SET_PITCHYAW,
+            GIMBAL_REPORT,
+            GIMBAL_TORQUE_CMD_REPORT,
+            GOPRO_GET_REQUEST,
+            GOPRO_SET_REQUEST,
+            GPS_INJECT_DATA,
+            LED_CONTROL,
+            LOGGING_ACK,
+            LOGGING_DATA,
+            LOGGING_DATA_ACKED,
+            LOG_ERASE,
+            LOG_REQUEST_DATA,
+            LOG_REQUEST_END,
+            LOG_REQUEST_LIST,
+            MISSION_ACK,
+            MISSION_CLEAR_ALL,
+            MISSION_COUNT,
+            MISSION_ITEM,
+            MISSION_ITEM_INT,
+            MISSION_REQUEST,
+            MISSION_REQUEST_INT,
+            MISSION_REQUEST_LIST,
+            MISSION_REQUEST_PARTIAL_LIST,
+            MISSION_SET_CURRENT,
+            MISSION_WRITE_PARTIAL_LIST,
+            MOUNT_CONFIGURE,
+            MOUNT_CONTROL,
+            MOUNT_STATUS,
+            OPEN_DRONE_ID_AUTHENTICATION,
+            OPEN_DRONE_ID_BASIC_ID,
+            OPEN_DRONE_ID_LOCATION,
+            OPEN_DRONE_ID_MESSAGE_PACK,
+            OPEN_DRONE_ID_OPERATOR_ID,
+            OPEN_DRONE_ID_SELF_ID,
+            OPEN_DRONE_ID_SYSTEM,
+            OPEN_DRONE_ID_SYSTEM_UPDATE,
+            OSD_PARAM_CONFIG,
+            OSD_PARAM_SHOW_CONFIG,
+            PARAM_EXT_REQUEST_LIST,
+            PARAM_EXT_REQUEST_READ,
+            PARAM_EXT_SET,
+            PARAM_MAP_RC,
+            PARAM_REQUEST_LIST,
+            PARAM_REQUEST_READ,
+            PARAM_SET,
+            PING,
+            PLAY_TUNE,
+            PLAY_TUNE_V2,
+            RALLY_FETCH_POINT,
+            RALLY_POINT,
+            RC_CHANNELS_OVERRIDE,
+            REMOTE_LOG_BLOCK_STATUS,
+            REMOTE_LOG_DATA_BLOCK,
+            REQUEST_DATA_STREAM,
+            REQUEST_EVENT,
+            RESPONSE_EVENT_ERROR,
+            SAFETY_SET_ALLOWED_AREA,
+            SERIAL_CONTROL,
+            SETUP_SIGNING,
+            SET_ACTUATOR_CONTROL_TARGET,
+            SET_ATTITUDE_TARGET,
+            SET_MAG_OFFSETS,
+            SET_POSITION_TARGET_GLOBAL_INT,
+            SET_POSITION_TARGET_LOCAL_NED,
+            SUPPORTED_TUNES,
+            TIMESYNC,
+            TUNNEL,
+            V2_EXTENSION,
+        ],
+        [
+            CAMERA_FEEDBACK,
+            CAMERA_STATUS,
+            CHANGE_OPERATOR_CONTROL,
+            SET_GPS_GLOBAL_ORIGIN,
+            SET_HOME_POSITION,
+            SET_MODE,
+        ]
+    )
+}
+
+#[derive(Debug, Default)]
+pub struct RoutingTable {
+    // Endpoints each (system, component) was seen on.
+    seen: HashMap<(u8, u8), BTreeSet<usize>>,
+}
+
+impl RoutingTable {
+    pub fn learn(&mut self, endpoint: usize, header: &MavHeader) {
+        self.seen
+            .entry((header.system_id, header.component_id))
+            .or_default()
+            .insert(endpoint);
+    }
+
+    // Endpoints a message received on `source` should be forwarded to.
+    pub fn route(&self, source: usize, endpoints: usize, msg: &MavMessage) -> BTreeSet<usize> {
+        let destinations: BTreeSet<usize> = match targets(msg) {
+            (0, _) => (0..endpoints).collect(),
+            (system, 0) => self
+                .seen
+                .iter()
+                .filter(|((s, _), _)| *s == system)
+                .flat_map(|(_, e)| e.iter().copied())
+                .collect(),
+            // Components are not always seen before being targeted, the system is enough then.
+            (system, component) => match self.seen.get(&(system, component)) {
+                Some(e) => e.clone(),
+                None => self
+                    .seen
+                    .iter()
+                    .filter(|((s, _), _)| *s == system)
+                    .flat_map(|(_, e)| e.iter().copied())
+                    .collect(),
+            },
+        };
+
+        destinations.into_iter().filter(|e| *e != source).collect()
+    }
+}
+
+type Connection = Arc<dyn MavConnection<MavMessage> + Send + Sync>;
+
+struct Endpoint {
+    config: EndpointConfig,
+    // None while (re)connecting.
+    connection: RwLock<Option<Connection>>,
+}
+
+pub struct Router {
+    endpoints: Vec<Endpoint>,
+    table: Mutex<RoutingTable>,
+}
+
+impl std::fmt::Debug for Router {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<_> = self.endpoints.iter().map(|e| &e.config.name).collect();
+        write!(f, "Router {names:?}")
+    }
+}
+
+impl Router {
+    pub fn new(config: Config) -> Arc<Self> {
+        Arc::new(Self {
+            endpoints: config
+                .endpoints
+                .into_iter()
+                .map(|config| Endpoint {
+                    config,
+                    connection: RwLock::new(None),
+                })
+                .collect(),
+            table: Default::default(),
+        })
+    }
+
+    // Starts one thread per endpoint. Endpoints that fail are reconnected, so the threads
+    // never return.
+    pub fn run(self: Arc<Self>) -> Vec<std::thread::JoinHandle<()>> {
+        (0..self.endpoints.len())
+            .map(|index| {
+                let router = self.clone();
+                std::thread::spawn(move || loop {
+                    router.serve(index);
+                    std::thread::sleep(RECONNECT_PERIOD);
+                })
+            })
+            .collect()
+    }
+
+    // Connects an endpoint and forwards what it receives, until it fails.
+    fn serve(&self, index: usize) {
+        let endpoint = &self.endpoints[index];
+        let connection = match &endpoint.config.signing {
+            Some(options) => signing::connect(&endpoint.config.url, options.clone())
+                .map(|connection| Arc::new(connection) as Connection),
+            None => mavlink::connect(&endpoint.config.url).map(Arc::from),
+        };
+        let connection = match connection {
+            Ok(connection) => connection,
+            Err(e) => {
+                tracing::event!(tracing::Level::WARN, name = endpoint.config.name, e = %e, "Could not connect");
+                return;
+            }
+        };
+        tracing::event!(
+            tracing::Level::INFO,
+            name = endpoint.config.name,
+            "Connected"
+        );
+        endpoint
+            .connection
+            .write()
+            .unwrap()
+            .replace(connection.clone());
+
+        loop {
+            match connection.recv() {
+                Ok((header, msg)) => self.forward(index, header, msg),
+                // Unknown messages and bad CRCs only cost the frame.
+                Err(MessageReadError::Parse(_)) => {}
+                Err(MessageReadError::Io(e)) => {
+                    tracing::event!(tracing::Level::WARN, name = endpoint.config.name, e = %e, "Endpoint failed");
+                    endpoint.connection.write().unwrap().take();
+                    return;
+                }
+            }
+        }
+    }
+
+    fn forward(&self, source: usize, header: MavHeader, msg: MavMessage) {
+        if !self.endpoints[source].config.incoming.passes(&header, &msg) {
+            return;
+        }
+
+        let destinations = {
+            let mut table = self.table.lock().unwrap();
+            table.learn(source, &header);
+            table.route(source, self.endpoints.len(), &msg)
+        };
+
+        for index in destinations {
+            let endpoint = &self.endpoints[index];
+            if !endpoint.config.outgoing.passes(&header, &msg) {
+                continue;
+            }
+            let connection = endpoint.connection.read().unwrap().clone();
+            if let Some(connection) = connection {
+                if let Err(e) = connection.send(&header, &msg) {
+                    tracing::event!(tracing::Level::DEBUG, name = endpoint.config.name, e = ?e, "Could not forward");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+
+    use mavlink::{
+        ardupilotmega::{MavMessage, COMMAND_LONG_DATA, HEARTBEAT_DATA},
+        MavConnection, MavHeader, Message,
+    };
+
+    use super::{targets, Config, Filter, Router, RoutingTable};
+    use crate::signing::SigningKey;
+
+    fn header(system_id: u8, component_id: u8) -> MavHeader {
+        MavHeader {
+            system_id,
+            component_id,
+            sequence: 0,
+        }
+    }
+
+    fn command(target_system: u8, target_component: u8) -> MavMessage {
+        MavMessage::COMMAND_LONG(COMMAND_LONG_DATA {
+            target_system,
+            target_component,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn reads_targets() {
+        assert_eq!(targets(&command(1, 191)), (1, 191));
+        assert_eq!(
+            targets(&MavMessage::HEARTBEAT(HEARTBEAT_DATA::default())),
+            (0, 0)
+        );
+    }
+
+    // Every message with target fields in the dialect, set through its serialized fields.
+    #[test]
+    fn knows_every_targeted_message() {
+        let mut targeted = 0;
+        for id in 0..=u16::MAX as u32 {
+            let Ok(msg) = MavMessage::default_message_from_id(id) else {
+                continue;
+            };
+            let Ok(serde_yaml::Value::Mapping(mut fields)) = serde_yaml::to_value(&msg) else {
+                continue;
+            };
+            if !fields.contains_key("target_system") {
+                assert_eq!(targets(&msg), (0, 0), "{}", msg.message_name());
+                continue;
+            }
+
+            fields.insert("target_system".into(), 7.into());
+            let component = if fields.contains_key("target_component") {
+                fields.insert("target_component".into(), 9.into());
+                9
+            } else {
+                0
+            };
+            let msg: MavMessage = serde_yaml::from_value(fields.into()).unwrap();
+            assert_eq!(targets(&msg), (7, component), "{}", msg.message_name());
+            targeted += 1;
+        }
+        assert!(targeted > 90);
+    }
+
+    #[test]
+    fn routes() {
+        let mut table = RoutingTable::default();
+        // A vehicle with a companion computer on endpoint 0, a GCS on endpoint 1.
+        table.learn(0, &header(1, 1));
+        table.learn(0, &header(1, 191));
+        table.learn(1, &header(255, 190));
+
+        let heartbeat = MavMessage::HEARTBEAT(Default::default());
+        assert_eq!(table.route(0, 3, &heartbeat), BTreeSet::from([1, 2]));
+        assert_eq!(table.route(1, 3, &command(1, 1)), BTreeSet::from([0]));
+        assert_eq!(table.route(1, 3, &command(1, 0)), BTreeSet::from([0]));
+        // Unknown component of a known system.
+        assert_eq!(table.route(1, 3, &command(1, 100)), BTreeSet::from([0]));
+        // Never back to where it came from, and unknown systems are not routed.
+        assert!(table.route(0, 3, &command(1, 1)).is_empty());
+        assert!(table.route(1, 3, &command(7, 1)).is_empty());
+    }
+
+    #[test]
+    fn filters() {
+        let filter = Filter {
+            deny: vec!["HEARTBEAT".to_string()],
+            systems: Some(vec![1]),
+            ..Default::default()
+        };
+
+        assert!(!filter.passes(&header(1, 1), &MavMessage::HEARTBEAT(Default::default())));
+        assert!(filter.passes(&header(1, 1), &command(1, 1)));
+        assert!(!filter.passes(&header(2, 1), &command(1, 1)));
+    }
+
+    #[test]
+    fn parses_config() {
+        let config = Config::from_yaml(
+            "
+endpoints:
+  - name: vehicle
+    url: serial:/dev/ttyACM0:115200
+    signing:
+      passphrase: secret
+      link_id: 2
+  - name: qgc
+    url: udpout:127.0.0.1:14550
+    outgoing:
+      deny: [RAW_IMU]
+",
+        )
+        .unwrap();
+
+        assert_eq!(config.endpoints.len(), 2);
+        assert_eq!(config.endpoints[1].outgoing.deny, vec!["RAW_IMU"]);
+        assert_eq!(config.endpoints[0].incoming, Filter::default());
+
+        let signing = config.endpoints[0].signing.as_ref().unwrap();
+        assert_eq!(signing.key, SigningKey::from_passphrase("secret"));
+        assert_eq!(signing.link_id, 2);
+        assert!(!signing.accept_unsigned);
+        assert!(config.endpoints[1].signing.is_none());
+    }
+
+    fn free_port() -> u16 {
+        std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    // Connects to the router, and returns everything received from it.
+    fn client(
+        port: u16,
+    ) -> (
+        std::sync::Arc<dyn MavConnection<MavMessage> + Send + Sync>,
+        std::sync::mpsc::Receiver<(MavHeader, MavMessage)>,
+    ) {
+        let connection: std::sync::Arc<dyn MavConnection<MavMessage> + Send + Sync> =
+            std::sync::Arc::from(mavlink::connect(&format!("udpout:127.0.0.1:{port}")).unwrap());
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn({
+            let connection = connection.clone();
+            move || {
+                while let Ok(frame) = connection.recv() {
+                    if tx.send(frame).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        (connection, rx)
+    }
+
+    // The first message named `name`, skipping the others.
+    fn wait_for(
+        rx: &std::sync::mpsc::Receiver<(MavHeader, MavMessage)>,
+        name: &str,
+    ) -> (MavHeader, MavMessage) {
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        loop {
+            let timeout = deadline.saturating_duration_since(std::time::Instant::now());
+            let frame = rx.recv_timeout(timeout).expect(name);
+            if frame.1.message_name() == name {
+                return frame;
+            }
+        }
+    }
+
+    #[test]
+    fn forwards_over_udp() {
+        let (vehicle_port, gcs_port) = (free_port(), free_port());
+        let router = Router::new(Config::from_urls([
+            format!("udpin:127.0.0.1:{vehicle_port}"),
+            format!("udpin:127.0.0.1:{gcs_port}"),
+        ]));
+        router.run();
+
+        let (vehicle, from_gcs) = client(vehicle_port);
+        let (gcs, from_vehicle) = client(gcs_port);
+        let heartbeat = MavMessage::HEARTBEAT(Default::default());
+
+        // Until both are known to the router, and to their udpin endpoints.
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        let mut learned = false;
+        while !learned {
+            assert!(std::time::Instant::now() < deadline, "Nothing forwarded");
+            vehicle.send(&header(1, 1), &heartbeat).unwrap();
+            gcs.send(&header(255, 190), &heartbeat).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            learned = from_vehicle.try_iter().count() > 0 && from_gcs.try_iter().count() > 0;
+        }
+
+        // Unknown systems are not routed, the command that follows is.
+        gcs.send(&header(255, 190), &command(7, 1)).unwrap();
+        gcs.send(&header(255, 190), &command(1, 1)).unwrap();
+        let (sender, msg) = wait_for(&from_gcs, "COMMAND_LONG");
+        assert_eq!((sender.system_id, sender.component_id), (255, 190));
+        assert_eq!(targets(&msg), (1, 1));
+
+        vehicle
+            .send(&header(1, 1), &MavMessage::COMMAND_ACK(Default::default()))
+            .unwrap();
+        let (sender, _) = wait_for(&from_vehicle, "COMMAND_ACK");
+        assert_eq!(sender.system_id, 1);
+    }
+}
@@ -0,0 +1,270 @@
+// Byte streams for signed connections, opened from the same addresses as `mavlink::connect`.
+// The connections of the mavlink crate write frames themselves, which leaves no room for a
+// signature.
+
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket},
+    sync::{Arc, Mutex},
+};
+
+use super::{Options, SignedConnection};
+
+pub type Reader = Box<dyn Read + Send>;
+pub type Writer = Box<dyn Write + Send>;
+
+// Largest UDP payload.
+const DATAGRAM_LEN: usize = 65_507;
+
+struct Udp {
+    socket: UdpSocket,
+    // Where frames are sent. Nothing is sent until udpin hears from someone, it then answers
+    // whoever it last heard from.
+    remote: Mutex<Option<SocketAddr>>,
+    follow: bool,
+}
+
+struct UdpReader {
+    udp: Arc<Udp>,
+    datagram: Vec<u8>,
+    start: usize,
+    end: usize,
+}
+
+impl Read for UdpReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.start == self.end {
+            let (len, from) = self.udp.socket.recv_from(&mut self.datagram)?;
+            if self.udp.follow {
+                self.udp.remote.lock().unwrap().replace(from);
+            }
+            (self.start, self.end) = (0, len);
+        }
+
+        let len = buf.len().min(self.end - self.start);
+        buf[..len].copy_from_slice(&self.datagram[self.start..self.start + len]);
+        self.start += len;
+        Ok(len)
+    }
+}
+
+struct UdpWriter(Arc<Udp>);
+
+// Every frame is written at once, and hence goes in its own datagram.
+impl Write for UdpWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let remote = *self.0.remote.lock().unwrap();
+        match remote {
+            Some(remote) => self.0.socket.send_to(buf, remote),
+            None => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn udp(socket: UdpSocket, remote: Option<SocketAddr>) -> (Reader, Writer) {
+    let udp = Arc::new(Udp {
+        socket,
+        follow: remote.is_none(),
+        remote: Mutex::new(remote),
+    });
+    let reader = UdpReader {
+        udp: udp.clone(),
+        datagram: vec![0; DATAGRAM_LEN],
+        start: 0,
+        end: 0,
+    };
+    (Box::new(reader), Box::new(UdpWriter(udp)))
+}
+
+// Reading and writing share the port. Reads time out regularly, which lets writes through.
+#[derive(Clone)]
+struct Serial(Arc<Mutex<serial::SystemPort>>);
+
+impl Read for Serial {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            match self.0.lock().unwrap().read(buf) {
+                Err(e) if e.kind() == std::io::ErrorKind::TimedOut => continue,
+                res => return res,
+            }
+        }
+    }
+}
+
+impl Write for Serial {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+// e.g. /dev/ttyACM0:115200
+fn serial(address: &str) -> std::io::Result<(Reader, Writer)> {
+    use serial::SerialPort;
+
+    let invalid = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid serial port {address}"),
+        )
+    };
+    let (port, baud) = address.rsplit_once(':').ok_or_else(invalid)?;
+    let baud = baud.parse().map_err(|_| invalid())?;
+
+    let mut port = serial::open(port)?;
+    port.configure(&serial::PortSettings {
+        baud_rate: serial::BaudRate::from_speed(baud),
+        char_size: serial::Bits8,
+        parity: serial::ParityNone,
+        stop_bits: serial::Stop1,
+        flow_control: serial::FlowNone,
+    })?;
+
+    let port = Serial(Arc::new(Mutex::new(port)));
+    Ok((Box::new(port.clone()), Box::new(port)))
+}
+
+fn resolve(address: &str) -> std::io::Result<SocketAddr> {
+    address.to_socket_addrs()?.next().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::AddrNotAvailable,
+            format!("Could not resolve {address}"),
+        )
+    })
+}
+
+// Any address understood by `mavlink::connect` but file:, e.g. serial:/dev/ttyACM0:115200,
+// udpin:0.0.0.0:14550, udpout:127.0.0.1:14550, udpbcast:192.168.1.255:14550,
+// tcpin:0.0.0.0:5760 or tcpout:127.0.0.1:5760. Like mavlink, tcpin waits for a client.
+pub fn connect(url: &str, options: Options) -> std::io::Result<SignedConnection<Reader, Writer>> {
+    let unsupported = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Cannot sign over {url}"),
+        )
+    };
+    let (kind, address) = url.split_once(':').ok_or_else(unsupported)?;
+
+    let (reader, writer): (Reader, Writer) = match kind {
+        "tcpout" => {
+            let stream = TcpStream::connect(address)?;
+            (Box::new(stream.try_clone()?), Box::new(stream))
+        }
+        "tcpin" => {
+            let (stream, _) = TcpListener::bind(address)?.accept()?;
+            (Box::new(stream.try_clone()?), Box::new(stream))
+        }
+        "udpin" => udp(UdpSocket::bind(address)?, None),
+        "udpout" => udp(UdpSocket::bind("0.0.0.0:0")?, Some(resolve(address)?)),
+        "udpbcast" => {
+            let socket = UdpSocket::bind("0.0.0.0:0")?;
+            socket.set_broadcast(true)?;
+            udp(socket, Some(resolve(address)?))
+        }
+        "serial" => serial(address)?,
+        _ => return Err(unsupported()),
+    };
+    Ok(SignedConnection::new(reader, writer, options))
+}
+
+#[cfg(test)]
+mod test {
+    use mavlink::{
+        ardupilotmega::{MavMessage, MavResult, COMMAND_ACK_DATA, COMMAND_LONG_DATA},
+        MavConnection, MavHeader,
+    };
+
+    use super::connect;
+    use crate::signing::{Options, SigningKey};
+
+    fn options(link_id: u8) -> Options {
+        Options {
+            key: SigningKey::from_passphrase("secret"),
+            link_id,
+            accept_unsigned: false,
+        }
+    }
+
+    fn free_port() -> u16 {
+        std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    fn command() -> MavMessage {
+        MavMessage::COMMAND_LONG(COMMAND_LONG_DATA {
+            param1: 1.0,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn signed_udp() {
+        let port = free_port();
+        let vehicle = connect(&format!("udpin:127.0.0.1:{port}"), options(0)).unwrap();
+        let gcs = connect(&format!("udpout:127.0.0.1:{port}"), options(1)).unwrap();
+
+        // Unsigned frames are dropped, the signed one that follows goes through.
+        let unsigned = mavlink::connect::<MavMessage>(&format!("udpout:127.0.0.1:{port}")).unwrap();
+        unsigned
+            .send(
+                &MavHeader::default(),
+                &MavMessage::COMMAND_ACK(Default::default()),
+            )
+            .unwrap();
+        MavConnection::<MavMessage>::send(&gcs, &MavHeader::default(), &command()).unwrap();
+        let (_, received) = MavConnection::<MavMessage>::recv(&vehicle).unwrap();
+        assert_eq!(received, command());
+
+        // Answered to where it came from.
+        let ack = MavMessage::COMMAND_ACK(COMMAND_ACK_DATA {
+            result: MavResult::MAV_RESULT_ACCEPTED,
+            ..Default::default()
+        });
+        MavConnection::<MavMessage>::send(&vehicle, &MavHeader::default(), &ack).unwrap();
+        let (_, received) = MavConnection::<MavMessage>::recv(&gcs).unwrap();
+        assert_eq!(received, ack);
+    }
+
+    #[test]
+    fn signed_tcp() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let vehicle = std::thread::spawn(move || {
+            let vehicle = connect(&format!("tcpin:127.0.0.1:{port}"), options(0)).unwrap();
+            MavConnection::<MavMessage>::recv(&vehicle).unwrap().1
+        });
+
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        let gcs = loop {
+            match connect(&format!("tcpout:127.0.0.1:{port}"), options(1)) {
+                Ok(gcs) => break gcs,
+                // Until the vehicle listens.
+                Err(_) if std::time::Instant::now() < deadline => {
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                }
+                Err(e) => panic!("{e}"),
+            }
+        };
+        MavConnection::<MavMessage>::send(&gcs, &MavHeader::default(), &command()).unwrap();
+
+        assert_eq!(vehicle.join().unwrap(), command());
+    }
+
+    #[test]
+    fn rejects_file() {
+        assert!(connect("file:mission.tlog", options(0)).is_err());
+    }
+}
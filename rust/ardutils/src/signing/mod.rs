@@ -0,0 +1,448 @@
+// MAVLink 2 message signing, https://mavlink.io/en/guide/message_signing.html
+//
+// Signed frames carry a link id, a timestamp and the first 6 bytes of
+// SHA-256(secret key + frame + link id + timestamp). Timestamps only ever increase on a stream,
+// a (system, component, link id) triple, which is what protects against replays.
+
+mod link;
+
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    io::{Read, Write},
+    net::TcpStream,
+    sync::Mutex,
+};
+
+use mavlink::{
+    ardupilotmega::{MavMessage, SETUP_SIGNING_DATA},
+    error::{MessageReadError, MessageWriteError},
+    MavConnection, MavHeader, MavlinkVersion, Message,
+};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+pub use link::connect;
+
+use crate::{connection::MavlinkConnection, error::Error};
+
+const STX_V2: u8 = 0xFD;
+const HEADER_LEN: usize = 10;
+const SIGNATURE_LEN: usize = 13;
+const IFLAG_SIGNED: u8 = 0x01;
+
+// 1 January 2015, where signing timestamps start, in seconds since the Unix epoch.
+const SIGNING_EPOCH: u64 = 1_420_070_400;
+// A new stream is accepted up to a minute behind the newest timestamp seen, in 10us units.
+const REPLAY_WINDOW: u64 = 60 * 100_000;
+
+// Current time in signing units: 10 microseconds since 1 January 2015.
+pub fn timestamp_now() -> u64 {
+    let since_unix = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    (since_unix.as_micros() as u64 / 10).saturating_sub(SIGNING_EPOCH * 100_000)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct SigningKey(pub [u8; 32]);
+
+impl SigningKey {
+    // The key MAVProxy and Mission Planner derive from a passphrase.
+    pub fn from_passphrase(passphrase: &str) -> Self {
+        Self(Sha256::digest(passphrase.as_bytes()).into())
+    }
+}
+
+// Keeps the key out of logs.
+impl Debug for SigningKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SigningKey(..)")
+    }
+}
+
+// Configured with the passphrase the key is derived from.
+fn passphrase<'de, D>(deserializer: D) -> Result<SigningKey, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let passphrase: String = serde::Deserialize::deserialize(deserializer)?;
+    Ok(SigningKey::from_passphrase(&passphrase))
+}
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize)]
+pub struct Options {
+    #[serde(rename = "passphrase", deserialize_with = "passphrase")]
+    pub key: SigningKey,
+    // Identifies this link in outgoing signatures, each link to a vehicle should use its own.
+    #[serde(default)]
+    pub link_id: u8,
+    // Unsigned frames are dropped unless this is set.
+    #[serde(default)]
+    pub accept_unsigned: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SignatureError {
+    Unsigned,
+    Invalid,
+    // The timestamp is not newer than the last one of its stream.
+    Replayed,
+    Malformed,
+}
+
+// MAVLink's CRC-16/MCRF4XX, over the frame after the start byte, followed by the message
+// CRC_EXTRA.
+fn crc(bytes: &[u8], extra: u8) -> u16 {
+    bytes
+        .iter()
+        .chain(std::iter::once(&extra))
+        .fold(0xFFFF, |crc: u16, byte| {
+            let mut tmp = byte ^ (crc & 0xFF) as u8;
+            tmp ^= tmp << 4;
+            let tmp = tmp as u16;
+            (crc >> 8) ^ (tmp << 8) ^ (tmp << 3) ^ (tmp >> 4)
+        })
+}
+
+fn message_id(frame: &[u8]) -> u32 {
+    u32::from_le_bytes([frame[7], frame[8], frame[9], 0])
+}
+
+// Length of a frame without its signature.
+fn unsigned_len(frame: &[u8]) -> usize {
+    HEADER_LEN + frame[1] as usize + 2
+}
+
+// Reads a MAVLink 2 frame, skipping anything before its start byte. MAVLink 1 frames cannot be
+// signed, so they are not looked for.
+pub fn read_frame<R: Read>(reader: &mut R) -> std::io::Result<Vec<u8>> {
+    let mut byte = [0];
+    loop {
+        reader.read_exact(&mut byte)?;
+        if byte[0] == STX_V2 {
+            break;
+        }
+    }
+
+    let mut frame = vec![0; HEADER_LEN];
+    frame[0] = STX_V2;
+    reader.read_exact(&mut frame[1..])?;
+
+    let signed = frame[2] & IFLAG_SIGNED != 0;
+    let len = unsigned_len(&frame) + if signed { SIGNATURE_LEN } else { 0 };
+    frame.resize(len, 0);
+    reader.read_exact(&mut frame[HEADER_LEN..])?;
+    Ok(frame)
+}
+
+#[derive(Debug)]
+pub struct Signer {
+    options: Options,
+    // Newest timestamp used or seen on the link.
+    timestamp: u64,
+    // Last timestamp of each (system, component, link id).
+    streams: HashMap<(u8, u8, u8), u64>,
+}
+
+impl Signer {
+    pub fn new(options: Options) -> Self {
+        Self {
+            options,
+            timestamp: timestamp_now(),
+            streams: Default::default(),
+        }
+    }
+
+    fn signature(&self, frame: &[u8], link_id: u8, timestamp: u64) -> [u8; 6] {
+        let digest = Sha256::new()
+            .chain_update(self.options.key.0)
+            .chain_update(frame)
+            .chain_update([link_id])
+            .chain_update(&timestamp.to_le_bytes()[..6])
+            .finalize();
+
+        let mut signature = [0; 6];
+        signature.copy_from_slice(&digest[..6]);
+        signature
+    }
+
+    // Signs an unsigned MAVLink 2 frame of message `M`, its CRC changes with the flags.
+    pub fn sign<M: Message>(&mut self, frame: &mut Vec<u8>) {
+        // Never reuse a timestamp, even when sending faster than the 10us resolution.
+        self.timestamp = timestamp_now().max(self.timestamp + 1);
+
+        let len = unsigned_len(frame);
+        frame.truncate(len);
+        frame[2] |= IFLAG_SIGNED;
+        let crc = crc(&frame[1..len - 2], M::extra_crc(message_id(frame)));
+        frame[len - 2..].copy_from_slice(&crc.to_le_bytes());
+
+        let signature = self.signature(frame, self.options.link_id, self.timestamp);
+        frame.push(self.options.link_id);
+        frame.extend_from_slice(&self.timestamp.to_le_bytes()[..6]);
+        frame.extend_from_slice(&signature);
+    }
+
+    // Checks the signature of a received frame, and records its timestamp once it is valid.
+    pub fn verify(&mut self, frame: &[u8]) -> Result<(), SignatureError> {
+        if frame.len() < HEADER_LEN || frame[0] != STX_V2 {
+            return Err(SignatureError::Malformed);
+        }
+        if frame[2] & IFLAG_SIGNED == 0 {
+            return if self.options.accept_unsigned {
+                Ok(())
+            } else {
+                Err(SignatureError::Unsigned)
+            };
+        }
+
+        let len = unsigned_len(frame);
+        if frame.len() != len + SIGNATURE_LEN {
+            return Err(SignatureError::Malformed);
+        }
+        let link_id = frame[len];
+        let mut timestamp = [0; 8];
+        timestamp[..6].copy_from_slice(&frame[len + 1..len + 7]);
+        let timestamp = u64::from_le_bytes(timestamp);
+
+        // In constant time, so that timing does not tell how much of a forged signature is right.
+        let signature = self.signature(&frame[..len], link_id, timestamp);
+        if !bool::from(signature.ct_eq(&frame[len + 7..])) {
+            return Err(SignatureError::Invalid);
+        }
+
+        let stream = (frame[5], frame[6], link_id);
+        let replayed = match self.streams.get(&stream) {
+            Some(last) => timestamp <= *last,
+            None => timestamp + REPLAY_WINDOW < self.timestamp,
+        };
+        if replayed {
+            return Err(SignatureError::Replayed);
+        }
+
+        self.streams.insert(stream, timestamp);
+        self.timestamp = self.timestamp.max(timestamp);
+        Ok(())
+    }
+}
+
+// A MAVLink 2 connection over a byte stream that signs everything it sends, and drops what it
+// receives unless it is correctly signed with the same key.
+pub struct SignedConnection<R, W> {
+    reader: Mutex<R>,
+    writer: Mutex<W>,
+    signer: Mutex<Signer>,
+}
+
+impl SignedConnection<TcpStream, TcpStream> {
+    // e.g. 127.0.0.1:5760 for a SITL instance.
+    pub fn tcp(address: &str, options: Options) -> std::io::Result<Self> {
+        let stream = TcpStream::connect(address)?;
+        Ok(Self::new(stream.try_clone()?, stream, options))
+    }
+}
+
+impl<R, W> SignedConnection<R, W>
+where
+    R: Read,
+    W: Write,
+{
+    pub fn new(reader: R, writer: W, options: Options) -> Self {
+        Self {
+            reader: Mutex::new(reader),
+            writer: Mutex::new(writer),
+            signer: Mutex::new(Signer::new(options)),
+        }
+    }
+}
+
+impl<R, W> Debug for SignedConnection<R, W> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "SignedConnection [link {}]",
+            self.signer.lock().unwrap().options.link_id
+        )
+    }
+}
+
+impl<M, R, W> MavConnection<M> for SignedConnection<R, W>
+where
+    M: Message,
+    R: Read,
+    W: Write,
+{
+    fn recv(&self) -> Result<(MavHeader, M), MessageReadError> {
+        let mut reader = self.reader.lock().unwrap();
+        loop {
+            let frame = read_frame(&mut *reader)?;
+
+            let len = unsigned_len(&frame);
+            let checksum = u16::from_le_bytes([frame[len - 2], frame[len - 1]]);
+            if checksum != crc(&frame[1..len - 2], M::extra_crc(message_id(&frame))) {
+                continue;
+            }
+            if let Err(e) = self.signer.lock().unwrap().verify(&frame) {
+                tracing::event!(tracing::Level::DEBUG, e = ?e, system = frame[5], "Dropped frame");
+                continue;
+            }
+
+            let msg = M::parse(
+                MavlinkVersion::V2,
+                message_id(&frame),
+                &frame[HEADER_LEN..len - 2],
+            )?;
+            return Ok((
+                MavHeader {
+                    sequence: frame[4],
+                    system_id: frame[5],
+                    component_id: frame[6],
+                },
+                msg,
+            ));
+        }
+    }
+
+    fn send(&self, header: &MavHeader, data: &M) -> Result<usize, MessageWriteError> {
+        let mut frame = vec![];
+        mavlink::write_v2_msg(&mut frame, *header, data)?;
+        self.signer.lock().unwrap().sign::<M>(&mut frame);
+
+        self.writer.lock().unwrap().write_all(&frame)?;
+        Ok(frame.len())
+    }
+
+    // Only MAVLink 2 frames can be signed.
+    fn set_protocol_version(&mut self, _version: MavlinkVersion) {}
+
+    fn get_protocol_version(&self) -> MavlinkVersion {
+        MavlinkVersion::V2
+    }
+}
+
+// Provisions the vehicle with the key, to be sent over a trusted link such as USB: the message
+// is neither signed nor acknowledged. An all-zero key disables signing on the vehicle.
+pub fn setup_signing<C>(connection: &C, key: SigningKey) -> Result<usize, Error>
+where
+    C: MavlinkConnection,
+{
+    connection.send(&MavMessage::SETUP_SIGNING(SETUP_SIGNING_DATA {
+        initial_timestamp: timestamp_now(),
+        target_system: connection.target_system(),
+        target_component: connection.target_component(),
+        secret_key: key.0,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{os::unix::net::UnixStream, sync::Arc};
+
+    use mavlink::{
+        ardupilotmega::{MavMessage, COMMAND_LONG_DATA},
+        MavConnection, MavHeader,
+    };
+
+    use super::{crc, message_id, Options, SignatureError, SignedConnection, Signer, SigningKey};
+    use crate::connection::{test::TestMavConnection, MavlinkConnection};
+
+    fn options(passphrase: &str) -> Options {
+        Options {
+            key: SigningKey::from_passphrase(passphrase),
+            link_id: 1,
+            accept_unsigned: false,
+        }
+    }
+
+    fn frame() -> Vec<u8> {
+        let mut frame = vec![];
+        mavlink::write_v2_msg(
+            &mut frame,
+            MavHeader::default(),
+            &MavMessage::COMMAND_LONG(COMMAND_LONG_DATA {
+                param1: 1.0,
+                ..Default::default()
+            }),
+        )
+        .unwrap();
+        frame
+    }
+
+    #[test]
+    fn matches_library_crc() {
+        use mavlink::Message;
+
+        let frame = frame();
+        let len = frame.len();
+        let extra = MavMessage::extra_crc(message_id(&frame));
+        assert_eq!(
+            crc(&frame[1..len - 2], extra).to_le_bytes(),
+            frame[len - 2..]
+        );
+    }
+
+    #[test]
+    fn derives_key_from_passphrase() {
+        let key: String = SigningKey::from_passphrase("abc")
+            .0
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        assert_eq!(
+            key,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn signs_and_verifies() {
+        let mut sender = Signer::new(options("secret"));
+        let mut receiver = Signer::new(options("secret"));
+
+        let mut signed = frame();
+        sender.sign::<MavMessage>(&mut signed);
+        assert_eq!(signed.len(), frame().len() + 13);
+
+        let mut tampered = signed.clone();
+        tampered[12] ^= 1;
+        assert_eq!(receiver.verify(&tampered), Err(SignatureError::Invalid));
+        assert_eq!(
+            Signer::new(options("other")).verify(&signed),
+            Err(SignatureError::Invalid)
+        );
+
+        assert_eq!(receiver.verify(&signed), Ok(()));
+        assert_eq!(receiver.verify(&signed), Err(SignatureError::Replayed));
+        assert_eq!(receiver.verify(&frame()), Err(SignatureError::Unsigned));
+    }
+
+    #[test]
+    fn signed_link() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let gcs = SignedConnection::new(a.try_clone().unwrap(), a, options("secret"));
+        let vehicle = SignedConnection::new(b.try_clone().unwrap(), b, options("secret"));
+
+        let msg = MavMessage::COMMAND_LONG(COMMAND_LONG_DATA {
+            param1: 1.0,
+            ..Default::default()
+        });
+        MavConnection::<MavMessage>::send(&gcs, &MavHeader::default(), &msg).unwrap();
+        let (_, received) = MavConnection::<MavMessage>::recv(&vehicle).unwrap();
+
+        assert_eq!(received, msg);
+    }
+
+    #[test]
+    fn provisions() {
+        let connection: Arc<Box<TestMavConnection>> = Default::default();
+
+        super::setup_signing(connection.as_ref(), SigningKey([7; 32])).unwrap();
+
+        let Some(MavMessage::SETUP_SIGNING(data)) = connection.last_sent() else {
+            panic!("SETUP_SIGNING not sent");
+        };
+        assert_eq!(data.secret_key, [7; 32]);
+        assert_eq!(data.target_system, connection.target_system());
+    }
+}
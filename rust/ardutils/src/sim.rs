@@ -0,0 +1,1141 @@
+// A simulated vehicle speaking enough of ArduPlane's MAVLink to exercise ardutils without SITL.
+// It answers the mission, parameter, command and FTP protocols, reflects mode changes in its
+// HEARTBEAT, and flies towards its current target in a straight line at a constant speed.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Condvar, Mutex,
+    },
+};
+
+use mavlink::{
+    ardupilotmega::{
+        MavAutopilot, MavCmd, MavMessage, MavMissionResult, MavModeFlag, MavParamType, MavResult,
+        MavState, MavType, MissionState, PlaneMode, COMMAND_ACK_DATA, FILE_TRANSFER_PROTOCOL_DATA,
+        GLOBAL_POSITION_INT_DATA, GPS_GLOBAL_ORIGIN_DATA, HEARTBEAT_DATA, HOME_POSITION_DATA,
+        MISSION_ACK_DATA, MISSION_COUNT_DATA, MISSION_CURRENT_DATA, MISSION_ITEM_INT_DATA,
+        MISSION_ITEM_REACHED_DATA, MISSION_REQUEST_INT_DATA, PARAM_VALUE_DATA, TIMESYNC_DATA,
+    },
+    error::{MessageReadError, MessageWriteError},
+    MavConnection, MavHeader, MavlinkVersion, Message,
+};
+
+use crate::{
+    ftp::payload::{crc32, Opcode, Payload, MAX_DATA_LEN},
+    geo::Location,
+    param::{param_id, param_name},
+};
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Options {
+    pub system_id: u8,
+    pub component_id: u8,
+    pub heartbeat_period: std::time::Duration,
+    // Period of GLOBAL_POSITION_INT, which is also the step of the position model.
+    pub telemetry_period: std::time::Duration,
+    // Ground speed in m/s, until changed by MAV_CMD_DO_CHANGE_SPEED.
+    pub speed: f32,
+    // Distance in meters under which a target is considered reached.
+    pub acceptance_radius: f32,
+    // Altitude in meters AMSL.
+    pub home: Location,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            system_id: 1,
+            component_id: 1,
+            heartbeat_period: std::time::Duration::from_secs(1),
+            telemetry_period: std::time::Duration::from_millis(100),
+            speed: 20.0,
+            acceptance_radius: 2.0,
+            home: Location {
+                lat: 47.397742,
+                lon: 8.545594,
+                alt: 488.0,
+            },
+        }
+    }
+}
+
+// Runs before the default handling of every message sent to the vehicle. Returning Some
+// replaces the default handling with the given responses.
+pub type Handler = Box<dyn Fn(&MavMessage) -> Option<Vec<MavMessage>> + Send + Sync>;
+
+// Decides whether a message sent by the vehicle is lost on its way, see `SimVehicle::set_loss`.
+pub type Loss = Box<dyn Fn(&MavMessage) -> bool + Send + Sync>;
+
+// NAK error codes, see https://mavlink.io/en/services/ftp.html#error_codes
+const FTP_EOF: u8 = 6;
+const FTP_UNKNOWN_COMMAND: u8 = 7;
+const FTP_FILE_NOT_FOUND: u8 = 10;
+const FTP_INVALID_SESSION: u8 = 4;
+const FTP_NO_SESSIONS_AVAILABLE: u8 = 5;
+
+// The single FTP session ArduPilot allows.
+struct FtpSession {
+    id: u8,
+    path: String,
+}
+
+struct Param {
+    id: String,
+    value: f32,
+    param_type: MavParamType,
+}
+
+struct State {
+    outbox: VecDeque<MavMessage>,
+    received: Vec<MavMessage>,
+    handler: Option<Handler>,
+    loss: Option<Loss>,
+    command_results: Vec<(MavCmd, MavResult)>,
+
+    custom_mode: u32,
+    armed: bool,
+    // Altitude relative to home.
+    position: Location,
+    // North, east and down in m/s.
+    velocity: (f32, f32, f32),
+    speed: f32,
+    // Where the vehicle flies to outside of AUTO.
+    target: Option<Location>,
+    // Altitudes AMSL, like `Options::home`.
+    home: Location,
+    ekf_origin: Option<Location>,
+
+    mission: Vec<MISSION_ITEM_INT_DATA>,
+    upload: Option<Upload>,
+    // Seq of the last item of the finished upload, acknowledged again if it is sent again.
+    uploaded: Option<u16>,
+    current: u16,
+
+    params: Vec<Param>,
+
+    // In memory file system served over FTP, by path.
+    files: BTreeMap<String, Vec<u8>>,
+    ftp_session: Option<FtpSession>,
+    // Seq of the last FTP request and its responses, resent when the request is retransmitted.
+    ftp_last: Option<(u16, Vec<MavMessage>)>,
+
+    booted: std::time::Instant,
+    last_heartbeat: Option<std::time::Instant>,
+    last_telemetry: std::time::Instant,
+}
+
+// A mission upload in progress.
+struct Upload {
+    // Seqs expected. A partial upload replaces them in the mission, a full one the whole mission.
+    range: std::ops::Range<u16>,
+    partial: bool,
+    // Received so far, in order.
+    items: Vec<MISSION_ITEM_INT_DATA>,
+}
+
+fn is_nav(item: &MISSION_ITEM_INT_DATA) -> bool {
+    item.command as u32 <= MavCmd::MAV_CMD_NAV_LAST as u32
+}
+
+impl State {
+    fn new(options: &Options) -> Self {
+        let now = std::time::Instant::now();
+        Self {
+            outbox: VecDeque::new(),
+            received: vec![],
+            handler: None,
+            loss: None,
+            command_results: vec![],
+            custom_mode: PlaneMode::PLANE_MODE_MANUAL as u32,
+            armed: false,
+            position: Location {
+                alt: 0.0,
+                ..options.home
+            },
+            velocity: (0.0, 0.0, 0.0),
+            speed: options.speed,
+            target: None,
+            home: options.home,
+            ekf_origin: None,
+            mission: vec![],
+            upload: None,
+            uploaded: None,
+            current: 0,
+            params: vec![],
+            files: BTreeMap::new(),
+            ftp_session: None,
+            ftp_last: None,
+            booted: now,
+            last_heartbeat: None,
+            last_telemetry: now,
+        }
+    }
+
+    fn heartbeat(&self) -> MavMessage {
+        let mut base_mode = MavModeFlag::MAV_MODE_FLAG_CUSTOM_MODE_ENABLED;
+        if self.armed {
+            base_mode |= MavModeFlag::MAV_MODE_FLAG_SAFETY_ARMED;
+        }
+        MavMessage::HEARTBEAT(HEARTBEAT_DATA {
+            custom_mode: self.custom_mode,
+            mavtype: MavType::MAV_TYPE_FIXED_WING,
+            autopilot: MavAutopilot::MAV_AUTOPILOT_ARDUPILOTMEGA,
+            base_mode,
+            system_status: if self.armed {
+                MavState::MAV_STATE_ACTIVE
+            } else {
+                MavState::MAV_STATE_STANDBY
+            },
+            mavlink_version: 3,
+        })
+    }
+
+    fn global_position(&self, options: &Options) -> MavMessage {
+        let (vn, ve, vd) = self.velocity;
+        let hdg = if vn == 0.0 && ve == 0.0 {
+            u16::MAX
+        } else {
+            (ve.atan2(vn).to_degrees().rem_euclid(360.0) * 100.0) as u16
+        };
+        MavMessage::GLOBAL_POSITION_INT(GLOBAL_POSITION_INT_DATA {
+            time_boot_ms: self.booted.elapsed().as_millis() as u32,
+            lat: self.position.lat_int(),
+            lon: self.position.lon_int(),
+            alt: ((options.home.alt + self.position.alt) * 1000.0) as i32,
+            relative_alt: (self.position.alt * 1000.0) as i32,
+            vx: (vn * 100.0) as i16,
+            vy: (ve * 100.0) as i16,
+            vz: (vd * 100.0) as i16,
+            hdg,
+        })
+    }
+
+    fn home_position(&self) -> MavMessage {
+        MavMessage::HOME_POSITION(HOME_POSITION_DATA {
+            latitude: self.home.lat_int(),
+            longitude: self.home.lon_int(),
+            altitude: (self.home.alt * 1000.0) as i32,
+            ..Default::default()
+        })
+    }
+
+    fn mission_current(&self) -> MavMessage {
+        let mission_state = if self.mission.is_empty() {
+            MissionState::MISSION_STATE_NO_MISSION
+        } else if self.current as usize >= self.mission.len() {
+            MissionState::MISSION_STATE_COMPLETE
+        } else if self.custom_mode == PlaneMode::PLANE_MODE_AUTO as u32 {
+            MissionState::MISSION_STATE_ACTIVE
+        } else {
+            MissionState::MISSION_STATE_NOT_STARTED
+        };
+        MavMessage::MISSION_CURRENT(MISSION_CURRENT_DATA {
+            seq: self.current,
+            total: self.mission.len() as u16,
+            mission_state,
+            ..Default::default()
+        })
+    }
+
+    fn mission_ack(&self, mavtype: MavMissionResult) -> MavMessage {
+        MavMessage::MISSION_ACK(MISSION_ACK_DATA {
+            mavtype,
+            ..Default::default()
+        })
+    }
+
+    fn param_value(&self, index: usize) -> MavMessage {
+        let param = &self.params[index];
+        MavMessage::PARAM_VALUE(PARAM_VALUE_DATA {
+            param_value: param.value,
+            param_count: self.params.len() as u16,
+            param_index: index as u16,
+            param_id: param_id(&param.id),
+            param_type: param.param_type,
+        })
+    }
+
+    fn param_index(&self, id: &str) -> Option<usize> {
+        self.params.iter().position(|p| p.id == id)
+    }
+
+    fn set_mode(&mut self, custom_mode: u32) {
+        self.custom_mode = custom_mode;
+        self.outbox.push_back(self.heartbeat());
+    }
+
+    // Target of the mission item being flown in AUTO. Non navigation items are skipped.
+    fn mission_target(&mut self) -> Option<Location> {
+        while let Some(item) = self.mission.get(self.current as usize) {
+            if is_nav(item) {
+                // Items without coordinates, like takeoff, keep the current position.
+                let position = if item.x == 0 && item.y == 0 {
+                    self.position
+                } else {
+                    Location::from_int(item.x, item.y, 0)
+                };
+                return Some(Location {
+                    alt: item.z,
+                    ..position
+                });
+            }
+            self.current += 1;
+            self.outbox.push_back(self.mission_current());
+        }
+        None
+    }
+
+    fn step(&mut self, dt: f32, options: &Options) {
+        let auto = self.custom_mode == PlaneMode::PLANE_MODE_AUTO as u32;
+        let target = match (self.armed, auto) {
+            (false, _) => None,
+            (true, true) => self.mission_target(),
+            (true, false) => self.target,
+        };
+
+        let Some(target) = target else {
+            self.velocity = (0.0, 0.0, 0.0);
+            return;
+        };
+
+        let (north, east, down) = self.position.offset_to(&target);
+        let distance = (north * north + east * east + down * down).sqrt() as f32;
+        let travel = self.speed * dt;
+
+        if distance <= travel.max(options.acceptance_radius) {
+            self.position = target;
+            self.velocity = (0.0, 0.0, 0.0);
+            if auto {
+                self.outbox.push_back(MavMessage::MISSION_ITEM_REACHED(
+                    MISSION_ITEM_REACHED_DATA { seq: self.current },
+                ));
+                self.current += 1;
+                self.outbox.push_back(self.mission_current());
+            } else {
+                self.target = None;
+            }
+            return;
+        }
+
+        let scale = travel / distance;
+        self.position = Location {
+            alt: self.position.alt - down as f32 * scale,
+            ..self
+                .position
+                .offset(north * scale as f64, east * scale as f64)
+        };
+        self.velocity = (
+            north as f32 * scale / dt,
+            east as f32 * scale / dt,
+            down as f32 * scale / dt,
+        );
+    }
+
+    // Queues the periodic messages that are due.
+    fn tick(&mut self, options: &Options) {
+        let now = std::time::Instant::now();
+
+        if self
+            .last_heartbeat
+            .is_none_or(|last| now - last >= options.heartbeat_period)
+        {
+            self.last_heartbeat = Some(now);
+            self.outbox.push_back(self.heartbeat());
+        }
+
+        let elapsed = now - self.last_telemetry;
+        if elapsed >= options.telemetry_period {
+            self.last_telemetry = now;
+            self.step(elapsed.as_secs_f32(), options);
+            self.outbox.push_back(self.global_position(options));
+        }
+    }
+
+    // How long until `tick` has something to queue.
+    fn next_due(&self, options: &Options) -> std::time::Duration {
+        let heartbeat = self
+            .last_heartbeat
+            .map(|last| options.heartbeat_period.saturating_sub(last.elapsed()))
+            .unwrap_or_default();
+        let telemetry = options
+            .telemetry_period
+            .saturating_sub(self.last_telemetry.elapsed());
+        heartbeat.min(telemetry)
+    }
+
+    fn command(
+        &mut self,
+        command: MavCmd,
+        params: [f32; 4],
+        target: Location,
+        options: &Options,
+    ) -> MavResult {
+        let configured = self
+            .command_results
+            .iter()
+            .find(|(cmd, _)| *cmd == command)
+            .map(|(_, result)| *result);
+
+        let result = match command {
+            MavCmd::MAV_CMD_NAV_TAKEOFF if !self.armed => MavResult::MAV_RESULT_FAILED,
+            MavCmd::MAV_CMD_DO_SET_MODE
+            | MavCmd::MAV_CMD_COMPONENT_ARM_DISARM
+            | MavCmd::MAV_CMD_NAV_TAKEOFF
+            | MavCmd::MAV_CMD_DO_REPOSITION
+            | MavCmd::MAV_CMD_DO_CHANGE_SPEED
+            | MavCmd::MAV_CMD_NAV_RETURN_TO_LAUNCH
+            | MavCmd::MAV_CMD_MISSION_START
+            | MavCmd::MAV_CMD_DO_SET_HOME
+            | MavCmd::MAV_CMD_REQUEST_MESSAGE => MavResult::MAV_RESULT_ACCEPTED,
+            _ => MavResult::MAV_RESULT_UNSUPPORTED,
+        };
+        let result = configured.unwrap_or(result);
+        if result != MavResult::MAV_RESULT_ACCEPTED {
+            return result;
+        }
+
+        match command {
+            MavCmd::MAV_CMD_DO_SET_MODE => self.set_mode(params[1] as u32),
+            MavCmd::MAV_CMD_COMPONENT_ARM_DISARM => {
+                self.armed = params[0] == 1.0;
+                self.outbox.push_back(self.heartbeat());
+            }
+            MavCmd::MAV_CMD_NAV_TAKEOFF => {
+                self.target = Some(Location {
+                    alt: target.alt,
+                    ..self.position
+                });
+                self.set_mode(PlaneMode::PLANE_MODE_TAKEOFF as u32);
+            }
+            MavCmd::MAV_CMD_DO_REPOSITION => {
+                self.target = Some(target);
+                self.set_mode(PlaneMode::PLANE_MODE_GUIDED as u32);
+            }
+            MavCmd::MAV_CMD_DO_CHANGE_SPEED if params[1] > 0.0 => self.speed = params[1],
+            MavCmd::MAV_CMD_NAV_RETURN_TO_LAUNCH => {
+                self.target = Some(Location {
+                    alt: self.position.alt,
+                    ..self.home
+                });
+                self.set_mode(PlaneMode::PLANE_MODE_RTL as u32);
+            }
+            MavCmd::MAV_CMD_MISSION_START => {
+                self.current = params[0] as u16;
+                self.set_mode(PlaneMode::PLANE_MODE_AUTO as u32);
+                self.outbox.push_back(self.mission_current());
+            }
+            // The target altitude is AMSL here.
+            MavCmd::MAV_CMD_DO_SET_HOME => {
+                self.home = if params[0] == 1.0 {
+                    Location {
+                        alt: options.home.alt + self.position.alt,
+                        ..self.position
+                    }
+                } else {
+                    target
+                };
+                self.outbox.push_back(self.home_position());
+            }
+            MavCmd::MAV_CMD_REQUEST_MESSAGE => {
+                let id = params[0] as u32;
+                if id == self.home_position().message_id() {
+                    self.outbox.push_back(self.home_position());
+                }
+                if let Some(origin) = self.ekf_origin {
+                    let origin = MavMessage::GPS_GLOBAL_ORIGIN(GPS_GLOBAL_ORIGIN_DATA {
+                        latitude: origin.lat_int(),
+                        longitude: origin.lon_int(),
+                        altitude: (origin.alt * 1000.0) as i32,
+                        ..Default::default()
+                    });
+                    if id == origin.message_id() {
+                        self.outbox.push_back(origin);
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        result
+    }
+
+    // Responses to an FTP request, following ArduPilot: one session at a time, and bursts
+    // reading up to the end of the file.
+    fn ftp(&mut self, request: &Payload) -> Vec<Payload> {
+        let ack = |data: &[u8]| Payload {
+            seq: request.seq.wrapping_add(1),
+            session: request.session,
+            req_opcode: request.opcode,
+            ..Payload::request(Opcode::Ack).with_data(data)
+        };
+        let nak = |error: u8| Payload {
+            opcode: Opcode::Nak,
+            ..ack(&[error])
+        };
+        let path = String::from_utf8_lossy(&request.data).into_owned();
+        let session = self
+            .ftp_session
+            .as_ref()
+            .filter(|session| session.id == request.session)
+            .map(|session| session.path.clone());
+
+        match request.opcode {
+            Opcode::ResetSessions => {
+                self.ftp_session = None;
+                vec![ack(&[])]
+            }
+            Opcode::TerminateSession if session.is_some() => {
+                self.ftp_session = None;
+                vec![ack(&[])]
+            }
+            Opcode::ListDirectory => {
+                let prefix = format!("{}/", path.trim_end_matches('/'));
+                let mut entries: Vec<String> = vec![];
+                for (name, data) in self.files.range(prefix.clone()..) {
+                    let Some(name) = name.strip_prefix(&prefix) else {
+                        break;
+                    };
+                    let entry = match name.split_once('/') {
+                        Some((dir, _)) => format!("D{dir}"),
+                        None => format!("F{name}\t{}", data.len()),
+                    };
+                    if !entries.contains(&entry) {
+                        entries.push(entry);
+                    }
+                }
+                if entries.is_empty() && !self.files.keys().any(|name| name.starts_with(&prefix)) {
+                    return vec![nak(FTP_FILE_NOT_FOUND)];
+                }
+
+                let mut data = vec![];
+                for entry in entries.iter().skip(request.offset as usize) {
+                    if data.len() + entry.len() + 1 > MAX_DATA_LEN {
+                        break;
+                    }
+                    data.extend_from_slice(entry.as_bytes());
+                    data.push(0);
+                }
+                if data.is_empty() {
+                    vec![nak(FTP_EOF)]
+                } else {
+                    vec![ack(&data)]
+                }
+            }
+            Opcode::OpenFileRO | Opcode::CreateFile if self.ftp_session.is_some() => {
+                vec![nak(FTP_NO_SESSIONS_AVAILABLE)]
+            }
+            Opcode::OpenFileRO => match self.files.get(&path) {
+                Some(data) => {
+                    let size = (data.len() as u32).to_le_bytes();
+                    self.ftp_session = Some(FtpSession { id: 0, path });
+                    vec![Payload {
+                        session: 0,
+                        ..ack(&size)
+                    }]
+                }
+                None => vec![nak(FTP_FILE_NOT_FOUND)],
+            },
+            Opcode::CreateFile => {
+                self.files.insert(path.clone(), vec![]);
+                self.ftp_session = Some(FtpSession { id: 0, path });
+                vec![Payload {
+                    session: 0,
+                    ..ack(&[])
+                }]
+            }
+            Opcode::ReadFile | Opcode::BurstReadFile if session.is_some() => {
+                let file = &self.files[session.as_ref().unwrap()];
+                let burst = request.opcode == Opcode::BurstReadFile;
+                let mut offset = request.offset as usize;
+                if offset >= file.len() {
+                    return vec![nak(FTP_EOF)];
+                }
+
+                let mut responses = vec![];
+                while offset < file.len() {
+                    let len = (file.len() - offset)
+                        .min(request.size as usize)
+                        .min(MAX_DATA_LEN);
+                    responses.push(Payload {
+                        seq: request.seq.wrapping_add(1 + responses.len() as u16),
+                        offset: offset as u32,
+                        ..ack(&file[offset..offset + len])
+                    });
+                    offset += len;
+                    if !burst {
+                        break;
+                    }
+                }
+                if let Some(last) = responses.last_mut() {
+                    last.burst_complete = burst;
+                }
+                responses
+            }
+            Opcode::WriteFile if session.is_some() => {
+                let file = self.files.get_mut(session.as_ref().unwrap()).unwrap();
+                let end = request.offset as usize + request.data.len();
+                if file.len() < end {
+                    file.resize(end, 0);
+                }
+                file[request.offset as usize..end].copy_from_slice(&request.data);
+                vec![ack(&[])]
+            }
+            Opcode::TerminateSession
+            | Opcode::ReadFile
+            | Opcode::BurstReadFile
+            | Opcode::WriteFile => {
+                vec![nak(FTP_INVALID_SESSION)]
+            }
+            Opcode::RemoveFile => match self.files.remove(&path) {
+                Some(_) => vec![ack(&[])],
+                None => vec![nak(FTP_FILE_NOT_FOUND)],
+            },
+            Opcode::CalcFileCRC32 => match self.files.get(&path) {
+                Some(data) => vec![ack(&crc32(0, data).to_le_bytes())],
+                None => vec![nak(FTP_FILE_NOT_FOUND)],
+            },
+            _ => vec![nak(FTP_UNKNOWN_COMMAND)],
+        }
+    }
+
+    fn handle(&mut self, msg: &MavMessage, options: &Options) {
+        self.received.push(msg.clone());
+
+        if let Some(responses) = self.handler.as_ref().and_then(|handler| handler(msg)) {
+            self.outbox.extend(responses);
+            return;
+        }
+
+        match msg {
+            MavMessage::MISSION_COUNT(data) if data.count == 0 => {
+                self.mission.clear();
+                self.current = 0;
+                self.outbox
+                    .push_back(self.mission_ack(MavMissionResult::MAV_MISSION_ACCEPTED));
+            }
+            MavMessage::MISSION_COUNT(data) => {
+                self.uploaded = None;
+                self.upload = Some(Upload {
+                    range: 0..data.count,
+                    partial: false,
+                    items: vec![],
+                });
+                self.outbox
+                    .push_back(MavMessage::MISSION_REQUEST_INT(MISSION_REQUEST_INT_DATA {
+                        seq: 0,
+                        ..Default::default()
+                    }));
+            }
+            MavMessage::MISSION_WRITE_PARTIAL_LIST(data)
+                if 0 <= data.start_index
+                    && data.start_index <= data.end_index
+                    && (data.end_index as usize) < self.mission.len() =>
+            {
+                let range = data.start_index as u16..data.end_index as u16 + 1;
+                self.uploaded = None;
+                self.outbox
+                    .push_back(MavMessage::MISSION_REQUEST_INT(MISSION_REQUEST_INT_DATA {
+                        seq: range.start,
+                        ..Default::default()
+                    }));
+                self.upload = Some(Upload {
+                    range,
+                    partial: true,
+                    items: vec![],
+                });
+            }
+            MavMessage::MISSION_WRITE_PARTIAL_LIST(_) => {
+                self.outbox
+                    .push_back(self.mission_ack(MavMissionResult::MAV_MISSION_ERROR));
+            }
+            // The last item, sent again because its acknowledgement was lost.
+            MavMessage::MISSION_ITEM_INT(item)
+                if self.upload.is_none() && self.uploaded == Some(item.seq) =>
+            {
+                self.outbox
+                    .push_back(self.mission_ack(MavMissionResult::MAV_MISSION_ACCEPTED));
+            }
+            MavMessage::MISSION_ITEM_INT(item) => {
+                let Some(Upload { range, items, .. }) = self.upload.as_mut() else {
+                    self.outbox
+                        .push_back(self.mission_ack(MavMissionResult::MAV_MISSION_ERROR));
+                    return;
+                };
+                if item.seq as usize == range.start as usize + items.len() {
+                    items.push(item.clone());
+                }
+                if items.len() == range.len() {
+                    let upload = self.upload.take().unwrap();
+                    self.uploaded = Some(upload.range.end - 1);
+                    if upload.partial {
+                        let range = upload.range.start as usize..upload.range.end as usize;
+                        self.mission.splice(range, upload.items);
+                    } else {
+                        self.mission = upload.items;
+                        self.current = 0;
+                    }
+                    self.outbox
+                        .push_back(self.mission_ack(MavMissionResult::MAV_MISSION_ACCEPTED));
+                } else {
+                    let seq = range.start + items.len() as u16;
+                    self.outbox.push_back(MavMessage::MISSION_REQUEST_INT(
+                        MISSION_REQUEST_INT_DATA {
+                            seq,
+                            ..Default::default()
+                        },
+                    ));
+                }
+            }
+            MavMessage::MISSION_REQUEST_LIST(_) => {
+                self.outbox
+                    .push_back(MavMessage::MISSION_COUNT(MISSION_COUNT_DATA {
+                        count: self.mission.len() as u16,
+                        ..Default::default()
+                    }));
+            }
+            MavMessage::MISSION_REQUEST_INT(data) => match self.mission.get(data.seq as usize) {
+                Some(item) => self
+                    .outbox
+                    .push_back(MavMessage::MISSION_ITEM_INT(item.clone())),
+                None => self
+                    .outbox
+                    .push_back(self.mission_ack(MavMissionResult::MAV_MISSION_INVALID_SEQUENCE)),
+            },
+            MavMessage::MISSION_CLEAR_ALL(_) => {
+                self.mission.clear();
+                self.current = 0;
+                self.outbox
+                    .push_back(self.mission_ack(MavMissionResult::MAV_MISSION_ACCEPTED));
+            }
+            MavMessage::MISSION_SET_CURRENT(data) if (data.seq as usize) < self.mission.len() => {
+                self.current = data.seq;
+                self.outbox.push_back(self.mission_current());
+            }
+            MavMessage::PARAM_REQUEST_LIST(_) => {
+                for index in 0..self.params.len() {
+                    self.outbox.push_back(self.param_value(index));
+                }
+            }
+            MavMessage::PARAM_REQUEST_READ(data) => {
+                let index = match usize::try_from(data.param_index) {
+                    Ok(index) => Some(index).filter(|i| *i < self.params.len()),
+                    Err(_) => self.param_index(&param_name(&data.param_id)),
+                };
+                if let Some(index) = index {
+                    self.outbox.push_back(self.param_value(index));
+                }
+            }
+            // Like ArduPilot, unknown parameters are silently ignored.
+            MavMessage::PARAM_SET(data) => {
+                if let Some(index) = self.param_index(&param_name(&data.param_id)) {
+                    self.params[index].value = data.param_value;
+                    self.outbox.push_back(self.param_value(index));
+                }
+            }
+            MavMessage::COMMAND_LONG(data) => {
+                let target = Location {
+                    lat: data.param5 as f64,
+                    lon: data.param6 as f64,
+                    alt: data.param7,
+                };
+                let result = self.command(
+                    data.command,
+                    [data.param1, data.param2, data.param3, data.param4],
+                    target,
+                    options,
+                );
+                self.outbox
+                    .push_back(MavMessage::COMMAND_ACK(COMMAND_ACK_DATA {
+                        command: data.command,
+                        result,
+                        ..Default::default()
+                    }));
+            }
+            MavMessage::COMMAND_INT(data) => {
+                let target = Location {
+                    alt: data.z,
+                    ..Location::from_int(data.x, data.y, 0)
+                };
+                let result = self.command(
+                    data.command,
+                    [data.param1, data.param2, data.param3, data.param4],
+                    target,
+                    options,
+                );
+                self.outbox
+                    .push_back(MavMessage::COMMAND_ACK(COMMAND_ACK_DATA {
+                        command: data.command,
+                        result,
+                        ..Default::default()
+                    }));
+            }
+            MavMessage::SET_MODE(data) => self.set_mode(data.custom_mode),
+            MavMessage::FILE_TRANSFER_PROTOCOL(data) => {
+                let Some(request) = Payload::decode(&data.payload) else {
+                    return;
+                };
+                // A retransmitted request gets the same answer, it is not run twice.
+                let responses = match self.ftp_last.take() {
+                    Some((seq, responses)) if seq == request.seq => responses,
+                    _ => self
+                        .ftp(&request)
+                        .into_iter()
+                        .map(|response| {
+                            let mut data = FILE_TRANSFER_PROTOCOL_DATA::default();
+                            data.payload
+                                .extend_from_slice(&response.encode())
+                                .expect("Encoded payload always fits");
+                            MavMessage::FILE_TRANSFER_PROTOCOL(data)
+                        })
+                        .collect(),
+                };
+                self.outbox.extend(responses.iter().cloned());
+                self.ftp_last = Some((request.seq, responses));
+            }
+            MavMessage::TIMESYNC(data) if data.tc1 == 0 => {
+                self.outbox.push_back(MavMessage::TIMESYNC(TIMESYNC_DATA {
+                    tc1: self.booted.elapsed().as_nanos() as i64,
+                    ts1: data.ts1,
+                    ..Default::default()
+                }));
+            }
+            // Like ArduPilot, the origin cannot be moved once set.
+            MavMessage::SET_GPS_GLOBAL_ORIGIN(data) if self.ekf_origin.is_none() => {
+                self.ekf_origin = Some(Location::from_int(
+                    data.latitude,
+                    data.longitude,
+                    data.altitude,
+                ));
+            }
+            MavMessage::SET_POSITION_TARGET_GLOBAL_INT(data)
+                if self.custom_mode == PlaneMode::PLANE_MODE_GUIDED as u32 =>
+            {
+                self.target = Some(Location {
+                    alt: data.alt,
+                    ..Location::from_int(data.lat_int, data.lon_int, 0)
+                });
+            }
+            _ => {}
+        }
+    }
+}
+
+pub struct SimVehicle {
+    options: Options,
+    state: Mutex<State>,
+    // Signalled whenever a response is queued.
+    queued: Condvar,
+    sequence: AtomicU8,
+}
+
+impl Default for SimVehicle {
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
+
+impl std::fmt::Debug for SimVehicle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = self.state.lock().unwrap();
+        write!(
+            f,
+            "SimVehicle [mode: {}, armed: {}, position: {:?}]",
+            state.custom_mode, state.armed, state.position
+        )
+    }
+}
+
+impl SimVehicle {
+    pub fn new(options: Options) -> Self {
+        Self {
+            state: Mutex::new(State::new(&options)),
+            options,
+            queued: Condvar::new(),
+            sequence: AtomicU8::new(0),
+        }
+    }
+
+    pub fn set_handler(&self, handler: Handler) {
+        self.state.lock().unwrap().handler = Some(handler);
+    }
+
+    // Messages sent by the vehicle for which `loss` returns true are never received.
+    pub fn set_loss(&self, loss: Loss) {
+        self.state.lock().unwrap().loss = Some(loss);
+    }
+
+    pub fn set_file(&self, path: &str, data: &[u8]) {
+        self.state
+            .lock()
+            .unwrap()
+            .files
+            .insert(path.to_string(), data.to_vec());
+    }
+
+    pub fn file(&self, path: &str) -> Option<Vec<u8>> {
+        self.state.lock().unwrap().files.get(path).cloned()
+    }
+
+    // Replaces the result the vehicle acknowledges `command` with. The command only takes
+    // effect when the result is MAV_RESULT_ACCEPTED.
+    pub fn set_command_result(&self, command: MavCmd, result: MavResult) {
+        let mut state = self.state.lock().unwrap();
+        state.command_results.retain(|(cmd, _)| *cmd != command);
+        state.command_results.push((command, result));
+    }
+
+    // Adds the parameter if it does not exist yet.
+    pub fn set_param(&self, id: &str, value: f32) {
+        let mut state = self.state.lock().unwrap();
+        match state.param_index(id) {
+            Some(index) => state.params[index].value = value,
+            None => state.params.push(Param {
+                id: id.to_string(),
+                value,
+                param_type: MavParamType::MAV_PARAM_TYPE_REAL32,
+            }),
+        }
+    }
+
+    pub fn param(&self, id: &str) -> Option<f32> {
+        let state = self.state.lock().unwrap();
+        state.param_index(id).map(|index| state.params[index].value)
+    }
+
+    pub fn set_mission(&self, mission: Vec<MISSION_ITEM_INT_DATA>) {
+        let mut state = self.state.lock().unwrap();
+        state.mission = mission;
+        state.current = 0;
+    }
+
+    pub fn mission(&self) -> Vec<MISSION_ITEM_INT_DATA> {
+        self.state.lock().unwrap().mission.clone()
+    }
+
+    pub fn custom_mode(&self) -> u32 {
+        self.state.lock().unwrap().custom_mode
+    }
+
+    pub fn armed(&self) -> bool {
+        self.state.lock().unwrap().armed
+    }
+
+    pub fn position(&self) -> Location {
+        self.state.lock().unwrap().position
+    }
+
+    // Everything that was sent to the vehicle, oldest first.
+    pub fn received(&self) -> Vec<MavMessage> {
+        self.state.lock().unwrap().received.clone()
+    }
+
+    // Queues a message as if the vehicle had sent it.
+    pub fn inject(&self, msg: MavMessage) {
+        self.state.lock().unwrap().outbox.push_back(msg);
+        self.queued.notify_all();
+    }
+}
+
+impl MavConnection<MavMessage> for SimVehicle {
+    fn recv(&self) -> Result<(MavHeader, MavMessage), MessageReadError> {
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            state.tick(&self.options);
+            if let Some(msg) = state.outbox.pop_front() {
+                if state.loss.as_ref().is_some_and(|loss| loss(&msg)) {
+                    continue;
+                }
+                let header = MavHeader {
+                    system_id: self.options.system_id,
+                    component_id: self.options.component_id,
+                    sequence: self.sequence.fetch_add(1, Ordering::Relaxed),
+                };
+                return Ok((header, msg));
+            }
+
+            let wait = state.next_due(&self.options);
+            state = self.queued.wait_timeout(state, wait).unwrap().0;
+        }
+    }
+
+    fn send(&self, header: &MavHeader, data: &MavMessage) -> Result<usize, MessageWriteError> {
+        // Messages for another vehicle sharing the link are ignored, as a real one would.
+        let (target, _) = crate::router::targets(data);
+        if target == 0 || target == self.options.system_id {
+            self.state.lock().unwrap().handle(data, &self.options);
+            self.queued.notify_all();
+        }
+        mavlink::write_v2_msg(&mut vec![], *header, data)
+    }
+
+    fn set_protocol_version(&mut self, _version: MavlinkVersion) {}
+
+    fn get_protocol_version(&self) -> MavlinkVersion {
+        MavlinkVersion::V2
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use mavlink::ardupilotmega::{
+        MavCmd, MavFrame, MavMessage, MavResult, PlaneMode, COMMAND_LONG_DATA,
+        MISSION_ITEM_INT_DATA, PARAM_REQUEST_READ_DATA,
+    };
+
+    use super::{Options, SimVehicle, State};
+    use crate::{
+        command::Command,
+        connection::{FilterRes, MavlinkConnection},
+        mission::{progress::MissionProgressMonitor, upload::MissionUpload},
+        mode::ChangeMode,
+        param::param_id,
+    };
+
+    fn waypoint(seq: u16, north: f64, alt: f32) -> MISSION_ITEM_INT_DATA {
+        let target = Options::default().home.offset(north, 0.0);
+        MISSION_ITEM_INT_DATA {
+            seq,
+            x: target.lat_int(),
+            y: target.lon_int(),
+            z: alt,
+            command: MavCmd::MAV_CMD_NAV_WAYPOINT,
+            frame: MavFrame::MAV_FRAME_GLOBAL_RELATIVE_ALT_INT,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn flies_to_target() {
+        let options = Options::default();
+        let mut state = State::new(&options);
+        let target = state.position.offset(100.0, 0.0);
+        state.armed = true;
+        state.target = Some(target);
+
+        state.step(1.0, &options);
+        assert!((state.velocity.0 - options.speed).abs() < 0.1);
+        assert!(state.position.lat > options.home.lat && state.position.lat < target.lat);
+
+        for _ in 0..5 {
+            state.step(1.0, &options);
+        }
+        assert_eq!(state.position, target);
+        assert!(state.target.is_none());
+    }
+
+    #[tokio::test]
+    async fn changes_mode() {
+        let connection: Arc<Box<SimVehicle>> = Default::default();
+
+        PlaneMode::PLANE_MODE_GUIDED
+            .change_mode(connection.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            connection.custom_mode(),
+            PlaneMode::PLANE_MODE_GUIDED as u32
+        );
+    }
+
+    #[tokio::test]
+    async fn configured_ack() {
+        let connection: Arc<Box<SimVehicle>> = Default::default();
+        connection.set_command_result(
+            MavCmd::MAV_CMD_COMPONENT_ARM_DISARM,
+            MavResult::MAV_RESULT_TEMPORARILY_REJECTED,
+        );
+
+        let mut rx = COMMAND_LONG_DATA {
+            command: MavCmd::MAV_CMD_COMPONENT_ARM_DISARM,
+            param1: 1.0,
+            ..Default::default()
+        }
+        .command_monitor(connection.clone(), Some(std::time::Duration::from_secs(1)))
+        .unwrap();
+
+        tokio::time::timeout(std::time::Duration::from_secs(1), rx.changed())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            rx.borrow().as_ref().unwrap().result,
+            MavResult::MAV_RESULT_TEMPORARILY_REJECTED
+        );
+        assert!(!connection.armed());
+    }
+
+    #[tokio::test]
+    async fn reads_param() {
+        let connection: Arc<Box<SimVehicle>> = Default::default();
+        connection.set_param("TRIM_ARSPD_CM", 1200.0);
+
+        let value = connection
+            .clone()
+            .send_wait(
+                &MavMessage::PARAM_REQUEST_READ(PARAM_REQUEST_READ_DATA {
+                    param_index: -1,
+                    param_id: param_id("TRIM_ARSPD_CM"),
+                    ..Default::default()
+                }),
+                std::time::Duration::from_secs(1),
+                |msg| match msg {
+                    MavMessage::PARAM_VALUE(data) => FilterRes::Ready(Some(data.param_value)),
+                    _ => FilterRes::NotReady,
+                },
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(value, Some(1200.0));
+    }
+
+    #[tokio::test]
+    async fn flies_uploaded_mission() {
+        let connection: Arc<Box<SimVehicle>> = Arc::new(Box::new(SimVehicle::new(Options {
+            speed: 200.0,
+            ..Default::default()
+        })));
+        let mission = vec![waypoint(0, 0.0, 50.0), waypoint(1, 100.0, 50.0)];
+
+        let count = mission
+            .clone()
+            .upload_mission(connection.clone(), Default::default())
+            .await
+            .unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(connection.mission(), mission);
+
+        let mut progress = mission.progress_monitor(connection.clone(), None);
+
+        connection
+            .send(&MavMessage::COMMAND_LONG(COMMAND_LONG_DATA {
+                command: MavCmd::MAV_CMD_COMPONENT_ARM_DISARM,
+                param1: 1.0,
+                ..Default::default()
+            }))
+            .unwrap();
+        connection
+            .send(&MavMessage::COMMAND_LONG(COMMAND_LONG_DATA {
+                command: MavCmd::MAV_CMD_MISSION_START,
+                ..Default::default()
+            }))
+            .unwrap();
+
+        tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            progress.wait_for(|p| p.complete),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(progress.borrow().reached, vec![0, 1]);
+    }
+}
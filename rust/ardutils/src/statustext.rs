@@ -0,0 +1,413 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    sync::{Arc, Mutex},
+};
+
+use mavlink::{
+    ardupilotmega::{MavMessage, MavSeverity, STATUSTEXT_DATA},
+    MavHeader,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::connection::{MavlinkConnection, MonitorHandle};
+
+// Chunks of a message that stop arriving for this long are emitted as they are.
+const CHUNK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatusText {
+    pub severity: MavSeverity,
+    pub text: String,
+    pub received: std::time::SystemTime,
+}
+
+impl StatusText {
+    fn trace(&self) {
+        let (severity, text) = (self.severity, self.text.as_str());
+        match severity {
+            MavSeverity::MAV_SEVERITY_EMERGENCY
+            | MavSeverity::MAV_SEVERITY_ALERT
+            | MavSeverity::MAV_SEVERITY_CRITICAL
+            | MavSeverity::MAV_SEVERITY_ERROR => {
+                tracing::event!(tracing::Level::ERROR, ?severity, text, "STATUSTEXT")
+            }
+            MavSeverity::MAV_SEVERITY_WARNING => {
+                tracing::event!(tracing::Level::WARN, ?severity, text, "STATUSTEXT")
+            }
+            MavSeverity::MAV_SEVERITY_NOTICE | MavSeverity::MAV_SEVERITY_INFO => {
+                tracing::event!(tracing::Level::INFO, ?severity, text, "STATUSTEXT")
+            }
+            MavSeverity::MAV_SEVERITY_DEBUG => {
+                tracing::event!(tracing::Level::DEBUG, ?severity, text, "STATUSTEXT")
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Partial {
+    severity: MavSeverity,
+    chunks: Vec<(u8, Vec<u8>)>,
+    // chunk_seq of the terminated chunk, once it has arrived.
+    last: Option<u8>,
+    updated: std::time::Instant,
+}
+
+impl Partial {
+    fn is_complete(&self) -> bool {
+        self.last
+            .is_some_and(|last| self.chunks.len() == last as usize + 1)
+    }
+
+    fn finish(mut self) -> StatusText {
+        self.chunks.sort_by_key(|(seq, _)| *seq);
+        let bytes: Vec<u8> = self.chunks.into_iter().flat_map(|(_, b)| b).collect();
+        StatusText {
+            severity: self.severity,
+            text: String::from_utf8_lossy(&bytes).into_owned(),
+            received: std::time::SystemTime::now(),
+        }
+    }
+}
+
+// System and component id of the sender of a STATUSTEXT.
+pub type Sender = (u8, u8);
+
+// Reassembles MAVLink 2 chunked STATUSTEXT messages, separately for each sender.
+// A chunk with a NUL terminator is the last of its `id`, id 0 means the message is not chunked.
+// A last chunk of exactly 50 bytes has no terminator: its message is emitted once the sender moves
+// on to another id, or by `flush_stale`.
+#[derive(Debug, Default)]
+pub struct Reassembler {
+    partials: HashMap<(Sender, u16), Partial>,
+}
+
+impl Reassembler {
+    // Returns the messages completed by this chunk, oldest first.
+    pub fn push(&mut self, sender: Sender, data: STATUSTEXT_DATA) -> Vec<StatusText> {
+        let terminated = data.text.iter().position(|b| *b == 0);
+        let bytes = data.text[..terminated.unwrap_or(data.text.len())].to_vec();
+
+        // Autopilots send every chunk of a message before the next one.
+        let mut complete = self.flush(|(s, id), _| *s == sender && *id != data.id);
+
+        if data.id == 0 {
+            complete.push(
+                Partial {
+                    severity: data.severity,
+                    chunks: vec![(0, bytes)],
+                    last: Some(0),
+                    updated: std::time::Instant::now(),
+                }
+                .finish(),
+            );
+            return complete;
+        }
+
+        let key = (sender, data.id);
+        let partial = self.partials.entry(key).or_insert_with(|| Partial {
+            severity: data.severity,
+            chunks: vec![],
+            last: None,
+            updated: std::time::Instant::now(),
+        });
+        partial.updated = std::time::Instant::now();
+        if !partial.chunks.iter().any(|(seq, _)| *seq == data.chunk_seq) {
+            partial.chunks.push((data.chunk_seq, bytes));
+        }
+        if terminated.is_some() {
+            partial.last = Some(data.chunk_seq);
+        }
+
+        if partial.is_complete() {
+            complete.extend(self.partials.remove(&key).map(Partial::finish));
+        }
+        complete
+    }
+
+    // Emits every message whose chunks stopped arriving, even if it is missing some.
+    pub fn flush_stale(&mut self, timeout: std::time::Duration) -> Vec<StatusText> {
+        self.flush(|_, partial| partial.updated.elapsed() > timeout)
+    }
+
+    fn flush(&mut self, done: impl Fn(&(Sender, u16), &Partial) -> bool) -> Vec<StatusText> {
+        let keys: Vec<(Sender, u16)> = self
+            .partials
+            .iter()
+            .filter(|(key, partial)| done(key, partial))
+            .map(|(key, _)| *key)
+            .collect();
+
+        keys.into_iter()
+            .filter_map(|key| self.partials.remove(&key))
+            .map(Partial::finish)
+            .collect()
+    }
+}
+
+// Bounded history of the most recent STATUSTEXT messages, oldest first. A capacity of 0 keeps
+// nothing, for captures that only trace.
+#[derive(Debug, Clone)]
+pub struct StatusTextHistory {
+    messages: Arc<Mutex<VecDeque<StatusText>>>,
+    capacity: usize,
+}
+
+impl StatusTextHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            messages: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    pub fn push(&self, text: StatusText) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut messages = self.messages.lock().unwrap();
+        if messages.len() == self.capacity {
+            messages.pop_front();
+        }
+        messages.push_back(text);
+    }
+
+    pub fn snapshot(&self) -> Vec<StatusText> {
+        self.messages.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn latest(&self) -> Option<StatusText> {
+        self.messages.lock().unwrap().back().cloned()
+    }
+
+    pub fn since(&self, time: std::time::SystemTime) -> Vec<StatusText> {
+        self.messages
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|t| t.received >= time)
+            .cloned()
+            .collect()
+    }
+}
+
+// Reassembles STATUSTEXT, forwards it into `tracing` and records it in a history. For monitors
+// that need STATUSTEXT next to other messages: each monitor only sees part of the messages when
+// several read the same connection.
+#[derive(Debug)]
+pub struct Capture {
+    history: StatusTextHistory,
+    reassembler: Mutex<Reassembler>,
+    sender: Mutex<Sender>,
+}
+
+impl Capture {
+    pub fn new(history: StatusTextHistory) -> Self {
+        Self {
+            history,
+            reassembler: Default::default(),
+            sender: Default::default(),
+        }
+    }
+
+    // Monitors only get the message, the header goes through their validation: this is the
+    // `validate` of `monitor_validated`, accepting what the connection does and recording the
+    // sender for the `update` that follows.
+    pub fn validate<C>(
+        self: Arc<Self>,
+        connection: Arc<C>,
+    ) -> impl Fn(MavHeader) -> bool + Send + Sync + 'static
+    where
+        C: MavlinkConnection + Send + Sync + 'static,
+    {
+        move |header| {
+            let valid = connection.validate(header);
+            if valid {
+                *self.sender.lock().unwrap() = (header.system_id, header.component_id);
+            }
+            valid
+        }
+    }
+
+    pub fn history(&self) -> &StatusTextHistory {
+        &self.history
+    }
+
+    pub fn update(&self, msg: &MavMessage) {
+        let mut reassembler = self.reassembler.lock().unwrap();
+
+        let mut complete = reassembler.flush_stale(CHUNK_TIMEOUT);
+        if let MavMessage::STATUSTEXT(data) = msg {
+            let sender = *self.sender.lock().unwrap();
+            complete.extend(reassembler.push(sender, data.clone()));
+        }
+
+        for text in complete {
+            text.trace();
+            self.history.push(text);
+        }
+    }
+}
+
+// Forwards every STATUSTEXT into `tracing`, and records it in the returned history, until the
+// returned handle is dropped.
+pub fn capture<C>(connection: Arc<C>, capacity: usize) -> (StatusTextHistory, MonitorHandle)
+where
+    C: MavlinkConnection + Debug + Send + Sync + 'static,
+{
+    let capture = Arc::new(Capture::new(StatusTextHistory::new(capacity)));
+    let history = capture.history().clone();
+
+    let validate = capture.clone().validate(connection.clone());
+    let handle =
+        connection.monitor_validated(None, CancellationToken::new(), validate, move |msg| {
+            capture.update(&msg);
+            Some(())
+        });
+
+    (history, handle)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use mavlink::ardupilotmega::{MavMessage, MavSeverity, STATUSTEXT_DATA};
+
+    use super::{capture, Reassembler, StatusTextHistory};
+    use crate::connection::test::*;
+
+    const VEHICLE: super::Sender = (1, 1);
+
+    fn chunk(id: u16, chunk_seq: u8, text: &str) -> STATUSTEXT_DATA {
+        let mut data = STATUSTEXT_DATA {
+            severity: MavSeverity::MAV_SEVERITY_WARNING,
+            id,
+            chunk_seq,
+            ..Default::default()
+        };
+        data.text.extend_from_slice(text.as_bytes()).unwrap();
+        if data.text.len() < data.text.capacity() {
+            data.text.push(0).unwrap();
+        }
+        data
+    }
+
+    #[test]
+    fn reassembles_chunks() {
+        let mut reassembler = Reassembler::default();
+        let first = "PreArm: Compass not calibrated, please calibrate t";
+
+        assert_eq!(first.len(), 50);
+        assert!(reassembler.push(VEHICLE, chunk(7, 0, first)).is_empty());
+        let text = reassembler
+            .push(VEHICLE, chunk(7, 1, "he compass"))
+            .pop()
+            .unwrap();
+        assert_eq!(
+            text.text,
+            "PreArm: Compass not calibrated, please calibrate the compass"
+        );
+        assert_eq!(text.severity, MavSeverity::MAV_SEVERITY_WARNING);
+
+        // The terminated chunk can overtake the ones before it.
+        assert!(reassembler
+            .push(VEHICLE, chunk(8, 1, "he compass"))
+            .is_empty());
+        let text = reassembler.push(VEHICLE, chunk(8, 0, first)).pop().unwrap();
+        assert_eq!(
+            text.text,
+            "PreArm: Compass not calibrated, please calibrate the compass"
+        );
+    }
+
+    #[test]
+    fn flushes_stale_chunks() {
+        let mut reassembler = Reassembler::default();
+        let first = "0123456789012345678901234567890123456789012345678A";
+
+        assert!(reassembler.push(VEHICLE, chunk(3, 0, first)).is_empty());
+        assert!(reassembler
+            .flush_stale(std::time::Duration::from_secs(60))
+            .is_empty());
+
+        let flushed = reassembler.flush_stale(std::time::Duration::ZERO);
+        assert_eq!(flushed.len(), 1);
+        assert_eq!(flushed[0].text, first);
+    }
+
+    #[test]
+    fn keeps_senders_apart() {
+        let mut reassembler = Reassembler::default();
+        let first = "PreArm: Compass not calibrated, please calibrate t";
+
+        // Both vehicles number their messages from the same id.
+        assert!(reassembler.push(VEHICLE, chunk(1, 0, first)).is_empty());
+        assert_eq!(reassembler.push((2, 1), chunk(1, 0, "Other")).len(), 1);
+        let text = reassembler
+            .push(VEHICLE, chunk(1, 1, "he compass"))
+            .pop()
+            .unwrap();
+        assert_eq!(
+            text.text,
+            "PreArm: Compass not calibrated, please calibrate the compass"
+        );
+    }
+
+    #[test]
+    fn emits_unterminated_message_on_next_id() {
+        let mut reassembler = Reassembler::default();
+        let first = "0123456789012345678901234567890123456789012345678A";
+        let second = "BCDEFGHIJKLMNOPQRSTUVWXYZABCDEFGHIJKLMNOPQRSTUVWXY";
+
+        // The last chunk fills the text, nothing says it is the last.
+        assert!(reassembler.push(VEHICLE, chunk(4, 0, first)).is_empty());
+        assert!(reassembler.push(VEHICLE, chunk(4, 1, second)).is_empty());
+        // Another sender does not end it.
+        assert_eq!(reassembler.push((2, 1), chunk(5, 0, "x")).len(), 1);
+
+        let texts: Vec<String> = reassembler
+            .push(VEHICLE, chunk(5, 0, "next"))
+            .into_iter()
+            .map(|t| t.text)
+            .collect();
+        assert_eq!(texts, [format!("{first}{second}"), "next".to_string()]);
+    }
+
+    #[test]
+    fn history_is_bounded() {
+        let history = StatusTextHistory::new(2);
+        let mut reassembler = Reassembler::default();
+
+        for text in ["one", "two", "three"] {
+            history.push(reassembler.push(VEHICLE, chunk(0, 0, text)).pop().unwrap());
+        }
+
+        let texts: Vec<String> = history.snapshot().into_iter().map(|t| t.text).collect();
+        assert_eq!(texts, vec!["two", "three"]);
+
+        let history = StatusTextHistory::new(0);
+        history.push(reassembler.push(VEHICLE, chunk(0, 0, "one")).pop().unwrap());
+        assert!(history.snapshot().is_empty());
+    }
+
+    #[tokio::test]
+    async fn captures_from_connection() {
+        let connection: Arc<Box<TestMavConnection>> = Default::default();
+
+        let (history, _handle) = capture(connection.clone(), 10);
+
+        connection.inject_msg(MavMessage::STATUSTEXT(chunk(0, 0, "PreArm: Throttle high")));
+
+        tokio::time::timeout(std::time::Duration::from_secs(1), async {
+            while history.latest().is_none() {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(history.latest().unwrap().text, "PreArm: Throttle high");
+    }
+}
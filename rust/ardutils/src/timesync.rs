@@ -0,0 +1,354 @@
+// Where the vehicle clock stands against ours, to put wall clock times on telemetry stamped with
+// `time_boot_ms` or `time_usec`.
+//
+// TIMESYNC gives both the offset and the round trip: we send our time in `ts1`, the vehicle
+// answers with its time since boot in `tc1`, both in nanoseconds. Until the vehicle answers,
+// SYSTEM_TIME gives the offset, off by the link latency.
+
+use std::{collections::VecDeque, fmt::Debug, sync::Arc};
+
+use mavlink::ardupilotmega::{MavMessage, SYSTEM_TIME_DATA, TIMESYNC_DATA};
+
+use crate::{
+    connection::{MavlinkConnection, MonitorHandle},
+    error::{Error, ErrorKind},
+};
+
+// `time_usec` values above this are since the Unix epoch, below since boot: 1e15 us is 2001.
+const UNIX_USEC_THRESHOLD: u64 = 1_000_000_000_000_000;
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Options {
+    // How often to send TIMESYNC.
+    pub period: std::time::Duration,
+    // Number of recent samples the estimate is chosen from.
+    pub window: usize,
+    // Samples with a longer round trip are dropped.
+    pub max_rtt: std::time::Duration,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            period: std::time::Duration::from_secs(1),
+            window: 10,
+            max_rtt: std::time::Duration::from_millis(500),
+        }
+    }
+}
+
+// Nanoseconds since the Unix epoch on our clock.
+fn now_ns() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as i64)
+}
+
+fn system_time(ns: i64) -> std::time::SystemTime {
+    match u64::try_from(ns) {
+        Ok(ns) => std::time::UNIX_EPOCH + std::time::Duration::from_nanos(ns),
+        Err(_) => std::time::UNIX_EPOCH - std::time::Duration::from_nanos(ns.unsigned_abs()),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockSource {
+    Timesync,
+    SystemTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockEstimate {
+    // When the vehicle booted, on our clock, in nanoseconds since the Unix epoch.
+    pub boot_ns: i64,
+    // Unknown without TIMESYNC.
+    pub rtt: Option<std::time::Duration>,
+    pub source: ClockSource,
+}
+
+impl ClockEstimate {
+    pub fn boot_time(&self) -> std::time::SystemTime {
+        system_time(self.boot_ns)
+    }
+
+    // One way latency, assuming a symmetric link.
+    pub fn latency(&self) -> Option<std::time::Duration> {
+        self.rtt.map(|rtt| rtt / 2)
+    }
+
+    pub fn from_time_boot_ms(&self, time_boot_ms: u32) -> std::time::SystemTime {
+        system_time(self.boot_ns + time_boot_ms as i64 * 1_000_000)
+    }
+
+    // `time_usec` is either since boot or, once the vehicle knows the time, e.g. from GPS,
+    // since the Unix epoch. The latter is the vehicle's clock, and is returned as is.
+    pub fn from_time_usec(&self, time_usec: u64) -> std::time::SystemTime {
+        if time_usec > UNIX_USEC_THRESHOLD {
+            std::time::UNIX_EPOCH + std::time::Duration::from_micros(time_usec)
+        } else {
+            system_time(self.boot_ns + time_usec as i64 * 1_000)
+        }
+    }
+}
+
+// Keeps the recent samples, and estimates from the one with the shortest round trip, which is
+// the least skewed by an asymmetric link.
+#[derive(Debug, Clone)]
+pub struct ClockEstimator {
+    options: Options,
+    samples: VecDeque<ClockEstimate>,
+    system_time: Option<ClockEstimate>,
+}
+
+impl ClockEstimator {
+    pub fn new(options: Options) -> Self {
+        Self {
+            options,
+            samples: VecDeque::new(),
+            system_time: None,
+        }
+    }
+
+    // A TIMESYNC answer: `ts1` is our time when the request was sent, `tc1` the vehicle time
+    // when it answered, and `received` our time now, all in nanoseconds.
+    pub fn add_timesync(&mut self, ts1: i64, tc1: i64, received: i64) -> Result<(), Error> {
+        let rtt = received - ts1;
+        if rtt < 0 || rtt > self.options.max_rtt.as_nanos() as i64 {
+            return Err(Error::new(ErrorKind::InvalidResponse(format!(
+                "round trip of {rtt} ns"
+            )))
+            .operation("timesync"));
+        }
+
+        let sample = ClockEstimate {
+            boot_ns: ts1 + rtt / 2 - tc1,
+            rtt: Some(std::time::Duration::from_nanos(rtt as u64)),
+            source: ClockSource::Timesync,
+        };
+        self.push(sample);
+        Ok(())
+    }
+
+    // `received` is our time when SYSTEM_TIME arrived, in nanoseconds.
+    pub fn add_system_time(&mut self, data: &SYSTEM_TIME_DATA, received: i64) {
+        self.system_time = Some(ClockEstimate {
+            boot_ns: received - data.time_boot_ms as i64 * 1_000_000,
+            rtt: None,
+            source: ClockSource::SystemTime,
+        });
+    }
+
+    fn push(&mut self, sample: ClockEstimate) {
+        // The vehicle rebooted: older samples are about another boot.
+        if let Some(current) = self.estimate() {
+            let jump = (sample.boot_ns - current.boot_ns).unsigned_abs();
+            if jump > self.options.max_rtt.as_nanos() as u64 * 2 {
+                tracing::event!(tracing::Level::INFO, jump, "Vehicle clock jumped");
+                self.samples.clear();
+            }
+        }
+
+        self.samples.push_back(sample);
+        while self.samples.len() > self.options.window.max(1) {
+            self.samples.pop_front();
+        }
+    }
+
+    pub fn estimate(&self) -> Option<ClockEstimate> {
+        self.samples
+            .iter()
+            .min_by_key(|sample| sample.rtt)
+            .copied()
+            .or(self.system_time)
+    }
+}
+
+// Sends TIMESYNC every period, answers the vehicle's own TIMESYNC requests, and follows
+// SYSTEM_TIME, until the returned handle is dropped.
+pub fn synchronize<C>(
+    connection: Arc<C>,
+    options: Options,
+) -> (
+    tokio::sync::watch::Receiver<Option<ClockEstimate>>,
+    MonitorHandle,
+)
+where
+    C: MavlinkConnection + Debug + Send + Sync + 'static,
+{
+    let (tx, rx) = tokio::sync::watch::channel(None);
+    let cancel = tokio_util::sync::CancellationToken::new();
+    let period = options.period;
+    let estimator = std::sync::Mutex::new(ClockEstimator::new(options));
+
+    let monitor = connection.clone().monitor_until(None, cancel.clone(), {
+        let connection = connection.clone();
+        move |msg| {
+            let received = now_ns();
+            let mut estimator = estimator.lock().unwrap();
+            match msg {
+                // The vehicle asking for our time.
+                MavMessage::TIMESYNC(data) if data.tc1 == 0 => {
+                    let answer = MavMessage::TIMESYNC(TIMESYNC_DATA {
+                        tc1: received,
+                        ts1: data.ts1,
+                        ..Default::default()
+                    });
+                    if let Err(e) = connection.send(&answer) {
+                        tracing::event!(tracing::Level::WARN, %e, "Could not answer TIMESYNC");
+                    }
+                    return Some(());
+                }
+                MavMessage::TIMESYNC(data) => {
+                    if let Err(e) = estimator.add_timesync(data.ts1, data.tc1, received) {
+                        tracing::event!(tracing::Level::DEBUG, %e, "Dropping TIMESYNC");
+                        return Some(());
+                    }
+                }
+                MavMessage::SYSTEM_TIME(data) => estimator.add_system_time(&data, received),
+                _ => return Some(()),
+            }
+
+            let estimate = estimator.estimate();
+            tx.send_if_modified(|current| {
+                let modified = *current != estimate;
+                *current = estimate;
+                modified
+            });
+            Some(())
+        }
+    });
+
+    let task = tokio::spawn(async move {
+        let mut monitor = monitor;
+        let mut interval = tokio::time::interval(period);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    let request = MavMessage::TIMESYNC(TIMESYNC_DATA {
+                        tc1: 0,
+                        ts1: now_ns(),
+                        target_system: connection.target_system(),
+                        target_component: connection.target_component(),
+                    });
+                    connection.send(&request).map_err(|e| e.operation("timesync"))?;
+                }
+                res = &mut monitor => return res,
+            }
+        }
+    });
+
+    (rx, MonitorHandle::new(task, cancel))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use mavlink::ardupilotmega::SYSTEM_TIME_DATA;
+
+    use super::{synchronize, ClockEstimator, ClockSource};
+    use crate::sim::SimVehicle;
+
+    const SECOND: i64 = 1_000_000_000;
+
+    #[test]
+    fn prefers_shortest_round_trip() {
+        let mut estimator = ClockEstimator::new(Default::default());
+        assert!(estimator.estimate().is_none());
+
+        // Vehicle booted at 1000 s, SYSTEM_TIME arrived 10 ms late.
+        estimator.add_system_time(
+            &SYSTEM_TIME_DATA {
+                time_boot_ms: 5_000,
+                ..Default::default()
+            },
+            1005 * SECOND + 10_000_000,
+        );
+        let estimate = estimator.estimate().unwrap();
+        assert_eq!(estimate.source, ClockSource::SystemTime);
+        assert_eq!(estimate.boot_ns, 1000 * SECOND + 10_000_000);
+
+        // 100 ms round trip, with the answer sent halfway.
+        estimator
+            .add_timesync(
+                1010 * SECOND,
+                10 * SECOND + 50_000_000,
+                1010 * SECOND + 100_000_000,
+            )
+            .unwrap();
+        // 20 ms round trip, answered late: the vehicle time only looks a bit ahead.
+        estimator
+            .add_timesync(
+                1011 * SECOND,
+                11 * SECOND + 12_000_000,
+                1011 * SECOND + 20_000_000,
+            )
+            .unwrap();
+        // Way too slow.
+        assert!(estimator
+            .add_timesync(1012 * SECOND, 12 * SECOND, 1013 * SECOND)
+            .is_err());
+
+        let estimate = estimator.estimate().unwrap();
+        assert_eq!(estimate.source, ClockSource::Timesync);
+        assert_eq!(estimate.boot_ns, 1000 * SECOND - 2_000_000);
+        assert_eq!(
+            estimate.latency(),
+            Some(std::time::Duration::from_millis(10))
+        );
+        assert_eq!(
+            estimate.from_time_boot_ms(2_000),
+            std::time::UNIX_EPOCH + std::time::Duration::from_millis(1_001_998)
+        );
+        assert_eq!(
+            estimate.from_time_usec(2_000_000),
+            estimate.from_time_boot_ms(2_000)
+        );
+        // Already Unix time.
+        assert_eq!(
+            estimate.from_time_usec(1_700_000_000_000_000),
+            std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000)
+        );
+    }
+
+    #[test]
+    fn restarts_after_reboot() {
+        let mut estimator = ClockEstimator::new(Default::default());
+        estimator
+            .add_timesync(1000 * SECOND, 100 * SECOND, 1000 * SECOND + 2_000_000)
+            .unwrap();
+        // Rebooted at 1500 s, with a worse link than before.
+        estimator
+            .add_timesync(1510 * SECOND, 10 * SECOND, 1510 * SECOND + 50_000_000)
+            .unwrap();
+
+        let estimate = estimator.estimate().unwrap();
+        assert_eq!(estimate.boot_ns, 1500 * SECOND + 25_000_000);
+    }
+
+    #[tokio::test]
+    async fn synchronizes_with_vehicle() {
+        let connection: Arc<Box<SimVehicle>> = Default::default();
+        let booted = std::time::SystemTime::now();
+
+        let (mut estimate, _handle) = synchronize(connection.clone(), Default::default());
+
+        let estimate = tokio::time::timeout(std::time::Duration::from_secs(2), async {
+            loop {
+                estimate.changed().await.unwrap();
+                let current = *estimate.borrow_and_update();
+                if let Some(current) = current.filter(|e| e.source == ClockSource::Timesync) {
+                    return current;
+                }
+            }
+        })
+        .await
+        .unwrap();
+
+        let error = match estimate.boot_time().duration_since(booted) {
+            Ok(d) => d,
+            Err(e) => e.duration(),
+        };
+        assert!(error < std::time::Duration::from_millis(50), "{error:?}");
+    }
+}
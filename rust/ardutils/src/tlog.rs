@@ -0,0 +1,297 @@
+// Telemetry logs in the `.tlog` format used by MAVProxy, QGroundControl and Mission Planner:
+// every frame is prefixed by the time it was seen, as big-endian microseconds since the Unix epoch.
+
+use std::{
+    fmt::Debug,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+use mavlink::{
+    ardupilotmega::MavMessage,
+    error::{MessageReadError, MessageWriteError},
+    MavConnection, MavHeader, MavlinkVersion,
+};
+
+// How long `recv` waits before reporting the end of a replay again.
+const EOF_BACKOFF: std::time::Duration = std::time::Duration::from_millis(100);
+
+fn now_micros() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
+
+// Wraps a connection, and records every frame it sends or receives.
+pub struct TlogRecorder<T, W = BufWriter<File>> {
+    inner: T,
+    writer: Mutex<W>,
+}
+
+impl<T> TlogRecorder<T>
+where
+    T: MavConnection<MavMessage>,
+{
+    pub fn create(inner: T, path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Ok(Self::new(inner, BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl<T, W> TlogRecorder<T, W>
+where
+    T: MavConnection<MavMessage>,
+    W: Write,
+{
+    pub fn new(inner: T, writer: W) -> Self {
+        Self {
+            inner,
+            writer: Mutex::new(writer),
+        }
+    }
+
+    pub fn flush(&self) -> std::io::Result<()> {
+        self.writer.lock().unwrap().flush()
+    }
+
+    fn record(&self, header: MavHeader, msg: &MavMessage) {
+        let mut frame = now_micros().to_be_bytes().to_vec();
+        let res = mavlink::write_versioned_msg(
+            &mut frame,
+            self.inner.get_protocol_version(),
+            header,
+            msg,
+        )
+        .map_err(|e| format!("{e:?}"))
+        .and_then(|_| {
+            self.writer
+                .lock()
+                .unwrap()
+                .write_all(&frame)
+                .map_err(|e| e.to_string())
+        });
+
+        // A failing log should never take the link down with it.
+        if let Err(e) = res {
+            tracing::event!(tracing::Level::WARN, e, "Could not record frame");
+        }
+    }
+}
+
+impl<T, W> Debug for TlogRecorder<T, W> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "TlogRecorder")
+    }
+}
+
+impl<T, W> MavConnection<MavMessage> for TlogRecorder<T, W>
+where
+    T: MavConnection<MavMessage>,
+    W: Write,
+{
+    fn recv(&self) -> Result<(MavHeader, MavMessage), MessageReadError> {
+        let (header, msg) = self.inner.recv()?;
+        self.record(header, &msg);
+        Ok((header, msg))
+    }
+
+    fn send(&self, header: &MavHeader, data: &MavMessage) -> Result<usize, MessageWriteError> {
+        let len = self.inner.send(header, data)?;
+        self.record(*header, data);
+        Ok(len)
+    }
+
+    fn set_protocol_version(&mut self, version: MavlinkVersion) {
+        self.inner.set_protocol_version(version)
+    }
+
+    fn get_protocol_version(&self) -> MavlinkVersion {
+        self.inner.get_protocol_version()
+    }
+}
+
+struct ReplayState<R> {
+    reader: R,
+    // Wall clock and log time of the first frame, every other frame is paced from them.
+    start: Option<(std::time::Instant, u64)>,
+}
+
+// Plays a tlog back as a connection. Frames are delivered paced by their timestamps,
+// divided by `speed`, use `f64::INFINITY` to deliver them as fast as they can be read.
+// Sent messages are dropped.
+pub struct TlogReplay<R = BufReader<File>> {
+    state: Mutex<ReplayState<R>>,
+    speed: f64,
+    // Frames from this system are skipped, typically the GCS that recorded the log.
+    exclude_system: Option<u8>,
+}
+
+impl TlogReplay {
+    pub fn open(path: impl AsRef<Path>, speed: f64) -> std::io::Result<Self> {
+        Ok(Self::new(BufReader::new(File::open(path)?), speed))
+    }
+}
+
+impl<R> TlogReplay<R>
+where
+    R: Read,
+{
+    pub fn new(reader: R, speed: f64) -> Self {
+        Self {
+            state: Mutex::new(ReplayState {
+                reader,
+                start: None,
+            }),
+            speed,
+            exclude_system: None,
+        }
+    }
+
+    pub fn exclude_system(self, system_id: u8) -> Self {
+        Self {
+            exclude_system: Some(system_id),
+            ..self
+        }
+    }
+
+    fn next_frame(
+        state: &mut ReplayState<R>,
+    ) -> Result<(u64, MavHeader, MavMessage), MessageReadError> {
+        let mut timestamp = [0; 8];
+        state.reader.read_exact(&mut timestamp)?;
+
+        let mut magic = [0; 1];
+        state.reader.read_exact(&mut magic)?;
+
+        let mut frame = (&magic[..]).chain(&mut state.reader);
+        let (header, msg) = match magic[0] {
+            mavlink::MAV_STX_V2 => mavlink::read_v2_msg(&mut frame)?,
+            _ => mavlink::read_v1_msg(&mut frame)?,
+        };
+
+        Ok((u64::from_be_bytes(timestamp), header, msg))
+    }
+}
+
+impl<R> Debug for TlogReplay<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "TlogReplay [speed: {}]", self.speed)
+    }
+}
+
+impl<R> MavConnection<MavMessage> for TlogReplay<R>
+where
+    R: Read,
+{
+    fn recv(&self) -> Result<(MavHeader, MavMessage), MessageReadError> {
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            let (timestamp, header, msg) = match Self::next_frame(&mut state) {
+                Err(MessageReadError::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    // Avoid spinning the monitors that keep polling a finished replay.
+                    std::thread::sleep(EOF_BACKOFF);
+                    return Err(MessageReadError::Io(e));
+                }
+                res => res?,
+            };
+
+            if self.exclude_system == Some(header.system_id) {
+                continue;
+            }
+
+            let (started, first) = *state
+                .start
+                .get_or_insert((std::time::Instant::now(), timestamp));
+
+            if self.speed.is_finite() && self.speed > 0.0 {
+                let offset = std::time::Duration::from_micros(timestamp.saturating_sub(first));
+                let due = started + offset.div_f64(self.speed);
+                std::thread::sleep(due.saturating_duration_since(std::time::Instant::now()));
+            }
+
+            return Ok((header, msg));
+        }
+    }
+
+    fn send(&self, _header: &MavHeader, _data: &MavMessage) -> Result<usize, MessageWriteError> {
+        Ok(0)
+    }
+
+    fn set_protocol_version(&mut self, _version: MavlinkVersion) {}
+
+    fn get_protocol_version(&self) -> MavlinkVersion {
+        MavlinkVersion::V2
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mavlink::{
+        ardupilotmega::{MavMessage, COMMAND_LONG_DATA, HEARTBEAT_DATA},
+        MavConnection, MavHeader,
+    };
+
+    use super::{TlogRecorder, TlogReplay};
+    use crate::connection::test::TestMavConnection;
+
+    fn frame(timestamp: u64, header: MavHeader, msg: &MavMessage) -> Vec<u8> {
+        let mut frame = timestamp.to_be_bytes().to_vec();
+        mavlink::write_v2_msg(&mut frame, header, msg).unwrap();
+        frame
+    }
+
+    #[test]
+    fn records_both_directions() {
+        let connection = TestMavConnection::default();
+        connection.inject_msg(MavMessage::HEARTBEAT(HEARTBEAT_DATA {
+            custom_mode: 7,
+            ..Default::default()
+        }));
+
+        let recorder = TlogRecorder::new(connection, vec![]);
+        recorder.recv().unwrap();
+        recorder
+            .send(
+                &Default::default(),
+                &MavMessage::COMMAND_LONG(COMMAND_LONG_DATA::default()),
+            )
+            .unwrap();
+
+        let log = recorder.writer.into_inner().unwrap();
+        let replay = TlogReplay::new(log.as_slice(), f64::INFINITY);
+
+        assert!(matches!(
+            replay.recv().unwrap().1,
+            MavMessage::HEARTBEAT(HEARTBEAT_DATA { custom_mode: 7, .. })
+        ));
+        assert!(matches!(
+            replay.recv().unwrap().1,
+            MavMessage::COMMAND_LONG(_)
+        ));
+        assert!(replay.recv().is_err());
+    }
+
+    #[test]
+    fn replays_in_real_time() {
+        let vehicle = MavHeader {
+            system_id: 1,
+            component_id: 1,
+            sequence: 0,
+        };
+        let heartbeat = MavMessage::HEARTBEAT(Default::default());
+
+        let mut log = frame(1_000_000, vehicle, &heartbeat);
+        log.extend(frame(1_000_500, Default::default(), &heartbeat));
+        log.extend(frame(1_050_000, vehicle, &heartbeat));
+
+        let replay = TlogReplay::new(log.as_slice(), 1.0).exclude_system(255);
+        let start = std::time::Instant::now();
+
+        assert_eq!(replay.recv().unwrap().0.system_id, 1);
+        assert_eq!(replay.recv().unwrap().0.system_id, 1);
+        assert!(start.elapsed() >= std::time::Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,556 @@
+// Traffic around the vehicle: aircraft reported by ADSB_VEHICLE, and tracks from other sources,
+// e.g. a ground radar, injected by the caller. Each track is extrapolated in a straight line to
+// its closest point of approach (CPA) with the vehicle, and an alert is raised when that comes
+// within the protected volume.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    sync::{Arc, Mutex},
+};
+
+use mavlink::ardupilotmega::{
+    AdsbAltitudeType, AdsbFlags, MavMessage, ADSB_VEHICLE_DATA, GLOBAL_POSITION_INT_DATA,
+};
+
+use crate::{
+    connection::{MavlinkConnection, MonitorHandle},
+    geo::Location,
+};
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Options {
+    // Tracks not updated for this long are dropped.
+    pub stale_after: std::time::Duration,
+    // How far ahead tracks are extrapolated.
+    pub horizon: std::time::Duration,
+    // Protected volume around the vehicle, in meters: a CPA closer than both raises an alert.
+    pub horizontal_separation: f64,
+    pub vertical_separation: f32,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            stale_after: std::time::Duration::from_secs(10),
+            horizon: std::time::Duration::from_secs(60),
+            horizontal_separation: 1000.0,
+            vertical_separation: 150.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TrackId {
+    // ICAO address.
+    Adsb(u32),
+    // Numbered by whoever injects the track.
+    External(u32),
+}
+
+// North, east and up, in m/s.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Velocity {
+    pub north: f32,
+    pub east: f32,
+    pub up: f32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Track {
+    pub id: TrackId,
+    pub callsign: Option<String>,
+    // Altitude AMSL.
+    pub location: Location,
+    // Without altitude, the track is assumed to be level with the vehicle. ADS-B pressure
+    // altitudes are not comparable with AMSL ones, they count as no altitude.
+    pub altitude_valid: bool,
+    // Without velocity, the track is assumed to hold its position.
+    pub velocity: Option<Velocity>,
+    pub updated: std::time::Instant,
+}
+
+impl Track {
+    // None without valid coordinates.
+    pub fn from_adsb(data: &ADSB_VEHICLE_DATA, received: std::time::Instant) -> Option<Self> {
+        if !data.flags.contains(AdsbFlags::ADSB_FLAGS_VALID_COORDS) {
+            return None;
+        }
+
+        let velocity = data
+            .flags
+            .contains(AdsbFlags::ADSB_FLAGS_VALID_HEADING | AdsbFlags::ADSB_FLAGS_VALID_VELOCITY)
+            .then(|| {
+                let speed = data.hor_velocity as f32 / 100.0;
+                let heading = (data.heading as f32 / 100.0).to_radians();
+                let up = if data
+                    .flags
+                    .contains(AdsbFlags::ADSB_FLAGS_VERTICAL_VELOCITY_VALID)
+                {
+                    data.ver_velocity as f32 / 100.0
+                } else {
+                    0.0
+                };
+                Velocity {
+                    north: speed * heading.cos(),
+                    east: speed * heading.sin(),
+                    up,
+                }
+            });
+        let callsign = data
+            .flags
+            .contains(AdsbFlags::ADSB_FLAGS_VALID_CALLSIGN)
+            .then(|| {
+                let len = data.callsign.iter().position(|b| *b == 0);
+                let bytes = &data.callsign[..len.unwrap_or(data.callsign.len())];
+                String::from_utf8_lossy(bytes).trim().to_string()
+            });
+
+        Some(Self {
+            id: TrackId::Adsb(data.ICAO_address),
+            callsign,
+            location: Location::from_int(data.lat, data.lon, data.altitude),
+            altitude_valid: data.flags.contains(AdsbFlags::ADSB_FLAGS_VALID_ALTITUDE)
+                && data.altitude_type == AdsbAltitudeType::ADSB_ALTITUDE_TYPE_GEOMETRIC,
+            velocity,
+            // Time since the last communication with the aircraft, in seconds.
+            updated: received
+                .checked_sub(std::time::Duration::from_secs(data.tslc as u64))
+                .unwrap_or(received),
+        })
+    }
+}
+
+// Where the vehicle is, from GLOBAL_POSITION_INT.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OwnState {
+    pub location: Location,
+    pub velocity: Velocity,
+}
+
+impl From<GLOBAL_POSITION_INT_DATA> for OwnState {
+    fn from(data: GLOBAL_POSITION_INT_DATA) -> Self {
+        Self {
+            location: Location::from_int(data.lat, data.lon, data.alt),
+            velocity: Velocity {
+                north: data.vx as f32 / 100.0,
+                east: data.vy as f32 / 100.0,
+                up: -data.vz as f32 / 100.0,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cpa {
+    // From now, 0 when the track is already moving away.
+    pub time: std::time::Duration,
+    // Separations at the CPA, in meters.
+    pub horizontal: f64,
+    pub vertical: f32,
+}
+
+// Closest point of approach of `track`, within `horizon`, flat earth and straight lines. The
+// track is first moved to where it is by now, it was reported where it was when last updated.
+// The time is that of the closest horizontal approach.
+pub fn cpa(own: &OwnState, track: &Track, horizon: std::time::Duration) -> Cpa {
+    let velocity = track.velocity.unwrap_or_default();
+    let age = track.updated.elapsed().as_secs_f64();
+
+    let (north, east, down) = own.location.offset_to(&track.location);
+    let north = north + velocity.north as f64 * age;
+    let east = east + velocity.east as f64 * age;
+    let up = if track.altitude_valid {
+        -down + velocity.up as f64 * age
+    } else {
+        0.0
+    };
+
+    let vn = (velocity.north - own.velocity.north) as f64;
+    let ve = (velocity.east - own.velocity.east) as f64;
+    let vu = if track.altitude_valid {
+        (velocity.up - own.velocity.up) as f64
+    } else {
+        0.0
+    };
+
+    let speed2 = vn * vn + ve * ve;
+    let time = if speed2 > 0.0 {
+        (-(north * vn + east * ve) / speed2).clamp(0.0, horizon.as_secs_f64())
+    } else {
+        0.0
+    };
+
+    Cpa {
+        time: std::time::Duration::from_secs_f64(time),
+        horizontal: (north + vn * time).hypot(east + ve * time),
+        vertical: (up + vu * time).abs() as f32,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TrafficAlert {
+    // The track is predicted to enter the protected volume.
+    Proximity {
+        id: TrackId,
+        callsign: Option<String>,
+        cpa: Cpa,
+    },
+    // The track no longer is, or was dropped.
+    Clear(TrackId),
+}
+
+#[derive(Debug)]
+struct Table {
+    options: Options,
+    own: Option<OwnState>,
+    tracks: HashMap<TrackId, Track>,
+    alerted: HashSet<TrackId>,
+    alerts: tokio::sync::mpsc::UnboundedSender<TrafficAlert>,
+}
+
+impl Table {
+    fn alert(&self, alert: TrafficAlert) {
+        tracing::event!(tracing::Level::WARN, ?alert, "Traffic");
+        // Nobody listening is not an error, the table is still kept.
+        let _ = self.alerts.send(alert);
+    }
+
+    fn expire(&mut self, now: std::time::Instant) {
+        let stale_after = self.options.stale_after;
+        let stale: Vec<TrackId> = self
+            .tracks
+            .values()
+            .filter(|track| now.saturating_duration_since(track.updated) > stale_after)
+            .map(|track| track.id)
+            .collect();
+
+        for id in stale {
+            self.tracks.remove(&id);
+            if self.alerted.remove(&id) {
+                self.alert(TrafficAlert::Clear(id));
+            }
+        }
+    }
+
+    fn check(&mut self, id: TrackId) {
+        let (Some(own), Some(track)) = (self.own, self.tracks.get(&id)) else {
+            return;
+        };
+        let cpa = cpa(&own, track, self.options.horizon);
+        let close = cpa.horizontal <= self.options.horizontal_separation
+            && cpa.vertical <= self.options.vertical_separation;
+
+        if close && self.alerted.insert(id) {
+            let callsign = track.callsign.clone();
+            self.alert(TrafficAlert::Proximity { id, callsign, cpa });
+        } else if !close && self.alerted.remove(&id) {
+            self.alert(TrafficAlert::Clear(id));
+        }
+    }
+
+    fn update(&mut self, track: Track) {
+        let id = track.id;
+        self.tracks.insert(id, track);
+        self.check(id);
+    }
+
+    fn update_own(&mut self, own: OwnState) {
+        self.own = Some(own);
+        let ids: Vec<TrackId> = self.tracks.keys().copied().collect();
+        for id in ids {
+            self.check(id);
+        }
+    }
+}
+
+// Tracks known around the vehicle, shared with the monitor feeding it.
+#[derive(Debug, Clone)]
+pub struct TrafficTable {
+    table: Arc<Mutex<Table>>,
+}
+
+impl TrafficTable {
+    pub fn new(options: Options) -> (Self, tokio::sync::mpsc::UnboundedReceiver<TrafficAlert>) {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let table = Table {
+            options,
+            own: None,
+            tracks: HashMap::new(),
+            alerted: HashSet::new(),
+            alerts: tx,
+        };
+        let table = Self {
+            table: Arc::new(Mutex::new(table)),
+        };
+        (table, rx)
+    }
+
+    // Adds or replaces a track from another source than ADS-B.
+    pub fn inject(&self, track: Track) {
+        let mut table = self.table.lock().unwrap();
+        table.expire(std::time::Instant::now());
+        table.update(track);
+    }
+
+    pub fn update_own(&self, own: OwnState) {
+        let mut table = self.table.lock().unwrap();
+        table.expire(std::time::Instant::now());
+        table.update_own(own);
+    }
+
+    pub fn expire(&self, now: std::time::Instant) {
+        self.table.lock().unwrap().expire(now);
+    }
+
+    pub fn own(&self) -> Option<OwnState> {
+        self.table.lock().unwrap().own
+    }
+
+    pub fn tracks(&self) -> Vec<Track> {
+        self.table
+            .lock()
+            .unwrap()
+            .tracks
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    // Tracks with their CPA, closest first. Empty until the vehicle position is known.
+    pub fn closest(&self) -> Vec<(Track, Cpa)> {
+        let table = self.table.lock().unwrap();
+        let Some(own) = table.own else {
+            return vec![];
+        };
+        let mut tracks: Vec<(Track, Cpa)> = table
+            .tracks
+            .values()
+            .map(|track| (track.clone(), cpa(&own, track, table.options.horizon)))
+            .collect();
+        tracks.sort_by(|(_, a), (_, b)| a.horizontal.total_cmp(&b.horizontal));
+        tracks
+    }
+}
+
+// Feeds a traffic table from ADSB_VEHICLE and GLOBAL_POSITION_INT, until the returned handle is
+// dropped. Other tracks can be added with `TrafficTable::inject`.
+pub fn track_traffic<C>(
+    connection: Arc<C>,
+    options: Options,
+) -> (
+    TrafficTable,
+    tokio::sync::mpsc::UnboundedReceiver<TrafficAlert>,
+    MonitorHandle,
+)
+where
+    C: MavlinkConnection + Debug + Send + Sync + 'static,
+{
+    let (table, alerts) = TrafficTable::new(options);
+
+    let handle = connection.monitor(None, {
+        let table = table.clone();
+        move |msg| {
+            let now = std::time::Instant::now();
+            match msg {
+                MavMessage::ADSB_VEHICLE(data) => match Track::from_adsb(&data, now) {
+                    Some(track) => table.inject(track),
+                    None => table.expire(now),
+                },
+                MavMessage::GLOBAL_POSITION_INT(data) => table.update_own(data.into()),
+                _ => {}
+            }
+            Some(())
+        }
+    });
+
+    (table, alerts, handle)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use mavlink::ardupilotmega::{AdsbAltitudeType, AdsbFlags, MavMessage, ADSB_VEHICLE_DATA};
+
+    use super::{
+        cpa, track_traffic, OwnState, Track, TrackId, TrafficAlert, TrafficTable, Velocity,
+    };
+    use crate::{geo::Location, sim::SimVehicle};
+
+    const OWN: OwnState = OwnState {
+        location: Location {
+            lat: 47.0,
+            lon: 8.0,
+            alt: 500.0,
+        },
+        velocity: Velocity {
+            north: 0.0,
+            east: 0.0,
+            up: 0.0,
+        },
+    };
+
+    fn track(id: u32, north: f64, velocity: Velocity) -> Track {
+        Track {
+            id: TrackId::External(id),
+            callsign: None,
+            location: Location {
+                alt: 550.0,
+                ..OWN.location.offset(north, 0.0)
+            },
+            altitude_valid: true,
+            velocity: Some(velocity),
+            updated: std::time::Instant::now(),
+        }
+    }
+
+    #[test]
+    fn computes_closest_approach() {
+        let horizon = std::time::Duration::from_secs(60);
+
+        // Flying south, passing 100 m east.
+        let mut crossing = track(
+            1,
+            2000.0,
+            Velocity {
+                north: -50.0,
+                ..Default::default()
+            },
+        );
+        crossing.location = crossing.location.offset(0.0, 100.0);
+        let res = cpa(&OWN, &crossing, horizon);
+        assert!((res.time.as_secs_f64() - 40.0).abs() < 0.01, "{res:?}");
+        assert!((res.horizontal - 100.0).abs() < 0.1, "{res:?}");
+        assert!((res.vertical - 50.0).abs() < 0.01, "{res:?}");
+
+        // Flying away.
+        let leaving = track(
+            2,
+            2000.0,
+            Velocity {
+                north: 50.0,
+                ..Default::default()
+            },
+        );
+        let res = cpa(&OWN, &leaving, horizon);
+        assert_eq!(res.time, std::time::Duration::ZERO);
+        assert!((res.horizontal - 2000.0).abs() < 0.1, "{res:?}");
+
+        // Too far to be reached within the horizon.
+        let res = cpa(&OWN, &crossing, std::time::Duration::from_secs(20));
+        assert!((res.horizontal - 1005.0).abs() < 1.0, "{res:?}");
+    }
+
+    #[test]
+    fn extrapolates_by_age() {
+        let horizon = std::time::Duration::from_secs(60);
+        let mut old = track(
+            3,
+            2000.0,
+            Velocity {
+                north: -50.0,
+                up: -1.0,
+                ..Default::default()
+            },
+        );
+        old.updated -= std::time::Duration::from_secs(20);
+
+        // Reported 2000 m north 20 s ago, it is 1000 m north by now.
+        let res = cpa(&OWN, &old, horizon);
+        assert!((res.time.as_secs_f64() - 20.0).abs() < 0.1, "{res:?}");
+        assert!(res.horizontal < 5.0, "{res:?}");
+        assert!((res.vertical - 10.0).abs() < 0.2, "{res:?}");
+    }
+
+    #[test]
+    fn pressure_altitude_is_unknown() {
+        let mut data = ADSB_VEHICLE_DATA {
+            lat: 470_000_000,
+            lon: 80_000_000,
+            altitude: 3_000_000,
+            flags: AdsbFlags::ADSB_FLAGS_VALID_COORDS | AdsbFlags::ADSB_FLAGS_VALID_ALTITUDE,
+            altitude_type: AdsbAltitudeType::ADSB_ALTITUDE_TYPE_PRESSURE_QNH,
+            ..Default::default()
+        };
+        let now = std::time::Instant::now();
+
+        let track = Track::from_adsb(&data, now).unwrap();
+        assert!(!track.altitude_valid);
+        assert_eq!(
+            cpa(&OWN, &track, std::time::Duration::from_secs(60)).vertical,
+            0.0
+        );
+
+        data.altitude_type = AdsbAltitudeType::ADSB_ALTITUDE_TYPE_GEOMETRIC;
+        assert!(Track::from_adsb(&data, now).unwrap().altitude_valid);
+    }
+
+    #[test]
+    fn alerts_and_expires() {
+        let (table, mut alerts) = TrafficTable::new(Default::default());
+        table.update_own(OWN);
+
+        let approaching = track(
+            7,
+            3000.0,
+            Velocity {
+                north: -60.0,
+                ..Default::default()
+            },
+        );
+        table.inject(approaching.clone());
+        let Ok(TrafficAlert::Proximity { id, cpa, .. }) = alerts.try_recv() else {
+            panic!("no alert");
+        };
+        assert_eq!(id, TrackId::External(7));
+        assert!((cpa.time.as_secs_f64() - 50.0).abs() < 0.01, "{cpa:?}");
+        // Raised once.
+        table.inject(approaching);
+        assert!(alerts.try_recv().is_err());
+
+        table.expire(std::time::Instant::now() + std::time::Duration::from_secs(11));
+        assert!(table.tracks().is_empty());
+        assert_eq!(
+            alerts.try_recv().unwrap(),
+            TrafficAlert::Clear(TrackId::External(7))
+        );
+    }
+
+    #[tokio::test]
+    async fn follows_adsb() {
+        let connection: Arc<Box<SimVehicle>> = Default::default();
+        let (table, mut alerts, _handle) = track_traffic(connection.clone(), Default::default());
+
+        // Home is at 47.397742, 8.545594, 488 m.
+        connection.inject(MavMessage::ADSB_VEHICLE(ADSB_VEHICLE_DATA {
+            ICAO_address: 0x4b1234,
+            lat: 474_157_420,
+            lon: 85_455_940,
+            altitude: 550_000,
+            // South, 70 m/s.
+            heading: 18000,
+            hor_velocity: 7000,
+            flags: AdsbFlags::ADSB_FLAGS_VALID_COORDS
+                | AdsbFlags::ADSB_FLAGS_VALID_ALTITUDE
+                | AdsbFlags::ADSB_FLAGS_VALID_HEADING
+                | AdsbFlags::ADSB_FLAGS_VALID_VELOCITY
+                | AdsbFlags::ADSB_FLAGS_VALID_CALLSIGN,
+            altitude_type: AdsbAltitudeType::ADSB_ALTITUDE_TYPE_GEOMETRIC,
+            callsign: *b"SWR123\0\0\0",
+            ..Default::default()
+        }));
+
+        let alert = tokio::time::timeout(std::time::Duration::from_secs(1), alerts.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        let TrafficAlert::Proximity { id, callsign, cpa } = alert else {
+            panic!("{alert:?}");
+        };
+        assert_eq!(id, TrackId::Adsb(0x4b1234));
+        assert_eq!(callsign.as_deref(), Some("SWR123"));
+        assert!(cpa.horizontal < 10.0, "{cpa:?}");
+        assert_eq!(table.closest().len(), 1);
+    }
+}